@@ -0,0 +1,331 @@
+//! GUID Partition Table parsing.
+//!
+//! The kernel exposes partition numbers and names, but neither partition UUIDs nor
+//! partition types. Both are needed for `root=PARTUUID=...` and for
+//! [GPT partition autodiscovery](https://systemd.io/DISCOVERABLE_PARTITIONS/), so the
+//! partition table is read directly from the disk.
+
+use crate::util::crc32_update;
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+/// GPT header signature.
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+/// Minimum (and usual) size of the GPT header.
+const GPT_HEADER_MIN_SIZE: usize = 92;
+
+/// Minimum (and usual) size of each partition entry.
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+
+/// Upper bound on the size of the partition entry array, to prevent a corrupt header from
+/// making us read the whole disk.
+const GPT_ENTRIES_MAX_SIZE: usize = 1024 * 1024;
+
+/// Attribute: the partition should not be automatically mounted (discoverable partitions).
+pub const GPT_ATTR_NO_AUTO: u64 = 1 << 63;
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// GUIDs are stored in mixed-endian format: the first three fields are little-endian.
+fn guid_from(raw: &[u8]) -> uuid::Uuid {
+    let mut bytes: [u8; 16] = raw[..16].try_into().unwrap();
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    uuid::Uuid::from_bytes(bytes)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+/// Single (used) GPT partition entry.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GptPartition {
+    number: u32,
+    type_guid: uuid::Uuid,
+    unique_guid: uuid::Uuid,
+    attributes: u64,
+    name: String,
+}
+impl GptPartition {
+    /// Partition number as assigned by the kernel: entry index + 1.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Partition type GUID.
+    pub fn type_guid(&self) -> uuid::Uuid {
+        self.type_guid
+    }
+
+    /// Unique partition GUID (`PARTUUID`).
+    pub fn unique_guid(&self) -> uuid::Uuid {
+        self.unique_guid
+    }
+
+    /// Check whether the given attribute flag(s) (`GPT_ATTR_*`) are set.
+    pub fn has_attribute(&self, attribute: u64) -> bool {
+        self.attributes & attribute == attribute
+    }
+
+    /// Partition name (`PARTLABEL`).
+    pub fn name(&self) -> &str {
+        &self.name[..]
+    }
+}
+
+/// GUID Partition Table of a disk.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Gpt {
+    partitions: Vec<GptPartition>,
+}
+impl Gpt {
+    /// Read the partition table of `disk`, whose logical blocks are `lba_size` bytes long
+    /// and whose total size is `disk_size` bytes.
+    ///
+    /// The primary header is read first. If it (or its partition entry array) is corrupt,
+    /// the backup header is read instead. `None` is returned if the disk doesn't contain
+    /// a valid GPT.
+    pub fn read(disk: &File, lba_size: u64, disk_size: u64) -> io::Result<Option<Self>> {
+        if lba_size < 512 || disk_size < lba_size * 2 {
+            return Ok(None);
+        }
+        let last_lba = disk_size / lba_size - 1;
+
+        if let Some(gpt) = Self::read_at_lba(disk, lba_size, 1)? {
+            return Ok(Some(gpt));
+        }
+        Self::read_at_lba(disk, lba_size, last_lba)
+    }
+
+    fn read_at_lba(disk: &File, lba_size: u64, lba: u64) -> io::Result<Option<Self>> {
+        let mut hdr = vec![0; lba_size as usize];
+        match disk.read_exact_at(&mut hdr, lba * lba_size) {
+            Ok(()) => {}
+            Err(io) if io.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(io) => return Err(io),
+        }
+        if !hdr.starts_with(GPT_SIGNATURE) {
+            return Ok(None);
+        }
+
+        let hdr_size = le32(&hdr, 12) as usize;
+        if hdr_size < GPT_HEADER_MIN_SIZE || hdr_size > hdr.len() {
+            return Ok(None);
+        }
+        let hdr_crc = le32(&hdr, 16);
+        hdr[16..20].fill(0);
+        if crc32(&hdr[..hdr_size]) != hdr_crc || le64(&hdr, 24) != lba {
+            return Ok(None);
+        }
+
+        let entries_lba = le64(&hdr, 72);
+        let num_entries = le32(&hdr, 80) as usize;
+        let entry_size = le32(&hdr, 84) as usize;
+        let entries_crc = le32(&hdr, 88);
+        if entry_size < GPT_ENTRY_MIN_SIZE
+            || !entry_size.is_multiple_of(8)
+            || num_entries.saturating_mul(entry_size) > GPT_ENTRIES_MAX_SIZE
+        {
+            return Ok(None);
+        }
+
+        let mut entries = vec![0; num_entries * entry_size];
+        match disk.read_exact_at(&mut entries, entries_lba * lba_size) {
+            Ok(()) => {}
+            Err(io) if io.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(io) => return Err(io),
+        }
+        if crc32(&entries) != entries_crc {
+            return Ok(None);
+        }
+
+        let partitions = entries
+            .chunks_exact(entry_size)
+            .enumerate()
+            .filter_map(|(i, entry)| {
+                let type_guid = guid_from(&entry[0..16]);
+                if type_guid.is_nil() {
+                    // Unused entry
+                    return None;
+                }
+                let name: Vec<u16> = entry[56..128]
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect();
+                Some(GptPartition {
+                    number: i as u32 + 1,
+                    type_guid,
+                    unique_guid: guid_from(&entry[16..32]),
+                    attributes: le64(entry, 48),
+                    name: String::from_utf16_lossy(&name),
+                })
+            })
+            .collect();
+
+        Ok(Some(Self { partitions }))
+    }
+
+    /// Get the partition with the given (kernel) partition number.
+    pub fn partition(&self, number: u32) -> Option<&GptPartition> {
+        self.partitions.iter().find(|p| p.number == number)
+    }
+
+    /// Get the partition with the given unique GUID (`PARTUUID`).
+    pub fn partition_by_guid(&self, unique_guid: uuid::Uuid) -> Option<&GptPartition> {
+        self.partitions
+            .iter()
+            .find(|p| p.unique_guid == unique_guid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestImage;
+
+    const LBA_SIZE: usize = 512;
+    const DISK_LBAS: usize = 64;
+    const NUM_ENTRIES: usize = 4;
+
+    const LINUX_ROOT: &str = "4f68bce3-e8cd-4db1-96e7-fbcaf984b709";
+    const ESP: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+    const ROOT_GUID: &str = "6f1b4d2a-9c3e-4b7a-8d55-0e2f1a3c4b5d";
+    const ESP_GUID: &str = "0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d";
+
+    // GUID in on-disk (mixed-endian) format
+    fn guid_bytes(guid: &str) -> [u8; 16] {
+        let mut bytes = *uuid::Uuid::parse_str(guid).unwrap().as_bytes();
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes
+    }
+
+    // Partition entry array: the ESP, an unused entry and the (no-auto) root partition
+    fn entries() -> Vec<u8> {
+        let mut entries = vec![0; NUM_ENTRIES * GPT_ENTRY_MIN_SIZE];
+        let parts = [
+            (0, ESP, ESP_GUID, 0, "esp"),
+            (2, LINUX_ROOT, ROOT_GUID, GPT_ATTR_NO_AUTO, "root"),
+        ];
+        for (index, type_guid, unique_guid, attributes, name) in parts {
+            let entry = &mut entries[index * GPT_ENTRY_MIN_SIZE..][..GPT_ENTRY_MIN_SIZE];
+            entry[0..16].copy_from_slice(&guid_bytes(type_guid));
+            entry[16..32].copy_from_slice(&guid_bytes(unique_guid));
+            entry[48..56].copy_from_slice(&attributes.to_le_bytes());
+            for (i, c) in name.encode_utf16().enumerate() {
+                entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        entries
+    }
+
+    // Header at `lba`, whose partition entry array is at `entries_lba`
+    fn header(lba: u64, alternate_lba: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut hdr = vec![0; LBA_SIZE];
+        hdr[0..8].copy_from_slice(GPT_SIGNATURE);
+        hdr[8..12].copy_from_slice(&0x00010000u32.to_le_bytes());
+        hdr[12..16].copy_from_slice(&(GPT_HEADER_MIN_SIZE as u32).to_le_bytes());
+        hdr[24..32].copy_from_slice(&lba.to_le_bytes());
+        hdr[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        hdr[40..48].copy_from_slice(&3u64.to_le_bytes());
+        hdr[48..56].copy_from_slice(&(DISK_LBAS as u64 - 3).to_le_bytes());
+        hdr[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        hdr[80..84].copy_from_slice(&(NUM_ENTRIES as u32).to_le_bytes());
+        hdr[84..88].copy_from_slice(&(GPT_ENTRY_MIN_SIZE as u32).to_le_bytes());
+        hdr[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        let crc = crc32(&hdr[..GPT_HEADER_MIN_SIZE]);
+        hdr[16..20].copy_from_slice(&crc.to_le_bytes());
+        hdr
+    }
+
+    // Disk with both the primary and the backup GPT
+    fn disk() -> Vec<u8> {
+        let last_lba = DISK_LBAS - 1;
+        let entries = entries();
+        let mut disk = vec![0; DISK_LBAS * LBA_SIZE];
+        let mut put = |lba: usize, data: &[u8]| {
+            disk[lba * LBA_SIZE..lba * LBA_SIZE + data.len()].copy_from_slice(data)
+        };
+        put(1, &header(1, last_lba as u64, 2, &entries));
+        put(2, &entries);
+        put(last_lba - 1, &entries);
+        put(
+            last_lba,
+            &header(last_lba as u64, 1, last_lba as u64 - 1, &entries),
+        );
+        disk
+    }
+
+    fn read(disk: &[u8]) -> Option<Gpt> {
+        Gpt::read(
+            TestImage::new(disk).file(),
+            LBA_SIZE as u64,
+            disk.len() as u64,
+        )
+        .unwrap()
+    }
+
+    fn guid(guid: &str) -> uuid::Uuid {
+        uuid::Uuid::parse_str(guid).unwrap()
+    }
+
+    #[test]
+    fn primary() {
+        let gpt = read(&disk()).expect("no GPT found");
+
+        let esp = gpt.partition(1).unwrap();
+        assert_eq!(esp.type_guid(), guid(ESP));
+        assert_eq!(esp.unique_guid(), guid(ESP_GUID));
+        assert_eq!(esp.name(), "esp");
+        assert!(!esp.has_attribute(GPT_ATTR_NO_AUTO));
+
+        // Unused entries keep their index
+        assert_eq!(gpt.partition(2), None);
+        let root = gpt.partition_by_guid(guid(ROOT_GUID)).unwrap();
+        assert_eq!(root.number(), 3);
+        assert_eq!(root.type_guid(), guid(LINUX_ROOT));
+        assert_eq!(root.name(), "root");
+        assert!(root.has_attribute(GPT_ATTR_NO_AUTO));
+    }
+
+    #[test]
+    fn corrupt_primary_header() {
+        let expected = read(&disk());
+        let mut disk = disk();
+        // Disk GUID, covered by the header CRC
+        disk[LBA_SIZE + 60] ^= 0xff;
+        assert_eq!(read(&disk), expected);
+
+        let last_lba = DISK_LBAS - 1;
+        disk[last_lba * LBA_SIZE + 60] ^= 0xff;
+        assert_eq!(read(&disk), None);
+    }
+
+    #[test]
+    fn bad_entries_crc() {
+        let expected = read(&disk());
+        let mut disk = disk();
+        // Name of the ESP in the primary partition entry array
+        disk[2 * LBA_SIZE + 56] = b'E';
+        assert_eq!(read(&disk), expected);
+
+        disk[(DISK_LBAS - 2) * LBA_SIZE + 56] = b'E';
+        assert_eq!(read(&disk), None);
+    }
+
+    #[test]
+    fn no_gpt() {
+        assert_eq!(read(&vec![0; DISK_LBAS * LBA_SIZE]), None);
+        assert_eq!(read(&disk()[..LBA_SIZE]), None);
+    }
+}
@@ -0,0 +1,299 @@
+//! Native block device probing.
+//!
+//! ignited doesn't depend on `libblkid`: filesystem superblocks and partition tables are
+//! read directly from the block devices the kernel knows about (as listed in
+//! `/sys/class/block`) in order to find out their type, UUID and label.
+
+pub mod gpt;
+mod superblock;
+
+use crate::PROGRAM_NAME;
+use gpt::{Gpt, GptPartition};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{read_dir, read_to_string, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Path where the kernel lists every known block device.
+const SYSFS_CLASS_BLOCK: &str = "/sys/class/block";
+
+/// Filesystem (or filesystem-like) identifier.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FsUuid {
+    /// Standard 128-bit UUID, used by most filesystems.
+    Uuid(uuid::Uuid),
+
+    /// 32-bit volume serial number, used by FAT filesystems (`XXXX-XXXX`).
+    VolumeId(u32),
+}
+impl Display for FsUuid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FsUuid::Uuid(uuid) => write!(f, "{}", uuid),
+            FsUuid::VolumeId(id) => write!(f, "{:04X}-{:04X}", id >> 16, id & 0xffff),
+        }
+    }
+}
+
+/// Filesystem type, UUID and label contained in a block device's superblock.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlkId {
+    fstype: &'static str,
+    uuid: Option<FsUuid>,
+    label: Option<String>,
+}
+impl BlkId {
+    fn new(fstype: &'static str, uuid: Option<FsUuid>, label: Option<String>) -> Self {
+        Self {
+            fstype,
+            uuid,
+            label,
+        }
+    }
+
+    /// Filesystem type as passed to `mount(2)` (e.g. `ext4`), or the `libblkid` name of
+    /// the format if it can't be mounted (e.g. `crypto_LUKS` or `swap`).
+    pub fn fstype(&self) -> &'static str {
+        self.fstype
+    }
+
+    /// Filesystem UUID, if any.
+    pub fn uuid(&self) -> Option<FsUuid> {
+        self.uuid
+    }
+
+    /// Filesystem label, if any.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
+/// Partition information as exposed by the kernel, complemented with the partition table
+/// of the disk containing it (if it's a GPT disk).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartInfo {
+    number: u32,
+    name: Option<String>,
+    gpt: Option<Arc<Gpt>>,
+}
+impl PartInfo {
+    /// Partition number (`PARTN`), starting at 1.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Partition name (GPT partition name or `PARTNAME`), if any.
+    pub fn name(&self) -> Option<&str> {
+        match self.gpt_entry() {
+            Some(entry) => Some(entry.name()).filter(|n| !n.is_empty()),
+            None => self.name.as_deref(),
+        }
+    }
+
+    /// Partition table of the disk containing this partition, if it's a GPT disk.
+    pub fn gpt(&self) -> Option<&Gpt> {
+        self.gpt.as_deref()
+    }
+
+    /// GPT entry corresponding to this partition, if it's in a GPT disk.
+    pub fn gpt_entry(&self) -> Option<&GptPartition> {
+        self.gpt()?.partition(self.number)
+    }
+
+    /// Unique partition GUID (`PARTUUID`), if it's in a GPT disk.
+    pub fn uuid(&self) -> Option<uuid::Uuid> {
+        self.gpt_entry().map(|entry| entry.unique_guid())
+    }
+
+    // Read the partition table of the disk containing the partition `name`.
+    fn read_gpt(name: &str) -> Option<Arc<Gpt>> {
+        // /sys/class/block/<partition> links to /sys/devices/.../<disk>/<partition>
+        let sys_path = Path::new(SYSFS_CLASS_BLOCK).join(name).canonicalize().ok()?;
+        let disk = sys_path.parent()?.file_name()?.to_str()?.to_string();
+        let disk_dev = BlockDevice::read_uevent(&disk).ok()?.devname?;
+
+        let lba_size = read_to_string(format!(
+            "{}/{}/queue/logical_block_size",
+            SYSFS_CLASS_BLOCK, disk
+        ))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(512u64);
+        let disk_size = BlockDevice::read_size(&disk) * 512;
+
+        let f = File::open(format!("/dev/{}", disk_dev)).ok()?;
+        Gpt::read(&f, lba_size, disk_size)
+            .ok()
+            .flatten()
+            .map(Arc::new)
+    }
+}
+
+// Relevant fields of a block device's uevent file
+#[derive(Debug, Default)]
+struct BlockUevent {
+    major: Option<u32>,
+    minor: Option<u32>,
+    devname: Option<String>,
+    partn: Option<u32>,
+    partname: Option<String>,
+}
+
+/// Probed block device.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockDevice {
+    name: String,
+    major: u32,
+    minor: u32,
+    size: u64,
+    part: Option<PartInfo>,
+    dm_name: Option<String>,
+    id: Option<BlkId>,
+}
+impl BlockDevice {
+    /// Probe the block device called `name` in `/sys/class/block` (e.g. `sda1`).
+    pub fn probe<S: AsRef<str>>(name: S) -> Result<Self, PrintableErrno<String>> {
+        Self::_probe(name.as_ref())
+    }
+    fn _probe(name: &str) -> Result<Self, PrintableErrno<String>> {
+        let uevent = Self::read_uevent(name)?;
+        let (major, minor) = uevent.major.zip(uevent.minor).ok_or_else(|| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("block device {} has no device number", name),
+            )
+        })?;
+        let part = uevent.partn.map(|number| PartInfo {
+            number,
+            name: uevent.partname,
+            gpt: PartInfo::read_gpt(name),
+        });
+        let size = Self::read_size(name);
+
+        let mut dev = Self {
+            name: uevent.devname.unwrap_or_else(|| name.to_string()),
+            major,
+            minor,
+            size,
+            part,
+            dm_name: Self::read_dm_name(name),
+            id: None,
+        };
+        if size != 0 {
+            // Devices without media (e.g. empty card readers) have a size of 0
+            let f = File::open(dev.path()).map_err(|io| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to open {}: {}", dev.path().display(), io),
+                )
+            })?;
+            dev.id = superblock::probe(&f).map_err(|io| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to probe {}: {}", dev.path().display(), io),
+                )
+            })?;
+        }
+        Ok(dev)
+    }
+
+    fn read_uevent(name: &str) -> Result<BlockUevent, PrintableErrno<String>> {
+        let uevent =
+            read_to_string(format!("{}/{}/uevent", SYSFS_CLASS_BLOCK, name)).map_err(|io| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to read uevent of block device {}: {}", name, io),
+                )
+            })?;
+
+        let mut res = BlockUevent::default();
+        for (key, value) in uevent.lines().filter_map(|l| l.split_once('=')) {
+            match key {
+                "MAJOR" => res.major = value.parse().ok(),
+                "MINOR" => res.minor = value.parse().ok(),
+                "DEVNAME" => res.devname = Some(value.to_string()),
+                "PARTN" => res.partn = value.parse().ok(),
+                "PARTNAME" => res.partname = Some(value.to_string()).filter(|p| !p.is_empty()),
+                _ => {}
+            }
+        }
+        Ok(res)
+    }
+
+    fn read_dm_name(name: &str) -> Option<String> {
+        read_to_string(format!("{}/{}/dm/name", SYSFS_CLASS_BLOCK, name))
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+    }
+
+    // Size is always expressed in 512-byte sectors, regardless of the device
+    fn read_size(name: &str) -> u64 {
+        read_to_string(format!("{}/{}/size", SYSFS_CLASS_BLOCK, name))
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Kernel name of this block device (e.g. `sda1` or `dm-0`).
+    pub fn name(&self) -> &str {
+        &self.name[..]
+    }
+
+    /// Path to the device node (e.g. `/dev/sda1`).
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/{}", self.name))
+    }
+
+    /// Major and minor device numbers.
+    pub fn devnum(&self) -> (u32, u32) {
+        (self.major, self.minor)
+    }
+
+    /// Size in 512-byte sectors.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Partition information, if this block device is a partition.
+    pub fn part(&self) -> Option<&PartInfo> {
+        self.part.as_ref()
+    }
+
+    /// Device-mapper name (i.e. `/dev/mapper/<name>`), if this is a device-mapper device.
+    pub fn dm_name(&self) -> Option<&str> {
+        self.dm_name.as_deref()
+    }
+
+    /// Filesystem information, if a known superblock was found.
+    pub fn id(&self) -> Option<&BlkId> {
+        self.id.as_ref()
+    }
+}
+
+/// List the names of all block devices currently known to the kernel.
+pub fn enumerate() -> Result<Vec<String>, PrintableErrno<String>> {
+    let entries = read_dir(SYSFS_CLASS_BLOCK).map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to read {}: {}", SYSFS_CLASS_BLOCK, io),
+        )
+    })?;
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect();
+    names.sort_unstable();
+    Ok(names)
+}
+
+/// Find the kernel name (e.g. `dm-0`) of the device-mapper device called `dm_name`.
+pub fn find_dm<S: AsRef<str>>(dm_name: S) -> Result<Option<String>, PrintableErrno<String>> {
+    let dm_name = dm_name.as_ref();
+    Ok(enumerate()?
+        .into_iter()
+        .find(|name| BlockDevice::read_dm_name(name).as_deref() == Some(dm_name)))
+}
@@ -0,0 +1,562 @@
+//! Filesystem superblock probing.
+//!
+//! Every supported format is identified by its on-disk magic and, if present, its UUID and
+//! label are read from the superblock. Offsets are the same ones used by `libblkid`.
+
+use super::{BlkId, FsUuid};
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+};
+
+type ProbeFn = fn(&File) -> io::Result<Option<BlkId>>;
+
+/// Read `len` bytes at `offset`. Returns `None` if the device is too small.
+fn read_at(dev: &File, offset: u64, len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = vec![0; len];
+    match dev.read_exact_at(&mut buf, offset) {
+        Ok(()) => Ok(Some(buf)),
+        Err(io) if io.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(io) => Err(io),
+    }
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+// All-zero UUIDs mean "no UUID".
+fn uuid_from(raw: &[u8]) -> Option<FsUuid> {
+    uuid::Uuid::from_slice(raw)
+        .ok()
+        .filter(|uuid| !uuid.is_nil())
+        .map(FsUuid::Uuid)
+}
+
+// Labels are NUL-padded (and sometimes space-padded) byte strings.
+fn label_from(raw: &[u8]) -> Option<String> {
+    let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+    let label = String::from_utf8_lossy(&raw[..end]).trim_end().to_string();
+    (!label.is_empty()).then_some(label)
+}
+
+/// Probe the given block device for a known superblock.
+///
+/// Order is important: formats that keep their magic further away from the start of the
+/// device are tried first, as a stale signature might still be present at the beginning
+/// of a reformatted device. `vfat` is tried last as its signature is the weakest.
+pub(super) fn probe(dev: &File) -> io::Result<Option<BlkId>> {
+    const PROBES: &[ProbeFn] = &[
+        probe_md,
+        probe_luks,
+        probe_lvm2,
+        probe_swap,
+        probe_btrfs,
+        probe_xfs,
+        probe_f2fs,
+        probe_ext,
+        probe_vfat,
+    ];
+
+    for probe_fn in PROBES {
+        if let Some(id) = probe_fn(dev)? {
+            return Ok(Some(id));
+        }
+    }
+    Ok(None)
+}
+
+/// Linux md RAID member superblocks: v1.1 at the start of the device, v1.2 at 4KiB, v1.0
+/// and v0.90 near the end. The members of a RAID1 array also contain the array's data as
+/// is, hence this is tried first.
+///
+/// See the [md][crate::md] module.
+fn probe_md(dev: &File) -> io::Result<Option<BlkId>> {
+    const MD_MAGIC: u32 = 0xa92b4efc;
+
+    let size = {
+        let mut dev = dev;
+        dev.seek(SeekFrom::End(0))?
+    };
+    let v1_end = (size >> 9).checked_sub(16).map(|sector| (sector & !7) << 9);
+    for offset in [Some(0), Some(4096), v1_end].iter().flatten() {
+        match read_at(dev, *offset, 64)? {
+            Some(sb) if le32(&sb, 0) == MD_MAGIC && le32(&sb, 4) == 1 => {
+                return Ok(Some(BlkId::new(
+                    "linux_raid_member",
+                    uuid_from(&sb[16..32]),
+                    label_from(&sb[32..64]),
+                )))
+            }
+            _ => {}
+        }
+    }
+
+    // v0.90: 64KiB from the end, 64KiB-aligned. The UUID is split in two.
+    if let Some(offset) = (size & !0xffff).checked_sub(0x10000) {
+        match read_at(dev, offset, 64)? {
+            Some(sb) if le32(&sb, 0) == MD_MAGIC && le32(&sb, 4) == 0 => {
+                let mut uuid = [0; 16];
+                uuid[..4].copy_from_slice(&sb[20..24]);
+                uuid[4..].copy_from_slice(&sb[52..64]);
+                return Ok(Some(BlkId::new(
+                    "linux_raid_member",
+                    uuid_from(&uuid),
+                    None,
+                )));
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// LUKS1 and LUKS2 (primary) headers.
+fn probe_luks(dev: &File) -> io::Result<Option<BlkId>> {
+    const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+
+    let hdr = match read_at(dev, 0, 512)? {
+        Some(hdr) if hdr.starts_with(LUKS_MAGIC) => hdr,
+        _ => return Ok(None),
+    };
+    let uuid = label_from(&hdr[168..208])
+        .and_then(|uuid| uuid::Uuid::parse_str(&uuid).ok())
+        .map(FsUuid::Uuid);
+    let label = match u16::from_be_bytes([hdr[6], hdr[7]]) {
+        2 => label_from(&hdr[24..72]),
+        _ => None,
+    };
+    Ok(Some(BlkId::new("crypto_LUKS", uuid, label)))
+}
+
+/// LVM2 physical volume label, in one of the first four sectors.
+///
+/// PV UUIDs aren't RFC 4122 UUIDs, so they aren't reported. See the
+/// [lvm][crate::lvm] module.
+fn probe_lvm2(dev: &File) -> io::Result<Option<BlkId>> {
+    for sector in 0..4 {
+        match read_at(dev, sector * 512, 32)? {
+            Some(label) if label.starts_with(b"LABELONE") && &label[24..32] == b"LVM2 001" => {
+                return Ok(Some(BlkId::new("LVM2_member", None, None)))
+            }
+            Some(_) => {}
+            None => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+/// Swap areas and suspended (hibernated) swap areas.
+fn probe_swap(dev: &File) -> io::Result<Option<BlkId>> {
+    const PAGE_SIZES: &[u64] = &[0x1000, 0x2000, 0x4000, 0x10000];
+    const SWSUSPEND_MAGICS: &[&[u8]] = &[b"S1SUSPEND", b"S2SUSPEND", b"ULSUSPEND", b"LINHIB0001"];
+
+    for page_size in PAGE_SIZES {
+        let magic = match read_at(dev, page_size - 10, 10)? {
+            Some(magic) => magic,
+            None => return Ok(None),
+        };
+        let fstype = if &magic[..] == b"SWAPSPACE2" {
+            "swap"
+        } else if &magic[..] == b"SWAP-SPACE" {
+            // Version 0 swap areas don't have a UUID nor a label
+            return Ok(Some(BlkId::new("swap", None, None)));
+        } else if SWSUSPEND_MAGICS.iter().any(|m| magic.starts_with(m)) {
+            "swsuspend"
+        } else {
+            continue;
+        };
+
+        // struct swap_header_v1_2 after 1024 bytes of bootbits
+        let hdr = match read_at(dev, 1024, 44)? {
+            Some(hdr) => hdr,
+            None => return Ok(None),
+        };
+        return Ok(Some(BlkId::new(
+            fstype,
+            uuid_from(&hdr[12..28]),
+            label_from(&hdr[28..44]),
+        )));
+    }
+    Ok(None)
+}
+
+/// Btrfs superblock at 64KiB.
+fn probe_btrfs(dev: &File) -> io::Result<Option<BlkId>> {
+    let sb = match read_at(dev, 0x10000, 0x22b)? {
+        Some(sb) if &sb[0x40..0x48] == b"_BHRfS_M" => sb,
+        _ => return Ok(None),
+    };
+    Ok(Some(BlkId::new(
+        "btrfs",
+        uuid_from(&sb[0x20..0x30]),
+        label_from(&sb[0x12b..0x22b]),
+    )))
+}
+
+/// XFS superblock at the start of the device.
+fn probe_xfs(dev: &File) -> io::Result<Option<BlkId>> {
+    let sb = match read_at(dev, 0, 120)? {
+        Some(sb) if &sb[0..4] == b"XFSB" => sb,
+        _ => return Ok(None),
+    };
+    Ok(Some(BlkId::new(
+        "xfs",
+        uuid_from(&sb[32..48]),
+        label_from(&sb[108..120]),
+    )))
+}
+
+/// F2FS superblock at 1KiB.
+fn probe_f2fs(dev: &File) -> io::Result<Option<BlkId>> {
+    const F2FS_MAGIC: u32 = 0xF2F52010;
+
+    let sb = match read_at(dev, 1024, 124 + 1024)? {
+        Some(sb) if le32(&sb, 0) == F2FS_MAGIC => sb,
+        _ => return Ok(None),
+    };
+
+    // Volume name is stored as (at most) 512 UTF-16LE code units
+    let volume_name: Vec<u16> = sb[124..]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    let label = String::from_utf16_lossy(&volume_name);
+    Ok(Some(BlkId::new(
+        "f2fs",
+        uuid_from(&sb[108..124]),
+        (!label.is_empty()).then_some(label),
+    )))
+}
+
+/// ext2, ext3 and ext4 superblock at 1KiB.
+fn probe_ext(dev: &File) -> io::Result<Option<BlkId>> {
+    const EXT_MAGIC: u16 = 0xEF53;
+    const COMPAT_HAS_JOURNAL: u32 = 0x0004;
+    const INCOMPAT_JOURNAL_DEV: u32 = 0x0008;
+
+    // Features understood by ext3. Anything else means ext4.
+    const EXT3_INCOMPAT_SUPP: u32 = 0x0002 | 0x0004 | 0x0010;
+    const EXT3_RO_COMPAT_SUPP: u32 = 0x0001 | 0x0002 | 0x0004;
+
+    let sb = match read_at(dev, 1024, 0x88)? {
+        Some(sb) if le16(&sb, 0x38) == EXT_MAGIC => sb,
+        _ => return Ok(None),
+    };
+    let compat = le32(&sb, 0x5c);
+    let incompat = le32(&sb, 0x60);
+    let ro_compat = le32(&sb, 0x64);
+
+    let fstype = if incompat & INCOMPAT_JOURNAL_DEV != 0 {
+        "jbd"
+    } else if incompat & !EXT3_INCOMPAT_SUPP != 0 || ro_compat & !EXT3_RO_COMPAT_SUPP != 0 {
+        "ext4"
+    } else if compat & COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    };
+    Ok(Some(BlkId::new(
+        fstype,
+        uuid_from(&sb[0x68..0x78]),
+        label_from(&sb[0x78..0x88]),
+    )))
+}
+
+/// FAT12, FAT16 and FAT32 boot sector.
+fn probe_vfat(dev: &File) -> io::Result<Option<BlkId>> {
+    let bs = match read_at(dev, 0, 512)? {
+        Some(bs) if bs[510] == 0x55 && bs[511] == 0xaa => bs,
+        _ => return Ok(None),
+    };
+    if bs[0] != 0xeb && bs[0] != 0xe9 {
+        return Ok(None);
+    }
+    if &bs[3..11] == b"NTFS    " || &bs[3..11] == b"EXFAT   " {
+        return Ok(None);
+    }
+
+    // BIOS Parameter Block sanity checks
+    let bytes_per_sector = le16(&bs, 11);
+    let sectors_per_cluster = bs[13];
+    let reserved_sectors = le16(&bs, 14);
+    let fats = bs[16];
+    if !bytes_per_sector.is_power_of_two()
+        || !(512..=4096).contains(&bytes_per_sector)
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved_sectors == 0
+        || fats == 0
+    {
+        return Ok(None);
+    }
+
+    // FAT32 has a 0 in the 16-bit sectors-per-FAT field and an extended BPB at 0x40
+    let ebpb = if le16(&bs, 22) == 0 { 0x40 } else { 0x24 };
+    if bs[ebpb + 2] != 0x29 {
+        // No volume serial nor label present
+        return Ok(Some(BlkId::new("vfat", None, None)));
+    }
+    let serial = le32(&bs, ebpb + 3);
+    let label = label_from(&bs[ebpb + 7..ebpb + 18]).filter(|label| label != "NO NAME");
+    Ok(Some(BlkId::new(
+        "vfat",
+        Some(FsUuid::VolumeId(serial)),
+        label,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestImage;
+
+    const UUID: [u8; 16] = [
+        0x3b, 0x0c, 0x6a, 0x2e, 0x41, 0x5d, 0x4f, 0x6e, 0x9a, 0x1c, 0x5e, 0x22, 0x7d, 0x08, 0x91,
+        0xf4,
+    ];
+
+    // Device image of `size` bytes with each chunk copied at its offset
+    fn image(size: usize, chunks: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut image = vec![0; size];
+        for (offset, data) in chunks {
+            image[*offset..*offset + data.len()].copy_from_slice(data);
+        }
+        image
+    }
+
+    // Probe `image` as if it were a block device
+    fn probe_image(image: &[u8]) -> Option<BlkId> {
+        probe(TestImage::new(image).file()).unwrap()
+    }
+
+    fn uuid() -> Option<FsUuid> {
+        Some(FsUuid::Uuid(uuid::Uuid::from_bytes(UUID)))
+    }
+
+    fn assert_id(id: Option<BlkId>, fstype: &str, uuid: Option<FsUuid>, label: Option<&str>) {
+        let id = id.expect("no superblock found");
+        assert_eq!(id.fstype(), fstype);
+        assert_eq!(id.uuid(), uuid);
+        assert_eq!(id.label(), label);
+    }
+
+    // ext superblock at 1KiB with the given feature flags
+    fn ext_image(compat: u32, incompat: u32, ro_compat: u32) -> Vec<u8> {
+        image(
+            0x2000,
+            &[
+                (1024 + 0x38, &0xEF53u16.to_le_bytes()),
+                (1024 + 0x5c, &compat.to_le_bytes()),
+                (1024 + 0x60, &incompat.to_le_bytes()),
+                (1024 + 0x64, &ro_compat.to_le_bytes()),
+                (1024 + 0x68, &UUID),
+                (1024 + 0x78, b"rootfs"),
+            ],
+        )
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(probe_image(&[0; 0x20000]), None);
+        assert_eq!(probe_image(&[]), None);
+    }
+
+    #[test]
+    fn ext() {
+        let fstype = |compat, incompat, ro_compat| {
+            probe_image(&ext_image(compat, incompat, ro_compat)).map(|id| id.fstype())
+        };
+        assert_eq!(fstype(0, 0, 0), Some("ext2"));
+        assert_eq!(fstype(0x4, 0x2, 0x1), Some("ext3"));
+        // extents
+        assert_eq!(fstype(0x4, 0x42, 0x1), Some("ext4"));
+        assert_eq!(fstype(0, 0x8, 0), Some("jbd"));
+        assert_id(
+            probe_image(&ext_image(0x4, 0x2, 0x1)),
+            "ext3",
+            uuid(),
+            Some("rootfs"),
+        );
+    }
+
+    #[test]
+    fn btrfs() {
+        let image = image(
+            0x20000,
+            &[
+                (0x10000 + 0x20, &UUID),
+                (0x10000 + 0x40, b"_BHRfS_M"),
+                (0x10000 + 0x12b, b"pool"),
+            ],
+        );
+        assert_id(probe_image(&image), "btrfs", uuid(), Some("pool"));
+    }
+
+    #[test]
+    fn xfs() {
+        let image = image(0x1000, &[(0, b"XFSB"), (32, &UUID), (108, b"data\0\0\0\0")]);
+        assert_id(probe_image(&image), "xfs", uuid(), Some("data"));
+    }
+
+    #[test]
+    fn f2fs() {
+        let label: Vec<u8> = "flash".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let image = image(
+            0x2000,
+            &[
+                (1024, &0xF2F52010u32.to_le_bytes()),
+                (1024 + 108, &UUID),
+                (1024 + 124, &label),
+            ],
+        );
+        assert_id(probe_image(&image), "f2fs", uuid(), Some("flash"));
+    }
+
+    // FAT boot sector, FAT32 if `sectors_per_fat` is 0
+    fn vfat_image(sectors_per_fat: u16, label: &[u8]) -> Vec<u8> {
+        let ebpb = if sectors_per_fat == 0 { 0x40 } else { 0x24 };
+        image(
+            0x1000,
+            &[
+                (0, &[0xeb, 0x58, 0x90]),
+                (3, b"mkfs.fat"),
+                (11, &512u16.to_le_bytes()),
+                (13, &[8]),
+                (14, &32u16.to_le_bytes()),
+                (16, &[2]),
+                (22, &sectors_per_fat.to_le_bytes()),
+                (ebpb + 2, &[0x29]),
+                (ebpb + 3, &0x1234abcdu32.to_le_bytes()),
+                (ebpb + 7, label),
+                (510, &[0x55, 0xaa]),
+            ],
+        )
+    }
+
+    #[test]
+    fn vfat() {
+        let serial = Some(FsUuid::VolumeId(0x1234abcd));
+        assert_id(
+            probe_image(&vfat_image(0, b"EFI        ")),
+            "vfat",
+            serial,
+            Some("EFI"),
+        );
+        assert_id(
+            probe_image(&vfat_image(64, b"BOOT       ")),
+            "vfat",
+            serial,
+            Some("BOOT"),
+        );
+        assert_id(
+            probe_image(&vfat_image(0, b"NO NAME    ")),
+            "vfat",
+            serial,
+            None,
+        );
+        assert_eq!(FsUuid::VolumeId(0x1234abcd).to_string(), "1234-ABCD");
+
+        let mut ntfs = vfat_image(0, b"NO NAME    ");
+        ntfs[3..11].copy_from_slice(b"NTFS    ");
+        assert_eq!(probe_image(&ntfs), None);
+    }
+
+    #[test]
+    fn swap() {
+        let swap = image(
+            0x2000,
+            &[
+                (4096 - 10, b"SWAPSPACE2"),
+                (1024 + 12, &UUID),
+                (1024 + 28, b"swap0"),
+            ],
+        );
+        assert_id(probe_image(&swap), "swap", uuid(), Some("swap0"));
+
+        // 16KiB pages, holding a hibernation image
+        let hibernated = image(
+            0x8000,
+            &[(16384 - 10, &b"S1SUSPEND\0"[..]), (1024 + 12, &UUID)],
+        );
+        assert_id(probe_image(&hibernated), "swsuspend", uuid(), None);
+    }
+
+    #[test]
+    fn luks() {
+        let uuid_str = b"3b0c6a2e-415d-4f6e-9a1c-5e227d0891f4";
+        let luks1 = image(0x1000, &[(0, b"LUKS\xba\xbe\0\x01"), (168, uuid_str)]);
+        assert_id(probe_image(&luks1), "crypto_LUKS", uuid(), None);
+
+        let luks2 = image(
+            0x1000,
+            &[
+                (0, b"LUKS\xba\xbe\0\x02"),
+                (24, b"cryptroot"),
+                (168, uuid_str),
+            ],
+        );
+        assert_id(
+            probe_image(&luks2),
+            "crypto_LUKS",
+            uuid(),
+            Some("cryptroot"),
+        );
+    }
+
+    #[test]
+    fn lvm2() {
+        let image = image(0x1000, &[(512, b"LABELONE"), (512 + 24, b"LVM2 001")]);
+        assert_id(probe_image(&image), "LVM2_member", None, None);
+    }
+
+    #[test]
+    fn md() {
+        let magic = 0xa92b4efcu32.to_le_bytes();
+        // v1.2 at 4KiB
+        let v1 = image(
+            0x10000,
+            &[
+                (4096, &magic),
+                (4096 + 4, &1u32.to_le_bytes()),
+                (4096 + 16, &UUID),
+                (4096 + 32, b"host:0"),
+            ],
+        );
+        assert_id(
+            probe_image(&v1),
+            "linux_raid_member",
+            uuid(),
+            Some("host:0"),
+        );
+
+        // v0.90 64KiB from the end, with the UUID split in two
+        let v0 = image(
+            0x30000,
+            &[
+                (0x20000, &magic),
+                (0x20000 + 20, &UUID[..4]),
+                (0x20000 + 52, &UUID[4..]),
+            ],
+        );
+        assert_id(probe_image(&v0), "linux_raid_member", uuid(), None);
+    }
+
+    #[test]
+    fn stale_signature() {
+        // Reformatted from vfat to ext4: the boot sector is still there
+        let mut image = ext_image(0x4, 0x42, 0x1);
+        image[..512].copy_from_slice(&vfat_image(0, b"OLD        ")[..512]);
+        assert_id(probe_image(&image), "ext4", uuid(), Some("rootfs"));
+    }
+}
@@ -0,0 +1,553 @@
+//! Block device handling.
+//!
+//! Block devices are discovered both by the `uevent` listener (hot-plugged devices) and
+//! by the `sysfs` walker (devices that appeared before the listener was ready). Both end
+//! up here, where each device is probed and matched against the root partition.
+//!
+//! LUKS volumes are unlocked, LVM2 logical volumes are activated and md RAID arrays are
+//! assembled here as well. The resulting devices are then handled like any other block
+//! device.
+//!
+//! If a resume device is given, the root partition isn't mounted until the resume device
+//! has been checked for a hibernation image (see the [resume][crate::resume] module).
+
+use crate::{
+    blkid::{self, BlockDevice, FsUuid},
+    config::{CmdlineArgs, RuntimeConfig},
+    early_logging::KConsole,
+    luks,
+    lvm::{label::PvLabel, metadata::VolumeGroup, VolumeGroups},
+    md::{superblock::MdMember, MdArray, MdArrays},
+    module::ModLoading,
+    mount::{Mount, RootOpts},
+    prompt::Prompter,
+    resume, PROGRAM_NAME,
+};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+// Progress of resuming from hibernation. The root partition isn't mounted until it's Done.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+enum ResumeState {
+    Waiting,
+    Checking,
+    #[default]
+    Done,
+}
+
+// Inner struct containing BlockHandling's fields. Meant to be guarded by a mutex.
+#[derive(Debug, Default)]
+struct BlockHandlingInner {
+    probed: BTreeSet<String>,
+    luks_opened: BTreeSet<String>,
+    volume_groups: VolumeGroups,
+    md_arrays: MdArrays,
+    resume: ResumeState,
+    root_pending: Option<String>,
+    root_mapping: Option<String>,
+    root_mounted: bool,
+}
+
+/// Block device probing and bookkeeping: records already probed devices, already unlocked
+/// LUKS volumes, LVM2 physical volumes and md RAID members seen so far, whether the resume
+/// device has already been checked and whether the root partition has already been mounted.
+#[derive(Debug, Clone)]
+pub struct BlockHandling {
+    bookkeeping: Arc<Mutex<BlockHandlingInner>>,
+    unlocking: Arc<Mutex<()>>,
+    config: Arc<RuntimeConfig>,
+    args: Arc<CmdlineArgs>,
+    mod_loading: ModLoading,
+    prompter: Prompter,
+}
+impl BlockHandling {
+    /// Build a new instance of this struct. This should only be called once.
+    pub fn new(
+        config: &Arc<RuntimeConfig>,
+        args: &Arc<CmdlineArgs>,
+        mod_loading: &ModLoading,
+        prompter: &Prompter,
+    ) -> Self {
+        let resume = if args.resume_source().is_some() {
+            ResumeState::Waiting
+        } else {
+            ResumeState::Done
+        };
+        Self {
+            bookkeeping: Arc::new(Mutex::new(BlockHandlingInner {
+                resume,
+                ..Default::default()
+            })),
+            unlocking: Arc::new(Mutex::new(())),
+            config: Arc::clone(config),
+            args: Arc::clone(args),
+            mod_loading: mod_loading.clone(),
+            prompter: prompter.clone(),
+        }
+    }
+
+    /// Handle a new (or changed) block device called `name` in `/sys/class/block`.
+    ///
+    /// The device is probed and, if it's the root partition, mounted at
+    /// [`/system_root`][crate::IGNITED_TARGET_ROOT_PATH]. If it's the resume device and
+    /// holds a hibernation image, the image is restored. If it's a LUKS volume that
+    /// should be unlocked, it's unlocked and the resulting device is handled in turn. If
+    /// it completes an LVM2 volume group, its logical volumes are activated and handled in
+    /// turn. If it completes an md RAID array, the array is assembled and handled in turn.
+    /// Returns `true` if this call mounted the root partition, in which case the main
+    /// thread should be notified.
+    ///
+    /// Devices are only probed once, unless `changed` is set (e.g. a `change` uevent after
+    /// a device-mapper table is loaded).
+    #[inline]
+    pub fn add_device<S: AsRef<str>>(
+        &self,
+        kcon: &mut KConsole,
+        name: S,
+        changed: bool,
+    ) -> Result<bool, PrintableErrno<String>> {
+        self._add_device(kcon, name.as_ref(), changed)
+    }
+    fn _add_device(
+        &self,
+        kcon: &mut KConsole,
+        name: &str,
+        changed: bool,
+    ) -> Result<bool, PrintableErrno<String>> {
+        {
+            let mut unlocked = self.lock()?;
+            if unlocked.root_mounted || (!unlocked.probed.insert(name.to_string()) && !changed) {
+                return Ok(false);
+            }
+        }
+
+        let device = BlockDevice::probe(name)?;
+        if device.size() == 0 {
+            // Nothing to see yet (e.g. device-mapper device without a table). Probe again
+            // when it changes.
+            self.lock()?.probed.remove(name);
+            return Ok(false);
+        }
+        match device.id() {
+            Some(id) => kdebug!(
+                kcon,
+                "probed block device {}: type={} uuid={} label={}",
+                name,
+                id.fstype(),
+                id.uuid().map(|u| u.to_string()).unwrap_or_default(),
+                id.label().unwrap_or_default()
+            ),
+            None => kdebug!(kcon, "probed block device {}: unknown type", name),
+        }
+
+        // A swap file's filesystem is both the resume device and (possibly) the root
+        // partition, so keep going after checking it.
+        let mounted = self.try_resume(kcon, &device)?;
+        let res = match device.id().map(|id| id.fstype()) {
+            Some("crypto_LUKS") => self.try_unlock_luks(kcon, &device),
+            Some("LVM2_member") => self.try_activate_lvm(kcon, &device),
+            Some("linux_raid_member") => self.try_assemble_md(kcon, &device),
+            _ => self.try_mount_root(kcon, &device),
+        };
+        res.map(|res| res || mounted)
+    }
+
+    /// Restore the hibernation image on the given device if it's the resume device. If it
+    /// holds no image (or restoring it fails), the root partition may be mounted from now
+    /// on.
+    ///
+    /// Returns `true` if this call mounted the root partition (found while waiting for the
+    /// resume device).
+    fn try_resume(
+        &self,
+        kcon: &mut KConsole,
+        device: &BlockDevice,
+    ) -> Result<bool, PrintableErrno<String>> {
+        if !matches!(self.args.resume_source(), Some(source) if source.matches(device)) {
+            return Ok(false);
+        }
+        {
+            let mut unlocked = self.lock()?;
+            if unlocked.resume != ResumeState::Waiting {
+                return Ok(false);
+            }
+            unlocked.resume = ResumeState::Checking;
+        }
+
+        let offset = self.args.resume_offset();
+        match resume::has_image(device, offset) {
+            Ok(true) => {
+                kinfo!(
+                    kcon,
+                    "resuming from hibernation image on {}",
+                    device.path().display()
+                );
+                // Only returns if the image couldn't be restored
+                match resume::resume(device, offset) {
+                    Ok(never) => match never {},
+                    Err(e) => kerr!(kcon, "{}", e),
+                }
+            }
+            Ok(false) => kdebug!(
+                kcon,
+                "no hibernation image on {}, booting normally",
+                device.path().display()
+            ),
+            Err(e) => kerr!(kcon, "{}", e),
+        }
+        self.finish_resume(kcon)
+    }
+
+    /// Stop waiting for the resume device and boot without resuming. Called once
+    /// [RESUME_TIMEOUT][crate::resume::RESUME_TIMEOUT] (or `mount-timeout`, if shorter)
+    /// expires.
+    ///
+    /// Returns `true` if this call mounted the root partition.
+    pub fn skip_resume(&self, kcon: &mut KConsole) -> Result<bool, PrintableErrno<String>> {
+        {
+            let mut unlocked = self.lock()?;
+            if unlocked.resume != ResumeState::Waiting {
+                return Ok(false);
+            }
+            unlocked.resume = ResumeState::Checking;
+        }
+        kwarn!(kcon, "resume device not found, booting without resuming");
+        self.finish_resume(kcon)
+    }
+
+    fn finish_resume(&self, kcon: &mut KConsole) -> Result<bool, PrintableErrno<String>> {
+        let root_pending = {
+            let mut unlocked = self.lock()?;
+            unlocked.resume = ResumeState::Done;
+            unlocked.root_pending.take()
+        };
+        match root_pending {
+            Some(name) => self._add_device(kcon, &name, true),
+            None => Ok(false),
+        }
+    }
+
+    /// Unlock the given LUKS volume if it's listed in `rd.luks.*`, if none is listed or if
+    /// it's the root partition (in which case it's unlocked as `/dev/mapper/root` by
+    /// default).
+    ///
+    /// If it can't be unlocked (e.g. no passphrase attempt was right), it's probed again on
+    /// its next uevent so that unlocking can be retried.
+    fn try_unlock_luks(
+        &self,
+        kcon: &mut KConsole,
+        device: &BlockDevice,
+    ) -> Result<bool, PrintableErrno<String>> {
+        let luks_targets = self.args.luks_targets();
+        let uuid = match device.id().and_then(|id| id.uuid()) {
+            Some(FsUuid::Uuid(uuid)) if luks_targets.is_enabled() => uuid,
+            _ => return Ok(false),
+        };
+        let is_root = matches!(
+            self.args.root_opts().get_source(),
+            Some(source) if source.matches(device)
+        );
+        let (name, options) = match luks_targets.find(uuid) {
+            Some(target) => (target.name().to_string(), target.options()),
+            None if is_root => ("root".to_string(), luks_targets.default_options()),
+            None if luks_targets.unlocks_all() => {
+                (format!("luks-{}", uuid), luks_targets.default_options())
+            }
+            None => return Ok(false),
+        };
+        let name = &name[..];
+        if !self.lock()?.luks_opened.insert(name.to_string()) {
+            // Already unlocked (or being unlocked) through another path to the same volume
+            return Ok(false);
+        }
+
+        let res = {
+            // Only prompt for one passphrase at a time
+            let _unlocking = self.unlocking.lock().map_err(|_| {
+                printable_error(PROGRAM_NAME, "unable to lock luks unlocking".to_string())
+            })?;
+            luks::open(
+                kcon,
+                &self.mod_loading,
+                &self.prompter,
+                device,
+                name,
+                options,
+            )
+        };
+        if let Err(e) = res {
+            let mut unlocked = self.lock()?;
+            unlocked.luks_opened.remove(name);
+            unlocked.probed.remove(device.name());
+            return Err(e);
+        }
+        if is_root {
+            self.lock()?.root_mapping = Some(name.to_string());
+        }
+
+        // The device-mapper device is announced through uevents too, but it's already
+        // usable at this point.
+        match blkid::find_dm(name)? {
+            Some(dm_device) => self._add_device(kcon, &dm_device, true),
+            None => Ok(false),
+        }
+    }
+
+    /// Record the given LVM2 physical volume and activate the logical volumes of every
+    /// volume group it completes, as selected by `rd.lvm.*`.
+    ///
+    /// Only done if LVM is enabled in [`engine.toml`][crate::config::IgnitedConfig::has_lvm].
+    fn try_activate_lvm(
+        &self,
+        kcon: &mut KConsole,
+        device: &BlockDevice,
+    ) -> Result<bool, PrintableErrno<String>> {
+        let lvm_targets = self.args.lvm_targets();
+        if !self.config.sysconf().has_lvm() || !lvm_targets.is_enabled() {
+            return Ok(false);
+        }
+
+        let path = device.path();
+        let read_err = |io| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to read LVM2 label of {}: {}", path.display(), io),
+            )
+        };
+        let dev = File::open(&path).map_err(read_err)?;
+        let label = match PvLabel::read(&dev).map_err(read_err)? {
+            Some(label) => label,
+            None => return Ok(false),
+        };
+        let vg = match label.read_metadata(&dev).map_err(read_err)? {
+            Some(text) => match VolumeGroup::parse(&text) {
+                Some(vg) => Some(vg),
+                None => {
+                    kwarn!(
+                        kcon,
+                        "invalid LVM2 metadata on {}, ignoring",
+                        path.display()
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+        kdebug!(
+            kcon,
+            "found LVM2 physical volume {} (volume group {})",
+            path.display(),
+            vg.as_ref().map(|vg| vg.name()).unwrap_or("<UNKNOWN>")
+        );
+        let complete = self
+            .lock()?
+            .volume_groups
+            .add_pv(label.uuid(), device.devnum(), vg);
+        if complete.is_empty() {
+            return Ok(false);
+        }
+
+        self.mod_loading
+            .load_modules(&["dm_mod".to_string()])?
+            .wait();
+        let sources: Vec<_> = [
+            self.args.root_opts().get_source(),
+            self.args.resume_source(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let mut mounted = false;
+        for vg in complete {
+            for name in vg.activate(kcon, lvm_targets, &sources)? {
+                // The device-mapper device is announced through uevents too, but it's
+                // already usable at this point.
+                if let Some(dm_device) = blkid::find_dm(&name)? {
+                    mounted |= self._add_device(kcon, &dm_device, true)?;
+                }
+            }
+        }
+        Ok(mounted)
+    }
+
+    /// Record the given md RAID member and assemble its array if it's now complete and
+    /// selected by `rd.md.*`.
+    ///
+    /// Only done if RAID is enabled in
+    /// [`engine.toml`][crate::config::IgnitedConfig::has_mdraid].
+    fn try_assemble_md(
+        &self,
+        kcon: &mut KConsole,
+        device: &BlockDevice,
+    ) -> Result<bool, PrintableErrno<String>> {
+        let md_targets = self.args.md_targets();
+        if !self.config.sysconf().has_mdraid() || !md_targets.is_enabled() {
+            return Ok(false);
+        }
+
+        let path = device.path();
+        let read_err = |io| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to read md superblock of {}: {}", path.display(), io),
+            )
+        };
+        let dev = File::open(&path).map_err(read_err)?;
+        let member = match MdMember::read(&dev).map_err(read_err)? {
+            Some(member) if md_targets.should_assemble(member.uuid()) => member,
+            _ => return Ok(false),
+        };
+        kdebug!(
+            kcon,
+            "found md member {} (array {}, slot {})",
+            path.display(),
+            member.uuid(),
+            member
+                .role()
+                .map(|r| r.to_string())
+                .unwrap_or_else(|| "spare".to_string())
+        );
+        let array = self.lock()?.md_arrays.add_member(device.devnum(), member);
+        match array {
+            Some(array) => self.assemble_md(kcon, &array),
+            None => Ok(false),
+        }
+    }
+
+    /// Start every md RAID array still missing members, in degraded mode. Called once
+    /// [`rd.retry`][crate::md::MdTargets::degraded_timeout] expires.
+    ///
+    /// Returns `true` if this call mounted the root partition.
+    pub fn start_degraded_md(&self, kcon: &mut KConsole) -> Result<bool, PrintableErrno<String>> {
+        let pending = self.lock()?.md_arrays.take_pending();
+        let mut mounted = false;
+        for array in pending {
+            kwarn!(
+                kcon,
+                "md array {} is missing members, starting it degraded",
+                array.uuid()
+            );
+            match self.assemble_md(kcon, &array) {
+                Ok(res) => mounted |= res,
+                Err(e) => kerr!(kcon, "{}", e),
+            }
+        }
+        Ok(mounted)
+    }
+
+    fn assemble_md(
+        &self,
+        kcon: &mut KConsole,
+        array: &MdArray,
+    ) -> Result<bool, PrintableErrno<String>> {
+        let name = array.assemble(kcon, &self.mod_loading)?;
+
+        // The array is announced through uevents too, but it's already usable at this
+        // point.
+        self._add_device(kcon, &name, true)
+    }
+
+    /// Mount the given device as root if it matches the requested root partition.
+    fn try_mount_root(
+        &self,
+        kcon: &mut KConsole,
+        device: &BlockDevice,
+    ) -> Result<bool, PrintableErrno<String>> {
+        // The device unlocked from a LUKS root partition is the root partition itself
+        let is_root_mapping =
+            device.dm_name().is_some() && self.lock()?.root_mapping.as_deref() == device.dm_name();
+        let root_opts = if is_root_mapping {
+            self.args.root_opts().build_with(device)
+        } else {
+            self.args.root_opts().try_build_with(device)
+        };
+        let root_opts = match root_opts {
+            Some(root_opts) => root_opts,
+            None => return Ok(false),
+        };
+
+        // Keep the lock while mounting: only one device may be mounted as root, even if
+        // multiple devices match (e.g. cloned disks).
+        let mut unlocked = self.lock()?;
+        if unlocked.root_mounted {
+            return Ok(false);
+        }
+        if unlocked.resume != ResumeState::Done {
+            // Mounting it now could corrupt it if a hibernation image is restored later on
+            kdebug!(
+                kcon,
+                "found root {}, waiting for the resume device before mounting it",
+                device.path().display()
+            );
+            unlocked.root_pending = Some(device.name().to_string());
+            return Ok(false);
+        }
+
+        self.mount_root(kcon, &mut unlocked, root_opts)?;
+        Ok(true)
+    }
+
+    /// Look for the root partition among every block device currently known to the kernel
+    /// and mount it. Called once `mount-timeout` expires, in case the root partition was
+    /// missed: if it still can't be mounted, the error tells why.
+    ///
+    /// If the resume device still hasn't been found, booting goes on without resuming. If
+    /// it's being checked for a hibernation image, root isn't mounted.
+    pub fn mount_root_now(&self, kcon: &mut KConsole) -> Result<(), PrintableErrno<String>> {
+        let root_opts = self.args.root_opts().build()?;
+        let mut unlocked = self.lock()?;
+        if unlocked.root_mounted {
+            return Ok(());
+        }
+        match unlocked.resume {
+            ResumeState::Waiting => {
+                kwarn!(kcon, "resume device not found, booting without resuming");
+                unlocked.resume = ResumeState::Done;
+                unlocked.root_pending = None;
+            }
+            // Mounting it now could corrupt it if the hibernation image is restored
+            ResumeState::Checking => {
+                return Err(printable_error(
+                    PROGRAM_NAME,
+                    "unable to mount root while resuming from hibernation".to_string(),
+                ))
+            }
+            ResumeState::Done => {}
+        }
+        self.mount_root(kcon, &mut unlocked, root_opts)
+    }
+
+    // Mount the root partition, keeping bookkeeping locked throughout
+    fn mount_root(
+        &self,
+        kcon: &mut KConsole,
+        unlocked: &mut BlockHandlingInner,
+        root_opts: RootOpts,
+    ) -> Result<(), PrintableErrno<String>> {
+        // Make sure the filesystem's kernel module (if any) is loaded before mounting
+        let fs_alias = format!("fs-{}", root_opts.fstype());
+        if let Some(wg) = self.mod_loading.load_modalias(&fs_alias)? {
+            wg.wait();
+        }
+
+        kinfo!(
+            kcon,
+            "mounting root {} ({})",
+            root_opts.source(),
+            root_opts.fstype()
+        );
+        Mount::Root(root_opts).mount()?;
+        unlocked.root_mounted = true;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, BlockHandlingInner>, PrintableErrno<String>> {
+        self.bookkeeping
+            .lock()
+            .map_err(|_| printable_error(PROGRAM_NAME, "unable to lock block-handling".to_string()))
+    }
+}
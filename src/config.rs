@@ -2,12 +2,15 @@
 
 use crate::{
     early_logging::{buf::KmsgBuf, KConsole, VerbosityLevel},
+    luks::{LuksTargets, LuksTargetsBuilder},
+    lvm::{LvmTargets, LvmTargetsBuilder},
+    md::{MdTargets, MdTargetsBuilder, MdUuid},
     module::ModParams,
     mount::{PartitionSourceBuilder, RootOpts, RootOptsBuilder},
     INIT_DEFAULT_PATH, PROGRAM_NAME,
 };
 use precisej_printable_errno::{printable_error, PrintableErrno};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::{
     collections::BTreeMap,
     ffi::{CStr, CString},
@@ -16,8 +19,8 @@ use std::{
     path::Path,
 };
 
-// Inner struct for InitramfsMetadata deserialization
-#[derive(Deserialize, Debug, Clone)]
+// Inner struct for InitramfsMetadata (de)serialization
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 #[serde(rename_all = "kebab-case")]
 struct InitramfsMetadataDe {
     #[serde(rename = "kver")]
@@ -29,12 +32,15 @@ struct InitramfsMetadataDe {
     module_post_deps: BTreeMap<String, Vec<String>>,
 }
 
-/// \[metadata] section.
+/// \[\[metadata]] section, once per kernel the initramfs was built for.
+///
+/// Images built for a single kernel by older versions of ignited have a single \[metadata]
+/// table instead, which is read the same way.
 ///
 /// Example:
 ///
 /// ```toml
-/// [metadata]
+/// [[metadata]]
 /// kver = "5.10.95-hardened1-1-hardened"
 /// module-builtin = ["foobar", "baz"]
 ///
@@ -59,7 +65,7 @@ impl<'a> InitramfsMetadata<'a> {
     /// (String) The kernel version this initramfs was built for.
     ///
     /// ```toml
-    /// [metadata]
+    /// [[metadata]]
     /// kver = "5.15.16-hardened1-1-precise"
     /// ```
     pub fn kernel_ver(&'_ self) -> &'_ str {
@@ -69,7 +75,7 @@ impl<'a> InitramfsMetadata<'a> {
     /// (Array\[String]) Modules that are already built-in to the kernel.
     ///
     /// ```toml
-    /// [metadata]
+    /// [[metadata]]
     /// module-builtin = ["lkrg", "tirdad"]
     /// ```
     pub fn module_builtin(&'_ self) -> &'_ [String] {
@@ -107,8 +113,8 @@ impl<'a> InitramfsMetadata<'a> {
     }
 }
 
-// Inner struct for IgnitedConfig deserialization
-#[derive(Deserialize, Debug, Clone)]
+// Inner struct for IgnitedConfig (de)serialization
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 #[serde(rename_all = "kebab-case")]
 struct IgnitedConfigDe {
     lvm: bool,
@@ -175,8 +181,8 @@ impl<'a> IgnitedConfig<'a> {
     }
 }
 
-// Inner struct for ConsoleConfig deserialization
-#[derive(Deserialize, Debug, Clone)]
+// Inner struct for ConsoleConfig (de)serialization
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 #[serde(rename_all = "kebab-case")]
 struct ConsoleConfigDe {
     utf: bool,
@@ -268,20 +274,53 @@ impl<'a> ConsoleConfig<'a> {
 ///
 /// [`[ignited]`][IgnitedConfig]
 ///
-/// [`[metadata]`][InitramfsMetadata]
+/// [`[[metadata]]`][InitramfsMetadata] # (Once per kernel, or a single `[metadata]`)
 ///
 /// [`[console]`][ConsoleConfig] # (Optional)
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct RuntimeConfig {
-    metadata: InitramfsMetadataDe,
+    #[serde(deserialize_with = "deserialize_metadata")]
+    metadata: Vec<InitramfsMetadataDe>,
     ignited: IgnitedConfigDe,
     console: Option<ConsoleConfigDe>,
 }
 impl RuntimeConfig {
-    /// `[metadata]`
+    /// Build a new config for an initramfs image targeting kernel version `kver`. Used by
+    /// the [generator][crate::generator].
+    #[inline]
+    pub fn builder<S: Into<String>>(kver: S) -> RuntimeConfigBuilder {
+        RuntimeConfigBuilder::new(kver.into())
+    }
+
+    /// Serialize this config as the contents of `/etc/ignited/engine.toml`.
+    pub fn to_toml(&self) -> Result<String, PrintableErrno<String>> {
+        toml::to_string(self).map_err(|ser| {
+            printable_error(PROGRAM_NAME, format!("error while writing config: {}", ser))
+        })
+    }
+
+    /// `[[metadata]]` of the kernel being booted: the one [selected][Self::select_kernel],
+    /// or the first one otherwise.
     pub fn metadata(&self) -> InitramfsMetadata<'_> {
-        InitramfsMetadata(&self.metadata)
+        InitramfsMetadata(&self.metadata[0])
+    }
+
+    /// `[[metadata]]` of every kernel, in order.
+    pub fn kernels(&self) -> impl Iterator<Item = InitramfsMetadata<'_>> {
+        self.metadata.iter().map(InitramfsMetadata)
+    }
+
+    /// Select the `[[metadata]]` of kernel version `kver` as the one returned by
+    /// [metadata][Self::metadata]. Returns `false` if there's none.
+    pub fn select_kernel(&mut self, kver: &str) -> bool {
+        match self.metadata.iter().position(|m| m.kernel_ver == kver) {
+            Some(i) => {
+                self.metadata.swap(0, i);
+                true
+            }
+            None => false,
+        }
     }
 
     /// `[ignited]`
@@ -294,14 +333,178 @@ impl RuntimeConfig {
         self.console.as_ref().map(ConsoleConfig)
     }
 }
+
+// Inner enum for [metadata] (single kernel, as written by older versions) or [[metadata]]
+// deserialization
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MetadataDe {
+    Single(InitramfsMetadataDe),
+    List(Vec<InitramfsMetadataDe>),
+}
+
+fn deserialize_metadata<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<InitramfsMetadataDe>, D::Error> {
+    Ok(match MetadataDe::deserialize(deserializer)? {
+        MetadataDe::Single(metadata) => vec![metadata],
+        MetadataDe::List(metadata) => metadata,
+    })
+}
+
+/// Builder: ignited TOML configuration file, as written by the initramfs
+/// [generator][crate::generator].
+///
+/// In case of conflicting values, the last specified value takes precedence. `[metadata]`
+/// values apply to the last kernel added.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfigBuilder {
+    prev_metadata: Vec<InitramfsMetadataDe>,
+    metadata: InitramfsMetadataDe,
+    ignited: IgnitedConfigDe,
+    console: Option<ConsoleConfigDe>,
+}
+impl RuntimeConfigBuilder {
+    fn new(kernel_ver: String) -> Self {
+        Self {
+            prev_metadata: Vec::new(),
+            metadata: InitramfsMetadataDe {
+                kernel_ver,
+                ..Default::default()
+            },
+            ignited: IgnitedConfigDe::default(),
+            console: None,
+        }
+    }
+
+    /// Builder: add another kernel version the initramfs targets, with its own
+    /// `[[metadata]]`.
+    pub fn kernel<S: Into<String>>(&mut self, kver: S) -> &mut Self {
+        let metadata = InitramfsMetadataDe {
+            kernel_ver: kver.into(),
+            ..Default::default()
+        };
+        self.prev_metadata.push(std::mem::replace(&mut self.metadata, metadata));
+        self
+    }
+
+    /// Builder: add a module that's built-in to the kernel (`[metadata] module-builtin`).
+    pub fn module_builtin<S: Into<String>>(&mut self, module: S) -> &mut Self {
+        self.metadata.module_builtin.push(module.into());
+        self
+    }
+
+    /// Builder: set the (pre-)dependencies of a module (`[metadata.module-deps]`).
+    pub fn module_deps<S: Into<String>>(&mut self, module: S, deps: Vec<String>) -> &mut Self {
+        self.metadata.module_deps.insert(module.into(), deps);
+        self
+    }
+
+    /// Builder: set the options of a module (`[metadata.module-opts]`).
+    pub fn module_opts<S1: Into<String>, S2: Into<String>>(
+        &mut self,
+        module: S1,
+        opts: S2,
+    ) -> &mut Self {
+        self.metadata.module_opts.insert(module.into(), opts.into());
+        self
+    }
+
+    /// Builder: set the post-dependencies of a module (`[metadata.module-post-deps]`).
+    pub fn module_post_deps<S: Into<String>>(
+        &mut self,
+        module: S,
+        deps: Vec<String>,
+    ) -> &mut Self {
+        self.metadata.module_post_deps.insert(module.into(), deps);
+        self
+    }
+
+    /// Builder: set whether LVM is required to mount the root partition (`[ignited] lvm`).
+    pub fn lvm(&mut self, lvm: bool) -> &mut Self {
+        self.ignited.lvm = lvm;
+        self
+    }
+
+    /// Builder: set whether RAID is required to mount the root partition
+    /// (`[ignited] mdraid`).
+    pub fn mdraid(&mut self, mdraid: bool) -> &mut Self {
+        self.ignited.mdraid = mdraid;
+        self
+    }
+
+    /// Builder: force a kernel module to load during initramfs (`[ignited] module-force`).
+    pub fn force_module<S: Into<String>>(&mut self, module: S) -> &mut Self {
+        self.ignited.module_force.push(module.into());
+        self
+    }
+
+    /// Builder: set the root mount timeout in seconds (`[ignited] mount-timeout`).
+    pub fn mount_timeout(&mut self, mount_timeout: Option<i64>) -> &mut Self {
+        self.ignited.mount_timeout = mount_timeout;
+        self
+    }
+
+    /// Builder: set whether UTF-8 is to be used in the console (`[console] utf`).
+    pub fn console_utf8(&mut self, utf: bool) -> &mut Self {
+        self.console.get_or_insert_with(Default::default).utf = utf;
+        self
+    }
+
+    /// Builder: set the path to the console font (`[console] font-file`).
+    pub fn font_file<S: Into<String>>(&mut self, font_file: S) -> &mut Self {
+        self.console.get_or_insert_with(Default::default).font_file_p = Some(font_file.into());
+        self
+    }
+
+    /// Builder: set the path to the console font map (`[console] font-map-file`).
+    pub fn font_map_file<S: Into<String>>(&mut self, font_map_file: S) -> &mut Self {
+        self.console.get_or_insert_with(Default::default).font_map_file_p =
+            Some(font_map_file.into());
+        self
+    }
+
+    /// Builder: set the path to the console font unicode map
+    /// (`[console] font-unicode-file`).
+    pub fn font_unicode_file<S: Into<String>>(&mut self, font_unicode_file: S) -> &mut Self {
+        self.console.get_or_insert_with(Default::default).font_unicode_file_p =
+            Some(font_unicode_file.into());
+        self
+    }
+
+    /// Builder: set the path to the console keymap (`[console] keymap-file`).
+    pub fn keymap_file<S: Into<String>>(&mut self, keymap_file: S) -> &mut Self {
+        self.console.get_or_insert_with(Default::default).keymap_file_p =
+            Some(keymap_file.into());
+        self
+    }
+
+    /// Builder: finalize the config.
+    pub fn build(mut self) -> RuntimeConfig {
+        self.prev_metadata.push(self.metadata);
+        RuntimeConfig {
+            metadata: self.prev_metadata,
+            ignited: self.ignited,
+            console: self.console,
+        }
+    }
+}
+
 impl TryFrom<&str> for RuntimeConfig {
     type Error = PrintableErrno<String>;
 
     #[inline(always)]
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        toml::from_str(value).map_err(|de| {
+        let config: Self = toml::from_str(value).map_err(|de| {
             printable_error(PROGRAM_NAME, format!("error while reading config: {}", de))
-        })
+        })?;
+        if config.metadata.is_empty() {
+            return Err(printable_error(
+                PROGRAM_NAME,
+                "error while reading config: missing [[metadata]]".to_string(),
+            ));
+        }
+        Ok(config)
     }
 }
 impl TryFrom<String> for RuntimeConfig {
@@ -348,6 +551,10 @@ pub struct CmdlineArgs {
     init: CString,
     root_opts: RootOptsBuilder,
     resume_source: Option<PartitionSourceBuilder>,
+    resume_offset: Option<u64>,
+    luks_targets: LuksTargets,
+    lvm_targets: LvmTargets,
+    md_targets: MdTargets,
     mod_params: ModParams,
 }
 impl CmdlineArgs {
@@ -388,19 +595,19 @@ impl CmdlineArgs {
     ///
     /// Many parameters can affect this value:
     /// - `root` to specify the root partitions device path (`/dev/sda1`), UUID
-    /// (`UUID=e0805d9f-8660-431d-9cfd-134161a9f1c1`), Label (`LABEL=system_a`), GPT Label
-    /// (`PARTLABEL=sysa`), GPT UUID (`PARTUUID=ff5fc4ff-4ff0-4364-9ca0-f75f85b9117d`), or
-    /// GPT UUID with PARTNROFF (`PARTUUID=ff5fc4ff-4ff0-4364-9ca0-f75f85b9117d/PARTNROFF=2`).
-    /// Optional, but if not given, root will be discovered through GPT partition autodiscovery
-    /// and should follow the architecture's appropriate guidelines in order for it to be
-    /// recognized. If no partition is found (or the root parameter specifies an invalid or
-    /// nonexistent one), boot fails.
+    ///   (`UUID=e0805d9f-8660-431d-9cfd-134161a9f1c1`), Label (`LABEL=system_a`), GPT Label
+    ///   (`PARTLABEL=sysa`), GPT UUID (`PARTUUID=ff5fc4ff-4ff0-4364-9ca0-f75f85b9117d`), or
+    ///   GPT UUID with PARTNROFF (`PARTUUID=ff5fc4ff-4ff0-4364-9ca0-f75f85b9117d/PARTNROFF=2`).
+    ///   Optional, but if not given, root will be discovered through GPT partition autodiscovery
+    ///   and should follow the architecture's appropriate guidelines in order for it to be
+    ///   recognized. If no partition is found (or the root parameter specifies an invalid or
+    ///   nonexistent one), boot fails.
     /// - `rootfstype` to specify the filesystem type (e.g. `ext4`). Optional.
     /// - `rootflags` to specify flags for mounting the filesystem (e.g. `relatime,diratime`).
-    /// Optional.
+    ///   Optional.
     /// - `ro` to mount the root filesystem as read-only. Note that your system may remount
-    /// itself as writable after transitioning to it and `ro` will do nothing to prevent it from
-    /// happening. Optional.
+    ///   itself as writable after transitioning to it and `ro` will do nothing to prevent it from
+    ///   happening. Optional.
     /// - `rw` to mount the root filesystem as writable. Optional.
     ///
     /// Example:
@@ -423,10 +630,62 @@ impl CmdlineArgs {
     /// ```no_check
     /// resume=UUID=e0805d9f-8660-431d-9cfd-134161a9f1c1
     /// ```
+    ///
+    /// `None` if no swap partition was given or if resuming was disabled through the
+    /// `noresume` parameter (see the [resume][crate::resume] module for details).
     pub fn resume_source(&self) -> Option<&PartitionSourceBuilder> {
         self.resume_source.as_ref()
     }
 
+    /// Offset (in pages) of the swap file used by the system to resume from hibernation,
+    /// inside the filesystem given as [resume source][Self::resume_source].
+    ///
+    /// Use parameter `resume_offset` to set this value. Example:
+    ///
+    /// ```no_check
+    /// resume=UUID=e0805d9f-8660-431d-9cfd-134161a9f1c1 resume_offset=38912
+    /// ```
+    pub fn resume_offset(&self) -> Option<u64> {
+        self.resume_offset
+    }
+
+    /// LUKS volumes to unlock.
+    ///
+    /// Use parameters `rd.luks.uuid`, `rd.luks.name` and `rd.luks.options` to set this value
+    /// (see [LuksTargetsBuilder] and the [luks][crate::luks] module for details). Without
+    /// `rd.luks.uuid` nor `rd.luks.name`, every LUKS volume is unlocked. Example:
+    ///
+    /// ```no_check
+    /// rd.luks.name=e0805d9f-8660-431d-9cfd-134161a9f1c1=cryptroot rd.luks.options=discard
+    /// ```
+    pub fn luks_targets(&self) -> &LuksTargets {
+        &self.luks_targets
+    }
+
+    /// LVM2 logical volumes to activate.
+    ///
+    /// Use parameters `rd.lvm`, `rd.lvm.vg` and `rd.lvm.lv` to set this value (see the
+    /// [lvm][crate::lvm] module for details). Example:
+    ///
+    /// ```no_check
+    /// rd.lvm.lv=vg0/root rd.lvm.lv=vg0/swap
+    /// ```
+    pub fn lvm_targets(&self) -> &LvmTargets {
+        &self.lvm_targets
+    }
+
+    /// md RAID arrays to assemble.
+    ///
+    /// Use parameters `rd.md`, `rd.md.uuid` and `rd.retry` to set this value (see the
+    /// [md][crate::md] module for details). Example:
+    ///
+    /// ```no_check
+    /// rd.md.uuid=4d8a8e2c:9a1b3f5e:0c7d2e4f:6a8b0c1d rd.retry=60
+    /// ```
+    pub fn md_targets(&self) -> &MdTargets {
+        &self.md_targets
+    }
+
     /// Parameters for kernel module initialization.
     ///
     /// Format for expressing in command-line arguments is `module.key = value`. Example:
@@ -447,6 +706,11 @@ impl CmdlineArgs {
         let mut init: Option<CString> = None;
         let mut root_opts = RootOpts::builder();
         let mut resume_source: Option<PartitionSourceBuilder> = None;
+        let mut resume_offset: Option<u64> = None;
+        let mut noresume = false;
+        let mut luks_targets = LuksTargets::builder();
+        let mut lvm_targets = LvmTargets::builder();
+        let mut md_targets = MdTargets::builder();
         let mut mod_params = ModParams::default();
         for arg in cmdline_spl {
             let (arg_key, arg_value) = match arg.split_once('=') {
@@ -465,24 +729,48 @@ impl CmdlineArgs {
                 "quiet" => Self::parse_quiet(&mut verbosity_level),
                 "root" => Self::parse_root(&mut kmsg_buf, &mut root_opts, arg_value)?,
                 "resume" => Self::parse_resume(&mut kmsg_buf, &mut resume_source, arg_value)?,
+                "resume_offset" => {
+                    Self::parse_resume_offset(&mut kmsg_buf, &mut resume_offset, arg_value)
+                }
+                "noresume" => Self::parse_noresume(&mut noresume),
                 "init" => Self::parse_init(&mut kmsg_buf, &mut init, arg_value)?,
                 "rootfstype" => Self::parse_rootfstype(&mut kmsg_buf, &mut root_opts, arg_value),
                 "rootflags" => Self::parse_rootflags(&mut kmsg_buf, &mut root_opts, arg_value),
                 "ro" => Self::parse_rootmode(&mut root_opts, false),
                 "rw" => Self::parse_rootmode(&mut root_opts, true),
-                "rd.luks.options" => Self::parse_luksopts(&mut kmsg_buf),
-                "rd.luks.name" => Self::parse_luksname(&mut kmsg_buf),
-                "rd.luks.uuid" => Self::parse_luksuuid(&mut kmsg_buf),
+                "rd.luks" | "luks" => Self::parse_luks(&mut kmsg_buf, &mut luks_targets, arg_value),
+                "rd.luks.options" | "luks.options" => {
+                    Self::parse_luksopts(&mut kmsg_buf, &mut luks_targets, arg_value)
+                }
+                "rd.luks.name" | "luks.name" => {
+                    Self::parse_luksname(&mut kmsg_buf, &mut luks_targets, arg_value)
+                }
+                "rd.luks.uuid" | "luks.uuid" => {
+                    Self::parse_luksuuid(&mut kmsg_buf, &mut luks_targets, arg_value)
+                }
+                "rd.lvm" => Self::parse_lvm(&mut kmsg_buf, &mut lvm_targets, arg_value),
+                "rd.lvm.vg" => Self::parse_lvmvg(&mut kmsg_buf, &mut lvm_targets, arg_value),
+                "rd.lvm.lv" => Self::parse_lvmlv(&mut kmsg_buf, &mut lvm_targets, arg_value),
+                "rd.md" => Self::parse_md(&mut kmsg_buf, &mut md_targets, arg_value),
+                "rd.md.uuid" => Self::parse_mduuid(&mut kmsg_buf, &mut md_targets, arg_value),
+                "rd.retry" => Self::parse_retry(&mut kmsg_buf, &mut md_targets, arg_value),
                 mod_param => {
                     Self::parse_mod_param(&mut kmsg_buf, &mut mod_params, mod_param, arg_value)
                 }
             }
         }
+        let luks_targets = luks_targets.build(&mut kmsg_buf);
+        let lvm_targets = lvm_targets.build(&mut kmsg_buf);
+        let md_targets = md_targets.build();
         kmsg_buf.flush_with_level(verbosity_level.unwrap_or_default());
         Ok(CmdlineArgs {
             init: init.unwrap_or_else(|| INIT_DEFAULT_PATH.into()),
             root_opts,
-            resume_source,
+            resume_source: resume_source.filter(|_| !noresume),
+            resume_offset,
+            luks_targets,
+            lvm_targets,
+            md_targets,
             mod_params,
         })
     }
@@ -499,10 +787,10 @@ impl CmdlineArgs {
     /// logging verbosity to the specified value.
     ///
     /// - `ignited.log=<VALUE>` is preferred, where `<VALUE>` corresponds to a textual
-    /// representation of a [VerbosityLevel] (see its documentation for more details).
+    ///   representation of a [VerbosityLevel] (see its documentation for more details).
     /// - `booster.log=<VALUE-1>[,<VALUE-2>[,<...>]]` is accepted, where:
     ///   - `<VALUE-N>` corresponds to a textual representation of a [VerbosityLevel]
-    /// (see its documentation for more details).
+    ///     (see its documentation for more details).
     ///   - In case of conflicting values, the first specified value takes precedence.
     ///   - The `console` value is ignored by ignited.
     fn parse_ignited_log(
@@ -598,6 +886,29 @@ impl CmdlineArgs {
         Ok(())
     }
 
+    /// `resume_offset=<PAGES>` sets the offset of the swap file from which to resume
+    /// hibernation.
+    fn parse_resume_offset(
+        kmsg_buf: &mut KmsgBuf,
+        resume_offset: &mut Option<u64>,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value.and_then(|av| av.parse().ok()) {
+            Some(offset) => {
+                resume_offset.get_or_insert(offset);
+            }
+            None => kmsg_buf.kwarn(format!(
+                "invalid resume_offset key {}, ignoring",
+                arg_value.unwrap_or("<EMPTY>")
+            )),
+        }
+    }
+
+    /// `noresume` disables resuming from hibernation, even if `resume` is given.
+    fn parse_noresume(noresume: &mut bool) {
+        *noresume = true;
+    }
+
     /// `root=<VALUE>` sets the root partition to mount.
     ///
     /// See [PartitionSourceBuilder] for details on how this parameter should be formatted.
@@ -659,16 +970,196 @@ impl CmdlineArgs {
         }
     }
 
-    fn parse_luksopts(_kmsg_buf: &mut KmsgBuf) {
-        todo!("Parse luks options")
+    /// `rd.luks=<BOOL>` enables or disables unlocking LUKS volumes. `rd.luks` alone is
+    /// equivalent to `rd.luks=1`.
+    fn parse_luks(
+        kmsg_buf: &mut KmsgBuf,
+        luks_targets: &mut LuksTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value {
+            None | Some("1" | "yes" | "true" | "on") => {
+                luks_targets.enable(true);
+            }
+            Some("0" | "no" | "false" | "off") => {
+                luks_targets.enable(false);
+            }
+            Some(arg_value) => kmsg_buf.kwarn(format!("unknown rd.luks key {}", arg_value)),
+        }
+    }
+
+    /// `rd.luks.options=[<UUID>=]<OPTIONS>` sets the options used to unlock LUKS volumes.
+    ///
+    /// See [LuksOptions][crate::luks::LuksOptions] for the supported options.
+    fn parse_luksopts(
+        kmsg_buf: &mut KmsgBuf,
+        luks_targets: &mut LuksTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        if let Some(arg_value) = arg_value {
+            // Options may contain '=' themselves (e.g. tries=3), so only treat the first
+            // part as a UUID if it actually is one.
+            match arg_value
+                .split_once('=')
+                .and_then(|(uuid, opts)| Some((LuksTargetsBuilder::parse_uuid(uuid)?, opts)))
+            {
+                Some((uuid, opts)) => luks_targets.options(Some(uuid), opts),
+                None => luks_targets.options(None, arg_value),
+            };
+        } else {
+            kmsg_buf.kwarn("rd.luks.options key is empty, ignoring".to_string());
+        }
+    }
+
+    /// `rd.luks.name=<UUID>=<NAME>` unlocks the LUKS volume with the given UUID as
+    /// `/dev/mapper/<NAME>`.
+    fn parse_luksname(
+        kmsg_buf: &mut KmsgBuf,
+        luks_targets: &mut LuksTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        let (uuid, name) = match arg_value.and_then(|av| av.split_once('=')) {
+            Some((uuid, name)) => (uuid, name),
+            None => {
+                kmsg_buf.kwarn(format!(
+                    "invalid rd.luks.name key {}, ignoring",
+                    arg_value.unwrap_or("<EMPTY>")
+                ));
+                return;
+            }
+        };
+        match LuksTargetsBuilder::parse_uuid(uuid) {
+            Some(_) if name.is_empty() || name.contains('/') => {
+                kmsg_buf.kwarn(format!("invalid rd.luks.name name {}, ignoring", name))
+            }
+            Some(uuid) => {
+                luks_targets.name(uuid, name);
+            }
+            None => kmsg_buf.kwarn(format!("invalid rd.luks.name uuid {}, ignoring", uuid)),
+        }
+    }
+
+    /// `rd.luks.uuid=[luks-]<UUID>` unlocks the LUKS volume with the given UUID as
+    /// `/dev/mapper/luks-<UUID>`.
+    fn parse_luksuuid(
+        kmsg_buf: &mut KmsgBuf,
+        luks_targets: &mut LuksTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value.and_then(LuksTargetsBuilder::parse_uuid) {
+            Some(uuid) => {
+                luks_targets.uuid(uuid);
+            }
+            None => kmsg_buf.kwarn(format!(
+                "invalid rd.luks.uuid key {}, ignoring",
+                arg_value.unwrap_or("<EMPTY>")
+            )),
+        }
+    }
+
+    /// `rd.lvm=<BOOL>` enables or disables activating LVM2 logical volumes. `rd.lvm` alone
+    /// is equivalent to `rd.lvm=1`.
+    fn parse_lvm(
+        kmsg_buf: &mut KmsgBuf,
+        lvm_targets: &mut LvmTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value {
+            None | Some("1" | "yes" | "true" | "on") => {
+                lvm_targets.enable(true);
+            }
+            Some("0" | "no" | "false" | "off") => {
+                lvm_targets.enable(false);
+            }
+            Some(arg_value) => kmsg_buf.kwarn(format!("unknown rd.lvm key {}", arg_value)),
+        }
+    }
+
+    /// `rd.lvm.vg=<VG>` activates all logical volumes of the given volume group.
+    fn parse_lvmvg(
+        kmsg_buf: &mut KmsgBuf,
+        lvm_targets: &mut LvmTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value {
+            Some(vg) if !vg.is_empty() && !vg.contains('/') => {
+                lvm_targets.vg(vg);
+            }
+            _ => kmsg_buf.kwarn(format!(
+                "invalid rd.lvm.vg key {}, ignoring",
+                arg_value.unwrap_or("<EMPTY>")
+            )),
+        }
+    }
+
+    /// `rd.lvm.lv=<VG>/<LV>` activates the given logical volume.
+    fn parse_lvmlv(
+        kmsg_buf: &mut KmsgBuf,
+        lvm_targets: &mut LvmTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value.and_then(LvmTargetsBuilder::parse_lv) {
+            Some((vg, lv)) => {
+                lvm_targets.lv(vg, lv);
+            }
+            None => kmsg_buf.kwarn(format!(
+                "invalid rd.lvm.lv key {}, ignoring",
+                arg_value.unwrap_or("<EMPTY>")
+            )),
+        }
     }
 
-    fn parse_luksname(_kmsg_buf: &mut KmsgBuf) {
-        todo!("Parse luks options")
+    /// `rd.md=<BOOL>` enables or disables assembling md RAID arrays. `rd.md` alone is
+    /// equivalent to `rd.md=1`.
+    fn parse_md(
+        kmsg_buf: &mut KmsgBuf,
+        md_targets: &mut MdTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value {
+            None | Some("1" | "yes" | "true" | "on") => {
+                md_targets.enable(true);
+            }
+            Some("0" | "no" | "false" | "off") => {
+                md_targets.enable(false);
+            }
+            Some(arg_value) => kmsg_buf.kwarn(format!("unknown rd.md key {}", arg_value)),
+        }
     }
 
-    fn parse_luksuuid(_kmsg_buf: &mut KmsgBuf) {
-        todo!("Parse luks options")
+    /// `rd.md.uuid=<UUID>` only assembles the md RAID array with the given UUID.
+    fn parse_mduuid(
+        kmsg_buf: &mut KmsgBuf,
+        md_targets: &mut MdTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value.and_then(MdUuid::parse) {
+            Some(uuid) => {
+                md_targets.uuid(uuid);
+            }
+            None => kmsg_buf.kwarn(format!(
+                "invalid rd.md.uuid key {}, ignoring",
+                arg_value.unwrap_or("<EMPTY>")
+            )),
+        }
+    }
+
+    /// `rd.retry=<SECONDS>` sets how long to wait for missing md RAID members. Degraded
+    /// arrays are started after 2/3 of it.
+    fn parse_retry(
+        kmsg_buf: &mut KmsgBuf,
+        md_targets: &mut MdTargetsBuilder,
+        arg_value: Option<&str>,
+    ) {
+        match arg_value.and_then(|av| av.parse().ok()) {
+            Some(retry) => {
+                md_targets.retry(retry);
+            }
+            None => kmsg_buf.kwarn(format!(
+                "invalid rd.retry key {}, ignoring",
+                arg_value.unwrap_or("<EMPTY>")
+            )),
+        }
     }
 
     /// `quiet` sets the logging verbosity level to Err.
@@ -690,3 +1181,91 @@ impl CmdlineArgs {
         verbosity_level.get_or_insert(VerbosityLevel::Err);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IGNITED: &str = r#"
+[ignited]
+lvm = false
+mdraid = true
+module-force = ["dm_crypt"]
+"#;
+
+    const METADATA: &str = r#"
+kver = "5.17.1-arch1-1"
+module-builtin = ["ext4"]
+
+[metadata.module-deps]
+dm_crypt = ["dm_mod"]
+
+[metadata.module-opts]
+dm_mod = "use_blk_mq=1"
+
+[metadata.module-post-deps]
+nvme = []
+"#;
+
+    const LTS_METADATA: &str = r#"
+kver = "5.15.32-1-lts"
+module-builtin = []
+
+[metadata.module-deps]
+
+[metadata.module-opts]
+
+[metadata.module-post-deps]
+"#;
+
+    fn kvers(config: &RuntimeConfig) -> Vec<&str> {
+        config.kernels().map(|m| m.0.kernel_ver.as_str()).collect()
+    }
+
+    #[test]
+    fn single_metadata() {
+        let toml = format!("{}\n[metadata]{}", IGNITED, METADATA);
+        let config = RuntimeConfig::try_from(toml).unwrap();
+        assert_eq!(kvers(&config), ["5.17.1-arch1-1"]);
+        let metadata = config.metadata();
+        assert_eq!(metadata.module_builtin(), ["ext4"]);
+        assert_eq!(metadata.module_deps()["dm_crypt"], ["dm_mod"]);
+        assert_eq!(metadata.module_opts()["dm_mod"], "use_blk_mq=1");
+        assert!(metadata.module_post_deps()["nvme"].is_empty());
+        assert!(config.sysconf().has_mdraid());
+    }
+
+    #[test]
+    fn metadata_list() {
+        let toml = format!(
+            "{}\n[[metadata]]{}\n[[metadata]]{}",
+            IGNITED, METADATA, LTS_METADATA
+        );
+        let mut config = RuntimeConfig::try_from(toml).unwrap();
+        assert_eq!(kvers(&config), ["5.17.1-arch1-1", "5.15.32-1-lts"]);
+        assert_eq!(config.metadata().kernel_ver(), "5.17.1-arch1-1");
+
+        assert!(config.select_kernel("5.15.32-1-lts"));
+        assert_eq!(config.metadata().kernel_ver(), "5.15.32-1-lts");
+        assert!(config.metadata().module_builtin().is_empty());
+        assert!(!config.select_kernel("5.10.109-1-lts"));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut builder = RuntimeConfig::builder("5.17.1-arch1-1");
+        builder.module_builtin("ext4").kernel("5.15.32-1-lts");
+        let toml = builder.build().to_toml().unwrap();
+        assert!(toml.contains("[[metadata]]"));
+        let config = RuntimeConfig::try_from(toml).unwrap();
+        assert_eq!(kvers(&config), ["5.17.1-arch1-1", "5.15.32-1-lts"]);
+        assert_eq!(config.metadata().module_builtin(), ["ext4"]);
+    }
+
+    #[test]
+    fn missing_metadata() {
+        assert!(RuntimeConfig::try_from(IGNITED).is_err());
+        let toml = format!("{}\nmetadata = []\n", IGNITED);
+        assert!(RuntimeConfig::try_from(toml).is_err());
+    }
+}
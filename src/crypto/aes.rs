@@ -0,0 +1,235 @@
+//! AES block cipher (FIPS 197) with 128, 192 and 256-bit keys.
+//!
+//! This is a plain table-based implementation. It's only used to decrypt LUKS keyslots
+//! once per boot, so speed isn't a concern (bulk encryption is done by the kernel).
+
+const SBOX: [u8; 256] = make_sbox();
+const INV_SBOX: [u8; 256] = make_inv_sbox();
+
+const fn xtime(x: u8) -> u8 {
+    (x << 1) ^ (((x >> 7) & 1) * 0x1b)
+}
+
+const fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut res = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            res ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    res
+}
+
+const fn make_sbox() -> [u8; 256] {
+    // Powers and logarithms of the generator 3 in GF(2^8)
+    let mut exp = [0u8; 255];
+    let mut log = [0u8; 256];
+    let mut x = 1u8;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x;
+        log[x as usize] = i as u8;
+        x ^= xtime(x);
+        i += 1;
+    }
+
+    let mut sbox = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        // Multiplicative inverse, followed by the affine transformation
+        let inv = if i == 0 {
+            0
+        } else {
+            exp[(255 - log[i] as usize) % 255]
+        };
+        sbox[i] = inv
+            ^ inv.rotate_left(1)
+            ^ inv.rotate_left(2)
+            ^ inv.rotate_left(3)
+            ^ inv.rotate_left(4)
+            ^ 0x63;
+        i += 1;
+    }
+    sbox
+}
+
+const fn make_inv_sbox() -> [u8; 256] {
+    let sbox = make_sbox();
+    let mut inv = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        inv[sbox[i] as usize] = i as u8;
+        i += 1;
+    }
+    inv
+}
+
+/// Expanded AES key.
+#[derive(Clone)]
+pub struct Aes {
+    // Boxed so that moving an Aes around doesn't leave copies of the key schedule behind
+    round_keys: Box<[[u8; 16]; 15]>,
+    rounds: usize,
+}
+impl Aes {
+    /// Expand a 16, 24 or 32-byte key. Returns `None` for any other key length.
+    pub fn new(key: &[u8]) -> Option<Self> {
+        let nk = match key.len() {
+            16 | 24 | 32 => key.len() / 4,
+            _ => return None,
+        };
+        let rounds = nk + 6;
+        let mut w = [[0u8; 4]; 60];
+        for (i, chunk) in key.chunks_exact(4).enumerate() {
+            w[i].copy_from_slice(chunk);
+        }
+        let mut rcon = 1u8;
+        for i in nk..4 * (rounds + 1) {
+            let mut t = w[i - 1];
+            if i % nk == 0 {
+                t = [
+                    SBOX[t[1] as usize] ^ rcon,
+                    SBOX[t[2] as usize],
+                    SBOX[t[3] as usize],
+                    SBOX[t[0] as usize],
+                ];
+                rcon = xtime(rcon);
+            } else if nk > 6 && i % nk == 4 {
+                t = t.map(|b| SBOX[b as usize]);
+            }
+            for j in 0..4 {
+                w[i][j] = w[i - nk][j] ^ t[j];
+            }
+        }
+
+        let mut round_keys = Box::new([[0u8; 16]; 15]);
+        for (r, rk) in round_keys.iter_mut().enumerate().take(rounds + 1) {
+            for c in 0..4 {
+                rk[4 * c..4 * c + 4].copy_from_slice(&w[4 * r + c]);
+            }
+        }
+        super::zeroize(&mut w);
+        Some(Self { round_keys, rounds })
+    }
+
+    fn add_round_key(state: &mut [u8; 16], rk: &[u8; 16]) {
+        for (s, k) in state.iter_mut().zip(rk) {
+            *s ^= k;
+        }
+    }
+
+    // State is stored column by column: byte (row r, column c) is at index 4c + r
+    fn shift_rows(state: &mut [u8; 16], inverse: bool) {
+        let s = *state;
+        for r in 1..4 {
+            for c in 0..4 {
+                let src = if inverse { (c + 4 - r) % 4 } else { (c + r) % 4 };
+                state[4 * c + r] = s[4 * src + r];
+            }
+        }
+    }
+
+    fn mix_columns(state: &mut [u8; 16], inverse: bool) {
+        let m: [u8; 4] = if inverse { [14, 11, 13, 9] } else { [2, 3, 1, 1] };
+        for c in 0..4 {
+            let col = [state[4 * c], state[4 * c + 1], state[4 * c + 2], state[4 * c + 3]];
+            for r in 0..4 {
+                state[4 * c + r] = gmul(col[r], m[0])
+                    ^ gmul(col[(r + 1) % 4], m[1])
+                    ^ gmul(col[(r + 2) % 4], m[2])
+                    ^ gmul(col[(r + 3) % 4], m[3]);
+            }
+        }
+    }
+
+    /// Encrypt a single 16-byte block in place.
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        Self::add_round_key(block, &self.round_keys[0]);
+        for round in 1..=self.rounds {
+            for b in block.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            Self::shift_rows(block, false);
+            if round != self.rounds {
+                Self::mix_columns(block, false);
+            }
+            Self::add_round_key(block, &self.round_keys[round]);
+        }
+    }
+
+    /// Decrypt a single 16-byte block in place.
+    pub fn decrypt_block(&self, block: &mut [u8; 16]) {
+        Self::add_round_key(block, &self.round_keys[self.rounds]);
+        for round in (0..self.rounds).rev() {
+            Self::shift_rows(block, true);
+            for b in block.iter_mut() {
+                *b = INV_SBOX[*b as usize];
+            }
+            Self::add_round_key(block, &self.round_keys[round]);
+            if round != 0 {
+                Self::mix_columns(block, true);
+            }
+        }
+    }
+}
+impl Drop for Aes {
+    fn drop(&mut self) {
+        super::zeroize(&mut *self.round_keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::from_hex;
+
+    fn check(key: &str, plaintext: &str, ciphertext: &str) {
+        let aes = Aes::new(&from_hex(key)).unwrap();
+        let plaintext: [u8; 16] = from_hex(plaintext).try_into().unwrap();
+        let ciphertext: [u8; 16] = from_hex(ciphertext).try_into().unwrap();
+
+        let mut block = plaintext;
+        aes.encrypt_block(&mut block);
+        assert_eq!(block, ciphertext);
+        aes.decrypt_block(&mut block);
+        assert_eq!(block, plaintext);
+    }
+
+    #[test]
+    fn fips197_appendix_b() {
+        check(
+            "2b7e151628aed2a6abf7158809cf4f3c",
+            "3243f6a8885a308d313198a2e0370734",
+            "3925841d02dc09fbdc118597196a0b32",
+        );
+    }
+
+    #[test]
+    fn fips197_appendix_c() {
+        let plaintext = "00112233445566778899aabbccddeeff";
+        check(
+            "000102030405060708090a0b0c0d0e0f",
+            plaintext,
+            "69c4e0d86a7b0430d8cdb78070b4c55a",
+        );
+        check(
+            "000102030405060708090a0b0c0d0e0f1011121314151617",
+            plaintext,
+            "dda97ca4864cdfe06eaf70a0ec0d7191",
+        );
+        check(
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            plaintext,
+            "8ea2b7ca516745bfeafc49904b496089",
+        );
+    }
+
+    #[test]
+    fn invalid_key_sizes() {
+        for len in [0, 8, 15, 17, 31, 33, 64] {
+            assert!(Aes::new(&vec![0; len]).is_none());
+        }
+    }
+}
@@ -0,0 +1,468 @@
+//! Argon2i and Argon2id (RFC 9106, version 0x13).
+//!
+//! Lanes are computed in parallel, one thread per lane, as memory-hard parameters chosen by
+//! `cryptsetup` on a multi-core machine would take too long to compute sequentially.
+
+use super::blake2b::Blake2b;
+use crate::PROGRAM_NAME;
+use crossbeam_utils::thread;
+use precisej_printable_errno::{printable_error, PrintableErrno};
+
+const VERSION: u32 = 0x13;
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: u32 = 4;
+const ADDRESSES_IN_BLOCK: u32 = 128;
+
+type Block = [u64; BLOCK_WORDS];
+
+/// Argon2 variant.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Argon2Variant {
+    /// Data-independent memory access.
+    Argon2i,
+
+    /// Data-independent memory access for the first half of the first pass only.
+    Argon2id,
+}
+impl Argon2Variant {
+    fn type_id(self) -> u32 {
+        match self {
+            Self::Argon2i => 1,
+            Self::Argon2id => 2,
+        }
+    }
+}
+
+/// Argon2 cost parameters.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Argon2Params {
+    /// Number of passes.
+    pub time: u32,
+
+    /// Memory size in KiB.
+    pub memory: u32,
+
+    /// Degree of parallelism.
+    pub lanes: u32,
+}
+
+// H' variable-length hash function
+fn hash_long(out: &mut [u8], parts: &[&[u8]]) {
+    let mut h = Blake2b::new(out.len().min(64));
+    h.update(&(out.len() as u32).to_le_bytes());
+    for part in parts {
+        h.update(part);
+    }
+    if out.len() <= 64 {
+        h.finalize_into(out);
+        return;
+    }
+
+    let mut v = [0u8; 64];
+    h.finalize_into(&mut v);
+    let mut pos = 0;
+    while out.len() - pos > 64 {
+        out[pos..pos + 32].copy_from_slice(&v[..32]);
+        pos += 32;
+        let mut h = Blake2b::new((out.len() - pos).min(64));
+        h.update(&v);
+        h.finalize_into(&mut v);
+    }
+    let rem = out.len() - pos;
+    out[pos..].copy_from_slice(&v[..rem]);
+}
+
+fn block_from_bytes(bytes: &[u8]) -> Block {
+    let mut block = [0u64; BLOCK_WORDS];
+    for (w, chunk) in block.iter_mut().zip(bytes.chunks_exact(8)) {
+        *w = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    block
+}
+
+#[inline(always)]
+fn gb(v: &mut Block, a: usize, b: usize, c: usize, d: usize) {
+    #[inline(always)]
+    fn fblamka(x: u64, y: u64) -> u64 {
+        let xy = (x & 0xffffffff) * (y & 0xffffffff);
+        x.wrapping_add(y).wrapping_add(xy.wrapping_mul(2))
+    }
+    v[a] = fblamka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = fblamka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = fblamka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = fblamka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+// Permutation P applied to the 16 words at the given indices
+#[inline(always)]
+fn permute(v: &mut Block, i: [usize; 16]) {
+    gb(v, i[0], i[4], i[8], i[12]);
+    gb(v, i[1], i[5], i[9], i[13]);
+    gb(v, i[2], i[6], i[10], i[14]);
+    gb(v, i[3], i[7], i[11], i[15]);
+    gb(v, i[0], i[5], i[10], i[15]);
+    gb(v, i[1], i[6], i[11], i[12]);
+    gb(v, i[2], i[7], i[8], i[13]);
+    gb(v, i[3], i[4], i[9], i[14]);
+}
+
+// Compression function G. If `xor` is set, the result is XORed into `out` (passes after the
+// first one) instead of overwriting it.
+fn compress(out: &mut Block, x: &Block, y: &Block, xor: bool) {
+    let mut r = [0u64; BLOCK_WORDS];
+    for i in 0..BLOCK_WORDS {
+        r[i] = x[i] ^ y[i];
+    }
+    let mut z = r;
+    for row in 0..8 {
+        let b = row * 16;
+        permute(
+            &mut z,
+            [
+                b, b + 1, b + 2, b + 3, b + 4, b + 5, b + 6, b + 7, b + 8, b + 9, b + 10, b + 11,
+                b + 12, b + 13, b + 14, b + 15,
+            ],
+        );
+    }
+    for col in 0..8 {
+        let b = col * 2;
+        permute(
+            &mut z,
+            [
+                b, b + 1, b + 16, b + 17, b + 32, b + 33, b + 48, b + 49, b + 64, b + 65,
+                b + 80, b + 81, b + 96, b + 97, b + 112, b + 113,
+            ],
+        );
+    }
+    for i in 0..BLOCK_WORDS {
+        if xor {
+            out[i] ^= z[i] ^ r[i];
+        } else {
+            out[i] = z[i] ^ r[i];
+        }
+    }
+}
+
+// Raw pointer to the memory matrix, shared between lane threads. Within a slice, each lane
+// only writes to its own segment and only reads blocks that aren't being written.
+#[derive(Clone, Copy)]
+struct MemPtr(*mut Block);
+unsafe impl Send for MemPtr {}
+unsafe impl Sync for MemPtr {}
+
+#[derive(Clone, Copy)]
+struct Position {
+    pass: u32,
+    slice: u32,
+    lane: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Geometry {
+    variant: Argon2Variant,
+    passes: u32,
+    lanes: u32,
+    blocks: u32,
+    lane_length: u32,
+    segment_length: u32,
+}
+impl Geometry {
+    fn fill_segment(&self, mem: MemPtr, pos: Position) {
+        let data_independent = match self.variant {
+            Argon2Variant::Argon2i => true,
+            Argon2Variant::Argon2id => pos.pass == 0 && pos.slice < SYNC_POINTS / 2,
+        };
+
+        let zero = [0u64; BLOCK_WORDS];
+        let mut input = [0u64; BLOCK_WORDS];
+        let mut addresses = [0u64; BLOCK_WORDS];
+        input[0] = pos.pass as u64;
+        input[1] = pos.lane as u64;
+        input[2] = pos.slice as u64;
+        input[3] = self.blocks as u64;
+        input[4] = self.passes as u64;
+        input[5] = self.variant.type_id() as u64;
+        let next_addresses = |input: &mut Block, addresses: &mut Block| {
+            input[6] += 1;
+            let mut tmp = [0u64; BLOCK_WORDS];
+            compress(&mut tmp, &zero, input, false);
+            compress(addresses, &zero, &tmp, false);
+        };
+
+        let mut start_index = 0;
+        if pos.pass == 0 && pos.slice == 0 {
+            // The first two blocks of each lane are already initialized
+            start_index = 2;
+            if data_independent {
+                next_addresses(&mut input, &mut addresses);
+            }
+        }
+
+        let lane_start = pos.lane * self.lane_length;
+        for index in start_index..self.segment_length {
+            let curr = pos.slice * self.segment_length + index;
+            let prev = if curr == 0 {
+                self.lane_length - 1
+            } else {
+                curr - 1
+            };
+
+            // SAFETY: prev is in this lane and has already been computed, see MemPtr
+            let prev_block = unsafe { &*mem.0.add((lane_start + prev) as usize) };
+            let pseudo_rand = if data_independent {
+                if index % ADDRESSES_IN_BLOCK == 0 {
+                    next_addresses(&mut input, &mut addresses);
+                }
+                addresses[(index % ADDRESSES_IN_BLOCK) as usize]
+            } else {
+                prev_block[0]
+            };
+
+            let ref_lane = if pos.pass == 0 && pos.slice == 0 {
+                pos.lane
+            } else {
+                ((pseudo_rand >> 32) % self.lanes as u64) as u32
+            };
+            let ref_index = self.index_alpha(pos, index, pseudo_rand as u32, ref_lane == pos.lane);
+
+            // SAFETY: the reference block is never part of a segment being computed, see
+            // MemPtr
+            let ref_block = unsafe { &*mem.0.add((ref_lane * self.lane_length + ref_index) as usize) };
+            let curr_block = unsafe { &mut *mem.0.add((lane_start + curr) as usize) };
+            compress(curr_block, prev_block, ref_block, pos.pass != 0);
+        }
+    }
+
+    // Map the pseudo-random value to a block index within the reference lane
+    fn index_alpha(&self, pos: Position, index: u32, pseudo_rand: u32, same_lane: bool) -> u32 {
+        let ref_area_size = if pos.pass == 0 {
+            if pos.slice == 0 {
+                index - 1
+            } else if same_lane {
+                pos.slice * self.segment_length + index - 1
+            } else if index == 0 {
+                pos.slice * self.segment_length - 1
+            } else {
+                pos.slice * self.segment_length
+            }
+        } else if same_lane {
+            self.lane_length - self.segment_length + index - 1
+        } else if index == 0 {
+            self.lane_length - self.segment_length - 1
+        } else {
+            self.lane_length - self.segment_length
+        };
+
+        let x = (pseudo_rand as u64 * pseudo_rand as u64) >> 32;
+        let y = (ref_area_size as u64 * x) >> 32;
+        let relative = ref_area_size - 1 - y as u32;
+        let start = if pos.pass != 0 && pos.slice != SYNC_POINTS - 1 {
+            (pos.slice + 1) * self.segment_length
+        } else {
+            0
+        };
+        (start + relative) % self.lane_length
+    }
+}
+
+/// Derive `out.len()` bytes from `password` and `salt`.
+pub fn argon2(
+    variant: Argon2Variant,
+    params: Argon2Params,
+    password: &[u8],
+    salt: &[u8],
+    out: &mut [u8],
+) -> Result<(), PrintableErrno<String>> {
+    argon2_keyed(variant, params, password, salt, &[], &[], out)
+}
+
+// Argon2 with a secret key and associated data, which LUKS2 doesn't use
+fn argon2_keyed(
+    variant: Argon2Variant,
+    params: Argon2Params,
+    password: &[u8],
+    salt: &[u8],
+    secret: &[u8],
+    associated_data: &[u8],
+    out: &mut [u8],
+) -> Result<(), PrintableErrno<String>> {
+    let Argon2Params {
+        time,
+        memory,
+        lanes,
+    } = params;
+    if time == 0 || lanes == 0 || lanes > 0xffffff || out.len() < 4 {
+        return Err(printable_error(
+            PROGRAM_NAME,
+            format!("invalid argon2 parameters: {:?}", params),
+        ));
+    }
+    let memory = memory.max(2 * SYNC_POINTS * lanes);
+    let segment_length = memory / (lanes * SYNC_POINTS);
+    let lane_length = segment_length * SYNC_POINTS;
+    let blocks = lane_length * lanes;
+    let geometry = Geometry {
+        variant,
+        passes: time,
+        lanes,
+        blocks,
+        lane_length,
+        segment_length,
+    };
+
+    let mut h0 = [0u8; 72];
+    {
+        let mut h = Blake2b::new(64);
+        for param in [lanes, out.len() as u32, params.memory, time, VERSION, variant.type_id()] {
+            h.update(&param.to_le_bytes());
+        }
+        for input in [password, salt, secret, associated_data] {
+            h.update(&(input.len() as u32).to_le_bytes());
+            h.update(input);
+        }
+        h.finalize_into(&mut h0[..64]);
+    }
+
+    let mut mem: Vec<Block> = Vec::new();
+    mem.try_reserve_exact(blocks as usize).map_err(|_| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to allocate {} KiB for argon2", blocks),
+        )
+    })?;
+    mem.resize(blocks as usize, [0; BLOCK_WORDS]);
+
+    let mut block_bytes = [0u8; BLOCK_WORDS * 8];
+    for lane in 0..lanes {
+        for i in 0..2u32 {
+            h0[64..68].copy_from_slice(&i.to_le_bytes());
+            h0[68..72].copy_from_slice(&lane.to_le_bytes());
+            hash_long(&mut block_bytes, &[&h0]);
+            mem[(lane * lane_length + i) as usize] = block_from_bytes(&block_bytes);
+        }
+    }
+
+    let ptr = MemPtr(mem.as_mut_ptr());
+    for pass in 0..time {
+        for slice in 0..SYNC_POINTS {
+            if lanes == 1 {
+                geometry.fill_segment(ptr, Position { pass, slice, lane: 0 });
+                continue;
+            }
+            thread::scope(|s| {
+                for lane in 0..lanes {
+                    s.spawn(move |_| geometry.fill_segment(ptr, Position { pass, slice, lane }));
+                }
+            })
+            .map_err(|_| printable_error(PROGRAM_NAME, "argon2 thread panicked".to_string()))?;
+        }
+    }
+
+    let mut last = mem[(lane_length - 1) as usize];
+    for lane in 1..lanes {
+        let block = &mem[(lane * lane_length + lane_length - 1) as usize];
+        for (l, b) in last.iter_mut().zip(block) {
+            *l ^= b;
+        }
+    }
+    for (chunk, w) in block_bytes.chunks_exact_mut(8).zip(last) {
+        chunk.copy_from_slice(&w.to_le_bytes());
+    }
+    hash_long(out, &[&block_bytes]);
+
+    // Don't leave derived material behind
+    for block in mem.iter_mut() {
+        super::zeroize(block);
+    }
+    super::zeroize(&mut block_bytes);
+    super::zeroize(&mut h0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::from_hex;
+
+    // RFC 9106 section 5 parameters
+    fn rfc9106(variant: Argon2Variant) -> Vec<u8> {
+        let params = Argon2Params {
+            time: 3,
+            memory: 32,
+            lanes: 4,
+        };
+        let mut out = vec![0; 32];
+        argon2_keyed(
+            variant,
+            params,
+            &[0x01; 32],
+            &[0x02; 16],
+            &[0x03; 8],
+            &[0x04; 12],
+            &mut out,
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn argon2i_rfc9106() {
+        assert_eq!(
+            rfc9106(Argon2Variant::Argon2i),
+            from_hex("c814d9d1dc7f37aa13f0d77f2494bda1c8de6b016dd388d29952a4c4672b6ce8")
+        );
+    }
+
+    #[test]
+    fn argon2id_rfc9106() {
+        assert_eq!(
+            rfc9106(Argon2Variant::Argon2id),
+            from_hex("0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659")
+        );
+    }
+
+    #[test]
+    fn single_lane_long_output() {
+        // Outputs longer than 64 bytes go through the H' chain
+        let params = Argon2Params {
+            time: 2,
+            memory: 64,
+            lanes: 1,
+        };
+        let mut out = [0u8; 100];
+        argon2(
+            Argon2Variant::Argon2id,
+            params,
+            b"password",
+            b"somesaltsomesalt",
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            &out[..],
+            from_hex(
+                "b08ff3fe1f65a2939a992830d55cd6665b76093b30d1152353081131a355b34b
+                 d33e4f7c596421b14685876b7c19a11d661202102c1632ddb1e705ba9929683c
+                 e85d425ec455ee1b1bd0ec2eb4e1fded5daf3250bc2628995a1480e36f600991
+                 28fca00f"
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_params() {
+        let mut out = [0u8; 32];
+        for (time, lanes) in [(0, 1), (1, 0)] {
+            let params = Argon2Params {
+                time,
+                memory: 64,
+                lanes,
+            };
+            assert!(argon2(Argon2Variant::Argon2i, params, b"", b"saltsalt", &mut out).is_err());
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! BLAKE2b (RFC 7693), as needed by Argon2. Keyed hashing isn't supported.
+
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+    0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+];
+
+const SIGMA: [[usize; 16]; 12] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+];
+
+/// BLAKE2b hash state with an output length between 1 and 64 bytes.
+#[derive(Clone)]
+pub struct Blake2b {
+    h: [u64; 8],
+    buf: [u8; 128],
+    buf_len: usize,
+    len: u128,
+    out_len: usize,
+}
+impl Blake2b {
+    /// Start a new hash with an output of `out_len` bytes.
+    pub fn new(out_len: usize) -> Self {
+        assert!((1..=64).contains(&out_len));
+        let mut h = IV;
+        h[0] ^= 0x01010000 ^ out_len as u64;
+        Self {
+            h,
+            buf: [0; 128],
+            buf_len: 0,
+            len: 0,
+            out_len,
+        }
+    }
+
+    fn compress(&mut self, last: bool) {
+        let mut m = [0u64; 16];
+        for (i, chunk) in self.buf.chunks_exact(8).enumerate() {
+            m[i] = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&IV);
+        v[12] ^= self.len as u64;
+        v[13] ^= (self.len >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+
+        fn g(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+            v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+            v[d] = (v[d] ^ v[a]).rotate_right(32);
+            v[c] = v[c].wrapping_add(v[d]);
+            v[b] = (v[b] ^ v[c]).rotate_right(24);
+            v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+            v[d] = (v[d] ^ v[a]).rotate_right(16);
+            v[c] = v[c].wrapping_add(v[d]);
+            v[b] = (v[b] ^ v[c]).rotate_right(63);
+        }
+        for s in SIGMA.iter() {
+            g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+            g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+            g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+            g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+            g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+            g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+            g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+
+    /// Feed `data` into the hash.
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // The last block must be compressed with the finalization flag, so only
+            // compress a full buffer once more data arrives.
+            if self.buf_len == 128 {
+                self.compress(false);
+                self.buf_len = 0;
+            }
+            let n = data.len().min(128 - self.buf_len);
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            self.len += n as u128;
+            data = &data[n..];
+        }
+    }
+
+    /// Finish the hash, writing `out_len` bytes into `out`.
+    pub fn finalize_into(mut self, out: &mut [u8]) {
+        self.buf[self.buf_len..].fill(0);
+        self.compress(true);
+        let mut res = [0u8; 64];
+        for (chunk, h) in res.chunks_exact_mut(8).zip(self.h) {
+            chunk.copy_from_slice(&h.to_le_bytes());
+        }
+        out[..self.out_len].copy_from_slice(&res[..self.out_len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::from_hex;
+
+    fn blake2b(out_len: usize, data: &[u8]) -> Vec<u8> {
+        let mut h = Blake2b::new(out_len);
+        h.update(data);
+        let mut out = vec![0; out_len];
+        h.finalize_into(&mut out);
+        out
+    }
+
+    #[test]
+    fn rfc7693_abc() {
+        assert_eq!(
+            blake2b(64, b"abc"),
+            from_hex(
+                "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1
+                 7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+            )
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            blake2b(64, b""),
+            from_hex(
+                "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419
+                 d25e1031afee585313896444934eb04b903a685b1448b755d56f701afe9be2ce"
+            )
+        );
+    }
+
+    #[test]
+    fn short_output_multiple_blocks() {
+        // 1 KiB: the last full block must only be compressed at finalization
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        let expected = from_hex("f1551feeb252c7e60bb362205bd1ac2f70b145260a91d41e8c5d0a187549a5f2");
+        assert_eq!(blake2b(32, &data), expected);
+
+        let mut h = Blake2b::new(32);
+        for chunk in data.chunks(100) {
+            h.update(chunk);
+        }
+        let mut out = [0u8; 32];
+        h.finalize_into(&mut out);
+        assert_eq!(&out[..], expected);
+    }
+}
@@ -0,0 +1,349 @@
+//! Disk encryption modes, as named by `dm-crypt` (e.g. `aes-xts-plain64`).
+//!
+//! Only decryption is needed. Data is always processed in 512-byte sectors, with the IV
+//! derived from the sector number.
+
+use super::{aes::Aes, HashAlg};
+use crate::PROGRAM_NAME;
+use precisej_printable_errno::{printable_error, PrintableErrno};
+
+const SECTOR_SIZE: usize = 512;
+
+// IV generators supported for CBC
+enum CbcIv {
+    Plain,
+    Plain64,
+    Essiv(Aes),
+}
+
+enum Mode {
+    Xts { data: Aes, tweak: Aes, plain64: bool },
+    Cbc { aes: Aes, iv: CbcIv },
+}
+
+/// Cipher used to decrypt data on a sector basis.
+pub struct SectorCipher {
+    mode: Mode,
+}
+impl SectorCipher {
+    /// Build a cipher from its `dm-crypt` specification and key. Supported specifications
+    /// are `aes-xts-plain64`, `aes-xts-plain`, `aes-cbc-essiv:<hash>`, `aes-cbc-plain64`
+    /// and `aes-cbc-plain`.
+    pub fn new(spec: &str, key: &[u8]) -> Result<Self, PrintableErrno<String>> {
+        let unsupported = || {
+            printable_error(
+                PROGRAM_NAME,
+                format!(
+                    "unsupported cipher {} with a {}-bit key",
+                    spec,
+                    key.len() * 8
+                ),
+            )
+        };
+        let aes = |key: &[u8]| Aes::new(key).ok_or_else(unsupported);
+
+        let mut parts = spec.splitn(3, '-');
+        let (cipher, mode, iv) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(cipher), Some(mode), Some(iv)) => (cipher, mode, iv),
+            _ => return Err(unsupported()),
+        };
+        if cipher != "aes" {
+            return Err(unsupported());
+        }
+
+        let mode = match (mode, iv) {
+            ("xts", "plain64" | "plain") => {
+                let (data, tweak) = key.split_at(key.len() / 2);
+                Mode::Xts {
+                    data: aes(data)?,
+                    tweak: aes(tweak)?,
+                    plain64: iv == "plain64",
+                }
+            }
+            ("cbc", "plain") => Mode::Cbc {
+                aes: aes(key)?,
+                iv: CbcIv::Plain,
+            },
+            ("cbc", "plain64") => Mode::Cbc {
+                aes: aes(key)?,
+                iv: CbcIv::Plain64,
+            },
+            ("cbc", essiv) => {
+                let hash = essiv
+                    .strip_prefix("essiv:")
+                    .and_then(HashAlg::from_name)
+                    .ok_or_else(unsupported)?;
+                let mut salt = hash.digest(&[key]);
+                let essiv = aes(&salt);
+                super::zeroize(&mut salt[..]);
+                Mode::Cbc {
+                    aes: aes(key)?,
+                    iv: CbcIv::Essiv(essiv?),
+                }
+            }
+            _ => return Err(unsupported()),
+        };
+        Ok(Self { mode })
+    }
+
+    /// Decrypt `data` in place. `data` must be a whole number of sectors, the first of
+    /// which has number `sector`.
+    pub fn decrypt(&self, data: &mut [u8], mut sector: u64) {
+        for chunk in data.chunks_exact_mut(SECTOR_SIZE) {
+            self.decrypt_sector(chunk, sector);
+            sector += 1;
+        }
+    }
+
+    fn decrypt_sector(&self, data: &mut [u8], sector: u64) {
+        let mut iv = [0u8; 16];
+        match &self.mode {
+            Mode::Xts {
+                data: aes,
+                tweak,
+                plain64,
+            } => {
+                if *plain64 {
+                    iv[..8].copy_from_slice(&sector.to_le_bytes());
+                } else {
+                    iv[..4].copy_from_slice(&(sector as u32).to_le_bytes());
+                }
+                tweak.encrypt_block(&mut iv);
+                for block in data.chunks_exact_mut(16) {
+                    let block: &mut [u8; 16] = block.try_into().unwrap();
+                    xor_block(block, &iv);
+                    aes.decrypt_block(block);
+                    xor_block(block, &iv);
+
+                    // Multiply the tweak by x in GF(2^128)
+                    let carry = iv[15] >> 7;
+                    for i in (1..16).rev() {
+                        iv[i] = (iv[i] << 1) | (iv[i - 1] >> 7);
+                    }
+                    iv[0] = (iv[0] << 1) ^ (carry * 0x87);
+                }
+            }
+            Mode::Cbc { aes, iv: iv_gen } => {
+                match iv_gen {
+                    CbcIv::Plain => iv[..4].copy_from_slice(&(sector as u32).to_le_bytes()),
+                    CbcIv::Plain64 => iv[..8].copy_from_slice(&sector.to_le_bytes()),
+                    CbcIv::Essiv(essiv) => {
+                        iv[..8].copy_from_slice(&sector.to_le_bytes());
+                        essiv.encrypt_block(&mut iv);
+                    }
+                }
+                for block in data.chunks_exact_mut(16) {
+                    let block: &mut [u8; 16] = block.try_into().unwrap();
+                    let next_iv = *block;
+                    aes.decrypt_block(block);
+                    xor_block(block, &iv);
+                    iv = next_iv;
+                }
+            }
+        }
+    }
+}
+
+fn xor_block(block: &mut [u8; 16], other: &[u8; 16]) {
+    for (b, o) in block.iter_mut().zip(other) {
+        *b ^= o;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::from_hex;
+
+    // IEEE 1619-2007 vector 5 (whose plaintext is the ciphertext of vector 4)
+    const XTS_128_VECTOR_5: &str = "
+        264d3ca8512194fec312c8c9891f279fefdd608d0c027b60483a3fa811d65ee5
+        9d52d9e40ec5672d81532b38b6b089ce951f0f9c35590b8b978d175213f329bb
+        1c2fd30f2f7f30492a61a532a79f51d36f5e31a7c9a12c286082ff7d2394d18f
+        783e1a8e72c722caaaa52d8f065657d2631fd25bfd8e5baad6e527d763517501
+        c68c5edc3cdd55435c532d7125c8614deed9adaa3acade5888b87bef641c4c99
+        4c8091b5bcd387f3963fb5bc37aa922fbfe3df4e5b915e6eb514717bdd2a7407
+        9a5073f5c4bfd46adf7d282e7a393a52579d11a028da4d9cd9c77124f9648ee3
+        83b1ac763930e7162a8d37f350b2f74b8472cf09902063c6b32e8c2d9290cefb
+        d7346d1c779a0df50edcde4531da07b099c638e83a755944df2aef1aa31752fd
+        323dcb710fb4bfbb9d22b925bc3577e1b8949e729a90bbafeacf7f7879e7b114
+        7e28ba0bae940db795a61b15ecf4df8db07b824bb062802cc98a9545bb2aaeed
+        77cb3fc6db15dcd7d80d7d5bc406c4970a3478ada8899b329198eb61c193fb62
+        75aa8ca340344a75a862aebe92eee1ce032fd950b47d7704a3876923b4ad6284
+        4bf4a09c4dbe8b4397184b7471360c9564880aedddb9baa4af2e75394b08cd32
+        ff479c57a07d3eab5d54de5f9738b8d27f27a9f0ab11799d7b7ffefb2704c95c
+        6ad12c39f1e867a4b7b1d7818a4b753dfd2a89ccb45e001a03a867b187f225dd";
+
+    // IEEE 1619-2007 vector 10
+    const XTS_256_VECTOR_10: &str = "
+        1c3b3a102f770386e4836c99e370cf9bea00803f5e482357a4ae12d414a3e63b
+        5d31e276f8fe4a8d66b317f9ac683f44680a86ac35adfc3345befecb4bb188fd
+        5776926c49a3095eb108fd1098baec70aaa66999a72a82f27d848b21d4a741b0
+        c5cd4d5fff9dac89aeba122961d03a757123e9870f8acf1000020887891429ca
+        2a3e7a7d7df7b10355165c8b9a6d0a7de8b062c4500dc4cd120c0f7418dae3d0
+        b5781c34803fa75421c790dfe1de1834f280d7667b327f6c8cd7557e12ac3a0f
+        93ec05c52e0493ef31a12d3d9260f79a289d6a379bc70c50841473d1a8cc81ec
+        583e9645e07b8d9670655ba5bbcfecc6dc3966380ad8fecb17b6ba02469a020a
+        84e18e8f84252070c13e9f1f289be54fbc481457778f616015e1327a02b140f1
+        505eb309326d68378f8374595c849d84f4c333ec4423885143cb47bd71c5edae
+        9be69a2ffeceb1bec9de244fbe15992b11b77c040f12bd8f6a975a44a0f90c29
+        a9abc3d4d893927284c58754cce294529f8614dcd2aba991925fedc4ae74ffac
+        6e333b93eb4aff0479da9a410e4450e0dd7ae4c6e2910900575da401fc07059f
+        645e8b7e9bfdef33943054ff84011493c27b3429eaedb4ed5376441a77ed4385
+        1ad77f16f541dfd269d50d6a5f14fb0aab1cbb4c1550be97f7ab4066193c4caa
+        773dad38014bd2092fa755c824bb5e54c4f36ffda9fcea70b9c6e693e148c151";
+
+    // aes-cbc-essiv:sha256 with key 000102..1f, sector 5
+    const CBC_ESSIV_SECTOR_5: &str = "
+        48c016b397feb53b0a508d772a0084ecde018ddb544876d2bccb55ea791ac980
+        475072191739e3bce33b2a8b354fd38d9d822a615c9b40c069fcad86fb96773d
+        3d567f4ef800e221dc23b11a18fb0a2696afaee125ae5b80326a17a5b8a674a8
+        8678865e8663f8171ded073b78be5321e8f60285e55e23922c9223678c7259dd
+        37e8fa18a788b86cf90afb252f659acf220a93ddfd6d0cbd34129ea258a87c54
+        3d33710a7f25d88bb43a08f40de56706bdd4f79c094452db446df893ed071012
+        6addbac400558a7149ce081aa935273116dd1fa347783de2c74987ae03c22ef8
+        c3a0ed4852103f20b35a1d8eca25fd3a55445a6f397dba7e4b6c054bfa59cc92
+        c6489d98db2ad639a5187031a06c2bf3f8cb34796248c45c45a99d7358bd6ceb
+        83f86b0c98d0896a515daffc5842f50a639cfa32a86089225d4c7c056d9890e8
+        fbc97e7ebb383c98ae26f3a0130a3014eebf078fb360a2aeff3662df224dbeb9
+        66f46ec27b6fd42f8b2dc21aa743f8a11aa364736e9b48298d47f2bba9fcf71a
+        bfceae2c2a2f8ac599cad53ba67c96b537d97a6e37b39cfccdec77e00194a506
+        29c4230c5acab5743a57c5f2d6b193fc7f3cdae776680f10512b9fbbe764b983
+        e5f005f01d1b0c5ed937ab445cfed0c4eeef939fdbd25b2528a6e58b1fc1dc59
+        4a2a60fa26a6e9fb4c0cbda0760b742482a704ef84ab0fbcc39d103b651fa1d0";
+
+    // aes-cbc-plain64 with key 000102..0f, sector 2^32 + 1
+    const CBC_PLAIN64_SECTOR_2_32_1: &str = "
+        b58c24dd33acb57058eb38d8a44056d312540437cc3cb11668bdec42ba751600
+        7d28fcad2970b64469fbd43409a40527407af294122e9b59272baab763cef853
+        c0f4e2824ee01b9f6aa8cb09780e054e6ebc2c494b505c1fc4cb65326a81cf36
+        5fdebf14e4fad1edd8e34d4c48c3a1675cb84d05fffb25508408928cb473774d
+        5a79847d9adae7271e4eaa8f9fafc264d97ff4667786a7bf62be15faea37e35f
+        9b095505f14276d058825f5d1631d1449b47bfd295b11804090750eb4f1ad303
+        6e831e234af4263d820186f266254ddc26825dfe4680bbd72ba8f87ab3f7173d
+        3977554698ef0331f6df370d7811efb1622e44f37df3cbddd1c549f1d4cfefb9
+        5c66556c5226edffb605f4e9286f02b88d3fb9bc439dacd8ef4a2d7419be75f5
+        60715691f74c9a99b30b348a1715fba7483e92f25375fb19fcff6f6085818346
+        e8f1be9457611d4cfad24f6e9581c7df05a598f935b2b289d1e32dc7c32b641a
+        7cdd4cdc18a2174e386913a2019714ffb060b1d9fc450a8dc26c151230aa8803
+        bfe711271b8e548a02e9f9568c7768528ff4c6e7af9433c5786d2fb68df97482
+        7dd2e72a7dbdf359757e15145ea09878cb5f8de9e1cb9536f4f7731bb85a7c53
+        7bb0a698e505b943535dcd68f0db8418e2f171266f58e9c6b839c4deb7c49291
+        a6aad2bbce9ea0371b22ed36fff74672bf68e58e9921e0ec8d045e6b17a36ec6";
+
+    // Plaintext of every vector above: bytes 00..ff, twice
+    fn plaintext() -> Vec<u8> {
+        (0..512).map(|i| i as u8).collect()
+    }
+
+    fn decrypt(spec: &str, key: &[u8], ciphertext: &str, sector: u64) -> Vec<u8> {
+        let mut data = from_hex(ciphertext);
+        SectorCipher::new(spec, key)
+            .unwrap()
+            .decrypt(&mut data, sector);
+        data
+    }
+
+    #[test]
+    fn xts_128_ieee1619() {
+        let key = from_hex(
+            "27182818284590452353602874713526
+             31415926535897932384626433832795",
+        );
+        let cipher = SectorCipher::new("aes-xts-plain64", &key).unwrap();
+        let mut data = from_hex(XTS_128_VECTOR_5);
+        cipher.decrypt(&mut data, 1);
+        assert_eq!(
+            &data[..32],
+            from_hex("27a7479befa1d476489f308cd4cfa6e2a96e4bbe3208ff25287dd3819616e89c")
+        );
+        cipher.decrypt(&mut data, 0);
+        assert_eq!(data, plaintext());
+    }
+
+    #[test]
+    fn xts_256_ieee1619() {
+        let key = from_hex(
+            "2718281828459045235360287471352662497757247093699959574966967627
+             3141592653589793238462643383279502884197169399375105820974944592",
+        );
+        assert_eq!(
+            decrypt("aes-xts-plain64", &key, XTS_256_VECTOR_10, 0xff),
+            plaintext()
+        );
+        assert_eq!(
+            decrypt("aes-xts-plain", &key, XTS_256_VECTOR_10, 0x1_0000_00ff),
+            plaintext()
+        );
+    }
+
+    #[test]
+    fn multiple_sectors() {
+        let key = from_hex(
+            "27182818284590452353602874713526
+             31415926535897932384626433832795",
+        );
+        let cipher = SectorCipher::new("aes-xts-plain64", &key).unwrap();
+        let mut vector_4 = from_hex(XTS_128_VECTOR_5);
+        cipher.decrypt(&mut vector_4, 1);
+
+        // Vectors 4 and 5 are consecutive sectors
+        let mut data = [&vector_4[..], &from_hex(XTS_128_VECTOR_5)].concat();
+        cipher.decrypt(&mut data, 0);
+        assert_eq!(data[..512], plaintext());
+        assert_eq!(data[512..], vector_4);
+    }
+
+    #[test]
+    fn cbc_essiv() {
+        let key: Vec<u8> = (0..32).collect();
+        assert_eq!(
+            decrypt("aes-cbc-essiv:sha256", &key, CBC_ESSIV_SECTOR_5, 5),
+            plaintext()
+        );
+        assert_ne!(
+            decrypt("aes-cbc-essiv:sha256", &key, CBC_ESSIV_SECTOR_5, 4),
+            plaintext()
+        );
+    }
+
+    #[test]
+    fn cbc_plain() {
+        let key: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            decrypt(
+                "aes-cbc-plain64",
+                &key,
+                CBC_PLAIN64_SECTOR_2_32_1,
+                0x1_0000_0001
+            ),
+            plaintext()
+        );
+        // plain truncates the sector number to 32 bits
+        assert_ne!(
+            decrypt(
+                "aes-cbc-plain",
+                &key,
+                CBC_PLAIN64_SECTOR_2_32_1,
+                0x1_0000_0001
+            ),
+            plaintext()
+        );
+    }
+
+    #[test]
+    fn unsupported() {
+        let key = [0u8; 32];
+        for spec in [
+            "twofish-xts-plain64",
+            "aes-ecb",
+            "aes-xts-benbi",
+            "aes-cbc-essiv:md5",
+            "aes-ctr-plain64",
+        ] {
+            assert!(SectorCipher::new(spec, &key).is_err(), "{}", spec);
+        }
+        assert!(SectorCipher::new("aes-xts-plain64", &[0; 40]).is_err());
+        assert!(SectorCipher::new("aes-cbc-essiv:sha256", &[0; 20]).is_err());
+    }
+}
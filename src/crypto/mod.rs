@@ -0,0 +1,392 @@
+//! Cryptographic primitives needed to unlock LUKS volumes without external programs.
+//!
+//! Only what `cryptsetup` uses by default (and its former defaults) is implemented:
+//! SHA-1/SHA-256/SHA-512, PBKDF2, Argon2i/Argon2id and AES in XTS and CBC modes. Bulk
+//! encryption of the unlocked volume is done by the kernel (`dm-crypt`), so these are only
+//! used once per keyslot.
+
+pub mod aes;
+pub mod argon2;
+pub mod blake2b;
+pub mod cipher;
+pub mod sha1;
+pub mod sha2;
+
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::{
+    ops::{Deref, DerefMut},
+    ptr::write_volatile,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+/// Plain data that can be securely overwritten with zeroes.
+pub trait Zeroize {
+    /// Overwrite `self` with zeroes. The writes are never optimized away.
+    fn zeroize(&mut self);
+}
+macro_rules! impl_zeroize {
+    ($($ty:ty),*) => {
+        $(impl Zeroize for $ty {
+            fn zeroize(&mut self) {
+                // SAFETY: self is a valid, aligned reference
+                unsafe { write_volatile(self, 0) }
+            }
+        })*
+    };
+}
+impl_zeroize!(u8, u32, u64);
+impl<T: Zeroize, const N: usize> Zeroize for [T; N] {
+    fn zeroize(&mut self) {
+        self.iter_mut().for_each(Zeroize::zeroize);
+    }
+}
+impl<T: Zeroize> Zeroize for [T] {
+    fn zeroize(&mut self) {
+        self.iter_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+/// Overwrite `buf` with zeroes.
+pub fn zeroize<T: Zeroize + ?Sized>(buf: &mut T) {
+    buf.zeroize();
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Byte buffer holding secret material (e.g. passphrases and keys). Zeroed on drop.
+#[derive(Default)]
+pub struct Secret(Vec<u8>);
+impl Secret {
+    /// Allocate a zero-filled secret of `len` bytes.
+    pub fn new(len: usize) -> Self {
+        Self(vec![0; len])
+    }
+}
+impl From<Vec<u8>> for Secret {
+    fn from(v: Vec<u8>) -> Self {
+        Self(v)
+    }
+}
+impl Deref for Secret {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+impl DerefMut for Secret {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0[..]
+    }
+}
+impl Drop for Secret {
+    fn drop(&mut self) {
+        zeroize(&mut self.0[..]);
+    }
+}
+
+/// Hash function.
+pub trait Digest: Clone {
+    /// Size of the internal block in bytes.
+    const BLOCK_SIZE: usize;
+
+    /// Size of the output in bytes.
+    const OUTPUT_SIZE: usize;
+
+    /// Start a new hash.
+    fn new() -> Self;
+
+    /// Feed `data` into the hash.
+    fn update(&mut self, data: &[u8]);
+
+    /// Finish the hash, writing exactly [Self::OUTPUT_SIZE] bytes into `out`.
+    fn finalize_into(self, out: &mut [u8]);
+}
+
+// Buffering and Merkle–Damgård padding shared by the SHA family, with blocks of N bytes
+#[derive(Clone)]
+struct BlockBuf<const N: usize> {
+    buf: [u8; N],
+    buf_len: usize,
+    len: u128,
+}
+impl<const N: usize> BlockBuf<N> {
+    fn new() -> Self {
+        Self {
+            buf: [0; N],
+            buf_len: 0,
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8], mut compress: impl FnMut(&[u8])) {
+        self.len += data.len() as u128;
+        if self.buf_len > 0 {
+            let n = data.len().min(N - self.buf_len);
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+            if self.buf_len < N {
+                return;
+            }
+            compress(&self.buf);
+            self.buf_len = 0;
+        }
+        let mut blocks = data.chunks_exact(N);
+        for block in &mut blocks {
+            compress(block);
+        }
+        let rem = blocks.remainder();
+        self.buf[..rem.len()].copy_from_slice(rem);
+        self.buf_len = rem.len();
+    }
+
+    // Append the padding and the message length in bits (big-endian, len_size bytes)
+    fn finalize(&mut self, len_size: usize, mut compress: impl FnMut(&[u8])) {
+        let bit_len = (self.len * 8).to_be_bytes();
+        self.buf[self.buf_len] = 0x80;
+        self.buf[self.buf_len + 1..].fill(0);
+        if self.buf_len + 1 > N - len_size {
+            compress(&self.buf);
+            self.buf.fill(0);
+        }
+        self.buf[N - len_size..].copy_from_slice(&bit_len[16 - len_size..]);
+        compress(&self.buf);
+        zeroize(&mut self.buf);
+    }
+}
+
+/// HMAC-based PBKDF2 (RFC 8018), writing `out.len()` bytes of derived key into `out`.
+pub fn pbkdf2<D: Digest>(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let mut key = [0u8; 128];
+    if password.len() > D::BLOCK_SIZE {
+        let mut h = D::new();
+        h.update(password);
+        h.finalize_into(&mut key[..D::OUTPUT_SIZE]);
+    } else {
+        key[..password.len()].copy_from_slice(password);
+    }
+
+    // Precompute the HMAC inner and outer states
+    let mut pad = [0u8; 128];
+    let mut inner = D::new();
+    let mut outer = D::new();
+    for (p, k) in pad.iter_mut().zip(key) {
+        *p = k ^ 0x36;
+    }
+    inner.update(&pad[..D::BLOCK_SIZE]);
+    for (p, k) in pad.iter_mut().zip(key) {
+        *p = k ^ 0x5c;
+    }
+    outer.update(&pad[..D::BLOCK_SIZE]);
+    zeroize(&mut key);
+    zeroize(&mut pad);
+
+    let n = D::OUTPUT_SIZE;
+    let mut u = [0u8; 64];
+    let mut t = [0u8; 64];
+    for (i, chunk) in out.chunks_mut(n).enumerate() {
+        let mut h = inner.clone();
+        h.update(salt);
+        h.update(&(i as u32 + 1).to_be_bytes());
+        h.finalize_into(&mut u[..n]);
+        let mut h = outer.clone();
+        h.update(&u[..n]);
+        h.finalize_into(&mut u[..n]);
+        t[..n].copy_from_slice(&u[..n]);
+
+        for _ in 1..iterations {
+            let mut h = inner.clone();
+            h.update(&u[..n]);
+            h.finalize_into(&mut u[..n]);
+            let mut h = outer.clone();
+            h.update(&u[..n]);
+            h.finalize_into(&mut u[..n]);
+            for (t, u) in t.iter_mut().zip(&u[..n]) {
+                *t ^= u;
+            }
+        }
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+    zeroize(&mut u);
+    zeroize(&mut t);
+}
+
+/// Hash function selected at runtime (e.g. from a LUKS header).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HashAlg {
+    /// SHA-1.
+    Sha1,
+
+    /// SHA-256.
+    Sha256,
+
+    /// SHA-512.
+    Sha512,
+}
+impl HashAlg {
+    /// Get the hash function called `name` (e.g. `sha256`), as named by `cryptsetup`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match &name.to_ascii_lowercase()[..] {
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Size of the output in bytes.
+    pub fn output_size(self) -> usize {
+        match self {
+            Self::Sha1 => Sha1::OUTPUT_SIZE,
+            Self::Sha256 => Sha256::OUTPUT_SIZE,
+            Self::Sha512 => Sha512::OUTPUT_SIZE,
+        }
+    }
+
+    /// Hash the concatenation of `parts`.
+    pub fn digest(self, parts: &[&[u8]]) -> Vec<u8> {
+        fn digest<D: Digest>(parts: &[&[u8]]) -> Vec<u8> {
+            let mut h = D::new();
+            for part in parts {
+                h.update(part);
+            }
+            let mut out = vec![0; D::OUTPUT_SIZE];
+            h.finalize_into(&mut out);
+            out
+        }
+        match self {
+            Self::Sha1 => digest::<Sha1>(parts),
+            Self::Sha256 => digest::<Sha256>(parts),
+            Self::Sha512 => digest::<Sha512>(parts),
+        }
+    }
+
+    /// PBKDF2 using HMAC with this hash function. See [pbkdf2].
+    pub fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+        match self {
+            Self::Sha1 => pbkdf2::<Sha1>(password, salt, iterations, out),
+            Self::Sha256 => pbkdf2::<Sha256>(password, salt, iterations, out),
+            Self::Sha512 => pbkdf2::<Sha512>(password, salt, iterations, out),
+        }
+    }
+}
+
+/// Decode a hexadecimal test vector. Whitespace is ignored, so that long vectors can be
+/// split over several lines.
+#[cfg(test)]
+pub fn from_hex(text: &str) -> Vec<u8> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derive(hash: HashAlg, password: &[u8], salt: &[u8], iterations: u32, len: usize) -> Vec<u8> {
+        let mut out = vec![0; len];
+        hash.pbkdf2(password, salt, iterations, &mut out);
+        out
+    }
+
+    #[test]
+    fn pbkdf2_sha1_rfc6070() {
+        let vectors: &[(&[u8], &[u8], u32, &str)] = &[
+            (b"password", b"salt", 1, "0c60c80f961f0e71f3a9b524af6012062fe037a6"),
+            (b"password", b"salt", 2, "ea6c014dc72d6f8ccd1ed92ace1d41f0d8de8957"),
+            (b"password", b"salt", 4096, "4b007901b765489abead49d926f721d065a429c1"),
+            (
+                b"passwordPASSWORDpassword",
+                b"saltSALTsaltSALTsaltSALTsaltSALTsalt",
+                4096,
+                "3d2eec4fe41c849b80c8d83662c0e44a8b291a964cf2f07038",
+            ),
+            (b"pass\0word", b"sa\0lt", 4096, "56fa6aa75548099dcc37d7f03425e0c3"),
+        ];
+        for (password, salt, iterations, expected) in vectors {
+            let expected = from_hex(expected);
+            assert_eq!(
+                derive(HashAlg::Sha1, password, salt, *iterations, expected.len()),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn pbkdf2_sha256_rfc7914() {
+        let vectors: &[(&[u8], &[u8], u32, &str)] = &[
+            (
+                b"passwd",
+                b"salt",
+                1,
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc
+                 49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783",
+            ),
+            (
+                b"Password",
+                b"NaCl",
+                80000,
+                "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56
+                 a1d425a1225833549adb841b51c9b3176a272bdebba1d078478f62b397f33c8d",
+            ),
+        ];
+        for (password, salt, iterations, expected) in vectors {
+            let expected = from_hex(expected);
+            assert_eq!(
+                derive(HashAlg::Sha256, password, salt, *iterations, expected.len()),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn pbkdf2_sha512() {
+        // No RFC vectors exist for HMAC-SHA-512; these match other implementations
+        assert_eq!(
+            derive(HashAlg::Sha512, b"password", b"salt", 1, 64),
+            from_hex(
+                "867f70cf1ade02cff3752599a3a53dc4af34c7a669815ae5d513554e1c8cf252
+                 c02d470a285a0501bad999bfe943c08f050235d7d68b1da55e63f73b60a57fce"
+            )
+        );
+        assert_eq!(
+            derive(
+                HashAlg::Sha512,
+                b"passwordPASSWORDpassword",
+                b"saltSALTsaltSALTsaltSALTsaltSALTsalt",
+                4096,
+                64
+            ),
+            from_hex(
+                "8c0511f4c6e597c6ac6315d8f0362e225f3c501495ba23b868c005174dc4ee71
+                 115b59f9e60cd9532fa33e0f75aefe30225c583a186cd82bd4daea9724a3d3b8"
+            )
+        );
+    }
+
+    #[test]
+    fn pbkdf2_long_password() {
+        // Passwords longer than the hash block are hashed first
+        let password = [b'p'; 200];
+        let mut hashed = [0u8; 32];
+        let mut h = Sha256::new();
+        h.update(&password);
+        h.finalize_into(&mut hashed);
+        assert_eq!(
+            derive(HashAlg::Sha256, &password, b"salt", 2, 40),
+            derive(HashAlg::Sha256, &hashed, b"salt", 2, 40)
+        );
+    }
+
+    #[test]
+    fn hash_alg_names() {
+        assert_eq!(HashAlg::from_name("sha1"), Some(HashAlg::Sha1));
+        assert_eq!(HashAlg::from_name("SHA256"), Some(HashAlg::Sha256));
+        assert_eq!(HashAlg::from_name("sha512"), Some(HashAlg::Sha512));
+        assert_eq!(HashAlg::from_name("ripemd160"), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! SHA-1 (FIPS 180-4). Only used for LUKS volumes created with old defaults.
+
+use super::{BlockBuf, Digest};
+
+/// SHA-1 hash state.
+#[derive(Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    buf: BlockBuf<64>,
+}
+impl Sha1 {
+    fn compress(state: &mut [u32; 5], block: &[u8]) {
+        let mut w = [0u32; 80];
+        for (i, chunk) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = *state;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+}
+impl Digest for Sha1 {
+    const BLOCK_SIZE: usize = 64;
+    const OUTPUT_SIZE: usize = 20;
+
+    fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0],
+            buf: BlockBuf::new(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let state = &mut self.state;
+        self.buf.update(data, |block| Self::compress(state, block));
+    }
+
+    fn finalize_into(mut self, out: &mut [u8]) {
+        let state = &mut self.state;
+        self.buf.finalize(8, |block| Self::compress(state, block));
+        for (chunk, s) in out.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&s.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{from_hex, HashAlg};
+
+    #[test]
+    fn fips180_vectors() {
+        let vectors: &[(&[u8], &str)] = &[
+            (b"", "da39a3ee5e6b4b0d3255bfef95601890afd80709"),
+            (b"abc", "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            ),
+        ];
+        for (msg, expected) in vectors {
+            assert_eq!(HashAlg::Sha1.digest(&[msg]), from_hex(expected));
+        }
+    }
+
+    #[test]
+    fn million_a() {
+        // Fed in uneven pieces to exercise the buffering
+        let msg = vec![b'a'; 1_000_000];
+        let mut h = Sha1::new();
+        for chunk in msg.chunks(997) {
+            h.update(chunk);
+        }
+        let mut out = [0u8; 20];
+        h.finalize_into(&mut out);
+        assert_eq!(
+            &out[..],
+            from_hex("34aa973cd4c4daa4f61eeb2bdbad27316534016f")
+        );
+    }
+}
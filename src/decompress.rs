@@ -0,0 +1,208 @@
+//! Decompression of files compressed with gzip, xz or zstd.
+//!
+//! Distributions usually compress kernel modules with one of these formats (`.ko.gz`,
+//! `.ko.xz` or `.ko.zst`). The format is detected through the data's magic number rather
+//! than the file name.
+//!
+//! Only pure Rust decoders are used, so that `/init` doesn't depend on C libraries. xz
+//! streams with SHA-256 checks are unsupported: kernel modules use CRC32.
+
+use flate2::read::MultiGzDecoder;
+use ruzstd::decoding::{
+    errors::{FrameDecoderError, ReadFrameHeaderError},
+    StreamingDecoder,
+};
+use std::io::{self, Read};
+
+/// Magic number of gzip compressed data.
+pub const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Magic number of xz compressed data.
+pub const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Magic number of zstd compressed data.
+pub const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Extensions of compressed files, as appended to the uncompressed file name.
+pub const COMPRESSED_EXTENSIONS: [&str; 3] = [".zst", ".xz", ".gz"];
+
+// Size of xz stream headers and footers
+const XZ_HEADER_SIZE: usize = 12;
+
+/// Decompress `data` if it's gzip, xz or zstd compressed. Otherwise, return it as-is.
+///
+/// Compressed data may be made of several concatenated streams (or frames) of the same
+/// format.
+pub fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    if data.starts_with(GZIP_MAGIC) {
+        MultiGzDecoder::new(&data[..]).read_to_end(&mut out)?;
+    } else if data.starts_with(XZ_MAGIC) {
+        for stream in xz_streams(&data)? {
+            lzma_rs::xz_decompress(&mut &stream[..], &mut out).map_err(invalid)?;
+        }
+    } else if data.starts_with(ZSTD_MAGIC) {
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            match StreamingDecoder::new(&mut rest) {
+                Ok(mut frame) => {
+                    frame.read_to_end(&mut out)?;
+                    // The decoder doesn't check the frame's checksum by itself
+                    let checksum = frame.decoder.get_checksum_from_data();
+                    if checksum.is_some() && checksum != frame.decoder.get_calculated_checksum() {
+                        return Err(invalid("zstd checksum mismatch"));
+                    }
+                }
+                Err(FrameDecoderError::ReadFrameHeaderError(
+                    ReadFrameHeaderError::SkipFrame { length, .. },
+                )) => {
+                    rest = rest
+                        .get(length as usize..)
+                        .ok_or_else(|| invalid("truncated zstd skippable frame"))?;
+                }
+                Err(e) => return Err(invalid(e)),
+            }
+        }
+    } else {
+        return Ok(data);
+    }
+    Ok(out)
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Split concatenated xz streams apart, as the decoder only handles one. Streams can only be
+// delimited from their end: the footer gives the size of the index, which in turn lists the
+// size of every block.
+fn xz_streams(data: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut streams = Vec::new();
+    let mut end = data.len();
+    while end > 0 {
+        // Stream padding: null bytes, in multiples of 4
+        if data[..end].ends_with(&[0; 4]) {
+            end -= 4;
+            continue;
+        }
+
+        let footer = data[..end]
+            .get(end.saturating_sub(XZ_HEADER_SIZE)..)
+            .filter(|footer| footer.len() == XZ_HEADER_SIZE && footer.ends_with(b"YZ"))
+            .ok_or_else(|| invalid("invalid xz stream footer"))?;
+        let backward_size = u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]);
+        let index_size = (backward_size as usize + 1) * 4;
+        let index_end = end - XZ_HEADER_SIZE;
+        let index = index_end
+            .checked_sub(index_size)
+            .map(|start| &data[start..index_end])
+            .ok_or_else(|| invalid("invalid xz index size"))?;
+
+        // 0x00, number of records, then (unpadded size, uncompressed size) per block
+        let mut fields = &index[1..];
+        let records = read_multibyte(&mut fields)?;
+        let mut blocks_size = 0usize;
+        for _ in 0..records {
+            let unpadded = read_multibyte(&mut fields)?;
+            read_multibyte(&mut fields)?;
+            blocks_size = usize::try_from(unpadded)
+                .ok()
+                .and_then(|unpadded| blocks_size.checked_add((unpadded + 3) & !3))
+                .ok_or_else(|| invalid("invalid xz block size"))?;
+        }
+
+        let start = blocks_size
+            .checked_add(index_size + 2 * XZ_HEADER_SIZE)
+            .and_then(|size| end.checked_sub(size))
+            .ok_or_else(|| invalid("invalid xz index"))?;
+        streams.push(&data[start..end]);
+        end = start;
+    }
+    streams.reverse();
+    Ok(streams)
+}
+
+// Read an xz variable-length integer: 7 bits per byte, least significant first
+fn read_multibyte(data: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0;
+    for i in 0..9 {
+        let (byte, rest) = data
+            .split_first()
+            .ok_or_else(|| invalid("truncated xz index"))?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("invalid xz index"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = include_bytes!("testdata/decompress/data");
+
+    fn twice() -> Vec<u8> {
+        [DATA, DATA].concat()
+    }
+
+    #[test]
+    fn uncompressed() {
+        assert_eq!(decompress(DATA.to_vec()).unwrap(), DATA);
+        assert_eq!(decompress(Vec::new()).unwrap(), b"");
+    }
+
+    #[test]
+    fn gzip() {
+        let data = include_bytes!("testdata/decompress/data.gz");
+        assert_eq!(decompress(data.to_vec()).unwrap(), DATA);
+        assert_eq!(decompress([&data[..], &data[..]].concat()).unwrap(), twice());
+    }
+
+    #[test]
+    fn xz() {
+        let data = include_bytes!("testdata/decompress/data.xz");
+        assert_eq!(decompress(data.to_vec()).unwrap(), DATA);
+        let blocks = include_bytes!("testdata/decompress/blocks.xz");
+        assert_eq!(decompress(blocks.to_vec()).unwrap(), DATA);
+
+        // With stream padding in between, and different checks
+        let concat = include_bytes!("testdata/decompress/concat.xz");
+        assert_eq!(decompress(concat.to_vec()).unwrap(), twice());
+        let padded = [&data[..], &[0; 4], &blocks[..]].concat();
+        assert_eq!(decompress(padded).unwrap(), twice());
+    }
+
+    #[test]
+    fn zstd() {
+        let data = include_bytes!("testdata/decompress/data.zst");
+        assert_eq!(decompress(data.to_vec()).unwrap(), DATA);
+
+        // With a skippable frame in between, and no checksum
+        let concat = include_bytes!("testdata/decompress/concat.zst");
+        assert_eq!(decompress(concat.to_vec()).unwrap(), twice());
+    }
+
+    #[test]
+    fn corrupted() {
+        for data in [
+            &include_bytes!("testdata/decompress/data.gz")[..],
+            &include_bytes!("testdata/decompress/data.xz")[..],
+            &include_bytes!("testdata/decompress/data.zst")[..],
+        ] {
+            let truncated = data[..data.len() - 100].to_vec();
+            assert!(decompress(truncated).is_err());
+
+            let mut flipped = data.to_vec();
+            flipped[data.len() / 2] ^= 0x40;
+            assert!(decompress(flipped).is_err());
+        }
+
+        let misaligned = [&include_bytes!("testdata/decompress/data.xz")[..], &[0; 3]].concat();
+        assert!(decompress(misaligned).is_err());
+        assert!(decompress(XZ_MAGIC.to_vec()).is_err());
+        assert!(decompress(ZSTD_MAGIC.to_vec()).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Device-mapper devices, created directly through `/dev/mapper/control` ioctls.
+//!
+//! This is what `libdevmapper` does for `cryptsetup` and `lvm2`: create a device, load its
+//! table and resume it. The kernel then announces the new `dm-N` block device through
+//! uevents like any other device.
+
+use crate::{crypto::zeroize, PROGRAM_NAME};
+use nix::{
+    errno::Errno,
+    ioctl_readwrite,
+    sys::stat::{major, makedev, minor, mknod, Mode, SFlag},
+};
+use precisej_printable_errno::{printable_error, ErrnoResult, PrintableErrno};
+use std::{
+    fs::{create_dir_all, File, OpenOptions},
+    os::unix::io::AsRawFd,
+};
+
+const DM_CONTROL: &str = "/dev/mapper/control";
+const DM_IOCTL: u8 = 0xfd;
+const DM_VERSION: [u32; 3] = [4, 0, 0];
+const DM_NAME_LEN: usize = 128;
+const DM_UUID_LEN: usize = 129;
+const DM_MAX_TYPE_NAME: usize = 16;
+
+// Room for target specifications and their parameters after struct dm_ioctl
+const DM_DATA_SIZE: usize = 16 * 1024;
+
+const DM_READONLY_FLAG: u32 = 1 << 0;
+const DM_SECURE_DATA_FLAG: u32 = 1 << 15;
+
+// struct dm_ioctl from linux/dm-ioctl.h
+#[repr(C)]
+struct DmIoctl {
+    version: [u32; 3],
+    data_size: u32,
+    data_start: u32,
+    target_count: u32,
+    open_count: i32,
+    flags: u32,
+    event_nr: u32,
+    padding: u32,
+    dev: u64,
+    name: [u8; DM_NAME_LEN],
+    uuid: [u8; DM_UUID_LEN],
+    data: [u8; 7],
+}
+
+// struct dm_ioctl followed by its payload
+#[repr(C)]
+struct DmIoctlBuf {
+    ioctl: DmIoctl,
+    payload: [u8; DM_DATA_SIZE],
+}
+impl DmIoctlBuf {
+    fn new(name: &str) -> Result<Box<Self>, PrintableErrno<String>> {
+        if name.is_empty() || name.len() >= DM_NAME_LEN || name.contains('/') {
+            return Err(printable_error(
+                PROGRAM_NAME,
+                format!("invalid device-mapper name {}", name),
+            ));
+        }
+        let mut buf = Box::new(Self {
+            ioctl: DmIoctl {
+                version: DM_VERSION,
+                data_size: std::mem::size_of::<DmIoctl>() as u32,
+                data_start: std::mem::size_of::<DmIoctl>() as u32,
+                target_count: 0,
+                open_count: 0,
+                flags: 0,
+                event_nr: 0,
+                padding: 0,
+                dev: 0,
+                name: [0; DM_NAME_LEN],
+                uuid: [0; DM_UUID_LEN],
+                data: [0; 7],
+            },
+            payload: [0; DM_DATA_SIZE],
+        });
+        buf.ioctl.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(buf)
+    }
+}
+impl Drop for DmIoctlBuf {
+    fn drop(&mut self) {
+        // Tables may contain keys
+        zeroize(&mut self.payload);
+    }
+}
+
+ioctl_readwrite!(dm_dev_create, DM_IOCTL, 3, DmIoctl);
+ioctl_readwrite!(dm_dev_remove, DM_IOCTL, 4, DmIoctl);
+ioctl_readwrite!(dm_dev_suspend, DM_IOCTL, 6, DmIoctl);
+ioctl_readwrite!(dm_table_load, DM_IOCTL, 9, DmIoctl);
+
+/// Single target (i.e. line) of a device-mapper table.
+pub struct DmTarget {
+    start: u64,
+    length: u64,
+    target_type: &'static str,
+    params: String,
+}
+impl DmTarget {
+    /// Map `length` sectors starting at sector `start` of the device using `target_type`
+    /// (e.g. `crypt` or `linear`) with the given parameters. Parameters are zeroed on drop,
+    /// as they may contain keys.
+    #[inline]
+    pub fn new<S: Into<String>>(
+        start: u64,
+        length: u64,
+        target_type: &'static str,
+        params: S,
+    ) -> Self {
+        Self::_new(start, length, target_type, params.into())
+    }
+    fn _new(start: u64, length: u64, target_type: &'static str, params: String) -> Self {
+        Self {
+            start,
+            length,
+            target_type,
+            params,
+        }
+    }
+}
+impl Drop for DmTarget {
+    fn drop(&mut self) {
+        // SAFETY: only zeroes (valid UTF-8) are written
+        zeroize(unsafe { self.params.as_bytes_mut() });
+    }
+}
+
+/// Open handle to `/dev/mapper/control`.
+pub struct DmControl(File);
+impl DmControl {
+    /// Open `/dev/mapper/control`. The `dm_mod` kernel module must be loaded.
+    pub fn open() -> Result<Self, PrintableErrno<String>> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(DM_CONTROL)
+            .map(Self)
+            .map_err(|io| {
+                printable_error(PROGRAM_NAME, format!("unable to open {}: {}", DM_CONTROL, io))
+            })
+    }
+
+    /// Create the device called `name` with a table of `targets`, and make it available
+    /// as `/dev/mapper/<name>`. Returns its device numbers.
+    ///
+    /// `uuid` is used by `udev` in the booted system to identify the device's owner (e.g.
+    /// `CRYPT-LUKS2-...` or `LVM-...`).
+    pub fn create_device(
+        &self,
+        name: &str,
+        uuid: &str,
+        targets: &[DmTarget],
+        read_only: bool,
+    ) -> Result<(u32, u32), PrintableErrno<String>> {
+        let mut buf = DmIoctlBuf::new(name)?;
+        let uuid = &uuid.as_bytes()[..uuid.len().min(DM_UUID_LEN - 1)];
+        buf.ioctl.uuid[..uuid.len()].copy_from_slice(uuid);
+        // SAFETY: buf starts with a valid struct dm_ioctl and data_size doesn't exceed it
+        unsafe { dm_dev_create(self.0.as_raw_fd(), &mut buf.ioctl) }.printable(
+            PROGRAM_NAME,
+            format!("unable to create device-mapper device {}", name),
+        )?;
+        let dev = buf.ioctl.dev;
+
+        let res = self
+            .load_table(name, targets, read_only)
+            .and_then(|_| self.resume(name));
+        if let Err(e) = res {
+            // Don't leave a half-created device behind
+            let _ = self.remove(name);
+            return Err(e);
+        }
+
+        let (major, minor) = (major(dev) as u32, minor(dev) as u32);
+        Self::make_node(name, major, minor)?;
+        Ok((major, minor))
+    }
+
+    fn load_table(
+        &self,
+        name: &str,
+        targets: &[DmTarget],
+        read_only: bool,
+    ) -> Result<(), PrintableErrno<String>> {
+        const SPEC_SIZE: usize = 40;
+
+        let mut buf = DmIoctlBuf::new(name)?;
+        let mut pos = 0;
+        for target in targets {
+            // struct dm_target_spec, followed by the NUL-terminated parameters. The next
+            // spec must be 8-byte aligned.
+            let next = (SPEC_SIZE + target.params.len() + 1 + 7) & !7;
+            if pos + next > DM_DATA_SIZE || target.target_type.len() >= DM_MAX_TYPE_NAME {
+                return Err(printable_error(
+                    PROGRAM_NAME,
+                    format!("device-mapper table for {} is too large", name),
+                ));
+            }
+            let spec = &mut buf.payload[pos..pos + next];
+            spec[0..8].copy_from_slice(&target.start.to_ne_bytes());
+            spec[8..16].copy_from_slice(&target.length.to_ne_bytes());
+            spec[20..24].copy_from_slice(&(next as u32).to_ne_bytes());
+            spec[24..24 + target.target_type.len()].copy_from_slice(target.target_type.as_bytes());
+            spec[SPEC_SIZE..SPEC_SIZE + target.params.len()]
+                .copy_from_slice(target.params.as_bytes());
+            pos += next;
+        }
+        buf.ioctl.data_size += pos as u32;
+        buf.ioctl.target_count = targets.len() as u32;
+        buf.ioctl.flags = DM_SECURE_DATA_FLAG;
+        if read_only {
+            buf.ioctl.flags |= DM_READONLY_FLAG;
+        }
+
+        // SAFETY: buf starts with a valid struct dm_ioctl and data_size doesn't exceed it
+        unsafe { dm_table_load(self.0.as_raw_fd(), &mut buf.ioctl) }.printable(
+            PROGRAM_NAME,
+            format!("unable to load device-mapper table for {}", name),
+        )?;
+        Ok(())
+    }
+
+    fn resume(&self, name: &str) -> Result<(), PrintableErrno<String>> {
+        let mut buf = DmIoctlBuf::new(name)?;
+        // SAFETY: buf starts with a valid struct dm_ioctl and data_size doesn't exceed it
+        unsafe { dm_dev_suspend(self.0.as_raw_fd(), &mut buf.ioctl) }.printable(
+            PROGRAM_NAME,
+            format!("unable to resume device-mapper device {}", name),
+        )?;
+        Ok(())
+    }
+
+    /// Remove the device called `name`.
+    pub fn remove(&self, name: &str) -> Result<(), PrintableErrno<String>> {
+        let mut buf = DmIoctlBuf::new(name)?;
+        // SAFETY: buf starts with a valid struct dm_ioctl and data_size doesn't exceed it
+        unsafe { dm_dev_remove(self.0.as_raw_fd(), &mut buf.ioctl) }.printable(
+            PROGRAM_NAME,
+            format!("unable to remove device-mapper device {}", name),
+        )?;
+        Ok(())
+    }
+
+    // devtmpfs only creates /dev/dm-N, the /dev/mapper/<name> node is up to us (as udev
+    // isn't running)
+    fn make_node(name: &str, major: u32, minor: u32) -> Result<(), PrintableErrno<String>> {
+        let _ = create_dir_all("/dev/mapper");
+        let path = format!("/dev/mapper/{}", name);
+        match mknod(
+            &path[..],
+            SFlag::S_IFBLK,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+            makedev(major as u64, minor as u64),
+        ) {
+            Ok(()) | Err(Errno::EEXIST) => Ok(()),
+            Err(e) => Err(e).printable(PROGRAM_NAME, format!("unable to create {}", path)),
+        }
+    }
+}
@@ -0,0 +1,884 @@
+//! `ignited build`: generate an initramfs image.
+
+use crate::{
+    config::RuntimeConfig,
+    generator::{
+        cache::{archive_key, Cache},
+        compress::Compressor,
+        config::BuildConfig,
+        elf::{ElfResolver, LD_SO_CACHE},
+        firmware,
+        host::HostModules,
+        image::{Data, Image},
+        kmod::{KernelModules, ModuleClosure},
+        microcode::{self, HostCpu},
+    },
+    kern_modules_dir,
+    module::ModParams,
+    IGNITED_CONFIG, IGNITED_MODULE_ALIASES, IGNITED_TARGET_ROOT_PATH, PROGRAM_NAME,
+};
+use goglob::GlobPattern;
+use precisej_printable_errno::{printable_error, ExitError, PrintableErrno, PrintableResult};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    ffi::OsString,
+    fmt::{self, Display, Formatter},
+    fs::{read, remove_file, rename, OpenOptions},
+    io::{self, BufWriter, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Directories binaries are searched in, when given by name.
+const BINARY_DIRS: [&str; 6] = [
+    "/usr/local/sbin",
+    "/usr/local/bin",
+    "/usr/sbin",
+    "/usr/bin",
+    "/sbin",
+    "/bin",
+];
+
+/// Environment variable overriding the modification time of every entry, per
+/// <https://reproducible-builds.org/specs/source-date-epoch/>.
+const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Kernel an image is generated for.
+#[derive(Debug, Clone)]
+pub struct TargetKernel {
+    pub(super) kver: String,
+    pub(super) modules_dir: PathBuf,
+}
+
+/// Options of `ignited build`.
+#[derive(Debug, Clone)]
+pub struct BuildOpts {
+    pub(super) config: PathBuf,
+    pub(super) config_optional: bool,
+    pub(super) kernels: Vec<TargetKernel>,
+    pub(super) init: PathBuf,
+    pub(super) host_only: bool,
+    pub(super) verify_reproducible: bool,
+    pub(super) output: PathBuf,
+}
+
+/// Generate the image described by `opts`.
+///
+/// Each kernel gets its own module set under `/usr/lib/modules/<kver>`, along with its
+/// `[[metadata]]` in `engine.toml` and its `ignited.alias`, so that `/init` can boot any of
+/// them. Files shared by every kernel, such as firmware, are only included once.
+///
+/// The modules are written ahead of the main archive, each compressed as an archive of its
+/// own (the kernel unpacks concatenated compressed archives), so that each can be
+/// [cached][Cache] and reused as long as it doesn't change. How long each phase took
+/// is printed once done.
+///
+/// Images are reproducible: entries are written in path order, owned by root, numbered
+/// sequentially and all modified at `$SOURCE_DATE_EPOCH` (or the Unix epoch if unset), so
+/// that the same inputs always give the same image. With `--verify-reproducible`, the image
+/// is generated a second time from scratch (reading the configuration again, without the
+/// cache) and compared with the first.
+pub fn build(opts: &BuildOpts) -> Result<(), ExitError<String>> {
+    let start = Instant::now();
+    let mut timings = Timings::default();
+    let mtime = image_mtime().bail(3)?;
+    let config = timings
+        .time("configuration", || {
+            BuildConfig::read(&opts.config, opts.config_optional)
+        })
+        .bail(3)?;
+    let mut cache = config.cache_dir().and_then(|dir| match Cache::open(dir) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            eprintln!("{}, building without it", e);
+            None
+        }
+    });
+
+    let generated = generate(opts, &config, mtime, cache.as_mut(), &mut timings, true)?;
+    timings
+        .time("writing", || write_image(&generated, mtime, &opts.output))
+        .bail(6)?;
+    if let Some(cache) = cache {
+        println!("cache: {} hits, {} misses", cache.hits(), cache.misses());
+        // The image is fine regardless
+        if let Err(e) = timings.time("cache", || cache.save()) {
+            eprintln!("{}", e);
+        }
+    }
+    if opts.verify_reproducible {
+        timings.time("verification", || {
+            // Nothing from the first run is reused, so that it can't hide a difference
+            let mtime = image_mtime().bail(3)?;
+            let config = BuildConfig::read(&opts.config, opts.config_optional).bail(3)?;
+            let generated = generate(opts, &config, mtime, None, &mut Timings::default(), false)?;
+            verify_image(&generated, mtime, &opts.output).bail(7)
+        })?;
+    }
+    println!(
+        "{}: generated in {:.3}s ({})",
+        opts.output.display(),
+        start.elapsed().as_secs_f64(),
+        timings
+    );
+    Ok(())
+}
+
+// Time spent in each phase of a build, in order
+#[derive(Debug, Default)]
+struct Timings(Vec<(&'static str, Duration)>);
+impl Timings {
+    // Run `phase`, adding the time it took to that of `name`
+    fn time<T>(&mut self, name: &'static str, phase: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = phase();
+        let elapsed = start.elapsed();
+        match self.0.iter_mut().find(|(phase, _)| *phase == name) {
+            Some((_, total)) => *total += elapsed,
+            None => self.0.push((name, elapsed)),
+        }
+        res
+    }
+}
+impl Display for Timings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, (name, time)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {:.3}s", name, time.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+// Image ready to be written
+struct Generated {
+    // Uncompressed archive preceding the others, with early microcode updates
+    early: Option<Image>,
+    // Compressed archive of every kernel's modules, preceding the main one
+    modules: Vec<u8>,
+    image: Image,
+    compressor: Compressor,
+}
+
+// Modules bundled for one of the image's kernels
+struct ModuleSet {
+    kver: String,
+    kmods: KernelModules,
+    host: Option<HostModules>,
+    closure: ModuleClosure,
+}
+
+// Collect the image described by `opts`, with every entry modified at `mtime`. Why each
+// module was included is only printed if `explain` is set.
+fn generate(
+    opts: &BuildOpts,
+    config: &BuildConfig,
+    mtime: u32,
+    mut cache: Option<&mut Cache>,
+    timings: &mut Timings,
+    explain: bool,
+) -> Result<Generated, ExitError<String>> {
+    let compressor = Compressor::new(
+        config.compression(),
+        config.compression_level(),
+        config.compression_threads(),
+    )
+    .bail(3)?;
+
+    let mut image = Image::new();
+    let mut modules = Image::new();
+    base_layout(&mut image);
+    let mut sets = Vec::new();
+    for kernel in &opts.kernels {
+        // Every kernel must be able to unpack the image
+        compressor.check_kernel(&kernel.kver).bail(3)?;
+        let (kmods, host, closure) = timings.time("modules", || {
+            let kmods = KernelModules::open(&kernel.modules_dir).bail(4)?;
+            let host = if opts.host_only {
+                Some(HostModules::detect(&kmods).bail(4)?)
+            } else {
+                None
+            };
+            let closure = add_modules(
+                &mut modules,
+                &kernel.kver,
+                &kmods,
+                config,
+                host.as_ref(),
+                cache.as_deref_mut(),
+            )
+            .bail(4)?;
+            Ok::<_, ExitError<String>>((kmods, host, closure))
+        })?;
+        if let Some(host) = host.as_ref().filter(|_| explain) {
+            if opts.kernels.len() > 1 {
+                println!("kernel {}:", kernel.kver);
+            }
+            explain_modules(host, config, &closure);
+        }
+        timings
+            .time("firmware", || {
+                add_firmware(&mut image, &kernel.kver, &kmods, &closure)
+            })
+            .bail(5)?;
+        let aliases = module_aliases(&kmods, &closure).bail(4)?;
+        image.file(
+            format!(
+                "{}/{}",
+                kern_modules_dir(&kernel.kver),
+                IGNITED_MODULE_ALIASES
+            ),
+            0o644,
+            Data::Bytes(aliases.into_bytes()),
+        );
+        sets.push(ModuleSet {
+            kver: kernel.kver.clone(),
+            kmods,
+            host,
+            closure,
+        });
+    }
+    add_console_files(&mut image, config).bail(5)?;
+    timings
+        .time("binaries", || {
+            add_binaries(&mut image, config, &opts.init, cache.as_deref_mut())
+        })
+        .bail(5)?;
+
+    let runtime_config = runtime_config(config, &sets).to_toml().bail(5)?;
+    image.file(
+        IGNITED_CONFIG,
+        0o644,
+        Data::Bytes(runtime_config.into_bytes()),
+    );
+
+    let early = timings.time("microcode", || {
+        if !config.microcode() {
+            return Ok(None);
+        }
+        let cpu = if opts.host_only {
+            Some(HostCpu::detect().bail(5)?)
+        } else {
+            None
+        };
+        microcode::early_image(cpu.as_ref()).bail(5)
+    })?;
+    let modules = timings
+        .time("modules archive", || {
+            module_archive(&modules, &compressor, mtime, cache)
+        })
+        .bail(6)?;
+    Ok(Generated {
+        early,
+        modules,
+        image,
+        compressor,
+    })
+}
+
+// Modification time of every entry: $SOURCE_DATE_EPOCH if set, the Unix epoch otherwise
+fn image_mtime() -> Result<u32, PrintableErrno<String>> {
+    match env::var(SOURCE_DATE_EPOCH) {
+        Ok(epoch) => epoch.parse().map_err(|e| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("invalid {} {}: {}", SOURCE_DATE_EPOCH, epoch, e),
+            )
+        }),
+        Err(_) => Ok(0),
+    }
+}
+
+// Directories and files every image needs, regardless of its configuration
+fn base_layout(image: &mut Image) {
+    for dir in ["/dev", "/proc", "/sys", "/run", IGNITED_TARGET_ROOT_PATH] {
+        image.dir(dir);
+    }
+    // The kernel opens /dev/console as init's stdin/stdout/stderr before devtmpfs exists
+    image.char_dev("/dev/console", 0o600, (5, 1));
+
+    // Per https://systemd.io/INITRD_INTERFACE/
+    let initrd_release = format!(
+        "NAME=\"ignited\"\nID=ignited\nVERSION_ID={}\nPRETTY_NAME=\"ignited initramfs\"\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    image.file(
+        "/etc/initrd-release",
+        0o644,
+        Data::Bytes(initrd_release.into_bytes()),
+    );
+}
+
+// Add the modules of kernel `kver` to bundle, along with everything they need. Returns their
+// closure. In host-only mode, the detected modules replace [modules] include.
+fn add_modules(
+    image: &mut Image,
+    kver: &str,
+    kmods: &KernelModules,
+    config: &BuildConfig,
+    host: Option<&HostModules>,
+    mut cache: Option<&mut Cache>,
+) -> Result<ModuleClosure, PrintableErrno<String>> {
+    let requested: Vec<&str> = match host {
+        Some(host) => host.modules().keys().map(|module| &module[..]).collect(),
+        None => config.modules().iter().map(|module| &module[..]).collect(),
+    };
+    let forced = config.force_modules().iter().map(|module| &module[..]);
+    let closure = kmods.closure(requested.into_iter().chain(forced))?;
+    for (module, path) in closure.modules() {
+        // Stored uncompressed: its archive is compressed instead
+        let path = kmods.dir().join(path);
+        let data = match cache.as_deref_mut() {
+            Some(cache) => Data::Host(cache.decompressed(&path)?),
+            None => Data::HostDecompressed(path),
+        };
+        image.file(
+            format!("{}/{}.ko", kern_modules_dir(kver), module),
+            0o644,
+            data,
+        );
+    }
+    Ok(closure)
+}
+
+// Add the firmware files the bundled modules may request. Missing files are only warned
+// about: drivers often list firmware for hardware variants that aren't present.
+fn add_firmware(
+    image: &mut Image,
+    kver: &str,
+    kmods: &KernelModules,
+    closure: &ModuleClosure,
+) -> Result<(), PrintableErrno<String>> {
+    for module in closure.modules().keys() {
+        for name in kmods.modinfo(module, "firmware")? {
+            match firmware::find(kver, &name) {
+                Some(path) => {
+                    image.host_file(path)?;
+                }
+                None => eprintln!(
+                    "{}: firmware {} needed by module {} not found",
+                    PROGRAM_NAME, name, module
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Print why each bundled module was included
+fn explain_modules(host: &HostModules, config: &BuildConfig, closure: &ModuleClosure) {
+    let forced: BTreeSet<_> = config
+        .force_modules()
+        .iter()
+        .map(|module| ModParams::normalize_module(module))
+        .collect();
+    for module in closure.modules().keys() {
+        let dependent = |deps: &BTreeMap<String, Vec<String>>| {
+            deps.iter()
+                .find(|(_, deps)| deps.contains(module))
+                .map(|(dependent, _)| dependent.clone())
+        };
+        let reason = match host.modules().get(module) {
+            Some(reason) => reason.clone(),
+            None if forced.contains(module) => "listed in [modules] force".to_string(),
+            None => match dependent(closure.deps()) {
+                Some(dependent) => format!("needed by {}", dependent),
+                None => match dependent(closure.post_deps()) {
+                    Some(dependent) => format!("soft dependency of {}", dependent),
+                    None => "needed by another module".to_string(),
+                },
+            },
+        };
+        println!("{}: {}", module, reason);
+    }
+}
+
+// Generate the image's ignited.alias: every alias of the bundled modules, as
+// `<pattern> <module>` lines. Patterns are checked the same way ModAliases parses them at
+// boot, so that a bad alias fails the build instead.
+fn module_aliases(
+    kmods: &KernelModules,
+    closure: &ModuleClosure,
+) -> Result<String, PrintableErrno<String>> {
+    let mut aliases = String::new();
+    for (pattern, module) in kmods.aliases() {
+        if !closure.modules().contains_key(module) {
+            continue;
+        }
+        GlobPattern::new(pattern).map_err(|e| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("malformed alias {} for module {}: {}", pattern, module, e),
+            )
+        })?;
+        aliases.push_str(pattern);
+        aliases.push(' ');
+        aliases.push_str(module);
+        aliases.push('\n');
+    }
+    Ok(aliases)
+}
+
+// Add the files referenced by [console] at the same path they have on the host
+fn add_console_files(
+    image: &mut Image,
+    config: &BuildConfig,
+) -> Result<(), PrintableErrno<String>> {
+    let files = [
+        config.font_file(),
+        config.font_map_file(),
+        config.font_unicode_file(),
+        config.keymap_file(),
+    ];
+    for file in files.into_iter().flatten() {
+        if !Path::new(file).is_file() {
+            return Err(printable_error(
+                PROGRAM_NAME,
+                format!("console file {} not found", file),
+            ));
+        }
+        image.file(file, 0o644, Data::Host(PathBuf::from(file)));
+    }
+    Ok(())
+}
+
+// Add ignited itself (`init`) as /init, the binaries in [binaries] and those ignited executes
+// at boot depending on the configuration. Their dynamic linkers and libraries are added as
+// well.
+fn add_binaries(
+    image: &mut Image,
+    config: &BuildConfig,
+    init: &Path,
+    cache: Option<&mut Cache>,
+) -> Result<(), PrintableErrno<String>> {
+    let mut binaries: Vec<_> = config.binaries().iter().map(|b| &b[..]).collect();
+    if config.font_file().is_some() {
+        // See vconsole::font::set_font
+        binaries.push("setfont");
+    }
+    let mut binaries = binaries
+        .into_iter()
+        .map(find_binary)
+        .collect::<Result<Vec<_>, _>>()?;
+    // Unless built statically, /init can't run without its dynamic linker and libraries
+    // either
+    let init_is_binary = binaries.iter().any(|binary| binary == init);
+    binaries.push(init.to_path_buf());
+    let resolver = ElfResolver::new();
+    let closure = match cache {
+        Some(cache) => cache.elf_closure(&resolver, &binaries)?,
+        None => resolver.closure(&binaries)?,
+    };
+    let dynamic = closure.iter().any(|path| !binaries.contains(path));
+    for path in closure {
+        if path != init || init_is_binary {
+            image.host_file(path)?;
+        }
+    }
+    image.file("/init", 0o755, Data::Host(init.to_path_buf()));
+
+    // Without its cache, the dynamic linker only searches the default directories, not
+    // those in /etc/ld.so.conf
+    if dynamic && Path::new(LD_SO_CACHE).is_file() {
+        image.host_file(LD_SO_CACHE)?;
+    }
+    Ok(())
+}
+
+// Find the binary called `name` on the host, unless it's already a path
+fn find_binary(name: &str) -> Result<PathBuf, PrintableErrno<String>> {
+    if name.contains('/') {
+        return Ok(PathBuf::from(name));
+    }
+    BINARY_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join(name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| printable_error(PROGRAM_NAME, format!("binary {} not found", name)))
+}
+
+// Generate the image's engine.toml, with one [[metadata]] per module set
+fn runtime_config(config: &BuildConfig, sets: &[ModuleSet]) -> RuntimeConfig {
+    let mut builder = RuntimeConfig::builder(&sets[0].kver);
+    for (i, set) in sets.iter().enumerate() {
+        if i > 0 {
+            builder.kernel(&set.kver);
+        }
+        for module in set.kmods.builtin() {
+            builder.module_builtin(module);
+        }
+        for (module, deps) in set.closure.deps() {
+            builder.module_deps(module, deps.clone());
+        }
+        for (module, post_deps) in set.closure.post_deps() {
+            builder.module_post_deps(module, post_deps.clone());
+        }
+        for (module, opts) in config.module_options() {
+            let module = ModParams::normalize_module(module);
+            if set.closure.modules().contains_key(&module) {
+                builder.module_opts(module, opts);
+            }
+        }
+    }
+
+    // The host is the same whichever kernel it's detected with
+    let host = sets.iter().find_map(|set| set.host.as_ref());
+    builder
+        .lvm(host.map_or(config.has_lvm(), HostModules::has_lvm))
+        .mdraid(host.map_or(config.has_mdraid(), HostModules::has_mdraid))
+        .mount_timeout(config.mount_timeout());
+    for module in config.force_modules() {
+        builder.force_module(ModParams::normalize_module(module));
+    }
+    if let Some(utf) = config.console_utf8() {
+        builder.console_utf8(utf);
+    }
+    if let Some(font_file) = config.font_file() {
+        builder.font_file(font_file);
+    }
+    if let Some(font_map_file) = config.font_map_file() {
+        builder.font_map_file(font_map_file);
+    }
+    if let Some(font_unicode_file) = config.font_unicode_file() {
+        builder.font_unicode_file(font_unicode_file);
+    }
+    if let Some(keymap_file) = config.keymap_file() {
+        builder.keymap_file(keymap_file);
+    }
+    builder.build()
+}
+
+// Compress every kernel's modules: their directories as one archive, then each module as an
+// archive of its own, so that only modules missing from the cache are compressed
+fn module_archive(
+    modules: &Image,
+    compressor: &Compressor,
+    mtime: u32,
+    mut cache: Option<&mut Cache>,
+) -> Result<Vec<u8>, PrintableErrno<String>> {
+    let compress_err =
+        |io| printable_error(PROGRAM_NAME, format!("unable to compress modules: {}", io));
+    let mut archives = Vec::new();
+    for part in modules.split() {
+        let mut cache = cache
+            .as_deref_mut()
+            .map(|cache| (archive_key(&part, compressor, mtime), cache));
+        if let Some(archive) = cache.as_mut().and_then(|(key, cache)| cache.archive(key)) {
+            archives.extend_from_slice(&archive);
+            continue;
+        }
+        let encoder = compressor.encoder(Vec::new()).map_err(compress_err)?;
+        let archive = part.write(encoder, mtime)?.finish().map_err(compress_err)?;
+        if let Some((key, cache)) = cache {
+            cache.store_archive(key, &archive)?;
+        }
+        archives.extend_from_slice(&archive);
+    }
+    Ok(archives)
+}
+
+// Write the image next to `output` first, so that a failed build never leaves a truncated
+// image behind.
+fn write_image(
+    generated: &Generated,
+    mtime: u32,
+    output: &Path,
+) -> Result<(), PrintableErrno<String>> {
+    let mut tmp = OsString::from(output);
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let write_err = |io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to write {}: {}", tmp.display(), io),
+        )
+    };
+    let out = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .map_err(write_err)?;
+    let res = write_archives(generated, mtime, BufWriter::new(out), write_err)
+        .and_then(|out| {
+            out.into_inner()
+                .map_err(|e| write_err(e.into_error()))?
+                .sync_all()
+                .map_err(write_err)
+        })
+        .and_then(|()| {
+            rename(&tmp, output).map_err(|io| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to write {}: {}", output.display(), io),
+                )
+            })
+        });
+    if res.is_err() {
+        let _ = remove_file(&tmp);
+    }
+    res
+}
+
+// Write the early archive uncompressed to `out`, followed by the modules archive and the main
+// archive compressed. Returns `out`.
+fn write_archives<W: Write>(
+    generated: &Generated,
+    mtime: u32,
+    mut out: W,
+    write_err: impl Fn(io::Error) -> PrintableErrno<String>,
+) -> Result<W, PrintableErrno<String>> {
+    if let Some(early) = &generated.early {
+        out = early.write(out, mtime)?;
+    }
+    out.write_all(&generated.modules).map_err(&write_err)?;
+    let out = generated.compressor.encoder(out).map_err(&write_err)?;
+    generated
+        .image
+        .write(out, mtime)?
+        .finish()
+        .map_err(write_err)
+}
+
+// Write the image again, in memory, and make sure it's identical to the one at `output`
+fn verify_image(
+    generated: &Generated,
+    mtime: u32,
+    output: &Path,
+) -> Result<(), PrintableErrno<String>> {
+    let second = write_archives(generated, mtime, Vec::new(), |io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to generate the image again: {}", io),
+        )
+    })?;
+    let first = read(output).map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to read {}: {}", output.display(), io),
+        )
+    })?;
+    let differs = first
+        .iter()
+        .zip(&second)
+        .position(|(a, b)| a != b)
+        .or_else(|| (first.len() != second.len()).then(|| first.len().min(second.len())));
+    match differs {
+        Some(offset) => Err(printable_error(
+            PROGRAM_NAME,
+            format!(
+                "{} is not reproducible: generating it again gives a different image from \
+                byte {} on",
+                output.display(),
+                offset
+            ),
+        )),
+        None => {
+            println!("{} is reproducible", output.display());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{
+        compress::{decompress_image, Compression},
+        cpio,
+        elf::Elf,
+        image::Entry,
+    };
+    use std::fs::canonicalize;
+
+    #[test]
+    fn init_closure() {
+        // The test binary is linked the same way as ignited
+        let init = env::current_exe().unwrap();
+        let mut image = Image::new();
+        add_binaries(&mut image, &BuildConfig::default(), &init, None).unwrap();
+        let entries: BTreeMap<_, _> = image.entries().collect();
+        assert_eq!(
+            entries.get("/init"),
+            Some(&&Entry::File(0o755, Data::Host(init.clone())))
+        );
+
+        // Files are added under their path on the host, along with the symlinks leading to
+        // them
+        let in_image = |path: &Path| {
+            let path = canonicalize(path).unwrap();
+            matches!(
+                entries.get(path.to_str().unwrap()),
+                Some(Entry::File(_, Data::Host(host))) if *host == path
+            )
+        };
+        let elf = Elf::parse(&read(&init).unwrap()).unwrap();
+        if let Some(interp) = elf.interp() {
+            assert!(in_image(Path::new(interp)), "{} not in image", interp);
+            assert_eq!(
+                entries.contains_key(LD_SO_CACHE),
+                Path::new(LD_SO_CACHE).is_file()
+            );
+        }
+        for path in ElfResolver::new().closure(&[&init]).unwrap() {
+            if path != init {
+                assert!(in_image(&path), "{} not in image", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn init_as_binary() {
+        let init = env::current_exe().unwrap();
+        let config: BuildConfig =
+            toml::from_str(&format!("[binaries]\ninclude = [\"{}\"]\n", init.display())).unwrap();
+        let mut image = Image::new();
+        add_binaries(&mut image, &config, &init, None).unwrap();
+        let entries: BTreeMap<_, _> = image.entries().collect();
+        assert!(entries.contains_key("/init"));
+        assert!(entries.contains_key(canonicalize(&init).unwrap().to_str().unwrap()));
+    }
+
+    #[test]
+    fn reproducible() {
+        let opts = BuildOpts {
+            config: PathBuf::new(),
+            config_optional: true,
+            kernels: vec![TargetKernel {
+                kver: "test".to_string(),
+                modules_dir: PathBuf::from(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/src/generator/testdata/modules"
+                )),
+            }],
+            init: env::current_exe().unwrap(),
+            host_only: false,
+            verify_reproducible: false,
+            output: PathBuf::new(),
+        };
+        let config: BuildConfig = toml::from_str(
+            "[compression]\nformat = \"none\"\n[microcode]\nenable = false\n[cache]\nenable = false\n",
+        )
+        .unwrap();
+        let build = |mtime| {
+            let generated =
+                generate(&opts, &config, mtime, None, &mut Timings::default(), false).unwrap();
+            let image = write_archives(&generated, mtime, Vec::new(), |io| {
+                printable_error(PROGRAM_NAME, io.to_string())
+            })
+            .unwrap();
+            // Modification time of the first entry, left uncompressed
+            let field = std::str::from_utf8(&image[6 + 5 * 8..6 + 6 * 8]).unwrap();
+            assert_eq!(u32::from_str_radix(field, 16).unwrap(), mtime);
+            image
+        };
+
+        // The only test touching $SOURCE_DATE_EPOCH
+        env::remove_var(SOURCE_DATE_EPOCH);
+        assert_eq!(image_mtime().unwrap(), 0);
+        let unset = build(0);
+        assert_eq!(build(0), unset);
+
+        env::set_var(SOURCE_DATE_EPOCH, "1700000000");
+        let mtime = image_mtime().unwrap();
+        assert_eq!(mtime, 1700000000);
+        let set = build(mtime);
+        assert_eq!(build(mtime), set);
+        assert_ne!(set, unset);
+
+        env::set_var(SOURCE_DATE_EPOCH, "yesterday");
+        assert!(image_mtime().is_err());
+        env::remove_var(SOURCE_DATE_EPOCH);
+    }
+
+    #[test]
+    fn cached_modules() {
+        let dir = env::temp_dir().join(format!("ignited-build-{}", std::process::id()));
+        let module = |name: &str, contents: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            Data::Host(path)
+        };
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut modules = Image::new();
+        modules
+            .file(
+                "/usr/lib/modules/6.1.0/a.ko",
+                0o644,
+                module("a", b"module a"),
+            )
+            .file(
+                "/usr/lib/modules/6.1.0/b.ko",
+                0o644,
+                module("b", b"module b"),
+            );
+        let compressor = Compressor::new(Compression::Gzip, None, None).unwrap();
+        let mut cache = Cache::open(&dir.join("cache")).unwrap();
+
+        // One archive for the directories, then one per module
+        let archive = module_archive(&modules, &compressor, 0, Some(&mut cache)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 3));
+        let cpio = decompress_image(&archive).unwrap();
+        let mut files = Vec::new();
+        let mut offset = 0;
+        while offset < cpio.len() {
+            let (entries, read) = cpio::read_archive(&cpio[offset..]).unwrap();
+            files.extend(
+                entries
+                    .iter()
+                    .filter(|entry| entry.is_file())
+                    .map(|entry| (entry.name().to_string(), entry.data().to_vec())),
+            );
+            offset += read;
+        }
+        assert_eq!(
+            files,
+            [
+                (
+                    "usr/lib/modules/6.1.0/a.ko".to_string(),
+                    b"module a".to_vec()
+                ),
+                (
+                    "usr/lib/modules/6.1.0/b.ko".to_string(),
+                    b"module b".to_vec()
+                ),
+            ]
+        );
+        assert_eq!(
+            module_archive(&modules, &compressor, 0, None).unwrap(),
+            archive
+        );
+
+        // Only the changed module is compressed again
+        assert_eq!(
+            module_archive(&modules, &compressor, 0, Some(&mut cache)).unwrap(),
+            archive
+        );
+        assert_eq!((cache.hits(), cache.misses()), (3, 3));
+        modules.file(
+            "/usr/lib/modules/6.1.0/b.ko",
+            0o644,
+            module("b2", b"module b2"),
+        );
+        let changed = module_archive(&modules, &compressor, 0, Some(&mut cache)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (5, 4));
+        assert_eq!(
+            changed,
+            module_archive(&modules, &compressor, 0, None).unwrap()
+        );
+        assert_ne!(changed, archive);
+
+        assert!(
+            module_archive(&Image::new(), &compressor, 0, Some(&mut cache))
+                .unwrap()
+                .is_empty()
+        );
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
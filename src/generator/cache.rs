@@ -0,0 +1,575 @@
+//! Cache of image generation results, shared by every build.
+//!
+//! Most builds only change the configuration or a few modules, so whatever is expensive to
+//! compute from files on the host is kept in `[cache] dir` (`/var/cache/ignited` by default)
+//! between builds:
+//!
+//! - Decompressed kernel modules, keyed on the compressed file's path, size and modification
+//!   time.
+//! - ELF closures of the bundled binaries, valid as long as none of their members changed
+//!   size or modification time, and neither did the library directories (as libraries are
+//!   added or removed), `/etc/ld.so.conf` nor `/etc/ld.so.cache`.
+//! - Compressed archives of the bundled modules, one per module, keyed on the hash of its
+//!   contents along with the compression settings. Images are made of these archives
+//!   concatenated, so an unchanged module is spliced as-is into the new image without being
+//!   read nor compressed again: changing a module only compresses that module again.
+//!
+//! Contents are stored in `objects/`, named after their SHA-256 hash, and referenced from
+//! `index.toml`. Whenever the cache is saved, entries whose files changed and archives unused
+//! for 30 days are dropped, along with the objects only they referenced. The cache is locked
+//! while open, so concurrent builds wait for each other.
+
+use crate::{
+    crypto::HashAlg,
+    decompress::decompress,
+    generator::{
+        compress::Compressor,
+        elf::{ElfResolver, LD_SO_CACHE, LD_SO_CONF},
+        image::{Data, Entry, Image},
+    },
+    PROGRAM_NAME,
+};
+use nix::fcntl::{flock, FlockArg};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{create_dir_all, metadata, read, read_dir, read_to_string, remove_file, rename, write},
+    fs::{File, OpenOptions},
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Directory the cache is kept in by default.
+pub const CACHE_DEFAULT_DIR: &str = "/var/cache/ignited";
+
+const INDEX: &str = "index.toml";
+const OBJECTS: &str = "objects";
+const LOCK: &str = "lock";
+
+// Module archives unused for longer than this are dropped, in seconds
+const ARCHIVE_MAX_AGE: u64 = 30 * 24 * 60 * 60;
+
+// Size and modification time (in nanoseconds) of a file on the host: if neither changed,
+// its contents are assumed to be the same
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+struct FileStamp {
+    size: u64,
+    mtime: i64,
+}
+impl FileStamp {
+    fn of(path: &Path) -> io::Result<Self> {
+        let meta = metadata(path)?;
+        Ok(Self {
+            size: meta.len(),
+            mtime: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+        })
+    }
+
+    fn is_current(&self, path: &Path) -> bool {
+        Self::of(path).is_ok_and(|stamp| stamp == *self)
+    }
+}
+
+// Decompressed contents of a file on the host
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct DecompressedDe {
+    size: u64,
+    mtime: i64,
+    hash: String,
+}
+impl DecompressedDe {
+    fn stamp(&self) -> FileStamp {
+        FileStamp {
+            size: self.size,
+            mtime: self.mtime,
+        }
+    }
+}
+
+// ELF closure, along with the stamp of each member and of what the search for them depends on
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct ClosureDe {
+    members: BTreeMap<String, FileStamp>,
+    #[serde(default)]
+    search: BTreeMap<String, FileStamp>,
+}
+impl ClosureDe {
+    fn is_current(&self) -> bool {
+        self.members
+            .iter()
+            .chain(&self.search)
+            .all(|(path, stamp)| stamp.is_current(Path::new(path)))
+    }
+}
+
+// Compressed module archive, along with when it was last used (in seconds since the epoch)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct ArchiveDe {
+    hash: String,
+    used: u64,
+}
+
+// Contents of index.toml
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct CacheIndex {
+    // By path of the compressed file
+    decompressed: BTreeMap<String, DecompressedDe>,
+    // By hash of the library directories and objects the closure was computed for
+    closures: BTreeMap<String, ClosureDe>,
+    // By archive key
+    archives: BTreeMap<String, ArchiveDe>,
+}
+
+/// Open cache, locked until dropped.
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    index: CacheIndex,
+    hits: usize,
+    misses: usize,
+    _lock: File,
+}
+impl Cache {
+    /// Open the cache in `dir`, creating it if needed. Waits until no other build is using
+    /// it.
+    pub fn open(dir: &Path) -> Result<Self, PrintableErrno<String>> {
+        let open_err = |e: String| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to open cache {}: {}", dir.display(), e),
+            )
+        };
+        create_dir_all(dir.join(OBJECTS)).map_err(|io| open_err(io.to_string()))?;
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK))
+            .map_err(|io| open_err(io.to_string()))?;
+        flock(lock.as_raw_fd(), FlockArg::LockExclusive)
+            .map_err(|errno| open_err(errno.to_string()))?;
+
+        let index = match read_to_string(dir.join(INDEX)) {
+            Ok(index) => toml::from_str(&index).unwrap_or_else(|de| {
+                // Only costs a full build
+                eprintln!(
+                    "{}: ignoring corrupted cache index in {}: {}",
+                    PROGRAM_NAME,
+                    dir.display(),
+                    de
+                );
+                CacheIndex::default()
+            }),
+            Err(io) if io.kind() == io::ErrorKind::NotFound => CacheIndex::default(),
+            Err(io) => return Err(open_err(io.to_string())),
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index,
+            hits: 0,
+            misses: 0,
+            _lock: lock,
+        })
+    }
+
+    /// Number of results taken from the cache so far.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Number of results computed (and added to the cache) so far.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    /// Path of a file holding the [decompressed][decompress] contents of the file at `path`,
+    /// which is only read if it changed since it was last cached.
+    pub fn decompressed(&mut self, path: &Path) -> Result<PathBuf, PrintableErrno<String>> {
+        let decompress_err = |io: io::Error| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to decompress {}: {}", path.display(), io),
+            )
+        };
+        let key = path.to_string_lossy().into_owned();
+        let stamp = FileStamp::of(path).map_err(decompress_err)?;
+        let cached = self
+            .index
+            .decompressed
+            .get(&key)
+            .filter(|entry| entry.stamp() == stamp)
+            .map(|entry| self.object(&entry.hash))
+            .filter(|object| object.is_file());
+        if let Some(object) = cached {
+            self.hits += 1;
+            return Ok(object);
+        }
+
+        self.misses += 1;
+        let data = read(path).and_then(decompress).map_err(decompress_err)?;
+        let hash = self.store(&data)?;
+        let object = self.object(&hash);
+        self.index.decompressed.insert(
+            key,
+            DecompressedDe {
+                size: stamp.size,
+                mtime: stamp.mtime,
+                hash,
+            },
+        );
+        Ok(object)
+    }
+
+    /// [ELF closure][ElfResolver::closure] of `objects`, only computed again if one of its
+    /// members or of `resolver`'s library directories changed since it was last cached.
+    pub fn elf_closure(
+        &mut self,
+        resolver: &ElfResolver,
+        objects: &[PathBuf],
+    ) -> Result<BTreeSet<PathBuf>, PrintableErrno<String>> {
+        let mut key = String::new();
+        for dir in resolver.lib_dirs() {
+            key.push_str(&format!("dir {}\n", dir.display()));
+        }
+        for object in objects {
+            key.push_str(&format!("object {}\n", object.display()));
+        }
+        let key = hash(key.as_bytes());
+        let search = search_stamps(resolver);
+        let cached = self
+            .index
+            .closures
+            .get(&key)
+            .filter(|closure| closure.search == search && closure.is_current());
+        if let Some(closure) = cached {
+            self.hits += 1;
+            return Ok(closure.members.keys().map(PathBuf::from).collect());
+        }
+
+        self.misses += 1;
+        let closure = resolver.closure(objects)?;
+        let members: Option<BTreeMap<_, _>> = closure
+            .iter()
+            .map(|path| Some((path.to_str()?.to_string(), FileStamp::of(path).ok()?)))
+            .collect();
+        // Closures with paths that can't be stored in the index are never cached
+        if let Some(members) = members {
+            self.index
+                .closures
+                .insert(key, ClosureDe { members, search });
+        }
+        Ok(closure)
+    }
+
+    /// Compressed module archive cached under `key` (see [archive_key]), if any.
+    pub fn archive(&mut self, key: &str) -> Option<Vec<u8>> {
+        let object = match self.index.archives.get(key) {
+            Some(archive) => self.object(&archive.hash),
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        match read(object) {
+            Ok(data) => {
+                self.hits += 1;
+                if let Some(archive) = self.index.archives.get_mut(key) {
+                    archive.used = now();
+                }
+                Some(data)
+            }
+            Err(_) => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the compressed module archive `data` under `key` (see [archive_key]).
+    pub fn store_archive(
+        &mut self,
+        key: String,
+        data: &[u8],
+    ) -> Result<(), PrintableErrno<String>> {
+        let hash = self.store(data)?;
+        self.index
+            .archives
+            .insert(key, ArchiveDe { hash, used: now() });
+        Ok(())
+    }
+
+    /// Drop stale entries along with the objects only they referenced, then write the index.
+    pub fn save(mut self) -> Result<(), PrintableErrno<String>> {
+        let save_err = |e: String| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to save cache {}: {}", self.dir.display(), e),
+            )
+        };
+        let now = now();
+        self.index
+            .decompressed
+            .retain(|path, entry| entry.stamp().is_current(Path::new(path)));
+        self.index
+            .closures
+            .retain(|_, closure| closure.is_current());
+        self.index
+            .archives
+            .retain(|_, archive| now.saturating_sub(archive.used) <= ARCHIVE_MAX_AGE);
+
+        let referenced: BTreeSet<_> = self
+            .index
+            .decompressed
+            .values()
+            .map(|entry| &entry.hash)
+            .chain(self.index.archives.values().map(|archive| &archive.hash))
+            .collect();
+        let objects = read_dir(self.dir.join(OBJECTS)).map_err(|io| save_err(io.to_string()))?;
+        for object in objects.flatten() {
+            let name = object.file_name().to_string_lossy().into_owned();
+            if !referenced.contains(&name) {
+                let _ = remove_file(object.path());
+            }
+        }
+
+        let index = toml::to_string(&self.index).map_err(|ser| save_err(ser.to_string()))?;
+        let tmp = self.dir.join(format!("{}.tmp", INDEX));
+        write(&tmp, index)
+            .and_then(|()| rename(&tmp, self.dir.join(INDEX)))
+            .map_err(|io| save_err(io.to_string()))
+    }
+
+    fn object(&self, hash: &str) -> PathBuf {
+        self.dir.join(OBJECTS).join(hash)
+    }
+
+    // Store `data` as an object, unless it already is. Returns its hash.
+    fn store(&self, data: &[u8]) -> Result<String, PrintableErrno<String>> {
+        let hash = hash(data);
+        let object = self.object(&hash);
+        if object.is_file() {
+            return Ok(hash);
+        }
+        let tmp = self.object(&format!("{}.tmp", hash));
+        write(&tmp, data)
+            .and_then(|()| rename(&tmp, &object))
+            .map_err(|io| {
+                let _ = remove_file(&tmp);
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to write {}: {}", object.display(), io),
+                )
+            })?;
+        Ok(hash)
+    }
+}
+
+// Stamps of what library lookups depend on besides the objects themselves: the library
+// directories, whose modification time changes as libraries are added or removed, ld.so.conf
+// and ld.so.cache. Missing ones are left out, so that creating one changes the stamps too.
+fn search_stamps(resolver: &ElfResolver) -> BTreeMap<String, FileStamp> {
+    resolver
+        .lib_dirs()
+        .iter()
+        .map(PathBuf::as_path)
+        .chain([Path::new(LD_SO_CONF), Path::new(LD_SO_CACHE)])
+        .filter_map(|path| Some((path.to_str()?.to_string(), FileStamp::of(path).ok()?)))
+        .collect()
+}
+
+/// Key of the module archive generated from `image` with `compressor`, with every entry
+/// modified at `mtime`. The number of threads isn't part of it, as it doesn't change the
+/// compressed output.
+///
+/// The host files of `image` must be [cache objects][Cache::decompressed]: as they're named
+/// after their contents, the image's layout is enough to identify the archive.
+pub fn archive_key(image: &Image, compressor: &Compressor, mtime: u32) -> String {
+    let mut key = KeyBuilder::default();
+    key.field(compressor.format().to_string())
+        .field(compressor.level().to_string())
+        .field(mtime.to_string());
+    for (path, entry) in image.entries() {
+        key.field(path);
+        match entry {
+            Entry::Dir => key.field("dir"),
+            Entry::File(mode, data) => {
+                key.field("file").field(format!("{:o}", mode));
+                match data {
+                    Data::Bytes(bytes) => key.field("bytes").field(hash(bytes)),
+                    Data::Host(path) => key.field("host").field(path.as_os_str().as_bytes()),
+                    Data::HostDecompressed(path) => key
+                        .field("host-decompressed")
+                        .field(path.as_os_str().as_bytes()),
+                }
+            }
+            Entry::Symlink(target) => key.field("symlink").field(target),
+            Entry::CharDev(mode, (major, minor)) => key
+                .field("char-dev")
+                .field(format!("{:o}", mode))
+                .field(format!("{}:{}", major, minor)),
+        };
+    }
+    hash(&key.0)
+}
+
+// Unambiguous concatenation of fields, each prefixed with its length
+#[derive(Default)]
+struct KeyBuilder(Vec<u8>);
+impl KeyBuilder {
+    fn field<B: AsRef<[u8]>>(&mut self, field: B) -> &mut Self {
+        let field = field.as_ref();
+        self.0
+            .extend_from_slice(&(field.len() as u64).to_le_bytes());
+        self.0.extend_from_slice(field);
+        self
+    }
+}
+
+// Hexadecimal SHA-256 hash of `data`
+fn hash(data: &[u8]) -> String {
+    HashAlg::Sha256
+        .digest(&[data])
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Current time, in seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{compress::Compression, elf::test_object};
+    use std::fs::remove_dir_all;
+
+    fn modules(contents: &[u8]) -> Image {
+        let mut image = Image::new();
+        image
+            .file(
+                "/usr/lib/modules/5.17.1/ignited.alias",
+                0o644,
+                Data::Bytes(contents.to_vec()),
+            )
+            .file(
+                "/usr/lib/modules/5.17.1/kernel/ext4.ko",
+                0o644,
+                Data::Host(PathBuf::from("/var/cache/ignited/objects/0123abcd")),
+            );
+        image
+    }
+
+    fn compressor(format: Compression, level: Option<i32>, threads: u32) -> Compressor {
+        Compressor::new(format, level, Some(threads)).unwrap()
+    }
+
+    #[test]
+    fn archive_key_settings() {
+        let image = modules(b"alias fs-ext4 ext4\n");
+        let gzip = compressor(Compression::Gzip, None, 1);
+        let key = archive_key(&image, &gzip, 0);
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, archive_key(&image.clone(), &gzip, 0));
+
+        assert_ne!(key, archive_key(&image, &gzip, 1));
+        assert_ne!(
+            key,
+            archive_key(&image, &compressor(Compression::Gzip, Some(9), 1), 0)
+        );
+        assert_eq!(
+            key,
+            archive_key(&image, &compressor(Compression::Gzip, None, 2), 0)
+        );
+        assert_ne!(
+            key,
+            archive_key(&image, &compressor(Compression::Lz4, None, 1), 0)
+        );
+    }
+
+    #[test]
+    fn archive_key_entries() {
+        let image = modules(b"alias fs-ext4 ext4\n");
+        let gzip = compressor(Compression::Gzip, None, 1);
+        let key = archive_key(&image, &gzip, 0);
+
+        assert_ne!(
+            key,
+            archive_key(&modules(b"alias fs-ext3 ext4\n"), &gzip, 0)
+        );
+        let mut changed = image.clone();
+        changed.file(
+            "/usr/lib/modules/5.17.1/kernel/ext4.ko",
+            0o600,
+            Data::Host(PathBuf::from("/var/cache/ignited/objects/0123abcd")),
+        );
+        assert_ne!(key, archive_key(&changed, &gzip, 0));
+        let mut changed = image.clone();
+        changed.file(
+            "/usr/lib/modules/5.17.1/kernel/ext4.ko",
+            0o644,
+            Data::HostDecompressed(PathBuf::from("/var/cache/ignited/objects/0123abcd")),
+        );
+        assert_ne!(key, archive_key(&changed, &gzip, 0));
+        let mut changed = image.clone();
+        changed.symlink("/usr/lib/modules/5.17.1/kernel/ext3.ko", "ext4.ko");
+        assert_ne!(key, archive_key(&changed, &gzip, 0));
+
+        // Fields can't run into each other
+        let mut a = Image::new();
+        a.symlink("/a", "b\n/c");
+        let mut b = Image::new();
+        b.symlink("/a", "b").dir("\n/c");
+        assert_ne!(archive_key(&a, &gzip, 0), archive_key(&b, &gzip, 0));
+    }
+
+    #[test]
+    fn elf_closure() {
+        let dir = std::env::temp_dir().join(format!("ignited-cache-{}", std::process::id()));
+        let (bin, lib1, lib2) = (dir.join("bin"), dir.join("lib1"), dir.join("lib2"));
+        for dir in [&bin, &lib1, &lib2] {
+            create_dir_all(dir).unwrap();
+        }
+        write(bin.join("sh"), test_object(&["libc.so.6"])).unwrap();
+        write(lib2.join("libc.so.6"), test_object(&[])).unwrap();
+        let objects = [bin.join("sh")];
+        let resolver = ElfResolver::with_lib_dirs(vec![lib1.clone(), lib2.clone()]);
+        let mut cache = Cache::open(&dir.join("cache")).unwrap();
+
+        let closure = BTreeSet::from([bin.join("sh"), lib2.join("libc.so.6")]);
+        assert_eq!(cache.elf_closure(&resolver, &objects).unwrap(), closure);
+        assert_eq!(cache.elf_closure(&resolver, &objects).unwrap(), closure);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // A library added earlier in the search path takes precedence
+        write(lib1.join("libc.so.6"), test_object(&[])).unwrap();
+        assert_eq!(
+            cache.elf_closure(&resolver, &objects).unwrap(),
+            BTreeSet::from([bin.join("sh"), lib1.join("libc.so.6")])
+        );
+        assert_eq!((cache.hits(), cache.misses()), (1, 2));
+
+        // So does a library directory being created
+        let lib0 = dir.join("lib0");
+        let resolver = ElfResolver::with_lib_dirs(vec![lib0.clone(), lib1.clone()]);
+        assert_eq!(cache.elf_closure(&resolver, &objects).unwrap().len(), 2);
+        create_dir_all(&lib0).unwrap();
+        write(lib0.join("libc.so.6"), test_object(&[])).unwrap();
+        assert_eq!(
+            cache.elf_closure(&resolver, &objects).unwrap(),
+            BTreeSet::from([bin.join("sh"), lib0.join("libc.so.6")])
+        );
+        assert_eq!((cache.hits(), cache.misses()), (1, 4));
+
+        drop(cache);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,560 @@
+//! Image compression.
+//!
+//! The cpio archive is streamed through the compressor as it's written. Only formats the
+//! kernel can unpack by itself are supported, and the target kernel's configuration is
+//! checked (when available) so that images it can't unpack are never generated.
+//!
+//! Kernel modules compressed on the host (`.ko.gz`, `.ko.xz` or `.ko.zst`) are
+//! [decompressed][crate::decompress] before being added to the image instead: compressing already
+//! compressed data gains nothing, while compressing the whole archive at once does.
+//!
+//! xz and zstd images are only available with the `xz` and `zstd` features, which link
+//! ignited to liblzma and libzstd. Since ignited is its own images' `/init`, these libraries
+//! would then be bundled in every image: both features are opt-in, and gzip is the default
+//! format unless the `zstd` feature is enabled.
+//!
+//! Compressed images are reproducible: the output only depends on the format and level,
+//! never on the number of threads used or on when the image was compressed.
+
+use crate::{
+    decompress::{decompress, GZIP_MAGIC, XZ_MAGIC, ZSTD_MAGIC},
+    util::get_booted_kernel_ver,
+    PROGRAM_NAME,
+};
+use flate2::read::GzDecoder;
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use serde::Deserialize;
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{read_to_string, File},
+    io::{self, Read, Write},
+    thread::available_parallelism,
+};
+#[cfg(feature = "xz")]
+use xz2::stream::{Check, MtStreamBuilder};
+
+const PROC_CONFIG_GZ: &str = "/proc/config.gz";
+
+// The kernel only understands the legacy lz4 format, made of independently compressed blocks
+// of up to 8 MiB
+const LZ4_LEGACY_MAGIC: u32 = 0x184c2102;
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Compression format of an image.
+#[derive(Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// Uncompressed cpio archive.
+    None,
+
+    /// gzip (`CONFIG_RD_GZIP`).
+    Gzip,
+
+    /// xz (`CONFIG_RD_XZ`).
+    Xz,
+
+    /// lz4, legacy format (`CONFIG_RD_LZ4`).
+    Lz4,
+
+    /// zstd (`CONFIG_RD_ZSTD`).
+    Zstd,
+}
+impl Compression {
+    // Whether images can be compressed in this format (xz and zstd need C libraries)
+    fn supported(&self) -> bool {
+        (*self != Compression::Xz || cfg!(feature = "xz"))
+            && (*self != Compression::Zstd || cfg!(feature = "zstd"))
+    }
+
+    /// Detect the format of the compressed data at the start of `data`, by its magic number.
+    /// Returns `None` if unknown.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if data.starts_with(XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else if data.starts_with(&LZ4_LEGACY_MAGIC.to_le_bytes()) {
+            Some(Compression::Lz4)
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    // Kernel config option needed to unpack images in this format
+    fn kernel_config(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("CONFIG_RD_GZIP"),
+            Compression::Xz => Some("CONFIG_RD_XZ"),
+            Compression::Lz4 => Some("CONFIG_RD_LZ4"),
+            Compression::Zstd => Some("CONFIG_RD_ZSTD"),
+        }
+    }
+
+    // Supported and default compression levels
+    fn levels(&self) -> Option<(i32, i32, i32)> {
+        match self {
+            Compression::None | Compression::Lz4 => None,
+            Compression::Gzip => Some((0, 9, 6)),
+            Compression::Xz => Some((0, 9, 6)),
+            // Levels above 19 need windows larger than the kernel allows
+            Compression::Zstd => Some((1, 19, 3)),
+        }
+    }
+}
+impl Default for Compression {
+    /// zstd if built with zstd support, gzip otherwise.
+    fn default() -> Self {
+        if Compression::Zstd.supported() {
+            Compression::Zstd
+        } else {
+            Compression::Gzip
+        }
+    }
+}
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Image compressor: compression format along with its settings.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Compressor {
+    format: Compression,
+    level: i32,
+    threads: u32,
+}
+impl Compressor {
+    /// Compress images in `format`. `level` defaults to a format-specific level, and
+    /// `threads` (only used by xz and zstd) to the number of available CPUs. The number of
+    /// threads doesn't change the output.
+    ///
+    /// lz4 has a single compression level: setting one is an error. So is choosing xz or
+    /// zstd when ignited is built without them.
+    pub fn new(
+        format: Compression,
+        level: Option<i32>,
+        threads: Option<u32>,
+    ) -> Result<Self, PrintableErrno<String>> {
+        if !format.supported() {
+            return Err(printable_error(
+                PROGRAM_NAME,
+                format!(
+                    "ignited was built without {} support (the {} feature), choose another \
+                    [compression] format",
+                    format, format
+                ),
+            ));
+        }
+        let level = match (format.levels(), level) {
+            (Some((min, max, _)), Some(level)) if level < min || level > max => {
+                return Err(printable_error(
+                    PROGRAM_NAME,
+                    format!(
+                        "invalid {} compression level {}: must be between {} and {}",
+                        format, level, min, max
+                    ),
+                ))
+            }
+            (Some(_), Some(level)) => level,
+            (Some((_, _, default)), None) => default,
+            (None, Some(_)) => {
+                return Err(printable_error(
+                    PROGRAM_NAME,
+                    format!("{} compression doesn't have levels", format),
+                ))
+            }
+            (None, None) => 0,
+        };
+        let threads = threads
+            .or_else(|| available_parallelism().ok().map(|n| n.get() as u32))
+            .unwrap_or(1)
+            .max(1);
+        Ok(Self {
+            format,
+            level,
+            threads,
+        })
+    }
+
+    /// Compression format.
+    pub fn format(&self) -> Compression {
+        self.format
+    }
+
+    /// Compression level (always 0 for formats without levels).
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// Make sure kernel `kver` is able to unpack images in this format, according to
+    /// `/boot/config-<kver>` or (if `kver` is running) `/proc/config.gz`.
+    ///
+    /// If neither is available, a warning is printed and the format is assumed to be
+    /// supported.
+    pub fn check_kernel(&self, kver: &str) -> Result<(), PrintableErrno<String>> {
+        if self.format.kernel_config().is_none() {
+            return Ok(());
+        }
+        match read_kernel_config(kver) {
+            Some(config) => self.check_config(kver, &config),
+            None => {
+                eprintln!(
+                    "{}: unable to find the configuration of kernel {}, assuming it supports \
+                    {} compressed images",
+                    PROGRAM_NAME, kver, self.format
+                );
+                Ok(())
+            }
+        }
+    }
+
+    // Make sure kernel `kver`, built with `config`, is able to unpack images in this format
+    fn check_config(&self, kver: &str, config: &str) -> Result<(), PrintableErrno<String>> {
+        let option = match self.format.kernel_config() {
+            Some(option) => option,
+            None => return Ok(()),
+        };
+        let enabled = config
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .any(|(key, value)| key == option && value == "y");
+        if enabled {
+            Ok(())
+        } else {
+            Err(printable_error(
+                PROGRAM_NAME,
+                format!(
+                    "kernel {} can't unpack {} compressed images ({} is not set), choose \
+                    another [compression] format",
+                    kver, self.format, option
+                ),
+            ))
+        }
+    }
+
+    /// Start compressing into `out`.
+    pub fn encoder<W: Write>(&self, out: W) -> io::Result<Encoder<W>> {
+        Ok(match self.format {
+            Compression::None => Encoder::None(out),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                out,
+                flate2::Compression::new(self.level as u32),
+            )),
+            #[cfg(feature = "xz")]
+            Compression::Xz => {
+                // The kernel only supports CRC32 checks. The multithreaded encoder is used
+                // even with a single thread: its output (split into blocks) differs from the
+                // single-threaded encoder's, but not between thread counts.
+                let stream = MtStreamBuilder::new()
+                    .preset(self.level as u32)
+                    .threads(self.threads)
+                    .check(Check::Crc32)
+                    .encoder()?;
+                #[cfg(feature = "xz")]
+                Encoder::Xz(xz2::write::XzEncoder::new_stream(out, stream))
+            }
+            Compression::Lz4 => Encoder::Lz4(Lz4LegacyEncoder::new(out)?),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(out, self.level)?;
+                encoder.include_checksum(true)?;
+                // Same as xz: any number of workers gives the same output, but no workers
+                // at all doesn't
+                encoder.multithread(self.threads)?;
+                #[cfg(feature = "zstd")]
+                Encoder::Zstd(encoder)
+            }
+            #[cfg(not(feature = "xz"))]
+            Compression::Xz => return Err(unsupported(self.format)),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => return Err(unsupported(self.format)),
+        })
+    }
+}
+
+/// Streaming compressor, as returned by [Compressor::encoder].
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "xz")]
+    Xz(xz2::write::XzEncoder<W>),
+    Lz4(Lz4LegacyEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+impl<W: Write> Encoder<W> {
+    /// Finish compressing, returning the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(w) => Ok(w),
+            Encoder::Gzip(w) => w.finish(),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(w) => w.finish(),
+            Encoder::Lz4(w) => w.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => w.finish(),
+        }
+    }
+}
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(w) => w.write(buf),
+            Encoder::Gzip(w) => w.write(buf),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(w) => w.write(buf),
+            Encoder::Lz4(w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(w) => w.flush(),
+            Encoder::Gzip(w) => w.flush(),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(w) => w.flush(),
+            Encoder::Lz4(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+/// Legacy lz4 format writer: a magic number followed by blocks of up to 8 MiB, each
+/// prefixed with its compressed size.
+pub struct Lz4LegacyEncoder<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+impl<W: Write> Lz4LegacyEncoder<W> {
+    fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&LZ4_LEGACY_MAGIC.to_le_bytes())?;
+        Ok(Self {
+            inner,
+            buf: Vec::with_capacity(LZ4_LEGACY_BLOCK_SIZE),
+        })
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let block = lz4_flex::block::compress(&self.buf);
+            self.inner.write_all(&(block.len() as u32).to_le_bytes())?;
+            self.inner.write_all(&block)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        Ok(self.inner)
+    }
+}
+impl<W: Write> Write for Lz4LegacyEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(LZ4_LEGACY_BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        if self.buf.len() == LZ4_LEGACY_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(len)
+    }
+
+    // Blocks can't be cut short: only full blocks are written before finishing
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decompress `data`, compressed in any of the formats images can be compressed in (even
+/// those ignited is built without). It may be made of several compressed archives,
+/// concatenated.
+pub fn decompress_image(data: &[u8]) -> io::Result<Vec<u8>> {
+    match Compression::detect(data) {
+        Some(Compression::Lz4) => decompress_lz4_legacy(data),
+        _ => decompress(data.to_vec()),
+    }
+}
+
+// Decompress legacy lz4 data, which may be made of several concatenated streams
+fn decompress_lz4_legacy(data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut out = Vec::new();
+    // The last block is usually shorter than the others
+    let mut buf = vec![0; LZ4_LEGACY_BLOCK_SIZE];
+    let mut rest = data;
+    while let Some(size) = rest.get(..4) {
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+        rest = &rest[4..];
+        if size == LZ4_LEGACY_MAGIC {
+            continue;
+        }
+        let block = rest
+            .get(..size as usize)
+            .ok_or_else(|| invalid("truncated lz4 block"))?;
+        let len = lz4_flex::block::decompress_into(block, &mut buf)
+            .map_err(|e| invalid(&format!("invalid lz4 block: {}", e)))?;
+        out.extend_from_slice(&buf[..len]);
+        rest = &rest[size as usize..];
+    }
+    Ok(out)
+}
+
+#[cfg(any(not(feature = "xz"), not(feature = "zstd")))]
+fn unsupported(format: Compression) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} compression is not supported", format),
+    )
+}
+
+// Configuration of kernel `kver`, if available
+fn read_kernel_config(kver: &str) -> Option<String> {
+    if let Ok(config) = read_to_string(format!("/boot/config-{}", kver)) {
+        return Some(config);
+    }
+    if kver != get_booted_kernel_ver() {
+        return None;
+    }
+    let mut config = String::new();
+    GzDecoder::new(File::open(PROC_CONFIG_GZ).ok()?)
+        .read_to_string(&mut config)
+        .ok()?;
+    Some(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = include_bytes!("../testdata/decompress/data");
+
+    const FORMATS: [Compression; 5] = [
+        Compression::None,
+        Compression::Gzip,
+        Compression::Xz,
+        Compression::Lz4,
+        Compression::Zstd,
+    ];
+
+    fn compress(compressor: &Compressor, data: &[u8]) -> Vec<u8> {
+        let mut encoder = compressor.encoder(Vec::new()).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn lz4_legacy() {
+        // More than one block, the last one partial
+        let data: Vec<u8> = DATA
+            .iter()
+            .copied()
+            .cycle()
+            .take(LZ4_LEGACY_BLOCK_SIZE + DATA.len())
+            .collect();
+        let mut encoder = Lz4LegacyEncoder::new(Vec::new()).unwrap();
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(Compression::detect(&compressed), Some(Compression::Lz4));
+        assert_eq!(decompress_lz4_legacy(&compressed).unwrap(), data);
+
+        // Concatenated streams
+        let twice = [&compressed[..], &compressed[..]].concat();
+        assert_eq!(
+            decompress_lz4_legacy(&twice).unwrap(),
+            [&data[..], &data[..]].concat()
+        );
+
+        let empty = Lz4LegacyEncoder::new(Vec::new()).unwrap().finish().unwrap();
+        assert_eq!(empty, LZ4_LEGACY_MAGIC.to_le_bytes());
+        assert_eq!(decompress_lz4_legacy(&empty).unwrap(), b"");
+
+        assert!(decompress_lz4_legacy(&compressed[..compressed.len() - 10]).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in FORMATS.into_iter().filter(Compression::supported) {
+            let compressor = Compressor::new(format, None, Some(2)).unwrap();
+            let compressed = compress(&compressor, DATA);
+            if format != Compression::None {
+                assert_eq!(Compression::detect(&compressed), Some(format));
+            }
+            assert_eq!(decompress_image(&compressed).unwrap(), DATA, "{}", format);
+
+            // Independent of the number of threads
+            let single = Compressor::new(format, None, Some(1)).unwrap();
+            assert_eq!(compress(&single, DATA), compressed, "{}", format);
+        }
+    }
+
+    #[test]
+    fn settings() {
+        assert!(Compressor::new(Compression::Gzip, Some(10), None).is_err());
+        assert!(Compressor::new(Compression::Lz4, Some(1), None).is_err());
+        assert_eq!(
+            Compressor::new(Compression::Gzip, None, None)
+                .unwrap()
+                .level(),
+            6
+        );
+        assert_eq!(
+            Compressor::new(Compression::Xz, None, None).is_ok(),
+            cfg!(feature = "xz")
+        );
+        assert_eq!(
+            Compressor::new(Compression::Zstd, None, None).is_ok(),
+            cfg!(feature = "zstd")
+        );
+        assert!(Compression::default().supported());
+    }
+
+    #[test]
+    fn kernel_config() {
+        let config = "\
+            CONFIG_BLK_DEV_INITRD=y\n\
+            CONFIG_RD_GZIP=y\n\
+            # CONFIG_RD_XZ is not set\n\
+            CONFIG_RD_LZ4=m\n\
+            CONFIG_RD_ZSTD=y\n";
+        let check = |format| {
+            let compressor = Compressor {
+                format,
+                level: 0,
+                threads: 1,
+            };
+            compressor.check_config("6.1.0", config).is_ok()
+        };
+        assert!(check(Compression::None));
+        assert!(check(Compression::Gzip));
+        assert!(!check(Compression::Xz));
+        assert!(!check(Compression::Lz4));
+        assert!(check(Compression::Zstd));
+
+        // Only uncompressed images without a config
+        let compressor = Compressor {
+            format: Compression::None,
+            level: 0,
+            threads: 1,
+        };
+        assert!(compressor.check_config("6.1.0", "").is_ok());
+        let compressor = Compressor {
+            format: Compression::Gzip,
+            ..compressor
+        };
+        assert!(compressor.check_config("6.1.0", "").is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Image generation configuration through `/etc/ignited/build.toml`.
+
+use crate::{
+    generator::{cache::CACHE_DEFAULT_DIR, compress::Compression},
+    PROGRAM_NAME,
+};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::read_to_string,
+    io,
+    path::{Path, PathBuf},
+};
+
+// Inner struct for the [ignited] section
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct BuildIgnitedDe {
+    lvm: bool,
+    mdraid: bool,
+    mount_timeout: Option<i64>,
+}
+
+// Inner struct for the [modules] section
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct BuildModulesDe {
+    include: Vec<String>,
+    force: Vec<String>,
+    options: BTreeMap<String, String>,
+}
+
+// Inner struct for the [binaries] section
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct BuildBinariesDe {
+    include: Vec<String>,
+}
+
+// Inner struct for the [console] section
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+struct BuildConsoleDe {
+    #[serde(default)]
+    utf: bool,
+    font_file: Option<String>,
+    font_map_file: Option<String>,
+    font_unicode_file: Option<String>,
+    keymap_file: Option<String>,
+}
+
+// Inner struct for the [compression] section
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", default)]
+struct BuildCompressionDe {
+    format: Compression,
+    level: Option<i32>,
+    threads: Option<u32>,
+}
+
+// Inner struct for the [microcode] section
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", default)]
+struct BuildMicrocodeDe {
+    enable: bool,
+}
+impl Default for BuildMicrocodeDe {
+    fn default() -> Self {
+        Self { enable: true }
+    }
+}
+
+// Inner struct for the [cache] section
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", default)]
+struct BuildCacheDe {
+    enable: bool,
+    dir: PathBuf,
+}
+impl Default for BuildCacheDe {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            dir: PathBuf::from(CACHE_DEFAULT_DIR),
+        }
+    }
+}
+
+/// Image generation TOML configuration file.
+///
+/// `/etc/ignited/build.toml` has seven sections, all of them optional:
+///
+/// ```toml
+/// # Copied as-is to the image's engine.toml (see RuntimeConfig)
+/// [ignited]
+/// lvm = false
+/// mdraid = false
+/// mount-timeout = 120
+///
+/// [modules]
+/// # Kernel modules to bundle, along with their (soft) dependencies
+/// include = ["ext4", "nvme"]
+/// # Kernel modules to bundle and always load at boot
+/// force = ["usbhid"]
+/// # Options to load kernel modules with
+/// options = { i915 = "modeset=1" }
+///
+/// [binaries]
+/// # Binaries to bundle along with their libraries, by path or by name (searched in
+/// # /usr/local/sbin, /usr/local/bin, /usr/sbin, /usr/bin, /sbin and /bin)
+/// include = ["/usr/bin/busybox", "lvm"]
+///
+/// # Copied to the image's engine.toml, along with the files it references
+/// [console]
+/// utf = true
+/// font-file = "/usr/share/kbd/consolefonts/LatArCyrHeb-19.psfu.gz"
+///
+/// [compression]
+/// # One of "gzip" (default), "zstd" (default with the zstd feature), "xz", "lz4" or "none"
+/// format = "gzip"
+/// # Defaults to 3 for zstd (1-19), 6 for xz and gzip (0-9). lz4 has no levels
+/// level = 6
+/// # Threads used by zstd and xz (default: number of CPUs)
+/// threads = 4
+///
+/// [microcode]
+/// # Prepend the CPU microcode updates in /lib/firmware (default: true)
+/// enable = true
+///
+/// [cache]
+/// # Reuse unchanged modules and libraries from previous builds (default: true)
+/// enable = true
+/// dir = "/var/cache/ignited"
+/// ```
+///
+/// See [RuntimeConfig][crate::config::RuntimeConfig] for the meaning of the `[ignited]` and
+/// `[console]` keys.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct BuildConfig {
+    ignited: BuildIgnitedDe,
+    modules: BuildModulesDe,
+    binaries: BuildBinariesDe,
+    console: Option<BuildConsoleDe>,
+    compression: BuildCompressionDe,
+    microcode: BuildMicrocodeDe,
+    cache: BuildCacheDe,
+}
+impl BuildConfig {
+    /// Read the config at `path`. If `optional` is set, a missing file is the same as an
+    /// empty one.
+    pub fn read(path: &Path, optional: bool) -> Result<Self, PrintableErrno<String>> {
+        let read_err = |e: String| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("error while reading {}: {}", path.display(), e),
+            )
+        };
+        match read_to_string(path) {
+            Ok(config) => toml::from_str(&config).map_err(|de| read_err(de.to_string())),
+            Err(io) if optional && io.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(io) => Err(read_err(io.to_string())),
+        }
+    }
+
+    /// `[ignited] lvm`
+    pub fn has_lvm(&self) -> bool {
+        self.ignited.lvm
+    }
+
+    /// `[ignited] mdraid`
+    pub fn has_mdraid(&self) -> bool {
+        self.ignited.mdraid
+    }
+
+    /// `[ignited] mount-timeout`
+    pub fn mount_timeout(&self) -> Option<i64> {
+        self.ignited.mount_timeout
+    }
+
+    /// `[modules] include`: kernel modules to bundle.
+    pub fn modules(&self) -> &[String] {
+        &self.modules.include[..]
+    }
+
+    /// `[modules] force`: kernel modules to bundle and always load at boot.
+    pub fn force_modules(&self) -> &[String] {
+        &self.modules.force[..]
+    }
+
+    /// `[modules.options]`: options to load kernel modules with.
+    pub fn module_options(&self) -> &BTreeMap<String, String> {
+        &self.modules.options
+    }
+
+    /// `[binaries] include`: binaries to bundle.
+    pub fn binaries(&self) -> &[String] {
+        &self.binaries.include[..]
+    }
+
+    /// `[console] utf`, or `None` if there's no `[console]` section.
+    pub fn console_utf8(&self) -> Option<bool> {
+        self.console.as_ref().map(|c| c.utf)
+    }
+
+    /// `[console] font-file`
+    pub fn font_file(&self) -> Option<&str> {
+        self.console.as_ref()?.font_file.as_deref()
+    }
+
+    /// `[console] font-map-file`
+    pub fn font_map_file(&self) -> Option<&str> {
+        self.console.as_ref()?.font_map_file.as_deref()
+    }
+
+    /// `[console] font-unicode-file`
+    pub fn font_unicode_file(&self) -> Option<&str> {
+        self.console.as_ref()?.font_unicode_file.as_deref()
+    }
+
+    /// `[console] keymap-file`
+    pub fn keymap_file(&self) -> Option<&str> {
+        self.console.as_ref()?.keymap_file.as_deref()
+    }
+
+    /// `[compression] format`
+    pub fn compression(&self) -> Compression {
+        self.compression.format
+    }
+
+    /// `[compression] level`, or `None` for the format's default.
+    pub fn compression_level(&self) -> Option<i32> {
+        self.compression.level
+    }
+
+    /// `[compression] threads`, or `None` for the number of CPUs.
+    pub fn compression_threads(&self) -> Option<u32> {
+        self.compression.threads
+    }
+
+    /// `[microcode] enable`: whether to prepend CPU microcode updates.
+    pub fn microcode(&self) -> bool {
+        self.microcode.enable
+    }
+
+    /// `[cache] dir`, or `None` if `[cache] enable` is unset.
+    pub fn cache_dir(&self) -> Option<&Path> {
+        self.cache.enable.then_some(self.cache.dir.as_path())
+    }
+}
@@ -0,0 +1,406 @@
+//! `newc` cpio archive reader and writer.
+//!
+//! The kernel unpacks initramfs images in the "new" (SVR4, without CRC) portable cpio
+//! format: every entry is a 110-byte ASCII header followed by the NUL-terminated entry name
+//! and the entry data, both padded to 4 bytes. The archive ends with a `TRAILER!!!` entry.
+//!
+//! Several archives may follow each other, separated by NUL padding, in which case later
+//! entries replace earlier ones at the same path.
+//!
+//! See the kernel's `Documentation/driver-api/early-userspace/buffer-format.rst`.
+
+use std::io::{self, Read, Write};
+
+const NEWC_MAGIC: &str = "070701";
+const TRAILER: &str = "TRAILER!!!";
+
+const HEADER_LEN: usize = 110;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+
+/// Streaming `newc` cpio archive writer.
+///
+/// Entries are owned by root. Parent directories must be written before their contents.
+#[derive(Debug)]
+pub struct CpioWriter<W: Write> {
+    inner: W,
+    ino: u32,
+    mtime: u32,
+    written: u64,
+}
+impl<W: Write> CpioWriter<W> {
+    /// Write a cpio archive to `inner`, with every entry's modification time set to
+    /// `mtime` (in seconds since the Unix epoch).
+    pub fn new(inner: W, mtime: u32) -> Self {
+        Self {
+            inner,
+            ino: 1,
+            mtime,
+            written: 0,
+        }
+    }
+
+    /// Write a directory with permissions `mode`.
+    pub fn dir(&mut self, name: &str, mode: u32) -> io::Result<()> {
+        self.header(name, S_IFDIR | mode, 2, 0, (0, 0))
+    }
+
+    /// Write a regular file with permissions `mode`, with `size` bytes of contents read from
+    /// `data`.
+    pub fn file(
+        &mut self,
+        name: &str,
+        mode: u32,
+        size: u64,
+        data: &mut impl Read,
+    ) -> io::Result<()> {
+        self.header(name, S_IFREG | mode, 1, size, (0, 0))?;
+        let mut data = data.take(size);
+        let mut buf = vec![0; 64 * 1024];
+        let mut copied = 0;
+        loop {
+            let read = match data.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(io) if io.kind() == io::ErrorKind::Interrupted => continue,
+                Err(io) => return Err(io),
+            };
+            self.put(&buf[..read])?;
+            copied += read as u64;
+        }
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrunk while being archived", name),
+            ));
+        }
+        self.pad()
+    }
+
+    /// Write a symbolic link pointing to `target`.
+    pub fn symlink(&mut self, name: &str, target: &str) -> io::Result<()> {
+        self.header(name, S_IFLNK | 0o777, 1, target.len() as u64, (0, 0))?;
+        self.put(target.as_bytes())?;
+        self.pad()
+    }
+
+    /// Write a character device node with permissions `mode` and device number `rdev`.
+    pub fn char_dev(&mut self, name: &str, mode: u32, rdev: (u32, u32)) -> io::Result<()> {
+        self.header(name, S_IFCHR | mode, 1, 0, rdev)
+    }
+
+    /// Write the trailer, ending the archive. Returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.ino = 0;
+        self.header(TRAILER, 0, 1, 0, (0, 0))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn header(
+        &mut self,
+        name: &str,
+        mode: u32,
+        nlink: u32,
+        size: u64,
+        rdev: (u32, u32),
+    ) -> io::Result<()> {
+        let size = u32::try_from(size).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is too big for a cpio archive", name),
+            )
+        })?;
+        // Names are relative to the root of the archive
+        let name = name.trim_start_matches('/');
+        let ino = self.ino;
+        self.ino += 1;
+
+        let header = format!(
+            "{}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            NEWC_MAGIC,
+            ino,
+            mode,
+            0, // uid
+            0, // gid
+            nlink,
+            self.mtime,
+            size,
+            0, // devmajor
+            0, // devminor
+            rdev.0,
+            rdev.1,
+            name.len() + 1,
+            0, // check
+        );
+        self.put(header.as_bytes())?;
+        self.put(name.as_bytes())?;
+        self.put(&[0])?;
+        self.pad()
+    }
+
+    fn put(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    // Pad the archive to a multiple of 4 bytes
+    fn pad(&mut self) -> io::Result<()> {
+        let padding = (4 - self.written % 4) % 4;
+        self.put(&[0; 3][..padding as usize])
+    }
+}
+
+/// Entry of a cpio archive, as read by [read_archive].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CpioEntry {
+    name: String,
+    mode: u32,
+    rdev: (u32, u32),
+    data: Vec<u8>,
+}
+impl CpioEntry {
+    /// Path of the entry, relative to the root of the archive.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// File type and permissions.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Device number of device nodes.
+    pub fn rdev(&self) -> (u32, u32) {
+        self.rdev
+    }
+
+    /// Contents of regular files, or target of symbolic links.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..]
+    }
+
+    /// Whether the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    /// Whether the entry is a regular file.
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    /// Whether the entry is a symbolic link.
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    /// Whether the entry is a character device node.
+    pub fn is_char_dev(&self) -> bool {
+        self.mode & S_IFMT == S_IFCHR
+    }
+}
+
+/// Whether `data` starts with a `newc` cpio archive.
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(NEWC_MAGIC.as_bytes())
+}
+
+/// Read the `newc` cpio archive at the start of `data`. Returns its entries in order, along
+/// with the number of bytes read, including the NUL padding following the archive.
+pub fn read_archive(data: &[u8]) -> io::Result<(Vec<CpioEntry>, usize)> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let (entry, next) = read_entry(data, offset)?;
+        offset = next;
+        if entry.name == TRAILER {
+            break;
+        }
+        entries.push(entry);
+    }
+    offset += data[offset.min(data.len())..]
+        .iter()
+        .take_while(|b| **b == 0)
+        .count();
+    Ok((entries, offset))
+}
+
+// Read the entry at `offset`. Returns it along with the offset of the next one.
+fn read_entry(data: &[u8], offset: usize) -> io::Result<(CpioEntry, usize)> {
+    let invalid = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} at offset {}", what, offset),
+        )
+    };
+    let header = data
+        .get(offset..offset + HEADER_LEN)
+        .filter(|header| header.starts_with(NEWC_MAGIC.as_bytes()))
+        .ok_or_else(|| invalid("truncated or invalid cpio header"))?;
+    let field = |i: usize| {
+        let start = NEWC_MAGIC.len() + i * 8;
+        std::str::from_utf8(&header[start..start + 8])
+            .ok()
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| invalid("invalid cpio header field"))
+    };
+    let mode = field(1)?;
+    let size = field(6)? as usize;
+    let rdev = (field(9)?, field(10)?);
+    let name_len = field(11)? as usize;
+
+    let name_start = offset + HEADER_LEN;
+    let name = data
+        .get(name_start..name_start + name_len)
+        .and_then(|name| name.strip_suffix(&[0]))
+        .ok_or_else(|| invalid("truncated or invalid cpio entry name"))?;
+    let name = String::from_utf8_lossy(name).into_owned();
+    let data_start = align(name_start + name_len);
+    let contents = data
+        .get(data_start..data_start + size)
+        .ok_or_else(|| invalid("truncated cpio entry"))?;
+    let entry = CpioEntry {
+        name,
+        mode,
+        rdev,
+        data: contents.to_vec(),
+    };
+    Ok((entry, align(data_start + size)))
+}
+
+// Round `offset` up to a multiple of 4 bytes
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> Vec<u8> {
+        let mut cpio = CpioWriter::new(Vec::new(), 1234);
+        cpio.dir("/etc", 0o755).unwrap();
+        cpio.file("/etc/hostname", 0o644, 5, &mut &b"host\n"[..])
+            .unwrap();
+        cpio.symlink("/etc/mtab", "../proc/self/mounts").unwrap();
+        cpio.char_dev("/dev/console", 0o600, (5, 1)).unwrap();
+        cpio.finish().unwrap()
+    }
+
+    #[test]
+    fn header() {
+        let data = archive();
+        let header = std::str::from_utf8(&data[..HEADER_LEN]).unwrap();
+        assert_eq!(
+            header,
+            "070701\
+             00000001\
+             000041ed\
+             00000000\
+             00000000\
+             00000002\
+             000004d2\
+             00000000\
+             00000000\
+             00000000\
+             00000000\
+             00000000\
+             00000004\
+             00000000"
+        );
+        // "etc\0", then padding up to the next header
+        assert_eq!(&data[HEADER_LEN..HEADER_LEN + 6], b"etc\0\0\0");
+        assert!(data[HEADER_LEN + 6..].starts_with(NEWC_MAGIC.as_bytes()));
+    }
+
+    #[test]
+    fn padding() {
+        let data = archive();
+        assert_eq!(data.len() % 4, 0);
+
+        // Every header starts on a 4-byte boundary
+        let mut offset = 0;
+        let mut names = Vec::new();
+        loop {
+            assert_eq!(offset % 4, 0);
+            let (entry, next) = read_entry(&data, offset).unwrap();
+            offset = next;
+            names.push(entry.name);
+            if names.last().unwrap() == TRAILER {
+                break;
+            }
+        }
+        assert_eq!(offset, data.len());
+        assert_eq!(
+            names,
+            ["etc", "etc/hostname", "etc/mtab", "dev/console", TRAILER]
+        );
+
+        // The trailer has inode 0, and is the last thing in the archive
+        let trailer = data.len() - align(HEADER_LEN + TRAILER.len() + 1);
+        assert!(data[trailer..].starts_with(b"07070100000000"));
+        let name = &data[trailer + HEADER_LEN..];
+        assert!(name.starts_with(b"TRAILER!!!\0"));
+        assert!(name[TRAILER.len()..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn read_back() {
+        let data = archive();
+        let (entries, read) = read_archive(&data).unwrap();
+        assert_eq!(read, data.len());
+        assert_eq!(entries.len(), 4);
+
+        assert_eq!(entries[0].name(), "etc");
+        assert!(entries[0].is_dir());
+        assert_eq!(entries[0].mode(), S_IFDIR | 0o755);
+
+        assert_eq!(entries[1].name(), "etc/hostname");
+        assert!(entries[1].is_file());
+        assert_eq!(entries[1].mode(), S_IFREG | 0o644);
+        assert_eq!(entries[1].data(), b"host\n");
+
+        assert_eq!(entries[2].name(), "etc/mtab");
+        assert!(entries[2].is_symlink());
+        assert_eq!(entries[2].mode(), S_IFLNK | 0o777);
+        assert_eq!(entries[2].data(), b"../proc/self/mounts");
+
+        assert_eq!(entries[3].name(), "dev/console");
+        assert!(entries[3].is_char_dev());
+        assert_eq!(entries[3].mode(), S_IFCHR | 0o600);
+        assert_eq!(entries[3].rdev(), (5, 1));
+        assert_eq!(entries[3].data(), b"");
+    }
+
+    #[test]
+    fn concatenated() {
+        let first = archive();
+        let data = [&first[..], &[0; 8], &archive()].concat();
+        assert!(is_archive(&data));
+        let (entries, read) = read_archive(&data).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(read, first.len() + 8);
+        let (entries, read) = read_archive(&data[read..]).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(read, first.len());
+    }
+
+    #[test]
+    fn invalid() {
+        let data = archive();
+        assert!(read_archive(&data[..data.len() - 20]).is_err());
+        assert!(read_archive(&data[..HEADER_LEN + 2]).is_err());
+        assert!(read_archive(b"not an archive").is_err());
+        assert!(!is_archive(b"070707"));
+
+        // Less data than announced
+        let mut cpio = CpioWriter::new(Vec::new(), 0);
+        assert!(cpio.file("/short", 0o644, 10, &mut &b"short"[..]).is_err());
+    }
+}
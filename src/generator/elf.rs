@@ -0,0 +1,718 @@
+//! ELF dependency resolution.
+//!
+//! Helper binaries bundled in the image (e.g. `setfont` or `busybox`) need their dynamic
+//! linker and shared libraries as well. Instead of running `ldd`, their program and dynamic
+//! section are read directly: `PT_INTERP` names the dynamic linker, and every `DT_NEEDED`
+//! library is looked up the way `ld.so(8)` does:
+//!
+//! 1. `DT_RPATH` of the object (only if it has no `DT_RUNPATH`).
+//! 2. `DT_RUNPATH` of the object.
+//! 3. Directories listed in `/etc/ld.so.conf`.
+//! 4. Default directories (`/lib64`, `/usr/lib64`, `/lib` and `/usr/lib`).
+//!
+//! `$ORIGIN` in `DT_RPATH` and `DT_RUNPATH` is replaced by the object's directory.
+//! Libraries of a different class or architecture than the object are skipped.
+
+use crate::PROGRAM_NAME;
+use goglob::GlobPattern;
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    collections::BTreeSet,
+    fs::{read, read_dir, read_to_string, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// Dynamic linker configuration, listing library directories.
+pub const LD_SO_CONF: &str = "/etc/ld.so.conf";
+
+/// Cache of the libraries in the dynamic linker's search path, as generated by
+/// `ldconfig(8)`.
+pub const LD_SO_CACHE: &str = "/etc/ld.so.cache";
+
+const DEFAULT_LIB_DIRS: [&str; 4] = ["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2MSB: u8 = 2;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+// Bounds-checked reader of ELF fields, in the file's class and byte order
+struct ElfReader<'a> {
+    data: &'a [u8],
+    is64: bool,
+    big_endian: bool,
+}
+impl<'a> ElfReader<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        if !data.starts_with(ELF_MAGIC) {
+            return None;
+        }
+        Some(Self {
+            data,
+            is64: *data.get(4)? == ELFCLASS64,
+            big_endian: *data.get(5)? == ELFDATA2MSB,
+        })
+    }
+
+    fn bytes<const N: usize>(&self, offset: u64) -> Option<[u8; N]> {
+        let offset = usize::try_from(offset).ok()?;
+        self.data
+            .get(offset..offset.checked_add(N)?)?
+            .try_into()
+            .ok()
+    }
+
+    fn u16(&self, offset: u64) -> Option<u16> {
+        let bytes = self.bytes(offset)?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: u64) -> Option<u32> {
+        let bytes = self.bytes(offset)?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&self, offset: u64) -> Option<u64> {
+        let bytes = self.bytes(offset)?;
+        Some(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    // Address-sized field
+    fn word(&self, offset: u64) -> Option<u64> {
+        if self.is64 {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+
+    // NUL-terminated string
+    fn str(&self, offset: u64) -> Option<String> {
+        let rest = self.data.get(usize::try_from(offset).ok()?..)?;
+        let len = rest.iter().position(|b| *b == 0)?;
+        String::from_utf8(rest[..len].to_vec()).ok()
+    }
+}
+
+// Program header fields ignited cares about
+struct ProgramHeader {
+    p_type: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+/// Dynamic linking information of an ELF object.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Elf {
+    is64: bool,
+    machine: u16,
+    interp: Option<String>,
+    needed: Vec<String>,
+    rpath: Vec<String>,
+    runpath: Vec<String>,
+}
+impl Elf {
+    /// Parse the ELF object in `data`. Returns `None` if it isn't one (e.g. a script).
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let r = ElfReader::new(data)?;
+        let (phoff, phentsize, phnum) = if r.is64 {
+            (r.u64(0x20)?, r.u16(0x36)?, r.u16(0x38)?)
+        } else {
+            (u64::from(r.u32(0x1c)?), r.u16(0x2a)?, r.u16(0x2c)?)
+        };
+        if usize::try_from(phoff).ok()? > data.len() {
+            return None;
+        }
+        let mut elf = Self {
+            is64: r.is64,
+            machine: r.u16(0x12)?,
+            ..Self::default()
+        };
+
+        let mut phdrs = Vec::with_capacity(phnum.into());
+        for i in 0..u64::from(phnum) {
+            let ph = phoff + i * u64::from(phentsize);
+            phdrs.push(if r.is64 {
+                ProgramHeader {
+                    p_type: r.u32(ph)?,
+                    offset: r.u64(ph + 0x08)?,
+                    vaddr: r.u64(ph + 0x10)?,
+                    filesz: r.u64(ph + 0x20)?,
+                }
+            } else {
+                ProgramHeader {
+                    p_type: r.u32(ph)?,
+                    offset: u64::from(r.u32(ph + 0x04)?),
+                    vaddr: u64::from(r.u32(ph + 0x08)?),
+                    filesz: u64::from(r.u32(ph + 0x10)?),
+                }
+            });
+        }
+
+        if let Some(interp) = phdrs.iter().find(|ph| ph.p_type == PT_INTERP) {
+            elf.interp = r.str(interp.offset);
+        }
+        let dynamic = match phdrs.iter().find(|ph| ph.p_type == PT_DYNAMIC) {
+            Some(dynamic) => dynamic,
+            // Statically linked
+            None => return Some(elf),
+        };
+
+        // (tag, value) entries up to DT_NULL
+        let entry_size = if r.is64 { 16 } else { 8 };
+        let mut entries = Vec::new();
+        for i in 0..dynamic.filesz / entry_size {
+            let entry = dynamic.offset.checked_add(i * entry_size)?;
+            let tag = r.word(entry)?;
+            if tag == DT_NULL {
+                break;
+            }
+            entries.push((tag, r.word(entry + entry_size / 2)?));
+        }
+
+        // DT_STRTAB is an address: find the file offset it's loaded from
+        let strtab_addr = entries.iter().find(|(tag, _)| *tag == DT_STRTAB)?.1;
+        let strtab = phdrs
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .find(|ph| strtab_addr >= ph.vaddr && strtab_addr - ph.vaddr < ph.filesz)
+            .and_then(|ph| (strtab_addr - ph.vaddr).checked_add(ph.offset))?;
+        for (tag, value) in entries {
+            let list = match tag {
+                DT_NEEDED => &mut elf.needed,
+                DT_RPATH => &mut elf.rpath,
+                DT_RUNPATH => &mut elf.runpath,
+                _ => continue,
+            };
+            let value = r.str(strtab.checked_add(value)?)?;
+            match tag {
+                DT_NEEDED => list.push(value),
+                _ => list.extend(value.split(':').map(str::to_string)),
+            }
+        }
+        Some(elf)
+    }
+
+    /// Dynamic linker (`PT_INTERP`), if dynamically linked.
+    pub fn interp(&self) -> Option<&str> {
+        self.interp.as_deref()
+    }
+
+    /// Shared libraries needed (`DT_NEEDED`).
+    pub fn needed(&self) -> &[String] {
+        &self.needed[..]
+    }
+
+    /// Library search path (`DT_RPATH`).
+    pub fn rpath(&self) -> &[String] {
+        &self.rpath[..]
+    }
+
+    /// Library search path (`DT_RUNPATH`).
+    pub fn runpath(&self) -> &[String] {
+        &self.runpath[..]
+    }
+}
+
+/// Contents of the section called `name` (e.g. `.modinfo`) of the ELF object in `data`.
+pub fn section<'a>(data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let r = ElfReader::new(data)?;
+    let (shoff, shentsize, shnum, shstrndx) = if r.is64 {
+        (r.u64(0x28)?, r.u16(0x3a)?, r.u16(0x3c)?, r.u16(0x3e)?)
+    } else {
+        (
+            u64::from(r.u32(0x20)?),
+            r.u16(0x2e)?,
+            r.u16(0x30)?,
+            r.u16(0x32)?,
+        )
+    };
+    if usize::try_from(shoff).ok()? > data.len() {
+        return None;
+    }
+
+    // (name offset, file offset, size) of every section
+    let sections = (0..u64::from(shnum))
+        .map(|i| {
+            let sh = shoff + i * u64::from(shentsize);
+            if r.is64 {
+                Some((r.u32(sh)?, r.u64(sh + 0x18)?, r.u64(sh + 0x20)?))
+            } else {
+                let (offset, size) = (r.u32(sh + 0x10)?, r.u32(sh + 0x14)?);
+                Some((r.u32(sh)?, u64::from(offset), u64::from(size)))
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let (_, shstrtab, _) = *sections.get(usize::from(shstrndx))?;
+    let (_, offset, size) = *sections.iter().find(|(name_offset, _, _)| {
+        shstrtab
+            .checked_add(u64::from(*name_offset))
+            .and_then(|offset| r.str(offset))
+            .is_some_and(|section_name| section_name == name)
+    })?;
+    let offset = usize::try_from(offset).ok()?;
+    data.get(offset..offset.checked_add(usize::try_from(size).ok()?)?)
+}
+
+/// Resolver of ELF objects' dependencies against the host's libraries.
+#[derive(Debug, Clone)]
+pub struct ElfResolver {
+    lib_dirs: Vec<PathBuf>,
+}
+impl ElfResolver {
+    /// Create a resolver searching the directories in `/etc/ld.so.conf`, then the default
+    /// ones.
+    pub fn new() -> Self {
+        let mut lib_dirs = Vec::new();
+        read_ld_so_conf(Path::new(LD_SO_CONF), &mut lib_dirs, 0);
+        lib_dirs.extend(DEFAULT_LIB_DIRS.iter().map(PathBuf::from));
+        Self { lib_dirs }
+    }
+
+    /// Create a resolver searching `lib_dirs`, for tests.
+    #[cfg(test)]
+    pub fn with_lib_dirs(lib_dirs: Vec<PathBuf>) -> Self {
+        Self { lib_dirs }
+    }
+
+    /// Directories libraries are searched in after those of the object itself, in order.
+    pub fn lib_dirs(&self) -> &[PathBuf] {
+        &self.lib_dirs[..]
+    }
+
+    /// Compute the closure of the given ELF objects: the objects themselves, along with
+    /// the dynamic linkers and shared libraries they need, transitively.
+    ///
+    /// Paths are returned as found (i.e. symlinks aren't resolved). Files that aren't ELF
+    /// objects (e.g. scripts) are part of the closure without any dependencies.
+    pub fn closure<P: AsRef<Path>>(
+        &self,
+        objects: &[P],
+    ) -> Result<BTreeSet<PathBuf>, PrintableErrno<String>> {
+        let mut closure = BTreeSet::new();
+        let mut queue: Vec<_> = objects.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        while let Some(path) = queue.pop() {
+            if closure.contains(&path) {
+                continue;
+            }
+            let data = read(&path).map_err(|io| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to read {}: {}", path.display(), io),
+                )
+            })?;
+            if let Some(elf) = Elf::parse(&data) {
+                if let Some(interp) = elf.interp() {
+                    queue.push(PathBuf::from(interp));
+                }
+                for lib in elf.needed() {
+                    queue.push(self.find(&path, &elf, lib)?);
+                }
+            }
+            closure.insert(path);
+        }
+        Ok(closure)
+    }
+
+    // Find the library `lib` needed by `elf` (at `path`).
+    fn find(&self, path: &Path, elf: &Elf, lib: &str) -> Result<PathBuf, PrintableErrno<String>> {
+        if lib.contains('/') {
+            return Ok(PathBuf::from(lib));
+        }
+        let origin = path.parent().unwrap_or_else(|| Path::new("/"));
+        let origin = origin.to_string_lossy();
+        let rpath = if elf.runpath().is_empty() {
+            elf.rpath()
+        } else {
+            &[]
+        };
+        let object_dirs = rpath
+            .iter()
+            .chain(elf.runpath())
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| {
+                PathBuf::from(
+                    dir.replace("${ORIGIN}", &origin)
+                        .replace("$ORIGIN", &origin),
+                )
+            });
+        for dir in object_dirs.chain(self.lib_dirs.iter().cloned()) {
+            let candidate = dir.join(lib);
+            if read_ident(&candidate) == Some((elf.is64, elf.machine)) {
+                return Ok(candidate);
+            }
+        }
+        Err(printable_error(
+            PROGRAM_NAME,
+            format!(
+                "unable to find library {} needed by {}",
+                lib,
+                path.display()
+            ),
+        ))
+    }
+}
+impl Default for ElfResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Class (whether it's 64-bit) and architecture of the ELF object at `path`, which must
+// match for a library to be loadable by an object.
+fn read_ident(path: &Path) -> Option<(bool, u16)> {
+    let mut header = [0; 20];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if !header.starts_with(ELF_MAGIC) {
+        return None;
+    }
+    let machine = [header[0x12], header[0x13]];
+    let machine = if header[5] == ELFDATA2MSB {
+        u16::from_be_bytes(machine)
+    } else {
+        u16::from_le_bytes(machine)
+    };
+    Some((header[4] == ELFCLASS64, machine))
+}
+
+// Add the directories listed in the ld.so.conf(5)-style file at `path` to `dirs`, following
+// `include` directives.
+fn read_ld_so_conf(path: &Path, dirs: &mut Vec<PathBuf>, depth: u32) {
+    // Guard against include loops
+    if depth > 8 {
+        return;
+    }
+    let conf = match read_to_string(path) {
+        Ok(conf) => conf,
+        Err(_) => return,
+    };
+    for line in conf.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() || line.starts_with("hwcap ") {
+            continue;
+        }
+        let include = match line.strip_prefix("include") {
+            Some(include) if include.starts_with(char::is_whitespace) => include.trim(),
+            _ => {
+                dirs.push(PathBuf::from(line));
+                continue;
+            }
+        };
+
+        // Relative patterns are relative to the including file's directory
+        let include = path
+            .parent()
+            .unwrap_or_else(|| Path::new("/"))
+            .join(include);
+        let (dir, pattern) = match (include.parent(), include.file_name()) {
+            (Some(dir), Some(pattern)) => (dir, pattern.to_string_lossy()),
+            _ => continue,
+        };
+        let pattern = match GlobPattern::new(&pattern) {
+            Ok(pattern) => pattern,
+            Err(_) => continue,
+        };
+        let mut files: Vec<_> = read_dir(dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter(|e| pattern.matches(e.file_name().to_string_lossy()))
+                    .map(|e| e.path())
+                    .collect()
+            })
+            .unwrap_or_default();
+        files.sort();
+        for file in files {
+            read_ld_so_conf(&file, dirs, depth + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+const EM_X86_64: u16 = 62;
+#[cfg(test)]
+const EM_AARCH64: u16 = 183;
+
+// Address the fixtures are loaded at, so that DT_STRTAB has to be mapped to an offset
+#[cfg(test)]
+const BASE: u64 = 0x400000;
+
+// Minimal little-endian ELF64 object: a single PT_LOAD covering the whole file, followed
+// by PT_INTERP if `interp` is set and by PT_DYNAMIC unless `dynamic` is empty. `dynamic`
+// lists string entries (DT_NEEDED, DT_RPATH or DT_RUNPATH).
+#[cfg(test)]
+fn elf64(machine: u16, interp: Option<&str>, dynamic: &[(u64, &str)]) -> Vec<u8> {
+    let phnum = 1 + u64::from(interp.is_some()) + u64::from(!dynamic.is_empty());
+    let strtab_offset = 0x40 + phnum * 56;
+    let mut strtab = vec![0];
+    let interp_offset = strtab_offset + strtab.len() as u64;
+    if let Some(interp) = interp {
+        strtab.extend_from_slice(interp.as_bytes());
+        strtab.push(0);
+    }
+    let mut entries = Vec::new();
+    for (tag, value) in dynamic {
+        entries.push((*tag, strtab.len() as u64));
+        strtab.extend_from_slice(value.as_bytes());
+        strtab.push(0);
+    }
+    entries.push((DT_STRTAB, BASE + strtab_offset));
+    entries.push((DT_NULL, 0));
+    strtab.resize((strtab.len() + 7) & !7, 0);
+    let dynamic_offset = strtab_offset + strtab.len() as u64;
+    let len = dynamic_offset + entries.len() as u64 * 16;
+
+    let mut data = Vec::new();
+    data.extend_from_slice(ELF_MAGIC);
+    data.extend_from_slice(&[ELFCLASS64, 1, 1]);
+    data.resize(0x10, 0);
+    data.extend_from_slice(&3u16.to_le_bytes()); // ET_DYN
+    data.extend_from_slice(&machine.to_le_bytes());
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    data.extend_from_slice(&0x40u64.to_le_bytes()); // e_phoff
+    data.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    data.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    data.extend_from_slice(&0x40u16.to_le_bytes()); // e_ehsize
+    data.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+    data.extend_from_slice(&(phnum as u16).to_le_bytes());
+    data.resize(0x40, 0);
+
+    let mut phdr = |p_type: u32, offset: u64, filesz: u64| {
+        data.extend_from_slice(&p_type.to_le_bytes());
+        data.extend_from_slice(&4u32.to_le_bytes()); // p_flags
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&(BASE + offset).to_le_bytes()); // p_vaddr
+        data.extend_from_slice(&(BASE + offset).to_le_bytes()); // p_paddr
+        data.extend_from_slice(&filesz.to_le_bytes());
+        data.extend_from_slice(&filesz.to_le_bytes()); // p_memsz
+        data.extend_from_slice(&8u64.to_le_bytes()); // p_align
+    };
+    phdr(PT_LOAD, 0, len);
+    if let Some(interp) = interp {
+        phdr(PT_INTERP, interp_offset, interp.len() as u64 + 1);
+    }
+    if !dynamic.is_empty() {
+        phdr(PT_DYNAMIC, dynamic_offset, len - dynamic_offset);
+    }
+    data.extend_from_slice(&strtab);
+    for (tag, value) in entries {
+        data.extend_from_slice(&tag.to_le_bytes());
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data
+}
+
+/// Minimal x86-64 ELF object needing the shared libraries `needed`, for tests.
+#[cfg(test)]
+pub fn test_object(needed: &[&str]) -> Vec<u8> {
+    let dynamic: Vec<_> = needed.iter().map(|lib| (DT_NEEDED, *lib)).collect();
+    elf64(EM_X86_64, None, &dynamic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, remove_file, write};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ignited-elf-{}-{}", std::process::id(), name));
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Write `data` to `path`, creating its parent directory
+    fn put(path: &Path, data: &[u8]) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(path, data).unwrap();
+    }
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse() {
+        let data = elf64(
+            EM_X86_64,
+            Some("/lib64/ld-linux-x86-64.so.2"),
+            &[
+                (DT_NEEDED, "libfoo.so.1"),
+                (DT_RPATH, "/opt/a:/opt/b"),
+                (DT_NEEDED, "libc.so.6"),
+                (DT_RUNPATH, "$ORIGIN/../lib"),
+            ],
+        );
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.interp(), Some("/lib64/ld-linux-x86-64.so.2"));
+        assert_eq!(elf.needed(), strings(&["libfoo.so.1", "libc.so.6"]));
+        assert_eq!(elf.rpath(), strings(&["/opt/a", "/opt/b"]));
+        assert_eq!(elf.runpath(), strings(&["$ORIGIN/../lib"]));
+        assert_eq!((elf.is64, elf.machine), (true, EM_X86_64));
+
+        let elf = Elf::parse(&elf64(EM_AARCH64, None, &[])).unwrap();
+        assert_eq!(elf.interp(), None);
+        assert!(elf.needed().is_empty());
+        assert_eq!(elf.machine, EM_AARCH64);
+
+        assert_eq!(Elf::parse(b"#!/bin/sh\n"), None);
+        assert_eq!(Elf::parse(&data[..100]), None);
+    }
+
+    #[test]
+    fn search_path() {
+        let dir = temp_dir("search-path");
+        let bin = dir.join("bin/true");
+        let origin_lib = dir.join("bin/../lib/libfoo.so.1");
+        let rpath_lib = dir.join("rpath/libfoo.so.1");
+        let system_lib = dir.join("system/libfoo.so.1");
+        let lib = elf64(EM_X86_64, None, &[]);
+        put(&origin_lib, &lib);
+        put(&rpath_lib, &lib);
+        put(&system_lib, &lib);
+        let resolver = ElfResolver {
+            lib_dirs: vec![dir.join("missing"), dir.join("system")],
+        };
+        let rpath = dir.join("rpath").to_string_lossy().into_owned();
+        let find = |dynamic: &[(u64, &str)]| {
+            let elf = Elf::parse(&elf64(EM_X86_64, None, dynamic)).unwrap();
+            resolver.find(&bin, &elf, "libfoo.so.1").unwrap()
+        };
+
+        assert_eq!(find(&[]), system_lib);
+        assert_eq!(find(&[(DT_RPATH, &rpath)]), rpath_lib);
+        // DT_RPATH is ignored if there's a DT_RUNPATH
+        assert_eq!(
+            find(&[(DT_RPATH, &rpath), (DT_RUNPATH, "$ORIGIN/../lib")]),
+            origin_lib
+        );
+        assert_eq!(
+            find(&[(DT_RPATH, &rpath), (DT_RUNPATH, "/nonexistent")]),
+            system_lib
+        );
+        assert_eq!(find(&[(DT_RUNPATH, "${ORIGIN}/../lib")]), origin_lib);
+        assert_eq!(find(&[(DT_RUNPATH, ":$ORIGIN/../lib")]), origin_lib);
+
+        // Libraries of another architecture are skipped
+        put(&rpath_lib, &elf64(EM_AARCH64, None, &[]));
+        assert_eq!(find(&[(DT_RPATH, &rpath)]), system_lib);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn closure() {
+        let dir = temp_dir("closure");
+        let interp = dir.join("ld.so");
+        let bin = dir.join("bin/sh");
+        let libfoo = dir.join("lib/libfoo.so.1");
+        let libbar = dir.join("lib/libbar.so.1");
+        let script = dir.join("bin/script");
+        put(&interp, &elf64(EM_X86_64, None, &[]));
+        put(
+            &bin,
+            &elf64(
+                EM_X86_64,
+                interp.to_str(),
+                &[(DT_NEEDED, "libfoo.so.1"), (DT_NEEDED, "libbar.so.1")],
+            ),
+        );
+        put(
+            &libfoo,
+            &elf64(EM_X86_64, None, &[(DT_NEEDED, "libbar.so.1")]),
+        );
+        put(&libbar, &elf64(EM_X86_64, None, &[]));
+        put(&script, b"#!/bin/sh\n");
+        let resolver = ElfResolver {
+            lib_dirs: vec![dir.join("lib")],
+        };
+
+        let closure = resolver.closure(&[&bin, &script]).unwrap();
+        assert_eq!(
+            closure,
+            BTreeSet::from([interp, bin.clone(), libfoo.clone(), libbar.clone(), script])
+        );
+
+        // Missing libraries and objects are errors
+        put(
+            &bin,
+            &elf64(EM_X86_64, None, &[(DT_NEEDED, "libmissing.so")]),
+        );
+        assert!(resolver.closure(&[&bin]).is_err());
+        assert!(resolver.closure(&[dir.join("missing")]).is_err());
+        remove_file(&libbar).unwrap();
+        assert!(resolver.closure(&[&libfoo]).is_err());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ld_so_conf() {
+        let dir = temp_dir("ld-so-conf");
+        let conf = dir.join("ld.so.conf");
+        put(
+            &conf,
+            b"# Comment\n\
+              /usr/local/lib\n\
+              include ld.so.conf.d/*.conf\n\
+              hwcap 0 nosegneg\n\
+              /opt/last # trailing comment\n",
+        );
+        put(
+            &dir.join("ld.so.conf.d/a.conf"),
+            b"/opt/a\ninclude ../nested/*.conf\n",
+        );
+        put(&dir.join("ld.so.conf.d/b.conf"), b"/opt/b\n");
+        put(&dir.join("ld.so.conf.d/c.txt"), b"/opt/ignored\n");
+        put(&dir.join("nested/nested.conf"), b"/opt/nested\n");
+        let mut dirs = Vec::new();
+        read_ld_so_conf(&conf, &mut dirs, 0);
+        assert_eq!(
+            dirs,
+            [
+                "/usr/local/lib",
+                "/opt/a",
+                "/opt/nested",
+                "/opt/b",
+                "/opt/last"
+            ]
+            .map(PathBuf::from)
+        );
+
+        // Include loops stop eventually
+        let looping = dir.join("loop.conf");
+        put(&looping, b"/opt/loop\ninclude loop.conf\n");
+        let mut dirs = Vec::new();
+        read_ld_so_conf(&looping, &mut dirs, 0);
+        assert_eq!(dirs.len(), 9);
+
+        let mut dirs = Vec::new();
+        read_ld_so_conf(&dir.join("missing.conf"), &mut dirs, 0);
+        assert!(dirs.is_empty());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
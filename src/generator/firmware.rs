@@ -0,0 +1,27 @@
+//! Firmware needed by kernel modules.
+//!
+//! Modules list the firmware files they may request through `firmware=` entries in their
+//! module information. Files are looked up the way the kernel's firmware loader does: in
+//! the directories below, in order, either uncompressed or compressed with zstd or xz.
+
+use std::path::{Path, PathBuf};
+
+const FIRMWARE_DIR: &str = "/lib/firmware";
+const FIRMWARE_EXTENSIONS: [&str; 3] = ["", ".zst", ".xz"];
+
+/// Find the firmware file `name` (e.g. `rtl_nic/rtl8168h-2.fw`) requested by kernel `kver`.
+pub fn find(kver: &str, name: &str) -> Option<PathBuf> {
+    let dirs = [
+        format!("{}/updates/{}", FIRMWARE_DIR, kver),
+        format!("{}/updates", FIRMWARE_DIR),
+        format!("{}/{}", FIRMWARE_DIR, kver),
+        FIRMWARE_DIR.to_string(),
+    ];
+    dirs.iter()
+        .flat_map(|dir| {
+            FIRMWARE_EXTENSIONS
+                .iter()
+                .map(move |ext| Path::new(dir).join(format!("{}{}", name, ext)))
+        })
+        .find(|path| path.is_file())
+}
@@ -0,0 +1,304 @@
+//! Host-only images: detect what this machine needs in order to mount its root filesystem.
+//!
+//! The root filesystem is found in `/proc/mounts`, and its block device is followed down
+//! to the disks it lives on through the `slaves/` directories in `/sys/class/block`:
+//!
+//! - Device-mapper devices (with a `dm/` directory) need `dm_mod`, and either `dm_crypt`
+//!   along with the kernel crypto modules of the LUKS volume's cipher, or LVM support.
+//! - md arrays (with an `md/` directory) need `md_mod`, their RAID personality and md RAID
+//!   support.
+//! - Disks need the drivers of every device between them and the storage controller, which
+//!   are found by matching their `modalias`es against `modules.alias`, as ignited does
+//!   at boot.
+//!
+//! The root filesystem needs its own module as well (`fs-<fstype>`). If a LUKS volume is
+//! involved, the drivers of every keyboard in `/sys/class/input` are added too, so that the
+//! passphrase can be typed in.
+
+use crate::{
+    blkid::BlockDevice,
+    generator::kmod::KernelModules,
+    luks::{crypt_aliases, header::LuksHeader},
+    PROGRAM_NAME,
+};
+use goglob::GlobPattern;
+use nix::sys::stat::{major, minor, stat, SFlag};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    collections::BTreeMap,
+    fs::{read_dir, read_to_string, File},
+    path::{Path, PathBuf},
+};
+
+const PROC_MOUNTS: &str = "/proc/mounts";
+const SYSFS_CLASS_BLOCK: &str = "/sys/class/block";
+const SYSFS_CLASS_INPUT: &str = "/sys/class/input";
+const SYSFS_DEV_BLOCK: &str = "/sys/dev/block";
+const SYSFS_DEVICES: &str = "/sys/devices";
+
+// Event types of input devices that are keyboards (EV_KEY | EV_REP)
+const EV_KEYBOARD: u64 = (1 << 0x01) | (1 << 0x14);
+
+/// Kernel modules and features this machine needs to mount its root filesystem.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct HostModules {
+    modules: BTreeMap<String, String>,
+    lvm: bool,
+    mdraid: bool,
+}
+impl HostModules {
+    /// Detect what the running system needs to mount its root filesystem, among the
+    /// modules in `kmods`.
+    pub fn detect(kmods: &KernelModules) -> Result<Self, PrintableErrno<String>> {
+        Detection::new(kmods).run()
+    }
+
+    /// Loadable modules needed, along with the reason why.
+    pub fn modules(&self) -> &BTreeMap<String, String> {
+        &self.modules
+    }
+
+    /// Whether the root filesystem is on an LVM logical volume.
+    pub fn has_lvm(&self) -> bool {
+        self.lvm
+    }
+
+    /// Whether the root filesystem is on an md array.
+    pub fn has_mdraid(&self) -> bool {
+        self.mdraid
+    }
+}
+
+// State of an ongoing detection
+struct Detection<'a> {
+    kmods: &'a KernelModules,
+    aliases: Vec<(GlobPattern, &'a str)>,
+    found: HostModules,
+    luks: bool,
+}
+impl<'a> Detection<'a> {
+    fn new(kmods: &'a KernelModules) -> Self {
+        // Malformed aliases are reported when generating ignited.alias, if they belong to
+        // a bundled module
+        let aliases = kmods
+            .aliases()
+            .iter()
+            .filter_map(|(pattern, module)| Some((GlobPattern::new(pattern).ok()?, &module[..])))
+            .collect();
+        Self {
+            kmods,
+            aliases,
+            found: HostModules::default(),
+            luks: false,
+        }
+    }
+
+    fn run(mut self) -> Result<HostModules, PrintableErrno<String>> {
+        let (source, fstype) = root_mount()?;
+        self.add_alias(
+            &format!("fs-{}", fstype),
+            &format!("root filesystem is {}", fstype),
+        );
+
+        let root = root_device(&source)?;
+        self.add_block(&root);
+        if self.luks {
+            self.add_keyboards();
+        }
+        Ok(self.found)
+    }
+
+    // Add the modules needed by the block device `name` and everything below it.
+    fn add_block(&mut self, name: &str) {
+        let sys_path = Path::new(SYSFS_CLASS_BLOCK).join(name);
+        let slaves = read_dir(sys_path.join("slaves"))
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|e| e.file_name().into_string().ok())
+                    .collect()
+            })
+            .unwrap_or_else(|_| Vec::new());
+
+        if let Some(dm_uuid) = read_attr(&sys_path.join("dm/uuid")) {
+            self.add("dm_mod", &format!("{} is a device-mapper device", name));
+            if dm_uuid.starts_with("CRYPT-") {
+                self.luks = true;
+                self.add("dm_crypt", &format!("{} is a LUKS volume", name));
+                for slave in &slaves {
+                    self.add_luks_cipher(name, slave);
+                }
+            } else if dm_uuid.starts_with("LVM-") {
+                // Linear and striped targets are part of dm_mod
+                self.found.lvm = true;
+            }
+        } else if let Some(level) = read_attr(&sys_path.join("md/level")) {
+            self.found.mdraid = true;
+            self.add("md_mod", &format!("{} is an md array", name));
+            self.add_alias(
+                &format!("md-{}", level),
+                &format!("{} is a {} md array", name, level),
+            );
+        } else if slaves.is_empty() {
+            self.add_device_chain(&sys_path, &format!("{} is on", name));
+        }
+
+        for slave in slaves {
+            self.add_block(&slave);
+        }
+    }
+
+    // Add the crypto modules used by the LUKS volume `name` stored on `slave`.
+    fn add_luks_cipher(&mut self, name: &str, slave: &str) {
+        let header = BlockDevice::probe(slave).and_then(|device| {
+            let f = File::open(device.path()).map_err(|io| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to open {}: {}", device.path().display(), io),
+                )
+            })?;
+            LuksHeader::read(&f)
+        });
+        match header {
+            Ok(header) => {
+                let spec = header.segment().encryption();
+                for alias in crypt_aliases(spec) {
+                    self.add_alias(&alias, &format!("{} is encrypted with {}", name, spec));
+                }
+            }
+            Err(e) => eprintln!(
+                "{}: unable to read the cipher of LUKS volume {}, its crypto modules won't \
+                be included: {}",
+                PROGRAM_NAME, name, e
+            ),
+        }
+    }
+
+    // Add the drivers of every keyboard, needed to type in LUKS passphrases.
+    fn add_keyboards(&mut self) {
+        let entries = match read_dir(SYSFS_CLASS_INPUT) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let ev = read_attr(&entry.path().join("capabilities/ev"))
+                .and_then(|ev| u64::from_str_radix(&ev, 16).ok())
+                .unwrap_or(0);
+            if ev & EV_KEYBOARD != EV_KEYBOARD {
+                continue;
+            }
+            let name = read_attr(&entry.path().join("name"))
+                .unwrap_or_else(|| entry.file_name().to_string_lossy().into_owned());
+            // The input device's own modalias matches every input handler (evdev, joydev,
+            // ...), so start from its parent
+            if let Ok(parent) = entry.path().join("device").canonicalize() {
+                self.add_device_chain(&parent, &format!("keyboard \"{}\" is on", name));
+            }
+        }
+    }
+
+    // Add the drivers of `sys_path` and each of its parent devices, by their modalias.
+    fn add_device_chain(&mut self, sys_path: &Path, reason: &str) {
+        let mut dir = sys_path
+            .canonicalize()
+            .unwrap_or_else(|_| PathBuf::from(sys_path));
+        while dir.starts_with(SYSFS_DEVICES) {
+            if let Some(modalias) = read_attr(&dir.join("modalias")) {
+                let device = dir.file_name().unwrap_or_default().to_string_lossy();
+                self.add_alias(&modalias, &format!("{} {} ({})", reason, device, modalias));
+            }
+            if !dir.pop() {
+                break;
+            }
+        }
+    }
+
+    // Add every loadable module matching `alias`.
+    fn add_alias(&mut self, alias: &str, reason: &str) {
+        let modules: Vec<_> = self
+            .aliases
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(alias))
+            .map(|(_, module)| *module)
+            .collect();
+        for module in modules {
+            self.add(module, reason);
+        }
+    }
+
+    // Add `module` if it's loadable. Only the first reason is kept.
+    fn add(&mut self, module: &str, reason: &str) {
+        if self.kmods.path(module).is_some() && !self.found.modules.contains_key(module) {
+            self.found
+                .modules
+                .insert(module.to_string(), reason.to_string());
+        }
+    }
+}
+
+// Contents of a sysfs attribute, without surrounding whitespace. None if missing or empty.
+fn read_attr(path: &Path) -> Option<String> {
+    read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+// Source and filesystem type of the root mount. The last entry wins, as later mounts hide
+// earlier ones.
+fn root_mount() -> Result<(String, String), PrintableErrno<String>> {
+    let mounts = read_to_string(PROC_MOUNTS).map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to read {}: {}", PROC_MOUNTS, io),
+        )
+    })?;
+    mounts
+        .lines()
+        .rev()
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(source), Some("/"), Some(fstype)) => {
+                    Some((source.to_string(), fstype.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .ok_or_else(|| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to find the root filesystem in {}", PROC_MOUNTS),
+            )
+        })
+}
+
+// Kernel name (e.g. `sda1` or `dm-0`) of the root filesystem's block device. `source` may not
+// exist (e.g. `/dev/root`) or may not be a block device (e.g. btrfs subvolumes report an
+// anonymous device number), so the mounted filesystem is tried as well.
+fn root_device(source: &str) -> Result<String, PrintableErrno<String>> {
+    let source_dev = stat(source)
+        .ok()
+        .filter(|st| SFlag::from_bits_truncate(st.st_mode) & SFlag::S_IFMT == SFlag::S_IFBLK)
+        .map(|st| st.st_rdev);
+    let devs = source_dev
+        .into_iter()
+        .chain(stat("/").ok().map(|st| st.st_dev));
+    for dev in devs {
+        let sys_path = format!("{}/{}:{}", SYSFS_DEV_BLOCK, major(dev), minor(dev));
+        let name = Path::new(&sys_path)
+            .canonicalize()
+            .ok()
+            .and_then(|p| Some(p.file_name()?.to_str()?.to_string()));
+        if let Some(name) = name {
+            return Ok(name);
+        }
+    }
+    Err(printable_error(
+        PROGRAM_NAME,
+        format!(
+            "unable to find the block device of the root filesystem ({})",
+            source
+        ),
+    ))
+}
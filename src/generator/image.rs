@@ -0,0 +1,361 @@
+//! Layout of an initramfs image.
+//!
+//! Entries are collected first and written out in path order afterwards, so that parent
+//! directories always precede their contents regardless of the order they were added in.
+
+use crate::{decompress::decompress, generator::cpio::CpioWriter, PROGRAM_NAME};
+use nix::libc::ELOOP;
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{metadata, read, read_link, symlink_metadata, File},
+    io::{self, Cursor, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+
+const DIR_MODE: u32 = 0o755;
+
+// Same limit as the kernel's
+const MAX_SYMLINKS: u32 = 40;
+
+/// Contents of a regular file in the image.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Data {
+    /// Generated contents.
+    Bytes(Vec<u8>),
+
+    /// Contents of a file on the host, read when writing the image.
+    Host(PathBuf),
+
+    /// Contents of a file on the host that may be compressed, read and
+    /// [decompressed][decompress] when writing the image.
+    HostDecompressed(PathBuf),
+}
+
+/// Entry of the image.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Entry {
+    /// Directory.
+    Dir,
+
+    /// Regular file with the given permissions.
+    File(u32, Data),
+
+    /// Symbolic link to the given target.
+    Symlink(String),
+
+    /// Character device node with the given permissions and device number.
+    CharDev(u32, (u32, u32)),
+}
+
+/// Layout of an initramfs image: every entry by its absolute path in the image.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Image {
+    entries: BTreeMap<String, Entry>,
+}
+impl Image {
+    /// Create an empty image.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a directory at `path`.
+    ///
+    /// Missing parent directories are added along with every entry.
+    #[inline]
+    pub fn dir<S: Into<String>>(&mut self, path: S) -> &mut Self {
+        self.add(path.into(), Entry::Dir)
+    }
+
+    /// Add a regular file at `path` with permissions `mode`.
+    #[inline]
+    pub fn file<S: Into<String>>(&mut self, path: S, mode: u32, data: Data) -> &mut Self {
+        self.add(path.into(), Entry::File(mode, data))
+    }
+
+    /// Add a symbolic link at `path` pointing to `target`.
+    #[inline]
+    pub fn symlink<S1: Into<String>, S2: Into<String>>(
+        &mut self,
+        path: S1,
+        target: S2,
+    ) -> &mut Self {
+        self.add(path.into(), Entry::Symlink(target.into()))
+    }
+
+    /// Add a character device node at `path` with permissions `mode`.
+    #[inline]
+    pub fn char_dev<S: Into<String>>(&mut self, path: S, mode: u32, rdev: (u32, u32)) -> &mut Self {
+        self.add(path.into(), Entry::CharDev(mode, rdev))
+    }
+
+    /// Add the regular file at `path` on the host at the same path, with the same
+    /// permissions.
+    ///
+    /// Every symbolic link leading to it is added as well (e.g. `/lib64` -> `usr/lib` on
+    /// merged-`/usr` systems, or `libfoo.so.1` -> `libfoo.so.1.2.3`), so that it can be
+    /// found in the image through the same paths as on the host.
+    #[inline]
+    pub fn host_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<&mut Self, PrintableErrno<String>> {
+        self._host_file(path.as_ref())
+    }
+    fn _host_file(&mut self, path: &Path) -> Result<&mut Self, PrintableErrno<String>> {
+        let add_err = |io: io::Error| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to add {} to image: {}", path.display(), io),
+            )
+        };
+
+        // Components left to resolve, in reverse order
+        let mut rest = path_components(path);
+        let mut resolved = PathBuf::from("/");
+        let mut links = 0;
+        while let Some(name) = rest.pop() {
+            if name == ".." {
+                resolved.pop();
+                continue;
+            }
+            let current = resolved.join(&name);
+            if !symlink_metadata(&current)
+                .map_err(add_err)?
+                .file_type()
+                .is_symlink()
+            {
+                resolved = current;
+                continue;
+            }
+
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(add_err(io::Error::from_raw_os_error(ELOOP)));
+            }
+            let target = read_link(&current).map_err(add_err)?;
+            self.symlink(current.to_string_lossy(), target.to_string_lossy());
+            if target.is_absolute() {
+                resolved = PathBuf::from("/");
+            }
+            rest.extend(path_components(&target));
+        }
+
+        let meta = metadata(&resolved).map_err(add_err)?;
+        if !meta.is_file() {
+            return Err(add_err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a regular file",
+            )));
+        }
+        let image_path = resolved.to_string_lossy().into_owned();
+        let mode = meta.permissions().mode() & 0o7777;
+        Ok(self.file(image_path, mode, Data::Host(resolved)))
+    }
+
+    /// Iterate over every entry, in path order.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &Entry)> {
+        self.entries.iter().map(|(path, entry)| (&path[..], entry))
+    }
+
+    /// Split the image into an image of every directory, followed by an image per other
+    /// entry. Written one after the other, they unpack to the same tree as the whole image.
+    pub fn split(&self) -> Vec<Image> {
+        let (dirs, others): (BTreeMap<_, _>, BTreeMap<_, _>) = self
+            .entries
+            .clone()
+            .into_iter()
+            .partition(|(_, entry)| *entry == Entry::Dir);
+        let others = others.into_iter().map(|entry| Image {
+            entries: BTreeMap::from([entry]),
+        });
+        std::iter::once(Image { entries: dirs })
+            .filter(|dirs| !dirs.entries.is_empty())
+            .chain(others)
+            .collect()
+    }
+
+    // Add an entry, replacing any previous one at the same path, along with its missing
+    // parent directories.
+    fn add(&mut self, path: String, entry: Entry) -> &mut Self {
+        let mut parent = &path[..];
+        while let Some((p, _)) = parent.rsplit_once('/') {
+            if p.is_empty() {
+                break;
+            }
+            self.entries.entry(p.to_string()).or_insert(Entry::Dir);
+            parent = p;
+        }
+        self.entries.insert(path, entry);
+        self
+    }
+
+    /// Write the image as an uncompressed `newc` cpio archive, with every entry's
+    /// modification time set to `mtime`. Returns `out`.
+    pub fn write<W: Write>(&self, out: W, mtime: u32) -> Result<W, PrintableErrno<String>> {
+        let write_err = |path: &str, io| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to write {} to image: {}", path, io),
+            )
+        };
+        let mut cpio = CpioWriter::new(out, mtime);
+        for (path, entry) in self.entries() {
+            let res = match entry {
+                Entry::Dir => cpio.dir(path, DIR_MODE),
+                Entry::File(mode, Data::Bytes(bytes)) => {
+                    cpio.file(path, *mode, bytes.len() as u64, &mut Cursor::new(bytes))
+                }
+                Entry::File(mode, Data::HostDecompressed(host_path)) => {
+                    let bytes = read(host_path).and_then(decompress).map_err(|io| {
+                        printable_error(
+                            PROGRAM_NAME,
+                            format!("unable to decompress {}: {}", host_path.display(), io),
+                        )
+                    })?;
+                    cpio.file(path, *mode, bytes.len() as u64, &mut Cursor::new(bytes))
+                }
+                Entry::File(mode, Data::Host(host_path)) => {
+                    let mut f = File::open(host_path).map_err(|io| {
+                        printable_error(
+                            PROGRAM_NAME,
+                            format!("unable to open {}: {}", host_path.display(), io),
+                        )
+                    })?;
+                    f.metadata()
+                        .and_then(|meta| cpio.file(path, *mode, meta.len(), &mut f))
+                }
+                Entry::Symlink(target) => cpio.symlink(path, target),
+                Entry::CharDev(mode, rdev) => cpio.char_dev(path, *mode, *rdev),
+            };
+            res.map_err(|io| write_err(path, io))?;
+        }
+        cpio.finish().map_err(|io| write_err("trailer", io))
+    }
+}
+
+// Names and `..` components of `path`, in reverse order
+fn path_components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .rev()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        generator::cpio::{read_archive, CpioEntry},
+        util::TestImage,
+    };
+
+    const DATA: &[u8] = include_bytes!("../testdata/decompress/data");
+
+    #[test]
+    fn write() {
+        let host = TestImage::new(b"odd length");
+        let compressed = TestImage::new(include_bytes!("../testdata/decompress/data.gz"));
+        let mut image = Image::new();
+        image
+            .file(
+                "/usr/lib/hello",
+                0o600,
+                Data::Host(host.path().to_path_buf()),
+            )
+            .file(
+                "/usr/lib/data",
+                0o644,
+                Data::HostDecompressed(compressed.path().to_path_buf()),
+            )
+            .file("/init", 0o755, Data::Bytes(b"#!".to_vec()))
+            .symlink("/lib", "usr/lib")
+            .char_dev("/dev/console", 0o600, (5, 1))
+            .dir("/proc");
+        let data = image.write(Vec::new(), 0).unwrap();
+        assert_eq!(data.len() % 4, 0);
+
+        let (entries, read) = read_archive(&data).unwrap();
+        assert_eq!(read, data.len());
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name(), entry.mode(), entry.rdev(), entry.data()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("dev", 0o040755, (0, 0), &b""[..]),
+                ("dev/console", 0o020600, (5, 1), b""),
+                ("init", 0o100755, (0, 0), b"#!"),
+                ("lib", 0o120777, (0, 0), b"usr/lib"),
+                ("proc", 0o040755, (0, 0), b""),
+                ("usr", 0o040755, (0, 0), b""),
+                ("usr/lib", 0o040755, (0, 0), b""),
+                ("usr/lib/data", 0o100644, (0, 0), DATA),
+                ("usr/lib/hello", 0o100600, (0, 0), b"odd length"),
+            ]
+        );
+    }
+
+    #[test]
+    fn split() {
+        let mut image = Image::new();
+        image
+            .file("/usr/lib/modules/a.ko", 0o644, Data::Bytes(b"a".to_vec()))
+            .file("/usr/lib/modules/b.ko", 0o644, Data::Bytes(b"b".to_vec()))
+            .symlink("/lib", "usr/lib")
+            .dir("/usr/lib/empty");
+        let parts = image.split();
+        let paths: Vec<Vec<_>> = parts
+            .iter()
+            .map(|part| part.entries().map(|(path, _)| path).collect())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                &["/usr", "/usr/lib", "/usr/lib/empty", "/usr/lib/modules"][..],
+                &["/lib"],
+                &["/usr/lib/modules/a.ko"],
+                &["/usr/lib/modules/b.ko"],
+            ]
+        );
+
+        // Unpacked in order, the parts give the same entries
+        let mut written = Vec::new();
+        for part in &parts {
+            written = part.write(written, 0).unwrap();
+        }
+        let mut unpacked = Vec::new();
+        let mut offset = 0;
+        while offset < written.len() {
+            let (entries, read) = read_archive(&written[offset..]).unwrap();
+            unpacked.extend(entries);
+            offset += read;
+        }
+        let (whole, _) = read_archive(&image.write(Vec::new(), 0).unwrap()).unwrap();
+        let names = |entries: &[CpioEntry]| {
+            let mut names: Vec<_> = entries
+                .iter()
+                .map(|entry| entry.name().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&unpacked), names(&whole));
+
+        assert!(Image::new().split().is_empty());
+    }
+
+    #[test]
+    fn write_missing() {
+        let mut image = Image::new();
+        image.file("/missing", 0o644, Data::Host(PathBuf::from("/nonexistent")));
+        assert!(image.write(Vec::new(), 0).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! `ignited inspect` and `ignited extract`: look inside an existing initramfs image.
+//!
+//! An image is made of one or more segments, unpacked by the kernel in order: uncompressed
+//! cpio archives (e.g. early microcode) followed by a [compressed][Compression] one. The
+//! compressed segment is assumed to extend to the end of the image, as every generator
+//! writes it last. It may be made of several compressed archives back to back, as ignited
+//! compresses the modules apart from the rest: they are decompressed as a single stream.
+
+use crate::{
+    config::{InitramfsMetadata, RuntimeConfig},
+    decompress::COMPRESSED_EXTENSIONS,
+    generator::{
+        compress::{decompress_image, Compression},
+        cpio::{is_archive, read_archive, CpioEntry},
+    },
+    kern_modules_dir, IGNITED_CONFIG, IGNITED_MODULE_ALIASES, PROGRAM_NAME,
+};
+use goglob::GlobPattern;
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use precisej_printable_errno::{printable_error, ExitError, PrintableErrno, PrintableResult};
+use std::{
+    collections::BTreeMap,
+    fs::{
+        create_dir_all, read, remove_dir, remove_file, set_permissions, symlink_metadata, write,
+        Permissions,
+    },
+    io,
+    os::unix::fs::{symlink, PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+
+/// Segment of an image: a cpio archive, possibly compressed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Segment {
+    offset: usize,
+    compression: Compression,
+    entries: Vec<CpioEntry>,
+}
+impl Segment {
+    /// Offset of the segment in the image.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Compression format of the segment.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Entries of the segment, in archive order.
+    pub fn entries(&self) -> &[CpioEntry] {
+        &self.entries[..]
+    }
+}
+
+/// Read every segment of the image at `path`.
+pub fn read_image(path: &Path) -> Result<Vec<Segment>, PrintableErrno<String>> {
+    let read_err = |io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to read {}: {}", path.display(), io),
+        )
+    };
+    let data = read(path).map_err(read_err)?;
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    while is_archive(&data[offset..]) {
+        let (entries, read) = read_archive(&data[offset..]).map_err(read_err)?;
+        segments.push(Segment {
+            offset,
+            compression: Compression::None,
+            entries,
+        });
+        offset += read;
+    }
+
+    if offset < data.len() {
+        let compression = Compression::detect(&data[offset..]).ok_or_else(|| {
+            printable_error(
+                PROGRAM_NAME,
+                format!(
+                    "unable to read {}: unknown data at offset {}",
+                    path.display(),
+                    offset
+                ),
+            )
+        })?;
+        let decompressed = decompress_image(&data[offset..]).map_err(read_err)?;
+        let mut entries = Vec::new();
+        let mut read = 0;
+        while is_archive(&decompressed[read..]) {
+            let (archive, len) = read_archive(&decompressed[read..]).map_err(read_err)?;
+            entries.extend(archive);
+            read += len;
+        }
+        segments.push(Segment {
+            offset,
+            compression,
+            entries,
+        });
+    }
+    Ok(segments)
+}
+
+/// Options of `ignited inspect`.
+#[derive(Debug, Clone)]
+pub struct InspectOpts {
+    pub(super) image: PathBuf,
+}
+
+/// List the contents of the image described by `opts`, along with its configuration.
+pub fn inspect(opts: &InspectOpts) -> Result<(), ExitError<String>> {
+    let segments = read_image(&opts.image).bail(3)?;
+    for (i, segment) in segments.iter().enumerate() {
+        let compression = match segment.compression() {
+            Compression::None => "uncompressed".to_string(),
+            compression => format!("{} compressed", compression),
+        };
+        println!(
+            "segment {} at offset {} ({}), {} entries:",
+            i + 1,
+            segment.offset(),
+            compression,
+            segment.entries().len()
+        );
+        for entry in segment.entries() {
+            println!("  {}", list_entry(entry));
+        }
+    }
+
+    // Later segments replace files of earlier ones, as when unpacked by the kernel
+    let files: BTreeMap<_, _> = segments
+        .iter()
+        .flat_map(|segment| segment.entries())
+        .map(|entry| (format!("/{}", entry.name()), entry))
+        .collect();
+    println!();
+    match files.get(IGNITED_CONFIG) {
+        Some(entry) => {
+            // Parsed the same way /init does
+            let config =
+                RuntimeConfig::try_from(&*String::from_utf8_lossy(entry.data())).bail(5)?;
+            println!("{}:\n{}", IGNITED_CONFIG, config.to_toml().bail(5)?);
+            for metadata in config.kernels() {
+                check_modules(&metadata, &files);
+                let aliases = format!(
+                    "{}/{}",
+                    kern_modules_dir(metadata.kernel_ver()),
+                    IGNITED_MODULE_ALIASES
+                );
+                match files.get(&aliases) {
+                    Some(entry) => {
+                        println!("{}: {} aliases", aliases, count_aliases(entry).bail(5)?)
+                    }
+                    None => println!("{} not found", aliases),
+                }
+            }
+        }
+        None => println!("{} not found", IGNITED_CONFIG),
+    }
+    Ok(())
+}
+
+// `ls -l`-like description of `entry`
+fn list_entry(entry: &CpioEntry) -> String {
+    let (kind, size) = if entry.is_dir() {
+        ('d', "0".to_string())
+    } else if entry.is_symlink() {
+        ('l', entry.data().len().to_string())
+    } else if entry.is_char_dev() {
+        ('c', format!("{},{}", entry.rdev().0, entry.rdev().1))
+    } else if entry.is_file() {
+        ('-', entry.data().len().to_string())
+    } else {
+        ('?', entry.data().len().to_string())
+    };
+    let mut perms = String::new();
+    for (i, c) in "rwxrwxrwx".chars().enumerate() {
+        perms.push(if entry.mode() & (0o400 >> i) != 0 {
+            c
+        } else {
+            '-'
+        });
+    }
+    let mut line = format!("{}{} {:>10} {}", kind, perms, size, entry.name());
+    if entry.is_symlink() {
+        line.push_str(" -> ");
+        line.push_str(&String::from_utf8_lossy(entry.data()));
+    }
+    line
+}
+
+// Print every module a kernel's [[metadata]] refers to that isn't in the image nor built-in
+fn check_modules(metadata: &InitramfsMetadata, files: &BTreeMap<String, &CpioEntry>) {
+    let deps = metadata.module_deps();
+    let post_deps = metadata.module_post_deps();
+    let mut referenced: BTreeMap<&str, &str> = BTreeMap::new();
+    for (module, deps) in deps.iter().chain(post_deps) {
+        referenced.entry(module).or_insert(module);
+        for dep in deps {
+            referenced.entry(dep).or_insert(module);
+        }
+    }
+
+    let is_bundled = |module: &str| {
+        let path = format!("{}/{}.ko", kern_modules_dir(metadata.kernel_ver()), module);
+        files.contains_key(&path)
+            || COMPRESSED_EXTENSIONS
+                .iter()
+                .any(|ext| files.contains_key(&format!("{}{}", path, ext)))
+    };
+    let missing: Vec<_> = referenced
+        .into_iter()
+        .filter(|(module, _)| {
+            !is_bundled(module) && !metadata.module_builtin().iter().any(|b| b == module)
+        })
+        .collect();
+    let kver = metadata.kernel_ver();
+    if missing.is_empty() {
+        println!(
+            "kernel {}: every module in module-deps is bundled or built-in",
+            kver
+        );
+    }
+    for (module, dependent) in missing {
+        if module == dependent {
+            println!("kernel {}: missing module: {}", kver, module);
+        } else {
+            println!(
+                "kernel {}: missing module: {} (needed by {})",
+                kver, module, dependent
+            );
+        }
+    }
+}
+
+// Number of aliases in ignited.alias, checked the same way ModAliases parses them at boot
+fn count_aliases(entry: &CpioEntry) -> Result<usize, PrintableErrno<String>> {
+    let aliases = String::from_utf8_lossy(entry.data());
+    for line in aliases.lines() {
+        let (pattern, _) = line.split_once(' ').ok_or_else(|| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("malformed alias line \"{}\": missing whitespace", line),
+            )
+        })?;
+        GlobPattern::new(pattern).map_err(|e| {
+            printable_error(PROGRAM_NAME, format!("malformed alias {}: {}", pattern, e))
+        })?;
+    }
+    Ok(aliases.lines().count())
+}
+
+/// Options of `ignited extract`.
+#[derive(Debug, Clone)]
+pub struct ExtractOpts {
+    pub(super) image: PathBuf,
+    pub(super) dir: PathBuf,
+}
+
+/// Unpack the image described by `opts` into a directory, as the kernel would.
+///
+/// Ownership and modification times aren't restored. Device nodes can only be created when
+/// running as root: otherwise, they're skipped with a warning.
+pub fn extract(opts: &ExtractOpts) -> Result<(), ExitError<String>> {
+    let segments = read_image(&opts.image).bail(3)?;
+    create_dir_all(&opts.dir)
+        .map_err(|io| extract_err(&opts.dir, io))
+        .bail(6)?;
+    for entry in segments.iter().flat_map(|segment| segment.entries()) {
+        extract_entry(&opts.dir, entry).bail(6)?;
+    }
+    Ok(())
+}
+
+fn extract_err(path: &Path, io: io::Error) -> PrintableErrno<String> {
+    printable_error(
+        PROGRAM_NAME,
+        format!("unable to extract {}: {}", path.display(), io),
+    )
+}
+
+// Unpack `entry` under `dir`, refusing to write outside of it
+fn extract_entry(dir: &Path, entry: &CpioEntry) -> Result<(), PrintableErrno<String>> {
+    let mut path = dir.to_path_buf();
+    for component in Path::new(entry.name()).components() {
+        match component {
+            Component::Normal(name) => {
+                // Entries must not be written through symbolic links, which may point
+                // anywhere on the host
+                let is_symlink = path != dir
+                    && symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_symlink());
+                if is_symlink {
+                    return Err(extract_err(
+                        &path.join(name),
+                        io::Error::new(io::ErrorKind::InvalidData, "parent is a symbolic link"),
+                    ));
+                }
+                path.push(name);
+            }
+            Component::CurDir => {}
+            _ => {
+                return Err(extract_err(
+                    &path,
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid entry name {}", entry.name()),
+                    ),
+                ))
+            }
+        }
+    }
+    if path == dir {
+        return Ok(());
+    }
+
+    // Replace whatever is there, as the kernel does, but keep existing directories
+    let replaced = match symlink_metadata(&path) {
+        Ok(meta) if meta.is_dir() && entry.is_dir() => Ok(()),
+        Ok(meta) if meta.is_dir() => remove_dir(&path),
+        Ok(_) => remove_file(&path),
+        Err(_) => Ok(()),
+    };
+    let perms = Permissions::from_mode(entry.mode() & 0o7777);
+    let res = replaced.and_then(|()| {
+        if entry.is_dir() {
+            create_dir_all(&path).and_then(|()| set_permissions(&path, perms))
+        } else if entry.is_symlink() {
+            symlink(&*String::from_utf8_lossy(entry.data()), &path)
+        } else if entry.is_char_dev() {
+            let (major, minor) = entry.rdev();
+            let dev = makedev(major as u64, minor as u64);
+            let mode = Mode::from_bits_truncate(entry.mode() & 0o7777);
+            if let Err(errno) = mknod(&path, SFlag::S_IFCHR, mode, dev) {
+                eprintln!(
+                    "{}: unable to create device node {}, skipping: {}",
+                    PROGRAM_NAME,
+                    path.display(),
+                    errno
+                );
+            }
+            Ok(())
+        } else {
+            write(&path, entry.data()).and_then(|()| set_permissions(&path, perms))
+        }
+    });
+    res.map_err(|io| extract_err(&path, io))
+}
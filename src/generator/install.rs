@@ -0,0 +1,173 @@
+//! `ignited kernel-install` and `ignited rebuild-all`: generate images as kernels are
+//! installed.
+//!
+//! `ignited kernel-install` follows the `kernel-install(8)` plugin interface, so a plugin only
+//! has to run it:
+//!
+//! ```sh
+//! #!/bin/sh
+//! # /etc/kernel/install.d/50-ignited.install
+//! exec ignited kernel-install "$@"
+//! ```
+//!
+//! Package managers without `kernel-install` can run `ignited rebuild-all` instead, after
+//! installing or removing kernels or anything bundled in images. Only images that aren't
+//! up to date are generated again: those targeting another kernel (`[[metadata]] kver`) or
+//! older than the kernel's `modules.dep`, `build.toml` or ignited itself.
+
+use crate::{
+    config::RuntimeConfig,
+    generator::{
+        build::{build, BuildOpts},
+        default_build_opts,
+        inspect::read_image,
+        KERNEL_MODULES_DIR,
+    },
+    IGNITED_CONFIG, PROGRAM_NAME,
+};
+use precisej_printable_errno::{printable_error, ExitError, PrintableResult};
+use std::{
+    collections::BTreeSet,
+    env,
+    fs::{metadata, read_dir},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Placeholder for the kernel version in `ignited rebuild-all`'s output path.
+pub const KVER_PLACEHOLDER: &str = "{kver}";
+
+/// Options of `ignited kernel-install`, as passed by `kernel-install(8)`.
+#[derive(Debug, Clone)]
+pub struct KernelInstallOpts {
+    pub(super) command: String,
+    pub(super) kver: String,
+    pub(super) entry_dir: PathBuf,
+    pub(super) initrds: Vec<PathBuf>,
+}
+
+/// Run the `kernel-install(8)` plugin command described by `opts`.
+///
+/// On `add`, the image is generated as `initrd` in `$KERNEL_INSTALL_STAGING_AREA`, or in the
+/// entry directory with older versions of `kernel-install`. Nothing is generated if
+/// `kernel-install` was given pre-built images or if `$KERNEL_INSTALL_INITRD_GENERATOR`
+/// names another generator. Other commands are ignored: on `remove`, `kernel-install`
+/// deletes the image along with the rest of the entry.
+pub fn kernel_install(opts: &KernelInstallOpts) -> Result<(), ExitError<String>> {
+    if opts.command != "add" || !opts.initrds.is_empty() {
+        return Ok(());
+    }
+    match env::var("KERNEL_INSTALL_INITRD_GENERATOR") {
+        Ok(generator) if !generator.is_empty() && generator != PROGRAM_NAME => return Ok(()),
+        _ => {}
+    }
+    let output = match env::var_os("KERNEL_INSTALL_STAGING_AREA") {
+        Some(staging) => PathBuf::from(staging).join("initrd"),
+        // The entry directory only exists with the bls layout
+        None if opts.entry_dir.is_dir() => opts.entry_dir.join("initrd"),
+        None => return Ok(()),
+    };
+    if env::var("KERNEL_INSTALL_VERBOSE").as_deref() == Ok("1") {
+        println!(
+            "{}: generating {} for kernel {}",
+            PROGRAM_NAME,
+            output.display(),
+            opts.kver
+        );
+    }
+    build(&default_build_opts(vec![opts.kver.clone()], output).bail(5)?)
+}
+
+/// Options of `ignited rebuild-all`.
+#[derive(Debug, Clone)]
+pub struct RebuildOpts {
+    pub(super) output: String,
+    pub(super) host_only: bool,
+    pub(super) force: bool,
+}
+
+/// Generate an image for every kernel with modules in `/lib/modules`, at the output path
+/// in `opts` with [KVER_PLACEHOLDER] replaced by the kernel version. Up-to-date images are
+/// skipped unless forced.
+pub fn rebuild_all(opts: &RebuildOpts) -> Result<(), ExitError<String>> {
+    let entries = read_dir(KERNEL_MODULES_DIR)
+        .map_err(|io| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to read {}: {}", KERNEL_MODULES_DIR, io),
+            )
+        })
+        .bail(4)?;
+    let kvers: BTreeSet<_> = entries
+        .flatten()
+        .filter(|entry| entry.path().join("modules.dep").is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+
+    let mut failed = Vec::new();
+    for kver in kvers {
+        let output = PathBuf::from(opts.output.replace(KVER_PLACEHOLDER, &kver));
+        let mut build_opts = default_build_opts(vec![kver.clone()], output).bail(5)?;
+        build_opts.host_only = opts.host_only;
+        if !opts.force && is_up_to_date(&build_opts) {
+            println!("{}: up to date", build_opts.output.display());
+            continue;
+        }
+        println!(
+            "{}: generating for kernel {}",
+            build_opts.output.display(),
+            kver
+        );
+        if let Err(e) = build(&build_opts) {
+            eprintln!("{}", e);
+            failed.push(kver);
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(printable_error(
+            PROGRAM_NAME,
+            format!("unable to generate images for {}", failed.join(", ")),
+        )
+        .bail(6))
+    }
+}
+
+// Whether the image described by `opts` exists, targets the right kernels and is newer than
+// its inputs
+fn is_up_to_date(opts: &BuildOpts) -> bool {
+    let modified = |path: &Path| metadata(path).and_then(|meta| meta.modified()).ok();
+    let image: SystemTime = match modified(&opts.output) {
+        Some(image) => image,
+        None => return false,
+    };
+    let inputs = opts
+        .kernels
+        .iter()
+        .map(|kernel| kernel.modules_dir.join("modules.dep"))
+        .chain([opts.config.clone(), opts.init.clone()]);
+    if inputs
+        .filter_map(|input| modified(&input))
+        .any(|input| input > image)
+    {
+        return false;
+    }
+    let kvers: Vec<_> = opts.kernels.iter().map(|kernel| &kernel.kver[..]).collect();
+    image_kvers(&opts.output).is_some_and(|image_kvers| image_kvers == kvers)
+}
+
+// Kernel versions the image at `path` targets, according to its engine.toml
+fn image_kvers(path: &Path) -> Option<Vec<String>> {
+    let segments = read_image(path).ok()?;
+    let entry = segments
+        .iter()
+        .flat_map(|segment| segment.entries())
+        .rev()
+        .find(|entry| format!("/{}", entry.name()) == IGNITED_CONFIG)?;
+    let config = RuntimeConfig::try_from(&*String::from_utf8_lossy(entry.data())).ok()?;
+    let kvers = config
+        .kernels()
+        .map(|kernel| kernel.kernel_ver().to_string());
+    Some(kvers.collect())
+}
@@ -0,0 +1,468 @@
+//! Kernel modules installed on the host, in `/lib/modules/<kver>`.
+//!
+//! The following files generated by `depmod(8)` are read:
+//!
+//! - `modules.dep`: path of every loadable module, along with its dependencies.
+//! - `modules.alias`: aliases of loadable modules, matched against device `modalias`es.
+//! - `modules.softdep`: soft dependencies of loadable modules, to be loaded before
+//!   (`pre:`) or after (`post:`) them.
+//! - `modules.builtin`: path of every module built into the kernel.
+//! - `modules.builtin.modinfo`: module information of built-in modules, including their
+//!   soft dependencies.
+//!
+//! Only `modules.dep` is required: the rest are treated as empty if missing.
+
+use crate::{decompress::decompress, generator::elf, module::ModParams, PROGRAM_NAME};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{read, read_to_string},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Get the (normalized) name of the module at `path` (e.g. `kernel/fs/ext4/ext4.ko.zst` is
+/// `ext4`).
+pub fn module_name(path: &str) -> String {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let name = file_name
+        .split_once(".ko")
+        .map_or(file_name, |(name, _)| name);
+    ModParams::normalize_module(name)
+}
+
+/// Soft dependencies of a module.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SoftDeps {
+    pre: Vec<String>,
+    post: Vec<String>,
+}
+impl SoftDeps {
+    /// Parse soft dependencies formatted as `pre: <MODULE>... post: <MODULE>...`, adding
+    /// them to `self`.
+    pub fn parse(&mut self, softdeps: &str) {
+        let mut post = false;
+        for word in softdeps.split_whitespace() {
+            match word {
+                "pre:" => post = false,
+                "post:" => post = true,
+                module if post => self.post.push(ModParams::normalize_module(module)),
+                module => self.pre.push(ModParams::normalize_module(module)),
+            }
+        }
+    }
+
+    /// Modules to be loaded before this one.
+    pub fn pre(&self) -> &[String] {
+        &self.pre[..]
+    }
+
+    /// Modules to be loaded after this one.
+    pub fn post(&self) -> &[String] {
+        &self.post[..]
+    }
+}
+
+/// Transitive closure of a set of modules: every module needed to load them.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ModuleClosure {
+    modules: BTreeMap<String, String>,
+    deps: BTreeMap<String, Vec<String>>,
+    post_deps: BTreeMap<String, Vec<String>>,
+}
+impl ModuleClosure {
+    /// Loadable modules to bundle, along with their path relative to
+    /// [the modules directory][KernelModules::dir].
+    pub fn modules(&self) -> &BTreeMap<String, String> {
+        &self.modules
+    }
+
+    /// Dependencies and `pre:` soft dependencies of each module (`module-deps`).
+    pub fn deps(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.deps
+    }
+
+    /// `post:` soft dependencies of each module (`module-post-deps`).
+    pub fn post_deps(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.post_deps
+    }
+}
+
+/// Kernel modules available for a kernel version.
+#[derive(Debug, Clone)]
+pub struct KernelModules {
+    dir: PathBuf,
+    paths: BTreeMap<String, String>,
+    deps: BTreeMap<String, Vec<String>>,
+    aliases: Vec<(String, String)>,
+    softdeps: BTreeMap<String, SoftDeps>,
+    builtin: BTreeSet<String>,
+}
+impl KernelModules {
+    /// Read the modules installed in `dir` (usually `/lib/modules/<kver>`).
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<Self, PrintableErrno<String>> {
+        let dir = dir.into();
+        let mut kmods = Self {
+            paths: BTreeMap::new(),
+            deps: BTreeMap::new(),
+            aliases: Vec::new(),
+            softdeps: BTreeMap::new(),
+            builtin: BTreeSet::new(),
+            dir,
+        };
+
+        // <path>: <dependency path> <dependency path> ...
+        let dep = read_to_string(kmods.dir.join("modules.dep"))
+            .map_err(|io| kmods.read_err("modules.dep", io))?;
+        for line in dep.lines() {
+            if let Some((path, deps)) = line.split_once(':') {
+                let name = module_name(path);
+                kmods.deps.insert(
+                    name.clone(),
+                    deps.split_whitespace().map(module_name).collect(),
+                );
+                kmods.paths.insert(name, path.to_string());
+            }
+        }
+
+        // alias <pattern> <module>
+        if let Some(alias) = kmods.read_optional("modules.alias")? {
+            for line in String::from_utf8_lossy(&alias).lines() {
+                let mut words = line.split_whitespace();
+                if let (Some("alias"), Some(pattern), Some(module)) =
+                    (words.next(), words.next(), words.next())
+                {
+                    kmods
+                        .aliases
+                        .push((pattern.to_string(), ModParams::normalize_module(module)));
+                }
+            }
+        }
+
+        // softdep <module> pre: <module>... post: <module>...
+        if let Some(softdep) = kmods.read_optional("modules.softdep")? {
+            for line in String::from_utf8_lossy(&softdep).lines() {
+                let mut words = line.splitn(3, char::is_whitespace);
+                if let (Some("softdep"), Some(module), Some(softdeps)) =
+                    (words.next(), words.next(), words.next())
+                {
+                    kmods
+                        .softdeps
+                        .entry(ModParams::normalize_module(module))
+                        .or_default()
+                        .parse(softdeps);
+                }
+            }
+        }
+
+        // <path>
+        if let Some(builtin) = kmods.read_optional("modules.builtin")? {
+            for line in String::from_utf8_lossy(&builtin).lines() {
+                if !line.is_empty() {
+                    kmods.builtin.insert(module_name(line));
+                }
+            }
+        }
+
+        // <module>.<key>=<value>, NUL-separated
+        if let Some(modinfo) = kmods.read_optional("modules.builtin.modinfo")? {
+            for entry in modinfo.split(|b| *b == 0) {
+                let entry = String::from_utf8_lossy(entry);
+                let (key, value) = match entry.split_once('=') {
+                    Some(kv) => kv,
+                    None => continue,
+                };
+                if let Some((module, "softdep")) = key.split_once('.') {
+                    kmods
+                        .softdeps
+                        .entry(ModParams::normalize_module(module))
+                        .or_default()
+                        .parse(value);
+                }
+            }
+        }
+        Ok(kmods)
+    }
+
+    fn read_optional(&self, file: &str) -> Result<Option<Vec<u8>>, PrintableErrno<String>> {
+        match read(self.dir.join(file)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(io) if io.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(io) => Err(self.read_err(file, io)),
+        }
+    }
+
+    fn read_err(&self, file: &str, io: io::Error) -> PrintableErrno<String> {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to read {}: {}", self.dir.join(file).display(), io),
+        )
+    }
+
+    /// Directory the modules are installed in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the given loadable module, relative to [the modules directory][Self::dir].
+    pub fn path(&self, module: &str) -> Option<&str> {
+        self.paths
+            .get(&ModParams::normalize_module(module))
+            .map(|p| &p[..])
+    }
+
+    /// Aliases of loadable modules as `(pattern, module)`, in the order they appear in
+    /// `modules.alias`.
+    pub fn aliases(&self) -> &[(String, String)] {
+        &self.aliases[..]
+    }
+
+    /// Values of `key` in the module information (`.modinfo` section) of the loadable
+    /// module `module` (e.g. every `firmware=` entry).
+    pub fn modinfo(&self, module: &str, key: &str) -> Result<Vec<String>, PrintableErrno<String>> {
+        let path = self.path(module).ok_or_else(|| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("module {} not found in {}", module, self.dir.display()),
+            )
+        })?;
+        let path = self.dir.join(path);
+        let data = read(&path).and_then(decompress).map_err(|io| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to read {}: {}", path.display(), io),
+            )
+        })?;
+        let modinfo = elf::section(&data, ".modinfo").ok_or_else(|| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("{} is not a kernel module", path.display()),
+            )
+        })?;
+
+        // <key>=<value>, NUL-separated
+        Ok(modinfo
+            .split(|b| *b == 0)
+            .filter_map(|entry| {
+                let entry = String::from_utf8_lossy(entry);
+                let (k, value) = entry.split_once('=')?;
+                (k == key).then(|| value.to_string())
+            })
+            .collect())
+    }
+
+    /// Modules built into the kernel.
+    pub fn builtin(&self) -> &BTreeSet<String> {
+        &self.builtin
+    }
+
+    /// Whether the given module is built into the kernel.
+    pub fn is_builtin(&self, module: &str) -> bool {
+        self.builtin.contains(&ModParams::normalize_module(module))
+    }
+
+    /// Compute the closure of the given modules: every loadable module needed to load them,
+    /// through dependencies and soft dependencies.
+    ///
+    /// Requested modules must exist, either as loadable or built-in modules. Built-in
+    /// modules are left out of the closure, as are soft dependencies that don't exist
+    /// (as `modprobe(8)` does).
+    pub fn closure<'a>(
+        &self,
+        modules: impl IntoIterator<Item = &'a str>,
+    ) -> Result<ModuleClosure, PrintableErrno<String>> {
+        let mut closure = ModuleClosure::default();
+        let mut visited = BTreeSet::new();
+        let mut queue: Vec<_> = modules
+            .into_iter()
+            .map(ModParams::normalize_module)
+            .collect();
+        while let Some(module) = queue.pop() {
+            if !visited.insert(module.clone()) {
+                continue;
+            }
+            // Built-in modules aren't bundled, but their soft dependencies might be
+            let path = self.paths.get(&module).filter(|_| !self.is_builtin(&module));
+            if path.is_none() && !self.is_builtin(&module) {
+                return Err(printable_error(
+                    PROGRAM_NAME,
+                    format!("module {} not found in {}", module, self.dir.display()),
+                ));
+            }
+
+            let exists = |m: &String| self.paths.contains_key(m) || self.is_builtin(m);
+            let softdeps = self.softdeps.get(&module).cloned().unwrap_or_default();
+            let mut deps = self.deps.get(&module).cloned().unwrap_or_default();
+            deps.extend(softdeps.pre().iter().filter(|m| exists(m)).cloned());
+            let post_deps: Vec<_> = softdeps
+                .post()
+                .iter()
+                .filter(|m| exists(m))
+                .cloned()
+                .collect();
+
+            queue.extend(deps.iter().chain(&post_deps).cloned());
+            if let Some(path) = path {
+                closure.modules.insert(module.clone(), path.clone());
+            }
+            if !deps.is_empty() {
+                closure.deps.insert(module.clone(), deps);
+            }
+            if !post_deps.is_empty() {
+                closure.post_deps.insert(module, post_deps);
+            }
+        }
+        Ok(closure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kmods() -> KernelModules {
+        KernelModules::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/generator/testdata/modules"
+        ))
+        .unwrap()
+    }
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(module_name("kernel/fs/ext4/ext4.ko.zst"), "ext4");
+        assert_eq!(module_name("kernel/drivers/md/dm-crypt.ko"), "dm_crypt");
+        assert_eq!(module_name("usb-storage.ko.xz"), "usb_storage");
+        assert_eq!(module_name("af_alg"), "af_alg");
+    }
+
+    #[test]
+    fn softdeps() {
+        let mut softdeps = SoftDeps::default();
+        softdeps.parse("crc32c-intel post: af_alg pre: dm-mod");
+        softdeps.parse("post: nvme");
+        assert_eq!(softdeps.pre(), ["crc32c_intel", "dm_mod"]);
+        assert_eq!(softdeps.post(), ["af_alg", "nvme"]);
+    }
+
+    #[test]
+    fn open() {
+        let kmods = kmods();
+        assert_eq!(
+            kmods.path("dm-crypt"),
+            Some("kernel/drivers/md/dm-crypt.ko.xz")
+        );
+        assert_eq!(
+            kmods.path("usb_storage"),
+            Some("kernel/drivers/usb/storage/usb-storage.ko.gz")
+        );
+        assert_eq!(kmods.path("xhci_hcd"), None);
+        assert_eq!(
+            kmods.aliases(),
+            [
+                ("fs-ext4".to_string(), "ext4".to_string()),
+                ("pci:v*d*sv*sd*bc01sc08i02*".to_string(), "nvme".to_string()),
+                (
+                    "usb:v*p*d*dc*dsc*dp*ic08isc06ip62in*".to_string(),
+                    "uas".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            kmods.builtin().iter().collect::<Vec<_>>(),
+            ["crc32c_generic", "libcrc32c", "xhci_hcd"]
+        );
+        assert!(kmods.is_builtin("xhci-hcd"));
+        assert!(!kmods.is_builtin("ext4"));
+    }
+
+    #[test]
+    fn missing_files() {
+        let dir = std::env::temp_dir().join(format!("ignited-kmod-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(KernelModules::open(&dir).is_err());
+
+        std::fs::write(
+            dir.join("modules.dep"),
+            "kernel/fs/vfat.ko: kernel/fs/fat.ko\n",
+        )
+        .unwrap();
+        let kmods = KernelModules::open(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(kmods.aliases().is_empty());
+        assert!(kmods.builtin().is_empty());
+        assert_eq!(kmods.path("vfat"), Some("kernel/fs/vfat.ko"));
+    }
+
+    #[test]
+    fn dependencies() {
+        let closure = kmods().closure(["ext4"]).unwrap();
+        assert_eq!(
+            closure.modules().keys().collect::<Vec<_>>(),
+            ["ext4", "jbd2", "mbcache"]
+        );
+        assert_eq!(closure.modules()["jbd2"], "kernel/fs/jbd2/jbd2.ko.zst");
+        // The crc32c soft dependency is an alias, not a module
+        assert_eq!(
+            closure.deps(),
+            &BTreeMap::from([("ext4".to_string(), strings(&["jbd2", "mbcache"]))])
+        );
+        assert!(closure.post_deps().is_empty());
+    }
+
+    #[test]
+    fn soft_dependencies() {
+        let closure = kmods().closure(["dm-crypt", "nvme"]).unwrap();
+        assert_eq!(
+            closure.modules().keys().collect::<Vec<_>>(),
+            ["af_alg", "dm_crypt", "dm_mod", "nvme", "nvme_core"]
+        );
+        assert_eq!(
+            closure.deps(),
+            &BTreeMap::from([
+                ("dm_crypt".to_string(), strings(&["dm_mod"])),
+                ("nvme".to_string(), strings(&["nvme_core"])),
+            ])
+        );
+        assert_eq!(
+            closure.post_deps(),
+            &BTreeMap::from([("dm_crypt".to_string(), strings(&["af_alg"]))])
+        );
+    }
+
+    #[test]
+    fn builtin() {
+        // Built-in modules aren't bundled...
+        let closure = kmods().closure(["xhci_hcd", "libcrc32c"]).unwrap();
+        assert_eq!(closure, ModuleClosure::default());
+
+        // ...nor are built-in dependencies...
+        let closure = kmods().closure(["uas"]).unwrap();
+        assert_eq!(
+            closure.modules().keys().collect::<Vec<_>>(),
+            ["uas", "usb_storage"]
+        );
+        assert_eq!(closure.deps()["uas"], strings(&["usb_storage", "xhci_hcd"]));
+
+        // ...but soft dependencies of built-in modules are
+        let closure = kmods().closure(["crc32c_generic"]).unwrap();
+        assert_eq!(
+            closure.modules().keys().collect::<Vec<_>>(),
+            ["crc32c_intel"]
+        );
+        assert_eq!(
+            closure.deps(),
+            &BTreeMap::from([("crc32c_generic".to_string(), strings(&["crc32c_intel"]))])
+        );
+    }
+
+    #[test]
+    fn not_found() {
+        assert!(kmods().closure(["ext4", "btrfs"]).is_err());
+        assert_eq!(kmods().closure([]).unwrap(), ModuleClosure::default());
+    }
+}
@@ -0,0 +1,174 @@
+//! Early CPU microcode updates.
+//!
+//! The kernel loads microcode updates from an uncompressed cpio archive at the very start of
+//! the image, before unpacking the rest of it: `kernel/x86/microcode/GenuineIntel.bin` for
+//! Intel CPUs and `kernel/x86/microcode/AuthenticAMD.bin` for AMD ones. Each is the
+//! concatenation of the vendor's update files, found in `/lib/firmware/intel-ucode` (one per
+//! `<family>-<model>-<stepping>`, in hexadecimal) and `/lib/firmware/amd-ucode` (one per
+//! family). Update files compressed with xz or zstd are decompressed first.
+//!
+//! See the kernel's `Documentation/arch/x86/microcode.rst`.
+
+use crate::{
+    decompress::decompress,
+    generator::image::{Data, Image},
+    PROGRAM_NAME,
+};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    collections::BTreeSet,
+    fs::{read, read_dir, read_to_string},
+    io,
+    path::Path,
+};
+
+const EARLY_MICROCODE_DIR: &str = "/kernel/x86/microcode";
+const PROC_CPUINFO: &str = "/proc/cpuinfo";
+
+/// CPU vendor that publishes microcode updates.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Vendor {
+    /// Intel (`intel-ucode`).
+    Intel,
+
+    /// AMD (`amd-ucode`).
+    Amd,
+}
+impl Vendor {
+    const ALL: [Vendor; 2] = [Vendor::Intel, Vendor::Amd];
+
+    /// Vendor identification string, as reported by the CPU.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Vendor::Intel => "GenuineIntel",
+            Vendor::Amd => "AuthenticAMD",
+        }
+    }
+
+    // Directory the vendor's update files are installed in
+    fn dir(&self) -> &'static str {
+        match self {
+            Vendor::Intel => "/lib/firmware/intel-ucode",
+            Vendor::Amd => "/lib/firmware/amd-ucode",
+        }
+    }
+
+    // Whether `name` is one of the vendor's update files (without any compression
+    // extension), needed by `cpu` if set
+    fn is_update(&self, name: &str, cpu: Option<&HostCpu>) -> bool {
+        match (self, cpu) {
+            (Vendor::Intel, None) => {
+                let fields: Vec<_> = name.split('-').collect();
+                fields.len() == 3
+                    && fields
+                        .iter()
+                        .all(|f| f.len() == 2 && u8::from_str_radix(f, 16).is_ok())
+            }
+            (Vendor::Intel, Some(cpu)) => {
+                name == format!("{:02x}-{:02x}-{:02x}", cpu.family, cpu.model, cpu.stepping)
+            }
+            (Vendor::Amd, None) => name.starts_with("microcode_amd") && name.ends_with(".bin"),
+            // Families before 15h share a single file
+            (Vendor::Amd, Some(cpu)) if cpu.family < 0x15 => name == "microcode_amd.bin",
+            (Vendor::Amd, Some(cpu)) => name == format!("microcode_amd_fam{:02x}h.bin", cpu.family),
+        }
+    }
+}
+
+/// Identification of this machine's CPU.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct HostCpu {
+    vendor: Option<Vendor>,
+    family: u32,
+    model: u32,
+    stepping: u32,
+}
+impl HostCpu {
+    /// Identify this machine's CPU through `/proc/cpuinfo`.
+    pub fn detect() -> Result<Self, PrintableErrno<String>> {
+        let cpuinfo = read_to_string(PROC_CPUINFO).map_err(|io| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to read {}: {}", PROC_CPUINFO, io),
+            )
+        })?;
+        let mut cpu = Self {
+            vendor: None,
+            family: 0,
+            model: 0,
+            stepping: 0,
+        };
+        // Every CPU is assumed to be the same: only the first one is read
+        for line in cpuinfo.lines().take_while(|line| !line.trim().is_empty()) {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+            match key {
+                "vendor_id" => cpu.vendor = Vendor::ALL.into_iter().find(|v| v.id() == value),
+                "cpu family" => cpu.family = value.parse().unwrap_or_default(),
+                "model" => cpu.model = value.parse().unwrap_or_default(),
+                "stepping" => cpu.stepping = value.parse().unwrap_or_default(),
+                _ => {}
+            }
+        }
+        Ok(cpu)
+    }
+}
+
+/// Collect the early microcode archive: every update available on the host, or only those
+/// needed by `cpu` if set. Returns `None` if there are none.
+pub fn early_image(cpu: Option<&HostCpu>) -> Result<Option<Image>, PrintableErrno<String>> {
+    let vendors = match cpu {
+        Some(cpu) => cpu.vendor.into_iter().collect(),
+        None => Vendor::ALL.to_vec(),
+    };
+    let mut image = Image::new();
+    let mut empty = true;
+    for vendor in vendors {
+        let updates = read_updates(vendor, cpu).map_err(|io| {
+            printable_error(
+                PROGRAM_NAME,
+                format!("unable to read {}: {}", vendor.dir(), io),
+            )
+        })?;
+        if !updates.is_empty() {
+            image.file(
+                format!("{}/{}.bin", EARLY_MICROCODE_DIR, vendor.id()),
+                0o644,
+                Data::Bytes(updates),
+            );
+            empty = false;
+        }
+    }
+    Ok((!empty).then_some(image))
+}
+
+// Concatenate the update files of `vendor` needed by `cpu`, in name order
+fn read_updates(vendor: Vendor, cpu: Option<&HostCpu>) -> io::Result<Vec<u8>> {
+    let entries = match read_dir(vendor.dir()) {
+        Ok(entries) => entries,
+        Err(io) if io.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(io) => return Err(io),
+    };
+    let mut names = BTreeSet::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.extend(entry.file_name().into_string());
+        }
+    }
+
+    let mut updates = Vec::new();
+    for name in names {
+        let update = name
+            .strip_suffix(".xz")
+            .or_else(|| name.strip_suffix(".zst"))
+            .unwrap_or(&name);
+        if vendor.is_update(update, cpu) {
+            let data = read(Path::new(vendor.dir()).join(&name)).and_then(decompress)?;
+            updates.extend_from_slice(&data);
+        }
+    }
+    Ok(updates)
+}
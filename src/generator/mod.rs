@@ -0,0 +1,698 @@
+//! Initramfs image generator.
+//!
+//! The same binary that runs as the initramfs' `/init` generates the images: when not
+//! run as `/init` (or `init`), ignited acts as a command-line tool instead.
+//!
+//! ```no_check
+//! ignited build [OPTIONS] <OUTPUT>
+//! ```
+//!
+//! writes a [compressed][compress] `newc` cpio archive to `<OUTPUT>` containing ignited itself as `/init`
+//! (along with its dynamic linker and libraries, unless statically linked), `/etc/initrd-release`, the [`engine.toml`][crate::config::RuntimeConfig] generated from
+//! [`/etc/ignited/build.toml`][config::BuildConfig] and the kernel modules to bundle along
+//! with their dependencies and aliases, resolved through
+//! [`depmod(8)`'s files][kmod::KernelModules], and the binaries to bundle along with their
+//! [shared libraries][elf::ElfResolver]. The [firmware] requested by the bundled modules
+//! is included as well, and the CPU [microcode] updates installed on the host are prepended
+//! in an uncompressed archive of their own. See [USAGE] for the available options.
+//!
+//! With `--host-only`, the modules to bundle are [detected][host::HostModules] from what the
+//! running system needs to mount its root filesystem instead of taken from
+//! `[modules] include`, and so are the `lvm` and `mdraid` keys of `[ignited]`. Only the
+//! microcode updates for this machine's CPU are included.
+//!
+//! Passing `--kver` several times generates a single image able to boot any of those
+//! kernels, each with its own module set: `/init` uses the one matching the running kernel.
+//!
+//! Images are [reproducible][build::build]: building one twice from the same inputs gives
+//! the same bytes. Every entry is modified at `SOURCE_DATE_EPOCH`, or the Unix epoch if unset.
+//!
+//! Decompressed modules, shared library lookups and compressed module archives are
+//! [cached][cache] between builds, so that regenerating an image after a configuration
+//! change only redoes what changed. How long each phase took is printed once done.
+//!
+//! ```no_check
+//! ignited inspect <IMAGE>
+//! ignited extract <IMAGE> <DIR>
+//! ```
+//!
+//! [list the contents][inspect::inspect] of an existing image, along with its `engine.toml`
+//! and any module it lacks, or [unpack it][inspect::extract] into `<DIR>`.
+//!
+//! ```no_check
+//! ignited uki [OPTIONS] --kernel <PATH> --initrd <PATH> <OUTPUT>
+//! ```
+//!
+//! [assembles][uki] a Unified Kernel Image from an EFI stub, a kernel and an image.
+//!
+//! ```no_check
+//! ignited kernel-install <COMMAND> <KVER> <ENTRY-DIR> [<KERNEL-IMAGE> [<INITRD>...]]
+//! ignited rebuild-all [OPTIONS]
+//! ```
+//!
+//! [generate images][install] as a `kernel-install(8)` plugin, or for every installed kernel
+//! whose image is out of date.
+
+pub mod build;
+pub mod cache;
+pub mod compress;
+pub mod config;
+pub mod cpio;
+pub mod elf;
+pub mod firmware;
+pub mod host;
+pub mod image;
+pub mod inspect;
+pub mod install;
+pub mod kmod;
+pub mod microcode;
+pub mod pe;
+pub mod uki;
+
+use crate::{util::get_booted_kernel_ver, PROGRAM_NAME};
+use build::{BuildOpts, TargetKernel};
+use inspect::{ExtractOpts, InspectOpts};
+use install::{KernelInstallOpts, RebuildOpts, KVER_PLACEHOLDER};
+use precisej_printable_errno::{printable_error, ExitError, PrintableErrno};
+use std::{ffi::OsString, path::PathBuf};
+use uki::UkiOpts;
+
+/// Path where the image generation config is located by default.
+const BUILD_CONFIG_DEFAULT_PATH: &str = "/etc/ignited/build.toml";
+
+/// Directory containing the modules of every installed kernel, one subdirectory per version.
+const KERNEL_MODULES_DIR: &str = "/lib/modules";
+
+/// Path `ignited rebuild-all` writes images to by default.
+const REBUILD_OUTPUT_DEFAULT: &str = "/boot/initramfs-{kver}.img";
+
+/// Command-line usage of the generator.
+pub const USAGE: &str = "\
+usage: ignited build [OPTIONS] <OUTPUT>
+       ignited inspect <IMAGE>
+       ignited extract <IMAGE> <DIR>
+       ignited uki [OPTIONS] --kernel <PATH> --initrd <PATH> <OUTPUT>
+       ignited kernel-install <COMMAND> <KVER> <ENTRY-DIR> [<KERNEL-IMAGE> [<INITRD>...]]
+       ignited rebuild-all [OPTIONS]
+
+Generate an initramfs image at OUTPUT, list the contents of IMAGE, unpack IMAGE into DIR,
+assemble a Unified Kernel Image at OUTPUT, generate the image of a kernel being installed
+by kernel-install(8), or generate the out-of-date images of every installed kernel.
+
+build options:
+  -c, --config <PATH>      image configuration (default: /etc/ignited/build.toml)
+  -k, --kver <KVER>        kernel version to build the image for (default: running kernel),
+                           may be repeated to boot any of several kernels
+  -m, --modules-dir <DIR>  kernel modules directory (default: /lib/modules/<KVER>), given
+                           once per --kver if repeated
+      --init <PATH>        ignited binary to use as /init (default: this binary)
+      --host-only          only bundle what this machine needs to mount its root
+      --verify-reproducible
+                           generate the image twice and make sure both are identical
+  -h, --help               show this help and exit
+
+uki options:
+      --stub <PATH>        EFI stub (default: /usr/lib/systemd/boot/efi/linux<ARCH>.efi.stub)
+      --kernel <PATH>      kernel, built with CONFIG_EFI_STUB
+      --initrd <PATH>      initramfs image
+      --cmdline <CMDLINE>  kernel command line (default: /etc/kernel/cmdline or the running
+                           kernel's)
+      --os-release <PATH>  os-release (default: /etc/os-release or /usr/lib/os-release)
+      --splash <PATH>      BMP image shown while booting
+  -h, --help               show this help and exit
+
+rebuild-all options:
+  -o, --output <PATH>      where to write images, {kver} being replaced by the kernel version
+                           (default: /boot/initramfs-{kver}.img)
+      --host-only          only bundle what this machine needs to mount its root
+  -f, --force              generate images even if they're up to date
+  -h, --help               show this help and exit";
+
+/// Run the generator with the given command-line arguments (including the program name).
+pub fn run(args: impl IntoIterator<Item = OsString>) -> Result<(), ExitError<String>> {
+    let mut args = args.into_iter().skip(1).map(|arg| arg.into_string());
+    match args.next() {
+        Some(Ok(command)) if command == "build" => {
+            let opts = parse_build(args).map_err(|e| e.bail(2))?;
+            match opts {
+                Some(opts) => build::build(&opts),
+                None => {
+                    println!("{}", USAGE);
+                    Ok(())
+                }
+            }
+        }
+        Some(Ok(command)) if command == "inspect" => {
+            match parse_paths(args, &["image"]).map_err(|e| e.bail(2))? {
+                Some(paths) => inspect::inspect(&InspectOpts {
+                    image: paths[0].clone(),
+                }),
+                None => {
+                    println!("{}", USAGE);
+                    Ok(())
+                }
+            }
+        }
+        Some(Ok(command)) if command == "extract" => {
+            match parse_paths(args, &["image", "directory"]).map_err(|e| e.bail(2))? {
+                Some(paths) => inspect::extract(&ExtractOpts {
+                    image: paths[0].clone(),
+                    dir: paths[1].clone(),
+                }),
+                None => {
+                    println!("{}", USAGE);
+                    Ok(())
+                }
+            }
+        }
+        Some(Ok(command)) if command == "uki" => match parse_uki(args).map_err(|e| e.bail(2))? {
+            Some(opts) => uki::uki(&opts),
+            None => {
+                println!("{}", USAGE);
+                Ok(())
+            }
+        },
+        Some(Ok(command)) if command == "kernel-install" => {
+            match parse_kernel_install(args).map_err(|e| e.bail(2))? {
+                Some(opts) => install::kernel_install(&opts),
+                None => {
+                    println!("{}", USAGE);
+                    Ok(())
+                }
+            }
+        }
+        Some(Ok(command)) if command == "rebuild-all" => {
+            match parse_rebuild(args).map_err(|e| e.bail(2))? {
+                Some(opts) => install::rebuild_all(&opts),
+                None => {
+                    println!("{}", USAGE);
+                    Ok(())
+                }
+            }
+        }
+        Some(Ok(help)) if help == "-h" || help == "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(Ok(command)) => Err(usage_error(format!("unknown command {}", command)).bail(2)),
+        Some(Err(_)) => Err(usage_error("invalid command".to_string()).bail(2)),
+        None => Err(usage_error("missing command".to_string()).bail(2)),
+    }
+}
+
+fn usage_error(message: String) -> PrintableErrno<String> {
+    printable_error(PROGRAM_NAME, format!("{}\n\n{}", message, USAGE))
+}
+
+// Parse the arguments of a command taking one path per name in `names`. Returns None if help
+// was requested.
+fn parse_paths(
+    args: impl Iterator<Item = Result<String, OsString>>,
+    names: &[&str],
+) -> Result<Option<Vec<PathBuf>>, PrintableErrno<String>> {
+    let mut paths = Vec::new();
+    for arg in args {
+        let arg =
+            arg.map_err(|arg| usage_error(format!("invalid argument {}", arg.to_string_lossy())))?;
+        match &arg[..] {
+            "-h" | "--help" => return Ok(None),
+            opt if opt.starts_with('-') && opt.len() > 1 => {
+                return Err(usage_error(format!("unknown option {}", opt)))
+            }
+            _ if paths.len() < names.len() => paths.push(PathBuf::from(arg)),
+            _ => return Err(usage_error(format!("unexpected argument {}", arg))),
+        }
+    }
+    match names.get(paths.len()) {
+        Some(name) => Err(usage_error(format!("missing {} path", name))),
+        None => Ok(Some(paths)),
+    }
+}
+
+// Command-line options, given as `-k <VALUE>`, `--key <VALUE>` or `--key=<VALUE>`
+struct Options<I> {
+    args: I,
+}
+impl<I: Iterator<Item = Result<String, OsString>>> Options<I> {
+    fn new(args: I) -> Self {
+        Self { args }
+    }
+
+    // Next argument, along with its value if given inline (`--key=<VALUE>`). Returns None
+    // once there are no arguments left.
+    fn next_arg(&mut self) -> Result<Option<OptionArg>, PrintableErrno<String>> {
+        let arg = match self.args.next() {
+            Some(arg) => arg.map_err(|arg| {
+                usage_error(format!("invalid argument {}", arg.to_string_lossy()))
+            })?,
+            None => return Ok(None),
+        };
+        let (key, inline_value) = match arg.split_once('=') {
+            Some((key, value)) if key.starts_with("--") => {
+                (key.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        Ok(Some(OptionArg {
+            arg,
+            key,
+            inline_value,
+        }))
+    }
+
+    // Value of option `opt`: its inline value, or the argument following it
+    fn value(&mut self, opt: &mut OptionArg) -> Result<String, PrintableErrno<String>> {
+        match opt.inline_value.take() {
+            Some(value) => Ok(value),
+            None => match self.args.next() {
+                Some(Ok(value)) => Ok(value),
+                _ => Err(usage_error(format!("missing value for {}", opt.key))),
+            },
+        }
+    }
+
+    // Set `slot` to the value of option `opt`, which may only be given once
+    fn set_value<T: From<String>>(
+        &mut self,
+        opt: &mut OptionArg,
+        slot: &mut Option<T>,
+    ) -> Result<(), PrintableErrno<String>> {
+        let value = self.value(opt)?;
+        match slot.replace(T::from(value)) {
+            Some(_) => Err(usage_error(format!("{} given twice", opt.key))),
+            None => Ok(()),
+        }
+    }
+}
+
+// Argument as returned by [Options::next_arg]: either an option (`key`) or a positional
+// argument (`arg`)
+struct OptionArg {
+    arg: String,
+    key: String,
+    inline_value: Option<String>,
+}
+impl OptionArg {
+    // Make sure this option, a flag, wasn't given a value (`--key=<VALUE>`)
+    fn flag(&self) -> Result<bool, PrintableErrno<String>> {
+        match self.inline_value {
+            Some(_) => Err(usage_error(format!("{} doesn't take a value", self.key))),
+            None => Ok(true),
+        }
+    }
+}
+
+// Parse the options of `ignited build`. Returns None if help was requested.
+fn parse_build(
+    args: impl Iterator<Item = Result<String, OsString>>,
+) -> Result<Option<BuildOpts>, PrintableErrno<String>> {
+    let mut config = None;
+    let mut kvers: Vec<String> = Vec::new();
+    let mut modules_dirs = Vec::new();
+    let mut init = None;
+    let mut host_only = false;
+    let mut verify_reproducible = false;
+    let mut output = None;
+    let mut args = Options::new(args);
+    while let Some(mut opt) = args.next_arg()? {
+        match &opt.key[..] {
+            "-h" | "--help" => return Ok(None),
+            "-c" | "--config" => args.set_value(&mut opt, &mut config)?,
+            "-k" | "--kver" => kvers.push(args.value(&mut opt)?),
+            "-m" | "--modules-dir" => modules_dirs.push(PathBuf::from(args.value(&mut opt)?)),
+            "--init" => args.set_value(&mut opt, &mut init)?,
+            "--host-only" => host_only = opt.flag()?,
+            "--verify-reproducible" => verify_reproducible = opt.flag()?,
+            key if key.starts_with('-') && key.len() > 1 => {
+                return Err(usage_error(format!("unknown option {}", key)))
+            }
+            _ if output.is_none() => output = Some(PathBuf::from(opt.arg)),
+            _ => return Err(usage_error(format!("unexpected argument {}", opt.arg))),
+        }
+    }
+
+    let output = output.ok_or_else(|| usage_error("missing output path".to_string()))?;
+    if kvers.is_empty() {
+        kvers.push(get_booted_kernel_ver());
+    }
+    if let Some(kver) = kvers
+        .iter()
+        .enumerate()
+        .find_map(|(i, kver)| kvers[..i].contains(kver).then_some(kver))
+    {
+        return Err(usage_error(format!("kernel {} given twice", kver)));
+    }
+    // Modules directories are paired with kernels in order
+    if !modules_dirs.is_empty() && modules_dirs.len() != kvers.len() {
+        return Err(usage_error(
+            "--modules-dir must be given once per kernel".to_string(),
+        ));
+    }
+    let mut opts = default_build_opts(kvers, output)?;
+    if let Some(config) = config {
+        opts.config = config;
+        opts.config_optional = false;
+    }
+    for (kernel, modules_dir) in opts.kernels.iter_mut().zip(modules_dirs) {
+        kernel.modules_dir = modules_dir;
+    }
+    if let Some(init) = init {
+        opts.init = init;
+    }
+    opts.host_only = host_only;
+    opts.verify_reproducible = verify_reproducible;
+    Ok(Some(opts))
+}
+
+// Options of `ignited build` generating the image of kernels `kvers` at `output`, with every
+// other option left to its default
+fn default_build_opts(
+    kvers: Vec<String>,
+    output: PathBuf,
+) -> Result<BuildOpts, PrintableErrno<String>> {
+    let init = std::env::current_exe().map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to locate the ignited binary: {}", io),
+        )
+    })?;
+    Ok(BuildOpts {
+        config_optional: true,
+        config: PathBuf::from(BUILD_CONFIG_DEFAULT_PATH),
+        kernels: kvers
+            .into_iter()
+            .map(|kver| TargetKernel {
+                modules_dir: PathBuf::from(format!("{}/{}", KERNEL_MODULES_DIR, kver)),
+                kver,
+            })
+            .collect(),
+        init,
+        host_only: false,
+        verify_reproducible: false,
+        output,
+    })
+}
+
+// Parse the arguments of `ignited kernel-install`, as passed by kernel-install(8). Returns
+// None if help was requested.
+fn parse_kernel_install(
+    args: impl Iterator<Item = Result<String, OsString>>,
+) -> Result<Option<KernelInstallOpts>, PrintableErrno<String>> {
+    let mut positional = Vec::new();
+    for arg in args {
+        let arg =
+            arg.map_err(|arg| usage_error(format!("invalid argument {}", arg.to_string_lossy())))?;
+        match &arg[..] {
+            "-h" | "--help" => return Ok(None),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let command = positional
+        .next()
+        .ok_or_else(|| usage_error("missing kernel-install command".to_string()))?;
+    let kver = positional
+        .next()
+        .ok_or_else(|| usage_error("missing kernel version".to_string()))?;
+    let entry_dir = positional
+        .next()
+        .ok_or_else(|| usage_error("missing entry directory".to_string()))?;
+    // The kernel image itself isn't needed: only the initrds passed after it are
+    Ok(Some(KernelInstallOpts {
+        command,
+        kver,
+        entry_dir: PathBuf::from(entry_dir),
+        initrds: positional.skip(1).map(PathBuf::from).collect(),
+    }))
+}
+
+// Parse the options of `ignited rebuild-all`. Returns None if help was requested.
+fn parse_rebuild(
+    args: impl Iterator<Item = Result<String, OsString>>,
+) -> Result<Option<RebuildOpts>, PrintableErrno<String>> {
+    let mut output = None;
+    let mut host_only = false;
+    let mut force = false;
+    let mut args = Options::new(args);
+    while let Some(mut opt) = args.next_arg()? {
+        match &opt.key[..] {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => args.set_value(&mut opt, &mut output)?,
+            "--host-only" => host_only = opt.flag()?,
+            "-f" | "--force" => force = opt.flag()?,
+            key if key.starts_with('-') && key.len() > 1 => {
+                return Err(usage_error(format!("unknown option {}", key)))
+            }
+            _ => return Err(usage_error(format!("unexpected argument {}", opt.arg))),
+        }
+    }
+
+    let output = output.unwrap_or_else(|| REBUILD_OUTPUT_DEFAULT.to_string());
+    // Every kernel would overwrite the same image otherwise
+    if !output.contains(KVER_PLACEHOLDER) {
+        return Err(usage_error(format!(
+            "output path {} doesn't contain {}",
+            output, KVER_PLACEHOLDER
+        )));
+    }
+    Ok(Some(RebuildOpts {
+        output,
+        host_only,
+        force,
+    }))
+}
+
+// Parse the options of `ignited uki`. Returns None if help was requested.
+fn parse_uki(
+    args: impl Iterator<Item = Result<String, OsString>>,
+) -> Result<Option<UkiOpts>, PrintableErrno<String>> {
+    let mut stub = None;
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut cmdline = None;
+    let mut os_release = None;
+    let mut splash = None;
+    let mut output = None;
+    let mut args = Options::new(args);
+    while let Some(mut opt) = args.next_arg()? {
+        match &opt.key[..] {
+            "-h" | "--help" => return Ok(None),
+            "--stub" => args.set_value(&mut opt, &mut stub)?,
+            "--kernel" => args.set_value(&mut opt, &mut kernel)?,
+            "--initrd" => args.set_value(&mut opt, &mut initrd)?,
+            "--cmdline" => args.set_value(&mut opt, &mut cmdline)?,
+            "--os-release" => args.set_value(&mut opt, &mut os_release)?,
+            "--splash" => args.set_value(&mut opt, &mut splash)?,
+            key if key.starts_with('-') && key.len() > 1 => {
+                return Err(usage_error(format!("unknown option {}", key)))
+            }
+            _ if output.is_none() => output = Some(PathBuf::from(opt.arg)),
+            _ => return Err(usage_error(format!("unexpected argument {}", opt.arg))),
+        }
+    }
+
+    Ok(Some(UkiOpts {
+        stub: stub
+            .or_else(uki::default_stub)
+            .ok_or_else(|| usage_error("missing --stub".to_string()))?,
+        kernel: kernel.ok_or_else(|| usage_error("missing --kernel".to_string()))?,
+        initrd: initrd.ok_or_else(|| usage_error("missing --initrd".to_string()))?,
+        cmdline,
+        os_release,
+        splash,
+        output: output.ok_or_else(|| usage_error("missing output path".to_string()))?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+
+    fn args<'a>(args: &'a [&str]) -> impl Iterator<Item = Result<String, OsString>> + 'a {
+        args.iter().map(|arg| Ok(arg.to_string()))
+    }
+
+    #[test]
+    fn build_options() {
+        let opts = parse_build(args(&[
+            "-c",
+            "/tmp/build.toml",
+            "--kver=6.1.0",
+            "-k",
+            "6.2.0",
+            "--modules-dir",
+            "/tmp/modules-6.1.0",
+            "--modules-dir=/tmp/modules-6.2.0",
+            "--init",
+            "/tmp/ignited",
+            "--host-only",
+            "--verify-reproducible",
+            "/tmp/initramfs.img",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(opts.config, PathBuf::from("/tmp/build.toml"));
+        assert!(!opts.config_optional);
+        let kernels: Vec<_> = opts
+            .kernels
+            .iter()
+            .map(|kernel| (&kernel.kver[..], kernel.modules_dir.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            kernels,
+            [
+                ("6.1.0", "/tmp/modules-6.1.0"),
+                ("6.2.0", "/tmp/modules-6.2.0")
+            ]
+        );
+        assert_eq!(opts.init, PathBuf::from("/tmp/ignited"));
+        assert!(opts.host_only);
+        assert!(opts.verify_reproducible);
+        assert_eq!(opts.output, PathBuf::from("/tmp/initramfs.img"));
+
+        let opts = parse_build(args(&["/tmp/initramfs.img", "-k", "6.1.0"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(opts.config, PathBuf::from(BUILD_CONFIG_DEFAULT_PATH));
+        assert!(opts.config_optional);
+        assert_eq!(opts.kernels.len(), 1);
+        assert_eq!(
+            opts.kernels[0].modules_dir,
+            PathBuf::from("/lib/modules/6.1.0")
+        );
+        assert_eq!(opts.init, std::env::current_exe().unwrap());
+        assert!(!opts.host_only);
+        assert!(!opts.verify_reproducible);
+
+        assert!(parse_build(args(&["-k", "6.1.0", "--help"]))
+            .unwrap()
+            .is_none());
+        assert!(parse_build(args(&["--frobnicate", "-h"])).is_err());
+    }
+
+    #[test]
+    fn build_errors() {
+        for invalid in [
+            &[][..],
+            &["-k", "6.1.0"],
+            &["out.img", "--kver"],
+            &["out.img", "--config"],
+            &["out.img", "-k", "6.1.0", "--kver=6.1.0"],
+            &["out.img", "-c", "a.toml", "--config=b.toml"],
+            &["out.img", "--init=a", "--init=b"],
+            &[
+                "out.img",
+                "-k",
+                "6.1.0",
+                "-k",
+                "6.2.0",
+                "-m",
+                "/tmp/modules",
+            ],
+            &["out.img", "-k", "6.1.0", "--host-only=yes"],
+            &["out.img", "--frobnicate"],
+            &["out.img", "-x"],
+            &["out.img", "other.img"],
+        ] {
+            assert!(parse_build(args(invalid)).is_err(), "{:?}", invalid);
+        }
+        let non_utf8 = OsString::from_vec(vec![0xff]);
+        assert!(parse_build([Ok("out.img".to_string()), Err(non_utf8)].into_iter()).is_err());
+    }
+
+    #[test]
+    fn uki_options() {
+        let opts = parse_uki(args(&[
+            "--stub",
+            "/tmp/linuxx64.efi.stub",
+            "--kernel=/tmp/vmlinuz",
+            "--initrd",
+            "/tmp/initramfs.img",
+            "--cmdline=root=/dev/sda1 quiet",
+            "--os-release",
+            "/tmp/os-release",
+            "--splash=/tmp/splash.bmp",
+            "/tmp/uki.efi",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(opts.stub, PathBuf::from("/tmp/linuxx64.efi.stub"));
+        assert_eq!(opts.kernel, PathBuf::from("/tmp/vmlinuz"));
+        assert_eq!(opts.initrd, PathBuf::from("/tmp/initramfs.img"));
+        assert_eq!(opts.cmdline.as_deref(), Some("root=/dev/sda1 quiet"));
+        assert_eq!(opts.os_release, Some(PathBuf::from("/tmp/os-release")));
+        assert_eq!(opts.splash, Some(PathBuf::from("/tmp/splash.bmp")));
+        assert_eq!(opts.output, PathBuf::from("/tmp/uki.efi"));
+
+        let opts = parse_uki(args(&["--kernel", "k", "--initrd", "i", "o"]));
+        match uki::default_stub() {
+            Some(stub) => {
+                let opts = opts.unwrap().unwrap();
+                assert_eq!(opts.stub, stub);
+                assert_eq!(opts.cmdline, None);
+                assert_eq!(opts.os_release, None);
+                assert_eq!(opts.splash, None);
+            }
+            None => assert!(opts.is_err()),
+        }
+
+        assert!(parse_uki(args(&["--kernel", "k", "-h"])).unwrap().is_none());
+    }
+
+    #[test]
+    fn uki_errors() {
+        for invalid in [
+            &["--initrd", "i", "o"][..],
+            &["--kernel", "k", "o"],
+            &["--kernel", "k", "--initrd", "i"],
+            &["--kernel", "k", "--initrd", "i", "o", "--cmdline"],
+            &["--kernel", "k", "--initrd", "i", "o", "--kernel=k2"],
+            &[
+                "--kernel", "k", "--initrd", "i", "o", "--stub", "a", "--stub", "b",
+            ],
+            &["--kernel", "k", "--initrd", "i", "o", "--host-only"],
+            &["--kernel", "k", "--initrd", "i", "o", "-k", "6.1.0"],
+            &["--kernel", "k", "--initrd", "i", "o", "o2"],
+        ] {
+            assert!(parse_uki(args(invalid)).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn rebuild_options() {
+        let opts = parse_rebuild(args(&[])).unwrap().unwrap();
+        assert_eq!(opts.output, REBUILD_OUTPUT_DEFAULT);
+        assert!(!opts.host_only);
+        assert!(!opts.force);
+
+        let opts = parse_rebuild(args(&["-o", "/boot/{kver}.img", "--host-only", "-f"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(opts.output, "/boot/{kver}.img");
+        assert!(opts.host_only);
+        assert!(opts.force);
+        let opts = parse_rebuild(args(&["--output=/boot/{kver}.img", "--force"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(opts.output, "/boot/{kver}.img");
+        assert!(opts.force);
+
+        assert!(parse_rebuild(args(&["-f", "--help"])).unwrap().is_none());
+    }
+
+    #[test]
+    fn rebuild_errors() {
+        for invalid in [
+            &["-o"][..],
+            &["--output", "/boot/initramfs.img"],
+            &["-o", "/boot/{kver}.img", "--output=/efi/{kver}.img"],
+            &["--force=yes"],
+            &["--kver", "6.1.0"],
+            &["--frobnicate"],
+            &["/boot/{kver}.img"],
+        ] {
+            assert!(parse_rebuild(args(invalid)).is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,491 @@
+//! PE/COFF image writer, adding sections to an existing EFI executable.
+//!
+//! Only what's needed to append initialized data sections is supported: the section table is
+//! extended in place (the executable must have room for it before its first section), and
+//! the new sections are laid out after the existing ones, aligned as the executable requires.
+//! Anything following the last section in the file is dropped: a signature, which adding
+//! sections would invalidate anyway, or a (deprecated) COFF symbol table.
+//!
+//! See <https://learn.microsoft.com/en-us/windows/win32/debug/pe-format>.
+
+use std::io;
+
+const DOS_MAGIC: &[u8] = b"MZ";
+const PE_MAGIC: &[u8] = b"PE\0\0";
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+// Offset of the PE signature's offset in the DOS header
+const E_LFANEW: usize = 0x3c;
+
+const COFF_HEADER_LEN: usize = 20;
+const COFF_NUMBER_OF_SECTIONS: usize = 2;
+const COFF_POINTER_TO_SYMBOL_TABLE: usize = 8;
+const COFF_NUMBER_OF_SYMBOLS: usize = 12;
+const COFF_SIZE_OF_OPTIONAL_HEADER: usize = 16;
+const SECTION_HEADER_LEN: usize = 40;
+
+// Offsets in the optional header, the same for PE32 and PE32+
+const OPT_SIZE_OF_INITIALIZED_DATA: usize = 8;
+const OPT_SECTION_ALIGNMENT: usize = 32;
+const OPT_FILE_ALIGNMENT: usize = 36;
+const OPT_SIZE_OF_IMAGE: usize = 56;
+const OPT_SIZE_OF_HEADERS: usize = 60;
+const OPT_CHECKSUM: usize = 64;
+
+// Offsets of the number of data directories and of the data directories themselves in the
+// optional header, for PE32 and PE32+
+const OPT_DATA_DIRECTORIES_PE32: (usize, usize) = (92, 96);
+const OPT_DATA_DIRECTORIES_PE32_PLUS: (usize, usize) = (108, 112);
+const CERTIFICATE_TABLE: usize = 4;
+
+/// Initialized, read-only data.
+const SECTION_CHARACTERISTICS: u32 = 0x00000040 | 0x40000000;
+
+/// PE/COFF executable, with the sections to add to it.
+#[derive(Debug, Clone)]
+pub struct PeImage {
+    data: Vec<u8>,
+    coff: usize,
+    opt: usize,
+    sections: Vec<([u8; 8], Vec<u8>)>,
+}
+impl PeImage {
+    /// Parse the PE/COFF executable `data`.
+    pub fn parse(data: Vec<u8>) -> io::Result<Self> {
+        if !data.starts_with(DOS_MAGIC) {
+            return Err(invalid("not a PE/COFF executable"));
+        }
+        let pe = read_u32(&data, E_LFANEW)? as usize;
+        if data.get(pe..pe + PE_MAGIC.len()) != Some(PE_MAGIC) {
+            return Err(invalid("not a PE/COFF executable"));
+        }
+        let coff = pe + PE_MAGIC.len();
+        let opt = coff + COFF_HEADER_LEN;
+        let magic = read_u16(&data, opt)?;
+        if magic != PE32_MAGIC && magic != PE32_PLUS_MAGIC {
+            return Err(invalid("unknown optional header"));
+        }
+        let image = Self {
+            data,
+            coff,
+            opt,
+            sections: Vec::new(),
+        };
+        // Make sure every header is within bounds
+        image.data_directory(CERTIFICATE_TABLE)?;
+        image.section_headers()?;
+        Ok(image)
+    }
+
+    /// Whether the executable already has a section called `name`.
+    pub fn has_section(&self, name: &str) -> io::Result<bool> {
+        let name = section_name(name)?;
+        Ok(self.section_headers()?.iter().any(|h| h.name == name)
+            || self.sections.iter().any(|(n, _)| *n == name))
+    }
+
+    /// Add an initialized, read-only data section called `name` (at most 8 bytes long)
+    /// containing `data`.
+    pub fn add_section(&mut self, name: &str, data: Vec<u8>) -> io::Result<&mut Self> {
+        if self.has_section(name)? {
+            return Err(invalid(&format!("section {} already exists", name)));
+        }
+        self.sections.push((section_name(name)?, data));
+        Ok(self)
+    }
+
+    /// Lay out the added sections and write the resulting executable.
+    pub fn write(&self) -> io::Result<Vec<u8>> {
+        let headers = self.section_headers()?;
+        let section_alignment = read_u32(&self.data, self.opt + OPT_SECTION_ALIGNMENT)?;
+        let file_alignment = read_u32(&self.data, self.opt + OPT_FILE_ALIGNMENT)?;
+        if !section_alignment.is_power_of_two() || !file_alignment.is_power_of_two() {
+            return Err(invalid("invalid section or file alignment"));
+        }
+
+        // The new section headers must fit before the first section's contents
+        let table = self.section_table();
+        let table_end = table + (headers.len() + self.sections.len()) * SECTION_HEADER_LEN;
+        let size_of_headers = read_u32(&self.data, self.opt + OPT_SIZE_OF_HEADERS)? as usize;
+        let first_raw = headers
+            .iter()
+            .filter(|h| h.raw_size > 0)
+            .map(|h| h.raw_offset as usize)
+            .min()
+            .unwrap_or(size_of_headers);
+        if table_end > size_of_headers.min(first_raw) {
+            return Err(invalid("no room left for more section headers"));
+        }
+
+        let mut virtual_end = headers
+            .iter()
+            .map(|h| h.virtual_address as u64 + h.virtual_size.max(h.raw_size) as u64)
+            .max()
+            .unwrap_or(size_of_headers as u64);
+        let raw_end = headers
+            .iter()
+            .map(|h| h.raw_offset as usize + h.raw_size as usize)
+            .max()
+            .unwrap_or(size_of_headers)
+            .max(size_of_headers);
+        let mut out = self
+            .data
+            .get(..raw_end)
+            .ok_or_else(|| invalid("truncated section"))?
+            .to_vec();
+        out.resize(align(raw_end as u64, file_alignment) as usize, 0);
+
+        let mut initialized_data = read_u32(&self.data, self.opt + OPT_SIZE_OF_INITIALIZED_DATA)?;
+        for (i, (name, data)) in self.sections.iter().enumerate() {
+            let virtual_address = align(virtual_end, section_alignment);
+            let raw_offset = out.len() as u64;
+            let raw_size = align(data.len() as u64, file_alignment);
+            let header = SectionHeader {
+                name: *name,
+                virtual_size: to_u32(data.len() as u64)?,
+                virtual_address: to_u32(virtual_address)?,
+                raw_size: to_u32(raw_size)?,
+                raw_offset: to_u32(raw_offset)?,
+            };
+            header.write(&mut out[table + (headers.len() + i) * SECTION_HEADER_LEN..]);
+            out.extend_from_slice(data);
+            out.resize((raw_offset + raw_size) as usize, 0);
+            virtual_end = virtual_address + data.len() as u64;
+            initialized_data = initialized_data.wrapping_add(header.raw_size);
+        }
+
+        let sections = to_u16(headers.len() + self.sections.len())?;
+        let size_of_image = to_u32(align(virtual_end, section_alignment))?;
+        write_u16(&mut out, self.coff + COFF_NUMBER_OF_SECTIONS, sections);
+        if read_u32(&out, self.coff + COFF_POINTER_TO_SYMBOL_TABLE)? as usize >= raw_end {
+            write_u32(&mut out, self.coff + COFF_POINTER_TO_SYMBOL_TABLE, 0);
+            write_u32(&mut out, self.coff + COFF_NUMBER_OF_SYMBOLS, 0);
+        }
+        write_u32(
+            &mut out,
+            self.opt + OPT_SIZE_OF_INITIALIZED_DATA,
+            initialized_data,
+        );
+        write_u32(&mut out, self.opt + OPT_SIZE_OF_IMAGE, size_of_image);
+        // The signature (if any) was dropped along with everything after the last section
+        let certificate_table = self.data_directory(CERTIFICATE_TABLE)?;
+        if let Some(dir) = certificate_table {
+            out[dir..dir + 8].fill(0);
+        }
+        write_u32(&mut out, self.opt + OPT_CHECKSUM, 0);
+        let checksum = checksum(&out);
+        write_u32(&mut out, self.opt + OPT_CHECKSUM, checksum);
+        Ok(out)
+    }
+
+    // Offset of the section table
+    fn section_table(&self) -> usize {
+        let opt_len = read_u16(&self.data, self.coff + COFF_SIZE_OF_OPTIONAL_HEADER)
+            .unwrap_or_default() as usize;
+        self.opt + opt_len
+    }
+
+    fn section_headers(&self) -> io::Result<Vec<SectionHeader>> {
+        let count = read_u16(&self.data, self.coff + COFF_NUMBER_OF_SECTIONS)? as usize;
+        let table = self.section_table();
+        (0..count)
+            .map(|i| SectionHeader::read(&self.data, table + i * SECTION_HEADER_LEN))
+            .collect()
+    }
+
+    // Offset of data directory `index`, if the optional header has it
+    fn data_directory(&self, index: usize) -> io::Result<Option<usize>> {
+        let (count, dirs) = match read_u16(&self.data, self.opt)? {
+            PE32_MAGIC => OPT_DATA_DIRECTORIES_PE32,
+            _ => OPT_DATA_DIRECTORIES_PE32_PLUS,
+        };
+        let count = read_u32(&self.data, self.opt + count)? as usize;
+        if index >= count {
+            return Ok(None);
+        }
+        let dir = self.opt + dirs + index * 8;
+        read_u32(&self.data, dir + 4)?;
+        Ok(Some(dir))
+    }
+}
+
+/// Minimal PE32+ EFI application with a single `.text` section, followed by a signature.
+#[cfg(test)]
+pub fn test_stub() -> Vec<u8> {
+    let mut data = vec![0; 0x610];
+    data[..2].copy_from_slice(DOS_MAGIC);
+    write_u32(&mut data, E_LFANEW, 0x40);
+    data[0x40..0x44].copy_from_slice(PE_MAGIC);
+    let (coff, opt) = (0x44, 0x44 + COFF_HEADER_LEN);
+    write_u16(&mut data, coff, 0x8664);
+    write_u16(&mut data, coff + COFF_NUMBER_OF_SECTIONS, 1);
+    write_u16(&mut data, coff + COFF_SIZE_OF_OPTIONAL_HEADER, 240);
+    write_u16(&mut data, coff + 18, 0x22);
+    write_u16(&mut data, opt, PE32_PLUS_MAGIC);
+    write_u32(&mut data, opt + 4, 0x200); // SizeOfCode
+    write_u32(&mut data, opt + OPT_SIZE_OF_INITIALIZED_DATA, 0x80);
+    write_u32(&mut data, opt + 16, 0x1000); // AddressOfEntryPoint
+    write_u32(&mut data, opt + OPT_SECTION_ALIGNMENT, 0x1000);
+    write_u32(&mut data, opt + OPT_FILE_ALIGNMENT, 0x200);
+    write_u32(&mut data, opt + OPT_SIZE_OF_IMAGE, 0x2000);
+    write_u32(&mut data, opt + OPT_SIZE_OF_HEADERS, 0x400);
+    write_u32(&mut data, opt + OPT_CHECKSUM, 0xdeadbeef);
+    write_u16(&mut data, opt + 68, 10); // EFI application
+    let (count, dirs) = OPT_DATA_DIRECTORIES_PE32_PLUS;
+    write_u32(&mut data, opt + count, 16);
+    write_u32(&mut data, opt + dirs + CERTIFICATE_TABLE * 8, 0x600);
+    write_u32(&mut data, opt + dirs + CERTIFICATE_TABLE * 8 + 4, 0x10);
+    let text = SectionHeader {
+        name: *b".text\0\0\0",
+        virtual_size: 0x123,
+        virtual_address: 0x1000,
+        raw_size: 0x200,
+        raw_offset: 0x400,
+    };
+    text.write(&mut data[opt + 240..]);
+    data[0x400..0x523].fill(0xcc);
+    data[0x600..].fill(0x5a);
+    data
+}
+
+/// Name and contents of every section of the PE/COFF executable `data`.
+#[cfg(test)]
+pub fn test_sections(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let image = PeImage::parse(data.to_vec()).unwrap();
+    image
+        .section_headers()
+        .unwrap()
+        .iter()
+        .map(|h| {
+            let name = String::from_utf8_lossy(&h.name);
+            let start = h.raw_offset as usize;
+            (
+                name.trim_end_matches('\0').to_string(),
+                data[start..start + h.virtual_size as usize].to_vec(),
+            )
+        })
+        .collect()
+}
+
+// Section table entry
+#[derive(Debug, Clone, Copy)]
+struct SectionHeader {
+    name: [u8; 8],
+    virtual_size: u32,
+    virtual_address: u32,
+    raw_size: u32,
+    raw_offset: u32,
+}
+impl SectionHeader {
+    fn read(data: &[u8], offset: usize) -> io::Result<Self> {
+        let header = data
+            .get(offset..offset + SECTION_HEADER_LEN)
+            .ok_or_else(|| invalid("truncated section table"))?;
+        let mut name = [0; 8];
+        name.copy_from_slice(&header[..8]);
+        Ok(Self {
+            name,
+            virtual_size: read_u32(header, 8)?,
+            virtual_address: read_u32(header, 12)?,
+            raw_size: read_u32(header, 16)?,
+            raw_offset: read_u32(header, 20)?,
+        })
+    }
+
+    // Write the header at the start of `out`
+    fn write(&self, out: &mut [u8]) {
+        out[..SECTION_HEADER_LEN].fill(0);
+        out[..8].copy_from_slice(&self.name);
+        write_u32(out, 8, self.virtual_size);
+        write_u32(out, 12, self.virtual_address);
+        write_u32(out, 16, self.raw_size);
+        write_u32(out, 20, self.raw_offset);
+        write_u32(out, 36, SECTION_CHARACTERISTICS);
+    }
+}
+
+// Section names are NUL-padded to 8 bytes
+fn section_name(name: &str) -> io::Result<[u8; 8]> {
+    let mut padded = [0; 8];
+    padded
+        .get_mut(..name.len())
+        .ok_or_else(|| invalid(&format!("section name {} is too long", name)))?
+        .copy_from_slice(name.as_bytes());
+    Ok(padded)
+}
+
+// Image checksum, as computed by the Windows loader: the 16-bit one's complement sum of the
+// file (with a zeroed checksum field) plus its length
+fn checksum(data: &[u8]) -> u32 {
+    let mut sum: u64 = 0;
+    for word in data.chunks(2) {
+        let word = match word {
+            [lo, hi] => u16::from_le_bytes([*lo, *hi]),
+            [lo] => *lo as u16,
+            _ => 0,
+        };
+        sum += word as u64;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    (sum as u32).wrapping_add(data.len() as u32)
+}
+
+fn align(value: u64, alignment: u32) -> u64 {
+    let alignment = alignment as u64;
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+fn to_u16(value: usize) -> io::Result<u16> {
+    u16::try_from(value).map_err(|_| invalid("too many sections"))
+}
+
+fn to_u32(value: u64) -> io::Result<u32> {
+    u32::try_from(value).map_err(|_| invalid("image too large"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("truncated header"))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("truncated header"))
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sections() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            (".osrel", b"ID=test\n".to_vec()),
+            (".cmdline", b"root=/dev/sda1 quiet".to_vec()),
+            (".splash", [&b"BM"[..], &[0x11; 0x3ff]].concat()),
+            (".initrd", vec![0x22; 0x1234]),
+            (".linux", [&b"MZ"[..], &[0x33; 0x201]].concat()),
+        ]
+    }
+
+    #[test]
+    fn add_sections() {
+        let stub = test_stub();
+        let mut image = PeImage::parse(stub.clone()).unwrap();
+        assert!(image.has_section(".text").unwrap());
+        for (name, data) in sections() {
+            image.add_section(name, data).unwrap();
+        }
+        let out = image.write().unwrap();
+
+        // Headers and the existing section are kept as-is, the signature is dropped
+        let written = PeImage::parse(out.clone()).unwrap();
+        let headers = written.section_headers().unwrap();
+        assert_eq!(headers.len(), 6);
+        assert_eq!(read_u16(&out, 0x44 + COFF_NUMBER_OF_SECTIONS).unwrap(), 6);
+        assert_eq!(out[..0x40], stub[..0x40]);
+        assert_eq!(out[0x148..0x170], stub[0x148..0x170]);
+        assert_eq!(&out[0x400..0x600], &stub[0x400..0x600]);
+        let last = headers.last().unwrap();
+        assert_eq!(out.len(), (last.raw_offset + last.raw_size) as usize);
+        let opt = 0x44 + COFF_HEADER_LEN;
+        let certificate_table = opt + OPT_DATA_DIRECTORIES_PE32_PLUS.1 + CERTIFICATE_TABLE * 8;
+        assert_eq!(out[certificate_table..certificate_table + 8], [0; 8]);
+
+        // Sections follow each other, aligned
+        let mut virtual_end = 0x1123;
+        let mut raw_end = 0x600;
+        let mut initialized_data = 0x80;
+        for (header, (name, data)) in headers[1..].iter().zip(sections()) {
+            assert_eq!(header.name, section_name(name).unwrap());
+            assert_eq!(header.virtual_address % 0x1000, 0);
+            assert!(header.virtual_address >= virtual_end);
+            assert!(header.virtual_address < virtual_end + 0x1000);
+            assert_eq!(header.virtual_size as usize, data.len());
+            assert_eq!(header.raw_offset, raw_end);
+            assert_eq!(header.raw_size % 0x200, 0);
+            assert!(header.raw_size as usize >= data.len());
+            assert!((header.raw_size as usize) < data.len() + 0x200);
+            let start = header.raw_offset as usize;
+            assert_eq!(out[start..start + data.len()], data[..]);
+            assert!(out[start + data.len()..start + header.raw_size as usize]
+                .iter()
+                .all(|b| *b == 0));
+            virtual_end = header.virtual_address + header.virtual_size;
+            raw_end = header.raw_offset + header.raw_size;
+            initialized_data += header.raw_size;
+        }
+        assert_eq!(
+            read_u32(&out, opt + OPT_SIZE_OF_IMAGE).unwrap(),
+            (virtual_end + 0xfff) & !0xfff
+        );
+        assert_eq!(
+            read_u32(&out, opt + OPT_SIZE_OF_INITIALIZED_DATA).unwrap(),
+            initialized_data
+        );
+
+        let mut unchecked = out.clone();
+        write_u32(&mut unchecked, opt + OPT_CHECKSUM, 0);
+        assert_eq!(
+            read_u32(&out, opt + OPT_CHECKSUM).unwrap(),
+            checksum(&unchecked)
+        );
+        assert_eq!(
+            test_sections(&out)
+                .iter()
+                .map(|(name, _)| &name[..])
+                .collect::<Vec<_>>(),
+            [".text", ".osrel", ".cmdline", ".splash", ".initrd", ".linux"]
+        );
+    }
+
+    #[test]
+    fn pe_checksum() {
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[0x01, 0x02, 0x03]), 0x0201 + 0x03 + 3);
+        // Carries wrap around
+        assert_eq!(checksum(&[0xff, 0xff, 0x02, 0x00]), 0x0002 + 4);
+        assert_eq!(checksum(&[0xff, 0xff, 0x01, 0x00]), 0x0001 + 4);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut image = PeImage::parse(test_stub()).unwrap();
+        assert!(image.add_section(".text", Vec::new()).is_err());
+        assert!(image.add_section(".toolong!", Vec::new()).is_err());
+        image.add_section(".initrd", Vec::new()).unwrap();
+        assert!(image.add_section(".initrd", Vec::new()).is_err());
+
+        // No room for more section headers before the first section
+        let mut stub = test_stub();
+        write_u32(
+            &mut stub,
+            0x44 + COFF_HEADER_LEN + OPT_SIZE_OF_HEADERS,
+            0x198,
+        );
+        let mut image = PeImage::parse(stub).unwrap();
+        image.add_section(".osrel", Vec::new()).unwrap();
+        assert!(image.write().is_ok());
+        image.add_section(".cmdline", Vec::new()).unwrap();
+        assert!(image.write().is_err());
+
+        assert!(PeImage::parse(b"MZ".to_vec()).is_err());
+        assert!(PeImage::parse(test_stub()[..0x100].to_vec()).is_err());
+        assert!(PeImage::parse(vec![0x7f; 0x400]).is_err());
+    }
+}
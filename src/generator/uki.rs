@@ -0,0 +1,258 @@
+//! `ignited uki`: assemble a Unified Kernel Image.
+//!
+//! A UKI is an EFI stub (usually systemd's `linux<arch>.efi.stub`) with everything it needs
+//! to boot the system embedded as [PE sections][PeImage], so that the whole can be signed
+//! for Secure Boot as a single EFI binary:
+//!
+//! - `.osrel`: the system's `os-release`, shown by boot menus.
+//! - `.cmdline`: the kernel command line.
+//! - `.splash`: a BMP image shown while booting, if any.
+//! - `.initrd`: the initramfs image.
+//! - `.linux`: the kernel, which must be built with `CONFIG_EFI_STUB`.
+//!
+//! See <https://uapi-group.org/specifications/specs/unified_kernel_image/>.
+
+use crate::{generator::pe::PeImage, PROGRAM_NAME};
+use precisej_printable_errno::{printable_error, ExitError, PrintableErrno, PrintableResult};
+use std::{
+    ffi::OsString,
+    fs::{read, read_to_string, remove_file, rename, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+/// Command line used when none is given, as read by `kernel-install(8)`.
+const KERNEL_CMDLINE: &str = "/etc/kernel/cmdline";
+
+/// Command line of the running kernel, used when [KERNEL_CMDLINE] doesn't exist.
+const PROC_CMDLINE: &str = "/proc/cmdline";
+
+/// `os-release` files, in order of precedence.
+const OS_RELEASE: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
+
+const BMP_MAGIC: &[u8] = b"BM";
+
+#[cfg(target_arch = "x86_64")]
+const EFI_ARCH: Option<&str> = Some("x64");
+#[cfg(target_arch = "x86")]
+const EFI_ARCH: Option<&str> = Some("ia32");
+#[cfg(target_arch = "aarch64")]
+const EFI_ARCH: Option<&str> = Some("aa64");
+#[cfg(target_arch = "riscv64")]
+const EFI_ARCH: Option<&str> = Some("riscv64");
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "x86",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+const EFI_ARCH: Option<&str> = None;
+
+/// Path of systemd's EFI stub for this architecture, if it has one.
+pub fn default_stub() -> Option<PathBuf> {
+    EFI_ARCH.map(|arch| PathBuf::from(format!("/usr/lib/systemd/boot/efi/linux{}.efi.stub", arch)))
+}
+
+/// Options of `ignited uki`.
+#[derive(Debug, Clone)]
+pub struct UkiOpts {
+    pub(super) stub: PathBuf,
+    pub(super) kernel: PathBuf,
+    pub(super) initrd: PathBuf,
+    pub(super) cmdline: Option<String>,
+    pub(super) os_release: Option<PathBuf>,
+    pub(super) splash: Option<PathBuf>,
+    pub(super) output: PathBuf,
+}
+
+/// Assemble the UKI described by `opts`.
+pub fn uki(opts: &UkiOpts) -> Result<(), ExitError<String>> {
+    let pe_err = |io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to use {} as EFI stub: {}", opts.stub.display(), io),
+        )
+    };
+    let mut pe = PeImage::parse(read_input(&opts.stub).bail(3)?)
+        .map_err(pe_err)
+        .bail(5)?;
+
+    let os_release = match &opts.os_release {
+        Some(os_release) => Some(read_input(os_release).bail(3)?),
+        None => OS_RELEASE.iter().find_map(|path| read(path).ok()),
+    };
+    let cmdline = match &opts.cmdline {
+        Some(cmdline) => cmdline.clone(),
+        None => default_cmdline().bail(3)?,
+    };
+    let splash = match &opts.splash {
+        Some(splash) => Some(read_checked(splash, BMP_MAGIC, "a BMP image").bail(3)?),
+        None => None,
+    };
+    let initrd = read_input(&opts.initrd).bail(3)?;
+    let kernel = read_checked(&opts.kernel, b"MZ", "an EFI-bootable kernel").bail(3)?;
+
+    // Same order as systemd's ukify, with the kernel last
+    match os_release {
+        Some(os_release) => {
+            pe.add_section(".osrel", os_release)
+                .map_err(pe_err)
+                .bail(5)?;
+        }
+        None => eprintln!(
+            "{}: os-release not found, boot menus won't be able to name {}",
+            PROGRAM_NAME,
+            opts.output.display()
+        ),
+    }
+    pe.add_section(".cmdline", cmdline.into_bytes())
+        .map_err(pe_err)
+        .bail(5)?;
+    if let Some(splash) = splash {
+        pe.add_section(".splash", splash).map_err(pe_err).bail(5)?;
+    }
+    pe.add_section(".initrd", initrd).map_err(pe_err).bail(5)?;
+    pe.add_section(".linux", kernel).map_err(pe_err).bail(5)?;
+    let uki = pe.write().map_err(pe_err).bail(5)?;
+    write_output(&opts.output, &uki).bail(6)
+}
+
+fn read_input(path: &Path) -> Result<Vec<u8>, PrintableErrno<String>> {
+    read(path).map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to read {}: {}", path.display(), io),
+        )
+    })
+}
+
+// Read `path`, making sure it starts with `magic`
+fn read_checked(path: &Path, magic: &[u8], what: &str) -> Result<Vec<u8>, PrintableErrno<String>> {
+    let data = read_input(path)?;
+    if data.starts_with(magic) {
+        Ok(data)
+    } else {
+        Err(printable_error(
+            PROGRAM_NAME,
+            format!("{} is not {}", path.display(), what),
+        ))
+    }
+}
+
+// /etc/kernel/cmdline, or the running kernel's command line without the options added by the
+// boot loader
+fn default_cmdline() -> Result<String, PrintableErrno<String>> {
+    if let Ok(cmdline) = read_to_string(KERNEL_CMDLINE) {
+        return Ok(cmdline.split_whitespace().collect::<Vec<_>>().join(" "));
+    }
+    let cmdline = read_to_string(PROC_CMDLINE).map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!(
+                "unable to read {} nor {}: {}",
+                KERNEL_CMDLINE, PROC_CMDLINE, io
+            ),
+        )
+    })?;
+    Ok(cmdline
+        .split_whitespace()
+        .filter(|opt| !opt.starts_with("BOOT_IMAGE=") && !opt.starts_with("initrd="))
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+// Write `data` next to `output` first, so that a failed write never leaves a truncated UKI
+// behind
+fn write_output(output: &Path, data: &[u8]) -> Result<(), PrintableErrno<String>> {
+    let mut tmp = OsString::from(output);
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let res = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut f| {
+            f.write_all(data)?;
+            f.sync_all()
+        })
+        .and_then(|()| rename(&tmp, output));
+    res.map_err(|io: io::Error| {
+        let _ = remove_file(&tmp);
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to write {}: {}", output.display(), io),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::pe::{test_sections, test_stub};
+    use std::{
+        fs::{create_dir_all, metadata, remove_dir_all, write},
+        os::unix::fs::PermissionsExt,
+    };
+
+    #[test]
+    fn assemble() {
+        let dir = std::env::temp_dir().join(format!("ignited-uki-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let kernel = [&b"MZ"[..], &[0x33; 0x1000]].concat();
+        let splash = [&b"BM"[..], &[0x11; 0x100]].concat();
+        for (name, data) in [
+            ("stub", &test_stub()[..]),
+            ("vmlinuz", &kernel),
+            ("initrd", b"070701"),
+            ("os-release", b"ID=test\n"),
+            ("splash.bmp", &splash),
+            ("notbmp", b"GIF89a"),
+        ] {
+            write(dir.join(name), data).unwrap();
+        }
+        let mut opts = UkiOpts {
+            stub: dir.join("stub"),
+            kernel: dir.join("vmlinuz"),
+            initrd: dir.join("initrd"),
+            cmdline: Some("root=/dev/sda1 quiet".to_string()),
+            os_release: Some(dir.join("os-release")),
+            splash: Some(dir.join("splash.bmp")),
+            output: dir.join("uki.efi"),
+        };
+        uki(&opts).unwrap();
+
+        let out = read(&opts.output).unwrap();
+        assert_eq!(
+            metadata(&opts.output).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(!dir.join("uki.efi.tmp").exists());
+        let sections = test_sections(&out);
+        let names: Vec<_> = sections.iter().map(|(name, _)| &name[..]).collect();
+        assert_eq!(
+            names,
+            [".text", ".osrel", ".cmdline", ".splash", ".initrd", ".linux"]
+        );
+        assert_eq!(sections[1].1, b"ID=test\n");
+        assert_eq!(sections[2].1, b"root=/dev/sda1 quiet");
+        assert_eq!(sections[3].1, splash);
+        assert_eq!(sections[4].1, b"070701");
+        assert_eq!(sections[5].1, kernel);
+
+        // Inputs are checked, and a failed build leaves the previous UKI alone
+        opts.splash = Some(dir.join("notbmp"));
+        assert!(uki(&opts).is_err());
+        opts.splash = None;
+        opts.kernel = dir.join("initrd");
+        assert!(uki(&opts).is_err());
+        opts.kernel = dir.join("vmlinuz");
+        opts.stub = dir.join("os-release");
+        assert!(uki(&opts).is_err());
+        assert_eq!(read(&opts.output).unwrap(), out);
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,662 @@
+//! LUKS1 and LUKS2 on-disk headers.
+//!
+//! Both versions are parsed into the same representation: a list of keyslots, the digests
+//! used to verify the volume key recovered from them, and the encrypted data segment.
+//! See the [LUKS1](https://gitlab.com/cryptsetup/cryptsetup/-/wikis/LUKS-standard/on-disk-format.pdf)
+//! and [LUKS2](https://gitlab.com/cryptsetup/LUKS2-docs) on-disk format specifications.
+
+use super::json::Json;
+use crate::{
+    crypto::{
+        argon2::{Argon2Params, Argon2Variant},
+        HashAlg,
+    },
+    PROGRAM_NAME,
+};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+const LUKS2_MAGIC_SECONDARY: &[u8] = b"SKUL\xba\xbe";
+const LUKS1_HEADER_SIZE: usize = 592;
+const LUKS1_KEYSLOTS: usize = 8;
+const LUKS1_KEYSLOT_ACTIVE: u32 = 0x00AC71F3;
+const LUKS2_BINARY_HEADER_SIZE: usize = 4096;
+
+// Offsets where the secondary LUKS2 header may be found if the primary one is corrupt
+const LUKS2_SECONDARY_OFFSETS: &[u64] = &[
+    0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000, 0x100000, 0x200000, 0x400000,
+];
+
+// Upper bound on the LUKS2 header size (binary header + JSON area)
+const LUKS2_MAX_HEADER_SIZE: u64 = 0x400000;
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// NUL-padded string
+fn str_from(raw: &[u8]) -> String {
+    let end = raw.iter().position(|b| *b == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..end]).into_owned()
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut acc = 0u32;
+        for c in chunk {
+            acc = (acc << 6) | value(*c)?;
+        }
+        acc <<= 6 * (4 - chunk.len()) as u32;
+        out.extend_from_slice(&acc.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+fn invalid<S: AsRef<str>>(what: S) -> PrintableErrno<String> {
+    printable_error(
+        PROGRAM_NAME,
+        format!("invalid LUKS header: {}", what.as_ref()),
+    )
+}
+
+fn io_error(io: io::Error) -> PrintableErrno<String> {
+    printable_error(PROGRAM_NAME, format!("unable to read LUKS header: {}", io))
+}
+
+/// Key derivation function of a keyslot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Kdf {
+    /// PBKDF2 with the given HMAC hash function.
+    Pbkdf2 {
+        /// Hash function.
+        hash: HashAlg,
+        /// Number of iterations.
+        iterations: u32,
+        /// Salt.
+        salt: Vec<u8>,
+    },
+
+    /// Argon2i or Argon2id.
+    Argon2 {
+        /// Variant.
+        variant: Argon2Variant,
+        /// Cost parameters.
+        params: Argon2Params,
+        /// Salt.
+        salt: Vec<u8>,
+    },
+}
+
+/// Keyslot: a copy of the volume key, encrypted with a key derived from a passphrase.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Keyslot {
+    pub(super) id: u32,
+    pub(super) priority: u32,
+    pub(super) key_size: usize,
+    pub(super) kdf: Kdf,
+    pub(super) area_offset: u64,
+    pub(super) area_encryption: String,
+    pub(super) area_key_size: usize,
+    pub(super) af_stripes: u32,
+    pub(super) af_hash: HashAlg,
+}
+
+/// Digest used to verify a volume key recovered from a keyslot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LuksDigest {
+    pub(super) keyslots: Vec<u32>,
+    pub(super) hash: HashAlg,
+    pub(super) iterations: u32,
+    pub(super) salt: Vec<u8>,
+    pub(super) digest: Vec<u8>,
+}
+
+/// Encrypted data segment.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Segment {
+    offset: u64,
+    size: Option<u64>,
+    iv_tweak: u64,
+    encryption: String,
+    sector_size: u32,
+}
+impl Segment {
+    /// Offset of the encrypted data in bytes.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size of the encrypted data in bytes, or `None` if it extends to the end of the
+    /// device.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// IV offset in 512-byte sectors.
+    pub fn iv_tweak(&self) -> u64 {
+        self.iv_tweak
+    }
+
+    /// Cipher specification (e.g. `aes-xts-plain64`).
+    pub fn encryption(&self) -> &str {
+        &self.encryption[..]
+    }
+
+    /// Encryption sector size in bytes.
+    pub fn sector_size(&self) -> u32 {
+        self.sector_size
+    }
+}
+
+/// Parsed LUKS1 or LUKS2 header.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LuksHeader {
+    version: u16,
+    uuid: String,
+    keyslots: Vec<Keyslot>,
+    digests: Vec<LuksDigest>,
+    segment: Segment,
+    flags: Vec<String>,
+}
+impl LuksHeader {
+    /// Read the LUKS header at the start of `dev`.
+    pub fn read(dev: &File) -> Result<Self, PrintableErrno<String>> {
+        let mut hdr = vec![0; LUKS1_HEADER_SIZE];
+        dev.read_exact_at(&mut hdr, 0).map_err(io_error)?;
+        if !hdr.starts_with(LUKS_MAGIC) && !hdr.starts_with(LUKS2_MAGIC_SECONDARY) {
+            return Err(invalid("bad magic"));
+        }
+        match be16(&hdr, 6) {
+            1 => Self::parse_luks1(&hdr),
+            2 => Self::read_luks2(dev),
+            version => Err(invalid(format!("unsupported version {}", version))),
+        }
+    }
+
+    fn parse_luks1(hdr: &[u8]) -> Result<Self, PrintableErrno<String>> {
+        let cipher = str_from(&hdr[8..40]);
+        let cipher_mode = str_from(&hdr[40..72]);
+        let hash_spec = str_from(&hdr[72..104]);
+        let hash = HashAlg::from_name(&hash_spec)
+            .ok_or_else(|| invalid(format!("unsupported hash {}", hash_spec)))?;
+        let payload_offset = be32(hdr, 104) as u64;
+        let key_size = be32(hdr, 108) as usize;
+        if key_size == 0 || key_size > 512 {
+            return Err(invalid(format!("invalid key size {}", key_size)));
+        }
+        let encryption = format!("{}-{}", cipher, cipher_mode);
+
+        let keyslots = (0..LUKS1_KEYSLOTS)
+            .filter_map(|i| {
+                let ks = &hdr[208 + i * 48..208 + (i + 1) * 48];
+                if be32(ks, 0) != LUKS1_KEYSLOT_ACTIVE {
+                    return None;
+                }
+                Some(Keyslot {
+                    id: i as u32,
+                    priority: 1,
+                    key_size,
+                    kdf: Kdf::Pbkdf2 {
+                        hash,
+                        iterations: be32(ks, 4),
+                        salt: ks[8..40].to_vec(),
+                    },
+                    area_offset: be32(ks, 40) as u64 * 512,
+                    area_encryption: encryption.clone(),
+                    area_key_size: key_size,
+                    af_stripes: be32(ks, 44),
+                    af_hash: hash,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Self {
+            version: 1,
+            uuid: str_from(&hdr[168..208]),
+            digests: vec![LuksDigest {
+                keyslots: keyslots.iter().map(|ks| ks.id).collect(),
+                hash,
+                iterations: be32(hdr, 164),
+                salt: hdr[132..164].to_vec(),
+                digest: hdr[112..132].to_vec(),
+            }],
+            keyslots,
+            segment: Segment {
+                offset: payload_offset * 512,
+                size: None,
+                iv_tweak: 0,
+                encryption,
+                sector_size: 512,
+            },
+            flags: Vec::new(),
+        })
+    }
+
+    // Read a LUKS2 binary header and its JSON area at `offset`. Returns None if there's no
+    // valid header there.
+    fn read_luks2_at(dev: &File, offset: u64) -> Option<(u64, u64, String, Json)> {
+        let mut bin = vec![0; LUKS2_BINARY_HEADER_SIZE];
+        dev.read_exact_at(&mut bin, offset).ok()?;
+        let magic = if offset == 0 {
+            LUKS_MAGIC
+        } else {
+            LUKS2_MAGIC_SECONDARY
+        };
+        if !bin.starts_with(magic) || be16(&bin, 6) != 2 {
+            return None;
+        }
+        let hdr_size = be64(&bin, 8);
+        if hdr_size <= LUKS2_BINARY_HEADER_SIZE as u64
+            || hdr_size > LUKS2_MAX_HEADER_SIZE
+            || be64(&bin, 256) != offset
+        {
+            return None;
+        }
+        let mut hdr = vec![0; hdr_size as usize];
+        dev.read_exact_at(&mut hdr, offset).ok()?;
+
+        // The checksum covers the whole header (with the checksum field zeroed)
+        let hash = HashAlg::from_name(&str_from(&hdr[72..104]))?;
+        let csum = hdr[448..448 + hash.output_size()].to_vec();
+        hdr[448..512].fill(0);
+        if hash.digest(&[&hdr]) != csum {
+            return None;
+        }
+
+        let json = Json::parse(&hdr[LUKS2_BINARY_HEADER_SIZE..])?;
+        Some((be64(&hdr, 16), hdr_size, str_from(&hdr[168..208]), json))
+    }
+
+    fn read_luks2(dev: &File) -> Result<Self, PrintableErrno<String>> {
+        let primary = Self::read_luks2_at(dev, 0);
+        let secondary = match primary {
+            Some((_, hdr_size, _, _)) => Self::read_luks2_at(dev, hdr_size),
+            None => LUKS2_SECONDARY_OFFSETS
+                .iter()
+                .find_map(|offset| Self::read_luks2_at(dev, *offset)),
+        };
+
+        // Use the most recent valid header
+        let (_, _, uuid, json) = match (primary, secondary) {
+            (Some(p), Some(s)) if s.0 > p.0 => s,
+            (Some(p), _) => p,
+            (None, Some(s)) => s,
+            (None, None) => return Err(invalid("no valid LUKS2 header found")),
+        };
+        Self::parse_luks2(uuid, &json)
+    }
+
+    fn parse_luks2(uuid: String, json: &Json) -> Result<Self, PrintableErrno<String>> {
+        let get_u64 = |obj: &Json, key: &str| {
+            obj.get(key)
+                .and_then(Json::as_u64)
+                .ok_or_else(|| invalid(format!("missing or invalid {}", key)))
+        };
+        let get_str = |obj: &Json, key: &str| {
+            obj.get(key)
+                .and_then(Json::as_str)
+                .map(|s| s.to_string())
+                .ok_or_else(|| invalid(format!("missing or invalid {}", key)))
+        };
+        let get_b64 = |obj: &Json, key: &str| {
+            obj.get(key)
+                .and_then(Json::as_str)
+                .and_then(base64_decode)
+                .ok_or_else(|| invalid(format!("missing or invalid {}", key)))
+        };
+        let get_hash = |obj: &Json, key: &str| {
+            let name = get_str(obj, key)?;
+            HashAlg::from_name(&name).ok_or_else(|| invalid(format!("unsupported hash {}", name)))
+        };
+        let get_ids = |obj: &Json, key: &str| {
+            obj.get(key)
+                .and_then(Json::as_array)
+                .map(|ids| ids.iter().filter_map(Json::as_u64).map(|id| id as u32).collect())
+                .ok_or_else(|| invalid(format!("missing or invalid {}", key)))
+        };
+
+        let config = json.get("config").ok_or_else(|| invalid("missing config"))?;
+        if let Some(mandatory) = config
+            .get("requirements")
+            .and_then(|r| r.get("mandatory"))
+            .and_then(Json::as_array)
+            .filter(|m| !m.is_empty())
+        {
+            return Err(invalid(format!(
+                "unsupported requirements {:?}",
+                mandatory.iter().filter_map(Json::as_str).collect::<Vec<_>>()
+            )));
+        }
+        let flags = config
+            .get("flags")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|f| f.as_str().map(|f| f.to_string()))
+            .collect();
+
+        let segments = json
+            .get("segments")
+            .and_then(Json::as_object)
+            .ok_or_else(|| invalid("missing segments"))?;
+        let segment = match (segments.len(), segments.get("0")) {
+            (1, Some(segment)) => segment,
+            _ => return Err(invalid("only volumes with a single segment are supported")),
+        };
+        if segment.get("type").and_then(Json::as_str) != Some("crypt")
+            || segment.get("integrity").is_some()
+        {
+            return Err(invalid("unsupported segment type"));
+        }
+        let segment = Segment {
+            offset: get_u64(segment, "offset")?,
+            size: match segment.get("size").and_then(Json::as_str) {
+                Some("dynamic") => None,
+                _ => Some(get_u64(segment, "size")?),
+            },
+            iv_tweak: get_u64(segment, "iv_tweak")?,
+            encryption: get_str(segment, "encryption")?,
+            sector_size: get_u64(segment, "sector_size")? as u32,
+        };
+
+        let mut keyslots = Vec::new();
+        for (id, keyslot) in json
+            .get("keyslots")
+            .and_then(Json::as_object)
+            .ok_or_else(|| invalid("missing keyslots"))?
+        {
+            if keyslot.get("type").and_then(Json::as_str) != Some("luks2") {
+                // e.g. reencryption keyslots
+                continue;
+            }
+            let kdf = keyslot.get("kdf").ok_or_else(|| invalid("missing kdf"))?;
+            let area = keyslot.get("area").ok_or_else(|| invalid("missing area"))?;
+            let af = keyslot.get("af").ok_or_else(|| invalid("missing af"))?;
+            if area.get("type").and_then(Json::as_str) != Some("raw")
+                || af.get("type").and_then(Json::as_str) != Some("luks1")
+            {
+                return Err(invalid(format!("unsupported keyslot {}", id)));
+            }
+
+            let kdf_type = get_str(kdf, "type")?;
+            let variant = match &kdf_type[..] {
+                "argon2i" => Some(Argon2Variant::Argon2i),
+                "argon2id" => Some(Argon2Variant::Argon2id),
+                _ => None,
+            };
+            let kdf = match variant {
+                Some(variant) => Kdf::Argon2 {
+                    variant,
+                    params: Argon2Params {
+                        time: get_u64(kdf, "time")? as u32,
+                        memory: get_u64(kdf, "memory")? as u32,
+                        lanes: get_u64(kdf, "cpus")? as u32,
+                    },
+                    salt: get_b64(kdf, "salt")?,
+                },
+                None if kdf_type == "pbkdf2" => Kdf::Pbkdf2 {
+                    hash: get_hash(kdf, "hash")?,
+                    iterations: get_u64(kdf, "iterations")? as u32,
+                    salt: get_b64(kdf, "salt")?,
+                },
+                None => return Err(invalid(format!("unsupported kdf {}", kdf_type))),
+            };
+
+            keyslots.push(Keyslot {
+                id: id.parse().map_err(|_| invalid(format!("invalid keyslot {}", id)))?,
+                priority: keyslot
+                    .get("priority")
+                    .and_then(Json::as_u64)
+                    .unwrap_or(1) as u32,
+                key_size: get_u64(keyslot, "key_size")? as usize,
+                kdf,
+                area_offset: get_u64(area, "offset")?,
+                area_encryption: get_str(area, "encryption")?,
+                area_key_size: get_u64(area, "key_size")? as usize,
+                af_stripes: get_u64(af, "stripes")? as u32,
+                af_hash: get_hash(af, "hash")?,
+            });
+        }
+
+        let mut digests = Vec::new();
+        for digest in json
+            .get("digests")
+            .and_then(Json::as_object)
+            .ok_or_else(|| invalid("missing digests"))?
+            .values()
+        {
+            if digest.get("type").and_then(Json::as_str) != Some("pbkdf2") {
+                return Err(invalid("unsupported digest type"));
+            }
+            let segments: Vec<u32> = get_ids(digest, "segments")?;
+            if !segments.contains(&0) {
+                continue;
+            }
+            digests.push(LuksDigest {
+                keyslots: get_ids(digest, "keyslots")?,
+                hash: get_hash(digest, "hash")?,
+                iterations: get_u64(digest, "iterations")? as u32,
+                salt: get_b64(digest, "salt")?,
+                digest: get_b64(digest, "digest")?,
+            });
+        }
+
+        Ok(Self {
+            version: 2,
+            uuid,
+            keyslots,
+            digests,
+            segment,
+            flags,
+        })
+    }
+
+    /// LUKS version (1 or 2).
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Volume UUID.
+    pub fn uuid(&self) -> &str {
+        &self.uuid[..]
+    }
+
+    /// Active keyslots.
+    pub fn keyslots(&self) -> &[Keyslot] {
+        &self.keyslots[..]
+    }
+
+    /// Encrypted data segment.
+    pub fn segment(&self) -> &Segment {
+        &self.segment
+    }
+
+    /// Persistent activation flags (LUKS2 only), e.g. `allow-discards`.
+    pub fn flags(&self) -> &[String] {
+        &self.flags[..]
+    }
+
+    /// Digests that can verify a volume key recovered from the given keyslot.
+    pub(super) fn digests_for(&self, keyslot: u32) -> impl Iterator<Item = &LuksDigest> {
+        self.digests
+            .iter()
+            .filter(move |d| d.keyslots.contains(&keyslot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestImage;
+
+    // See testdata/make-images.py
+    const LUKS1: &[u8] = include_bytes!("testdata/luks1.img");
+    const LUKS2: &[u8] = include_bytes!("testdata/luks2.img");
+
+    #[test]
+    fn luks1() {
+        let image = TestImage::new(LUKS1);
+        let header = LuksHeader::read(image.file()).unwrap();
+        assert_eq!(header.version(), 1);
+        assert_eq!(header.uuid(), "6a0b6e4c-2f57-4d0a-9c61-3e8f1b2d7a90");
+        assert!(header.flags().is_empty());
+
+        let segment = header.segment();
+        assert_eq!(segment.offset(), 4096 * 512);
+        assert_eq!(segment.size(), None);
+        assert_eq!(segment.iv_tweak(), 0);
+        assert_eq!(segment.encryption(), "aes-cbc-essiv:sha256");
+        assert_eq!(segment.sector_size(), 512);
+
+        // Only the first of the 8 keyslots is active
+        let [keyslot] = header.keyslots() else {
+            panic!("expected a single keyslot");
+        };
+        assert_eq!(keyslot.id, 0);
+        assert_eq!(keyslot.key_size, 32);
+        assert!(matches!(
+            keyslot.kdf,
+            Kdf::Pbkdf2 {
+                hash: HashAlg::Sha1,
+                iterations: 1000,
+                ..
+            }
+        ));
+        assert_eq!(keyslot.area_offset, 8 * 512);
+        assert_eq!(keyslot.area_encryption, "aes-cbc-essiv:sha256");
+        assert_eq!(keyslot.af_stripes, 4000);
+        assert_eq!(keyslot.af_hash, HashAlg::Sha1);
+
+        let [digest] = &header.digests[..] else {
+            panic!("expected a single digest");
+        };
+        assert_eq!(digest.keyslots, [0]);
+        assert_eq!(digest.iterations, 1000);
+        assert_eq!(digest.digest.len(), 20);
+    }
+
+    #[test]
+    fn luks2() {
+        let image = TestImage::new(LUKS2);
+        let header = LuksHeader::read(image.file()).unwrap();
+        assert_eq!(header.version(), 2);
+        assert_eq!(header.uuid(), "d3f1c2a4-8b6e-4f0d-a1c3-5e7b9d2f4a68");
+        assert_eq!(header.flags(), ["allow-discards"]);
+
+        let segment = header.segment();
+        assert_eq!(segment.offset(), 16 * 1024 * 1024);
+        assert_eq!(segment.size(), None);
+        assert_eq!(segment.encryption(), "aes-xts-plain64");
+        assert_eq!(segment.sector_size(), 512);
+
+        let [argon2, pbkdf2] = header.keyslots() else {
+            panic!("expected two keyslots");
+        };
+        assert_eq!(argon2.id, 0);
+        assert_eq!(
+            argon2.kdf,
+            Kdf::Argon2 {
+                variant: Argon2Variant::Argon2id,
+                params: Argon2Params {
+                    time: 4,
+                    memory: 1024,
+                    lanes: 2,
+                },
+                // Base64 with an escaped slash in the JSON
+                salt: base64_decode("bAd26RHT3TUqmzAmmvb9aW/QqrrpXraIxxa34L8wzMw=").unwrap(),
+            }
+        );
+        assert_eq!(argon2.area_offset, 32768);
+        assert_eq!(argon2.af_hash, HashAlg::Sha256);
+        assert_eq!(pbkdf2.id, 1);
+        assert!(matches!(
+            pbkdf2.kdf,
+            Kdf::Pbkdf2 {
+                hash: HashAlg::Sha512,
+                iterations: 1000,
+                ..
+            }
+        ));
+        assert_eq!(pbkdf2.area_offset, 163840);
+        assert_eq!(pbkdf2.area_key_size, 32);
+        assert_eq!(pbkdf2.af_hash, HashAlg::Sha512);
+
+        assert_eq!(header.digests_for(0).count(), 1);
+        assert_eq!(header.digests_for(1).count(), 1);
+        assert_eq!(header.digests_for(2).count(), 0);
+    }
+
+    #[test]
+    fn luks2_secondary_header() {
+        let expected = LuksHeader::read(TestImage::new(LUKS2).file()).unwrap();
+
+        // Corrupt the primary JSON area: the secondary header is used instead
+        let mut data = LUKS2.to_vec();
+        data[LUKS2_BINARY_HEADER_SIZE + 2] ^= 0xff;
+        let image = TestImage::new(&data);
+        assert_eq!(LuksHeader::read(image.file()).unwrap(), expected);
+
+        // Only the secondary header left
+        data[..LUKS2_BINARY_HEADER_SIZE].fill(0);
+        data[..LUKS2_MAGIC_SECONDARY.len()].copy_from_slice(LUKS2_MAGIC_SECONDARY);
+        data[6..8].copy_from_slice(&2u16.to_be_bytes());
+        let image = TestImage::new(&data);
+        assert_eq!(LuksHeader::read(image.file()).unwrap(), expected);
+
+        // Both corrupt
+        data[0x4000 + LUKS2_BINARY_HEADER_SIZE + 2] ^= 0xff;
+        let image = TestImage::new(&data);
+        assert!(LuksHeader::read(image.file()).is_err());
+    }
+
+    #[test]
+    fn not_luks() {
+        let image = TestImage::new(&[0; 4096]);
+        assert!(LuksHeader::read(image.file()).is_err());
+
+        let mut data = LUKS1.to_vec();
+        data[6..8].copy_from_slice(&3u16.to_be_bytes());
+        let image = TestImage::new(&data);
+        assert!(LuksHeader::read(image.file()).is_err());
+
+        // Too short to hold a header
+        let image = TestImage::new(&LUKS1[..100]);
+        assert!(LuksHeader::read(image.file()).is_err());
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_decode(""), Some(vec![]));
+        assert_eq!(base64_decode("Zg=="), Some(b"f".to_vec()));
+        assert_eq!(base64_decode("Zm8="), Some(b"fo".to_vec()));
+        assert_eq!(base64_decode("Zm9v"), Some(b"foo".to_vec()));
+        assert_eq!(base64_decode("+/+/"), Some(vec![0xfb, 0xff, 0xbf]));
+        assert_eq!(base64_decode("Zm9vY"), None);
+        assert_eq!(base64_decode("Zm9v!A=="), None);
+    }
+}
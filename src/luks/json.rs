@@ -0,0 +1,342 @@
+//! Minimal JSON parser for the LUKS2 metadata area.
+//!
+//! LUKS2 stores 64-bit values as strings and everything else as small integers, so numbers
+//! are kept as their textual representation and only converted on access.
+
+use std::collections::BTreeMap;
+
+/// JSON value.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Json {
+    /// `null`.
+    Null,
+
+    /// `true` or `false`.
+    Bool(bool),
+
+    /// Number, as written in the source text.
+    Number(String),
+
+    /// String.
+    String(String),
+
+    /// Array.
+    Array(Vec<Json>),
+
+    /// Object. Duplicate keys keep the last value.
+    Object(BTreeMap<String, Json>),
+}
+impl Json {
+    /// Parse `text` as a single JSON value. Trailing NUL bytes (the padding of the LUKS2
+    /// metadata area) are ignored. Returns `None` on malformed input.
+    pub fn parse(text: &[u8]) -> Option<Self> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value(0)?;
+        parser.skip_ws();
+        if text[parser.pos..].iter().all(|b| *b == 0) {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Get the value of `key`, if `self` is an object containing it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(obj) => obj.get(key),
+            _ => None,
+        }
+    }
+
+    /// Get the entries of `self`, if it's an object.
+    pub fn as_object(&self) -> Option<&BTreeMap<String, Json>> {
+        match self {
+            Json::Object(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// Get the elements of `self`, if it's an array.
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(arr) => Some(&arr[..]),
+            _ => None,
+        }
+    }
+
+    /// Get `self` as a string slice, if it's a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(&s[..]),
+            _ => None,
+        }
+    }
+
+    /// Get `self` as an unsigned integer, if it's either a number or a string containing
+    /// one (as used by LUKS2 for 64-bit values).
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) | Json::String(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+// Maximum nesting depth, to avoid overflowing the stack with malicious input
+const MAX_DEPTH: usize = 32;
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, lit: &[u8]) -> Option<()> {
+        self.text[self.pos..].starts_with(lit).then(|| {
+            self.pos += lit.len();
+        })
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Json> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        self.skip_ws();
+        match self.peek()? {
+            b'n' => self.expect(b"null").map(|_| Json::Null),
+            b't' => self.expect(b"true").map(|_| Json::Bool(true)),
+            b'f' => self.expect(b"false").map(|_| Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.pos += 1;
+                let mut arr = Vec::new();
+                self.skip_ws();
+                if self.peek()? == b']' {
+                    self.pos += 1;
+                    return Some(Json::Array(arr));
+                }
+                loop {
+                    arr.push(self.value(depth + 1)?);
+                    self.skip_ws();
+                    match self.next()? {
+                        b',' => continue,
+                        b']' => return Some(Json::Array(arr)),
+                        _ => return None,
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut obj = BTreeMap::new();
+                self.skip_ws();
+                if self.peek()? == b'}' {
+                    self.pos += 1;
+                    return Some(Json::Object(obj));
+                }
+                loop {
+                    self.skip_ws();
+                    let key = self.string()?;
+                    self.skip_ws();
+                    if self.next()? != b':' {
+                        return None;
+                    }
+                    obj.insert(key, self.value(depth + 1)?);
+                    self.skip_ws();
+                    match self.next()? {
+                        b',' => continue,
+                        b'}' => return Some(Json::Object(obj)),
+                        _ => return None,
+                    }
+                }
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = self.pos;
+                while matches!(
+                    self.peek(),
+                    Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                ) {
+                    self.pos += 1;
+                }
+                let n = std::str::from_utf8(&self.text[start..self.pos]).ok()?;
+                Some(Json::Number(n.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u16> {
+        let hex = self.text.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        u16::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.next()? != b'"' {
+            return None;
+        }
+        let mut out = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    let c = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hi = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&hi) {
+                                // Surrogate pair
+                                self.expect(b"\\u")?;
+                                let lo = self.hex4()?;
+                                char::decode_utf16([hi, lo]).next()?.ok()?
+                            } else {
+                                char::from_u32(hi as u32)?
+                            }
+                        }
+                        _ => return None,
+                    };
+                    let mut buf = [0u8; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                b if b < 0x20 => return None,
+                b => out.push(b),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Option<Json> {
+        Json::parse(text.as_bytes())
+    }
+
+    fn string(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    #[test]
+    fn scalars() {
+        assert_eq!(parse("null"), Some(Json::Null));
+        assert_eq!(parse(" true "), Some(Json::Bool(true)));
+        assert_eq!(parse("\tfalse\r\n"), Some(Json::Bool(false)));
+        assert_eq!(parse("\"luks2\""), Some(string("luks2")));
+        assert_eq!(parse("-1.5e3"), Some(Json::Number("-1.5e3".to_string())));
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("nul"), None);
+        assert_eq!(parse("True"), None);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            parse(r#""\"\\\/\b\f\n\r\t""#),
+            Some(string("\"\\/\u{8}\u{c}\n\r\t"))
+        );
+        assert_eq!(parse(r#""caf\u00e9 \u00C9""#), Some(string("café É")));
+        assert_eq!(parse(r#""\ud83d\udd11""#), Some(string("\u{1f511}")));
+
+        // Unknown escapes, truncated and lone surrogates, raw control characters
+        for bad in [
+            r#""\x41""#,
+            r#""\u00e""#,
+            r#""\ud83d""#,
+            r#""\ud83dx""#,
+            r#""\udd11""#,
+            "\"a\nb\"",
+            "\"unterminated",
+        ] {
+            assert_eq!(parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn nesting() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_some());
+        assert_eq!(parse(&nested(MAX_DEPTH + 2)), None);
+
+        let objects = |depth: usize| format!("{}1{}", "{\"a\":".repeat(depth), "}".repeat(depth));
+        assert!(parse(&objects(MAX_DEPTH)).is_some());
+        assert_eq!(parse(&objects(MAX_DEPTH + 1)), None);
+
+        // Deep enough to overflow the stack without the limit
+        assert_eq!(parse(&nested(1_000_000)), None);
+    }
+
+    #[test]
+    fn containers() {
+        let json = parse(r#"{"a": [1, "2", {}], "b": {}, "a": []}"#).unwrap();
+        assert_eq!(json.as_object().unwrap().len(), 2);
+        // Duplicate keys keep the last value
+        assert_eq!(json.get("a").and_then(Json::as_array), Some(&[][..]));
+        assert_eq!(json.get("b"), Some(&Json::Object(BTreeMap::new())));
+        assert_eq!(json.get("c"), None);
+        assert_eq!(string("a").get("a"), None);
+
+        for bad in [
+            "[1,]",
+            "[1 2]",
+            "{\"a\" 1}",
+            "{\"a\":1,}",
+            "{1:1}",
+            "[",
+            "{",
+        ] {
+            assert_eq!(parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn numbers_as_strings() {
+        // LUKS2 writes 64-bit values as strings
+        let json = parse(
+            r#"{"offset": "16777216", "size": "dynamic", "max": "18446744073709551615",
+                "over": "18446744073709551616", "neg": -1, "iterations": 1000}"#,
+        )
+        .unwrap();
+        assert_eq!(json.get("offset").and_then(Json::as_u64), Some(16777216));
+        assert_eq!(json.get("max").and_then(Json::as_u64), Some(u64::MAX));
+        assert_eq!(json.get("iterations").and_then(Json::as_u64), Some(1000));
+        assert_eq!(json.get("size").and_then(Json::as_u64), None);
+        assert_eq!(json.get("over").and_then(Json::as_u64), None);
+        assert_eq!(json.get("neg").and_then(Json::as_u64), None);
+        assert_eq!(json.get("offset").and_then(Json::as_str), Some("16777216"));
+        assert_eq!(json.get("iterations").and_then(Json::as_str), None);
+    }
+
+    #[test]
+    fn padding() {
+        // The metadata area is padded with NUL bytes up to its size
+        let mut area = b"{\"keyslots\": {}}\n".to_vec();
+        area.resize(12288, 0);
+        assert!(Json::parse(&area).is_some());
+
+        area[100] = b'x';
+        assert_eq!(Json::parse(&area), None);
+        assert_eq!(parse("{} {}"), None);
+    }
+}
@@ -0,0 +1,196 @@
+//! Recovering the volume key from a keyslot.
+//!
+//! A keyslot holds the volume key split into `stripes` pieces by the anti-forensic (AF)
+//! splitter, encrypted with a key derived from the passphrase. Decrypting the keyslot area
+//! and merging the pieces back yields a candidate volume key, which is then checked against
+//! the header's digest.
+
+use super::header::{Kdf, Keyslot, LuksHeader};
+use crate::{
+    crypto::{argon2::argon2, cipher::SectorCipher, zeroize, HashAlg, Secret},
+    PROGRAM_NAME,
+};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{fs::File, os::unix::fs::FileExt};
+
+// Upper bound on the size of a keyslot area, to prevent a corrupt header from making us
+// allocate (and decrypt) huge amounts of memory
+const KEYSLOT_AREA_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+// AF diffusion: hash each digest-sized chunk of `block`, prefixed by its index.
+fn diffuse(hash: HashAlg, block: &mut [u8]) {
+    let digest_size = hash.output_size();
+    for (i, chunk) in block.chunks_mut(digest_size).enumerate() {
+        let mut digest = hash.digest(&[&(i as u32).to_be_bytes(), chunk]);
+        chunk.copy_from_slice(&digest[..chunk.len()]);
+        zeroize(&mut digest[..]);
+    }
+}
+
+// AF merge: recover the key from its `stripes` pieces.
+fn af_merge(hash: HashAlg, split: &[u8], key_size: usize, stripes: usize) -> Secret {
+    let mut key = Secret::new(key_size);
+    for (i, stripe) in split.chunks_exact(key_size).take(stripes).enumerate() {
+        key.iter_mut().zip(stripe).for_each(|(k, s)| *k ^= s);
+        if i + 1 < stripes {
+            diffuse(hash, &mut key);
+        }
+    }
+    key
+}
+
+impl Keyslot {
+    /// Derive the keyslot key from `passphrase`.
+    fn derive_key(&self, passphrase: &[u8]) -> Result<Secret, PrintableErrno<String>> {
+        let mut key = Secret::new(self.area_key_size);
+        match &self.kdf {
+            Kdf::Pbkdf2 {
+                hash,
+                iterations,
+                salt,
+            } => hash.pbkdf2(passphrase, salt, *iterations, &mut key),
+            Kdf::Argon2 {
+                variant,
+                params,
+                salt,
+            } => argon2(*variant, *params, passphrase, salt, &mut key)?,
+        }
+        Ok(key)
+    }
+
+    /// Try to recover the volume key with `passphrase`. The key is not verified.
+    fn recover(&self, dev: &File, passphrase: &[u8]) -> Result<Secret, PrintableErrno<String>> {
+        let split_size = self.key_size.saturating_mul(self.af_stripes as usize);
+        let area_size = (split_size + 511) & !511;
+        if self.key_size == 0 || self.af_stripes == 0 || area_size > KEYSLOT_AREA_MAX_SIZE {
+            return Err(printable_error(
+                PROGRAM_NAME,
+                format!("invalid LUKS keyslot {}", self.id),
+            ));
+        }
+
+        let key = self.derive_key(passphrase)?;
+        let cipher = SectorCipher::new(&self.area_encryption, &key)?;
+        let mut split = Secret::new(area_size);
+        dev.read_exact_at(&mut split, self.area_offset)
+            .map_err(|io| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to read LUKS keyslot {}: {}", self.id, io),
+                )
+            })?;
+        cipher.decrypt(&mut split, 0);
+        Ok(af_merge(
+            self.af_hash,
+            &split[..split_size],
+            self.key_size,
+            self.af_stripes as usize,
+        ))
+    }
+}
+
+impl LuksHeader {
+    /// Try to recover the volume key of `dev` with `passphrase`, trying every keyslot in
+    /// order of priority (keyslots with priority `0` are ignored, as `cryptsetup` does).
+    /// Returns the number of the keyslot that was unlocked and the volume key, or `None` if
+    /// the passphrase doesn't unlock any keyslot.
+    pub fn unlock(
+        &self,
+        dev: &File,
+        passphrase: &[u8],
+    ) -> Result<Option<(u32, Secret)>, PrintableErrno<String>> {
+        let mut keyslots: Vec<_> = self.keyslots().iter().filter(|ks| ks.priority > 0).collect();
+        keyslots.sort_by_key(|ks| std::cmp::Reverse(ks.priority));
+
+        for keyslot in keyslots {
+            let key = keyslot.recover(dev, passphrase)?;
+            for digest in self.digests_for(keyslot.id) {
+                let mut check = vec![0; digest.digest.len()];
+                digest
+                    .hash
+                    .pbkdf2(&key, &digest.salt, digest.iterations, &mut check);
+                if check == digest.digest {
+                    return Ok(Some((keyslot.id, key)));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::from_hex, util::TestImage};
+
+    // See testdata/make-images.py
+    const LUKS1: &[u8] = include_bytes!("testdata/luks1.img");
+    const LUKS2: &[u8] = include_bytes!("testdata/luks2.img");
+    const LUKS1_KEY: &str = "2f9d55ccbb06034edef4aee54eeba5d607cf0da943837d3acd58c69a7939638c";
+    const LUKS2_KEY: &str = "b89640d96f1f06955dbd57e07d937f6a93fedbadfeab4839569b61eba86392f1";
+
+    fn unlock(data: &[u8], passphrase: &[u8]) -> Option<(u32, Vec<u8>)> {
+        let image = TestImage::new(data);
+        let header = LuksHeader::read(image.file()).unwrap();
+        header
+            .unlock(image.file(), passphrase)
+            .unwrap()
+            .map(|(keyslot, key)| (keyslot, key.to_vec()))
+    }
+
+    #[test]
+    fn luks1_pbkdf2() {
+        assert_eq!(
+            unlock(LUKS1, b"luks1 passphrase"),
+            Some((0, from_hex(LUKS1_KEY)))
+        );
+        assert_eq!(unlock(LUKS1, b"luks1 passphrasf"), None);
+        assert_eq!(unlock(LUKS1, b""), None);
+    }
+
+    #[test]
+    fn luks2_argon2id() {
+        assert_eq!(
+            unlock(LUKS2, b"argon2id passphrase"),
+            Some((0, from_hex(LUKS2_KEY)))
+        );
+    }
+
+    #[test]
+    fn luks2_pbkdf2() {
+        assert_eq!(
+            unlock(LUKS2, b"pbkdf2 passphrase"),
+            Some((1, from_hex(LUKS2_KEY)))
+        );
+        assert_eq!(unlock(LUKS2, b"luks1 passphrase"), None);
+    }
+
+    #[test]
+    fn truncated_keyslot() {
+        let image = TestImage::new(&LUKS1[..LUKS1.len() - 512]);
+        let header = LuksHeader::read(image.file()).unwrap();
+        assert!(header.unlock(image.file(), b"luks1 passphrase").is_err());
+    }
+
+    #[test]
+    fn af_roundtrip() {
+        // Splitting as cryptsetup does: every stripe but the last is random, the last one
+        // is the key XORed with the diffused result of the others
+        let key: Vec<u8> = (0..20u8).collect();
+        let stripes = 5;
+        let mut split: Vec<u8> = (0..key.len() * stripes).map(|i| (i * 7) as u8).collect();
+        let mut d = vec![0u8; key.len()];
+        for stripe in split.chunks(key.len()).take(stripes - 1) {
+            d.iter_mut().zip(stripe).for_each(|(d, s)| *d ^= s);
+            diffuse(HashAlg::Sha256, &mut d);
+        }
+        let last = split.len() - key.len();
+        for (s, (d, k)) in split[last..].iter_mut().zip(d.iter().zip(&key)) {
+            *s = d ^ k;
+        }
+        assert_eq!(
+            &af_merge(HashAlg::Sha256, &split, key.len(), stripes)[..],
+            &key[..]
+        );
+    }
+}
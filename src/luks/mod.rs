@@ -0,0 +1,465 @@
+//! LUKS encrypted volumes.
+//!
+//! Volumes to unlock are specified through the `rd.luks.*` boot-time parameters, following
+//! the semantics of `systemd-cryptsetup-generator(8)` and `dracut.cmdline(7)`:
+//!
+//! - `rd.luks=<BOOL>` enables (default) or disables unlocking LUKS volumes altogether.
+//! - `rd.luks.uuid=[luks-]<UUID>` unlocks the volume with the given UUID as
+//!   `/dev/mapper/luks-<UUID>`.
+//! - `rd.luks.name=<UUID>=<NAME>` unlocks the volume with the given UUID as
+//!   `/dev/mapper/<NAME>`.
+//! - `rd.luks.options=[<UUID>=]<OPTIONS>` sets the (comma-separated) options used to
+//!   unlock the volume with the given UUID. If no UUID is given, the options apply to all
+//!   volumes without options of their own.
+//!
+//! The `luks.*` variants are accepted as well. If no volume is listed through
+//! `rd.luks.uuid` or `rd.luks.name`, every LUKS volume found is unlocked as
+//! `/dev/mapper/luks-<UUID>`, as with dracut and systemd. Additionally, if the root partition
+//! is found to be a LUKS volume (e.g. through GPT partition autodiscovery) it's unlocked as
+//! `/dev/mapper/root`, unless explicitly named.
+//!
+//! Unlocking is done natively, without `cryptsetup`: the LUKS1/LUKS2 header is parsed, the
+//! volume key is recovered from a keyslot with the passphrase read from the console, and
+//! the `dm-crypt` device is set up through device-mapper ioctls.
+
+pub mod header;
+mod json;
+mod keyslot;
+
+use crate::{
+    blkid::BlockDevice,
+    dm::{DmControl, DmTarget},
+    early_logging::{buf::KmsgBuf, KConsole},
+    module::ModLoading,
+    prompt::Prompter,
+    PROGRAM_NAME,
+};
+use header::LuksHeader;
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{collections::BTreeMap, fmt::Write, fs::File};
+
+// Number of passphrase attempts if tries= isn't specified (as cryptsetup)
+const DEFAULT_TRIES: u32 = 3;
+
+/// Options used to unlock a LUKS volume.
+///
+/// Supported options (see `crypttab(5)`):
+///
+/// - `discard` (or `allow-discards`): allow discard requests to be passed through.
+/// - `same-cpu-crypt`, `submit-from-crypt-cpus`, `no-read-workqueue`,
+///   `no-write-workqueue`: `dm-crypt` performance options.
+/// - `read-only` (or `readonly`): set up a read-only mapping.
+/// - `tries=<N>`: number of passphrase attempts. `0` means unlimited.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LuksOptions {
+    discard: bool,
+    same_cpu_crypt: bool,
+    submit_from_crypt_cpus: bool,
+    no_read_workqueue: bool,
+    no_write_workqueue: bool,
+    read_only: bool,
+    tries: Option<u32>,
+}
+impl LuksOptions {
+    /// Parse a comma-separated list of options. Unknown (or invalid) options are returned
+    /// alongside the result.
+    pub fn parse(opts: &str) -> (Self, Vec<&str>) {
+        let mut res = Self::default();
+        let mut unknown = Vec::new();
+        for opt in opts.split(',').filter(|o| !o.is_empty()) {
+            match opt.split_once('=') {
+                None if opt == "discard" || opt == "allow-discards" => res.discard = true,
+                None if opt == "same-cpu-crypt" => res.same_cpu_crypt = true,
+                None if opt == "submit-from-crypt-cpus" => res.submit_from_crypt_cpus = true,
+                None if opt == "no-read-workqueue" => res.no_read_workqueue = true,
+                None if opt == "no-write-workqueue" => res.no_write_workqueue = true,
+                None if opt == "read-only" || opt == "readonly" => res.read_only = true,
+                None if opt == "luks" => {}
+                Some(("tries", tries)) => match tries.parse() {
+                    Ok(tries) => res.tries = Some(tries),
+                    Err(_) => unknown.push(opt),
+                },
+                _ => unknown.push(opt),
+            }
+        }
+        (res, unknown)
+    }
+
+    /// Whether discard requests are passed through to the underlying device.
+    pub fn discard(&self) -> bool {
+        self.discard
+    }
+
+    /// Whether encryption is performed on the same CPU that submitted the request.
+    pub fn same_cpu_crypt(&self) -> bool {
+        self.same_cpu_crypt
+    }
+
+    /// Whether write requests are submitted from the encryption threads.
+    pub fn submit_from_crypt_cpus(&self) -> bool {
+        self.submit_from_crypt_cpus
+    }
+
+    /// Whether read requests bypass the `dm-crypt` workqueue.
+    pub fn no_read_workqueue(&self) -> bool {
+        self.no_read_workqueue
+    }
+
+    /// Whether write requests bypass the `dm-crypt` workqueue.
+    pub fn no_write_workqueue(&self) -> bool {
+        self.no_write_workqueue
+    }
+
+    /// Whether the mapping is read-only.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Number of passphrase attempts, if specified. `Some(0)` means unlimited.
+    pub fn tries(&self) -> Option<u32> {
+        self.tries
+    }
+
+    /// Combine these options with the persistent flags stored in a LUKS2 header (e.g.
+    /// `allow-discards`).
+    pub fn with_persistent_flags(&self, flags: &[String]) -> Self {
+        let mut res = self.clone();
+        for flag in flags {
+            match &flag[..] {
+                "allow-discards" => res.discard = true,
+                "same-cpu-crypt" => res.same_cpu_crypt = true,
+                "submit-from-crypt-cpus" => res.submit_from_crypt_cpus = true,
+                "no-read-workqueue" => res.no_read_workqueue = true,
+                "no-write-workqueue" => res.no_write_workqueue = true,
+                _ => {}
+            }
+        }
+        res
+    }
+}
+
+/// LUKS volume to unlock.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LuksTarget {
+    uuid: uuid::Uuid,
+    name: String,
+    options: LuksOptions,
+}
+impl LuksTarget {
+    /// Name of the unlocked volume in `/dev/mapper`.
+    pub fn name(&self) -> &str {
+        &self.name[..]
+    }
+
+    /// Options used to unlock the volume.
+    pub fn options(&self) -> &LuksOptions {
+        &self.options
+    }
+}
+
+/// LUKS volumes to unlock, as specified by the `rd.luks.*` boot-time parameters.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LuksTargets {
+    enabled: bool,
+    default_options: LuksOptions,
+    targets: Vec<LuksTarget>,
+}
+impl LuksTargets {
+    /// Builder: create a new [LuksTargetsBuilder].
+    pub fn builder() -> LuksTargetsBuilder {
+        Default::default()
+    }
+
+    /// Whether LUKS volumes should be unlocked at all (`rd.luks=0` disables them).
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Options used for volumes that aren't explicitly listed (e.g. the autodiscovered
+    /// root partition).
+    pub fn default_options(&self) -> &LuksOptions {
+        &self.default_options
+    }
+
+    /// Whether every LUKS volume should be unlocked, as none is explicitly listed.
+    pub fn unlocks_all(&self) -> bool {
+        self.enabled && self.targets.is_empty()
+    }
+
+    /// Get the listed volume with the given UUID, if any.
+    pub fn find(&self, uuid: uuid::Uuid) -> Option<&LuksTarget> {
+        if !self.enabled {
+            return None;
+        }
+        self.targets.iter().find(|target| target.uuid == uuid)
+    }
+}
+
+/// Builder: LUKS volumes to unlock.
+///
+/// Parameters may be given in any order (e.g. `rd.luks.options=<UUID>=...` before
+/// `rd.luks.uuid=<UUID>`), so they are only combined in [LuksTargetsBuilder::build].
+/// In case of conflicting values, the first specified value takes precedence.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LuksTargetsBuilder {
+    enabled: Option<bool>,
+    uuids: Vec<uuid::Uuid>,
+    names: BTreeMap<uuid::Uuid, String>,
+    options: BTreeMap<uuid::Uuid, String>,
+    default_options: Option<String>,
+}
+impl LuksTargetsBuilder {
+    /// Parse a LUKS UUID, optionally prefixed by `luks-` (as accepted by dracut).
+    pub fn parse_uuid(uuid: &str) -> Option<uuid::Uuid> {
+        let uuid = uuid.strip_prefix("luks-").unwrap_or(uuid);
+        uuid::Uuid::parse_str(uuid).ok()
+    }
+
+    /// Enable or disable unlocking LUKS volumes.
+    pub fn enable(&mut self, enabled: bool) -> &mut Self {
+        self.enabled.get_or_insert(enabled);
+        self
+    }
+
+    /// Unlock the volume with the given UUID.
+    pub fn uuid(&mut self, uuid: uuid::Uuid) -> &mut Self {
+        if !self.uuids.contains(&uuid) {
+            self.uuids.push(uuid);
+        }
+        self
+    }
+
+    /// Unlock the volume with the given UUID as `/dev/mapper/<name>`.
+    #[inline]
+    pub fn name<S: Into<String>>(&mut self, uuid: uuid::Uuid, name: S) -> &mut Self {
+        self._name(uuid, name.into());
+        self
+    }
+    fn _name(&mut self, uuid: uuid::Uuid, name: String) {
+        self.uuid(uuid);
+        self.names.entry(uuid).or_insert(name);
+    }
+
+    /// Set the options used to unlock the volume with the given UUID or, if `None`, all
+    /// volumes without options of their own.
+    #[inline]
+    pub fn options<S: Into<String>>(&mut self, uuid: Option<uuid::Uuid>, options: S) -> &mut Self {
+        self._options(uuid, options.into());
+        self
+    }
+    fn _options(&mut self, uuid: Option<uuid::Uuid>, options: String) {
+        match uuid {
+            Some(uuid) => {
+                self.options.entry(uuid).or_insert(options);
+            }
+            None => {
+                self.default_options.get_or_insert(options);
+            }
+        }
+    }
+
+    /// Builder: build the list of LUKS volumes to unlock. Unknown options are reported
+    /// through `kmsg_buf`.
+    pub fn build(self, kmsg_buf: &mut KmsgBuf) -> LuksTargets {
+        let mut parse_options = |options: &str| {
+            let (options, unknown) = LuksOptions::parse(options);
+            for opt in unknown {
+                kmsg_buf.kwarn(format!("unknown rd.luks.options key {}, ignoring", opt));
+            }
+            options
+        };
+
+        let default_options = parse_options(self.default_options.as_deref().unwrap_or(""));
+        let targets = self
+            .uuids
+            .iter()
+            .map(|uuid| LuksTarget {
+                uuid: *uuid,
+                name: self
+                    .names
+                    .get(uuid)
+                    .cloned()
+                    .unwrap_or_else(|| format!("luks-{}", uuid)),
+                options: match self.options.get(uuid) {
+                    Some(options) => parse_options(options),
+                    None => default_options.clone(),
+                },
+            })
+            .collect();
+        LuksTargets {
+            enabled: self.enabled.unwrap_or(true),
+            default_options,
+            targets,
+        }
+    }
+}
+
+/// Unlock the LUKS volume `device` as `/dev/mapper/<name>`.
+///
+/// The passphrase is asked for on every active console, up to `tries=` times.
+pub fn open(
+    kcon: &mut KConsole,
+    mod_loading: &ModLoading,
+    prompter: &Prompter,
+    device: &BlockDevice,
+    name: &str,
+    options: &LuksOptions,
+) -> Result<(), PrintableErrno<String>> {
+    let path = device.path();
+    kinfo!(kcon, "unlocking {} as /dev/mapper/{}", path.display(), name);
+
+    let dev = File::open(&path).map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("unable to open {}: {}", path.display(), io),
+        )
+    })?;
+    let header = LuksHeader::read(&dev)?;
+    let options = options.with_persistent_flags(header.flags());
+    load_crypt_modules(mod_loading, header.segment().encryption())?;
+
+    let tries = options.tries().unwrap_or(DEFAULT_TRIES);
+    let prompt = format!(
+        "Please enter passphrase for disk {} ({}): ",
+        path.display(),
+        name
+    );
+    let mut attempt = 0;
+    let key = loop {
+        attempt += 1;
+        let passphrase = prompter.ask_passphrase(kcon, &prompt)?;
+        match header.unlock(&dev, &passphrase)? {
+            Some((keyslot, key)) => {
+                kdebug!(kcon, "unlocked {} with keyslot {}", path.display(), keyslot);
+                break key;
+            }
+            None if tries == 0 || attempt < tries => {
+                kwarn!(kcon, "wrong passphrase for {}", path.display());
+                let message = match tries {
+                    0 => "Wrong passphrase, please try again.".to_string(),
+                    tries => format!(
+                        "Wrong passphrase, please try again ({} attempts left).",
+                        tries - attempt
+                    ),
+                };
+                prompter.show_message(kcon, &message);
+            }
+            None => {
+                prompter.show_message(kcon, "Wrong passphrase, giving up.");
+                return Err(printable_error(
+                    PROGRAM_NAME,
+                    format!(
+                        "unable to unlock {}: no key available with this passphrase after {} attempts",
+                        path.display(),
+                        attempt
+                    ),
+                ));
+            }
+        }
+    };
+
+    let target = crypt_target(&header, &key, device, &options)?;
+    let dm_uuid = format!(
+        "CRYPT-LUKS{}-{}-{}",
+        header.version(),
+        header.uuid().replace('-', ""),
+        name
+    );
+    DmControl::open()?.create_device(name, &dm_uuid, &[target], options.read_only())?;
+    Ok(())
+}
+
+/// Load `dm-crypt` and the kernel crypto modules used by the cipher specification `spec`
+/// (e.g. `aes-xts-plain64`), which would otherwise be requested by the kernel through
+/// `modprobe`.
+fn load_crypt_modules(mod_loading: &ModLoading, spec: &str) -> Result<(), PrintableErrno<String>> {
+    let mut wgs = vec![mod_loading.load_modules(&["dm_crypt".to_string()])?];
+    for alias in crypt_aliases(spec) {
+        // The algorithm may be built into the kernel
+        if let Some(wg) = mod_loading.load_modalias(alias)? {
+            wgs.push(wg);
+        }
+    }
+    wgs.into_iter().for_each(|wg| wg.wait());
+    Ok(())
+}
+
+/// Module aliases of the kernel crypto algorithms used by the cipher specification `spec`
+/// (e.g. `crypto-aes` and `crypto-xts` for `aes-xts-plain64`).
+pub fn crypt_aliases(spec: &str) -> Vec<String> {
+    // <cipher>-<mode>-<iv>[:<hash>]
+    let mut aliases = Vec::new();
+    let mut parts = spec.splitn(3, '-');
+    if let (Some(cipher), Some(mode)) = (parts.next(), parts.next()) {
+        aliases.push(format!("crypto-{}", cipher));
+        aliases.push(format!("crypto-{}", mode));
+        if let Some((iv, hash)) = parts.next().and_then(|iv| iv.split_once(':')) {
+            aliases.push(format!("crypto-{}", iv));
+            aliases.push(format!("crypto-{}", hash));
+        }
+    }
+    aliases
+}
+
+/// Build the `dm-crypt` table for the unlocked volume.
+fn crypt_target(
+    header: &LuksHeader,
+    key: &[u8],
+    device: &BlockDevice,
+    options: &LuksOptions,
+) -> Result<DmTarget, PrintableErrno<String>> {
+    let segment = header.segment();
+    let offset = segment.offset() / 512;
+    let length = match segment.size() {
+        Some(size) => size / 512,
+        None => device.size().saturating_sub(offset),
+    };
+    if length == 0 {
+        return Err(printable_error(
+            PROGRAM_NAME,
+            format!("LUKS volume {} is empty", device.path().display()),
+        ));
+    }
+
+    let mut extra = Vec::new();
+    if options.discard() {
+        extra.push("allow_discards".to_string());
+    }
+    if options.same_cpu_crypt() {
+        extra.push("same_cpu_crypt".to_string());
+    }
+    if options.submit_from_crypt_cpus() {
+        extra.push("submit_from_crypt_cpus".to_string());
+    }
+    if options.no_read_workqueue() {
+        extra.push("no_read_workqueue".to_string());
+    }
+    if options.no_write_workqueue() {
+        extra.push("no_write_workqueue".to_string());
+    }
+    if segment.sector_size() > 512 {
+        extra.push(format!("sector_size:{}", segment.sector_size()));
+        extra.push("iv_large_sectors".to_string());
+    }
+
+    // <cipher> <key> <iv_offset> <device> <offset> [<#opt_params> <opt_params>]
+    // Allocated upfront so that the key isn't left behind by a reallocation.
+    let (major, minor) = device.devnum();
+    let mut params = String::with_capacity(segment.encryption().len() + key.len() * 2 + 512);
+    let _ = write!(params, "{} ", segment.encryption());
+    for b in key {
+        let _ = write!(params, "{:02x}", b);
+    }
+    let _ = write!(
+        params,
+        " {} {}:{} {}",
+        segment.iv_tweak(),
+        major,
+        minor,
+        offset
+    );
+    if !extra.is_empty() {
+        let _ = write!(params, " {} {}", extra.len(), extra.join(" "));
+    }
+    Ok(DmTarget::new(0, length, "crypt", params))
+}
@@ -0,0 +1,247 @@
+#!/usr/bin/env python3
+"""Generate the LUKS images used by the header and keyslot tests.
+
+The images follow the layout written by cryptsetup 2.x, truncated after the last keyslot
+area. They are built with the `cryptography` package (instead of ignited's own primitives)
+from a fixed seed, so the output is reproducible. Equivalent cryptsetup commands:
+
+luks1.img:
+    cryptsetup luksFormat --type luks1 --cipher aes-cbc-essiv:sha256 --key-size 256 \
+        --hash sha1 --pbkdf-force-iterations 1000 --uuid <LUKS1_UUID> \
+        --volume-key-file <LUKS1_KEY> luks1.img  # passphrase: LUKS1_PASSPHRASE
+
+luks2.img:
+    cryptsetup luksFormat --type luks2 --cipher aes-xts-plain64 --key-size 256 \
+        --pbkdf argon2id --pbkdf-force-iterations 4 --pbkdf-memory 1024 \
+        --pbkdf-parallel 2 --uuid <LUKS2_UUID> \
+        --volume-key-file <LUKS2_KEY> luks2.img  # passphrase: ARGON2_PASSPHRASE
+    cryptsetup luksAddKey --pbkdf pbkdf2 --hash sha512 --pbkdf-force-iterations 1000 \
+        luks2.img  # new passphrase: PBKDF2_PASSPHRASE
+    cryptsetup refresh --persistent --allow-discards
+"""
+
+import base64
+import hashlib
+import random
+import struct
+import sys
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+from cryptography.hazmat.primitives.kdf.argon2 import Argon2id
+
+LUKS1_UUID = "6a0b6e4c-2f57-4d0a-9c61-3e8f1b2d7a90"
+LUKS1_KEY = hashlib.sha256(b"ignited luks1 volume key").digest()
+LUKS1_PASSPHRASE = b"luks1 passphrase"
+
+LUKS2_UUID = "d3f1c2a4-8b6e-4f0d-a1c3-5e7b9d2f4a68"
+LUKS2_KEY = hashlib.sha256(b"ignited luks2 volume key").digest()
+ARGON2_PASSPHRASE = b"argon2id passphrase"
+PBKDF2_PASSPHRASE = b"pbkdf2 passphrase"
+
+STRIPES = 4000
+SECTOR = 512
+
+rng = random.Random(0x1A1)
+
+
+def randbytes(n):
+    return bytes(rng.getrandbits(8) for _ in range(n))
+
+
+def diffuse(hash_name, block):
+    size = hashlib.new(hash_name).digest_size
+    out = b""
+    for i in range(0, len(block), size):
+        chunk = block[i : i + size]
+        digest = hashlib.new(hash_name, struct.pack(">I", i // size) + chunk).digest()
+        out += digest[: len(chunk)]
+    return out
+
+
+def af_split(hash_name, key):
+    split = b""
+    d = bytes(len(key))
+    for _ in range(STRIPES - 1):
+        stripe = randbytes(len(key))
+        split += stripe
+        d = diffuse(hash_name, bytes(a ^ b for a, b in zip(d, stripe)))
+    return split + bytes(a ^ b for a, b in zip(d, key))
+
+
+def encrypt(spec, key, data):
+    out = b""
+    for sector in range(len(data) // SECTOR):
+        block = data[sector * SECTOR : (sector + 1) * SECTOR]
+        if spec == "aes-xts-plain64":
+            mode = modes.XTS(sector.to_bytes(16, "little"))
+        elif spec == "aes-cbc-essiv:sha256":
+            essiv = Cipher(algorithms.AES(hashlib.sha256(key).digest()), modes.ECB())
+            mode = modes.CBC(essiv.encryptor().update(sector.to_bytes(16, "little")))
+        else:
+            raise ValueError(spec)
+        enc = Cipher(algorithms.AES(key), mode).encryptor()
+        out += enc.update(block) + enc.finalize()
+    return out
+
+
+def keyslot_area(spec, keyslot_key, af_hash, volume_key):
+    split = af_split(af_hash, volume_key)
+    split += bytes(-len(split) % SECTOR)
+    return encrypt(spec, keyslot_key, split)
+
+
+def pad(raw, size):
+    return raw + bytes(size - len(raw))
+
+
+def luks1():
+    key_size = len(LUKS1_KEY)
+    digest_salt = randbytes(32)
+    digest = hashlib.pbkdf2_hmac("sha1", LUKS1_KEY, digest_salt, 1000, 20)
+    area_sectors = (key_size * STRIPES + 4095) // 4096 * 8
+
+    hdr = b"LUKS\xba\xbe" + struct.pack(">H", 1)
+    hdr += pad(b"aes", 32) + pad(b"cbc-essiv:sha256", 32) + pad(b"sha1", 32)
+    hdr += struct.pack(">II", 4096, key_size)
+    hdr += digest + digest_salt + struct.pack(">I", 1000)
+    hdr += pad(LUKS1_UUID.encode(), 40)
+
+    salt = randbytes(32)
+    area = keyslot_area(
+        "aes-cbc-essiv:sha256",
+        hashlib.pbkdf2_hmac("sha1", LUKS1_PASSPHRASE, salt, 1000, key_size),
+        "sha1",
+        LUKS1_KEY,
+    )
+    for i in range(8):
+        offset = 8 + i * area_sectors
+        if i == 0:
+            hdr += struct.pack(">II", 0x00AC71F3, 1000) + salt
+        else:
+            hdr += struct.pack(">II", 0x0000DEAD, 0) + bytes(32)
+        hdr += struct.pack(">II", offset, STRIPES)
+
+    return pad(hdr, 8 * SECTOR) + area
+
+
+def json_text(value):
+    # cryptsetup writes compact JSON with json-c, which escapes forward slashes
+    if isinstance(value, dict):
+        items = ",".join(f"{json_text(k)}:{json_text(v)}" for k, v in value.items())
+        return "{" + items + "}"
+    if isinstance(value, list):
+        return "[" + ",".join(json_text(v) for v in value) + "]"
+    if isinstance(value, int):
+        return str(value)
+    return '"' + value.replace("/", "\\/") + '"'
+
+
+def b64(raw):
+    return base64.b64encode(raw).decode()
+
+
+def luks2():
+    hdr_size = 16384
+    key_size = len(LUKS2_KEY)
+    area_size = (key_size * STRIPES + 4095) // 4096 * 4096
+    offsets = [2 * hdr_size, 2 * hdr_size + area_size]
+
+    argon2_salt = randbytes(32)
+    argon2_key = Argon2id(
+        salt=argon2_salt, length=key_size, iterations=4, lanes=2, memory_cost=1024
+    ).derive(ARGON2_PASSPHRASE)
+    pbkdf2_salt = randbytes(32)
+    pbkdf2_key = hashlib.pbkdf2_hmac("sha512", PBKDF2_PASSPHRASE, pbkdf2_salt, 1000, key_size)
+    areas = [
+        keyslot_area("aes-xts-plain64", argon2_key, "sha256", LUKS2_KEY),
+        keyslot_area("aes-xts-plain64", pbkdf2_key, "sha512", LUKS2_KEY),
+    ]
+    digest_salt = randbytes(32)
+
+    def area(i):
+        return {
+            "type": "raw",
+            "offset": str(offsets[i]),
+            "size": str(area_size),
+            "encryption": "aes-xts-plain64",
+            "key_size": key_size,
+        }
+
+    metadata = {
+        "keyslots": {
+            "0": {
+                "type": "luks2",
+                "key_size": key_size,
+                "af": {"type": "luks1", "stripes": STRIPES, "hash": "sha256"},
+                "area": area(0),
+                "kdf": {
+                    "type": "argon2id",
+                    "time": 4,
+                    "memory": 1024,
+                    "cpus": 2,
+                    "salt": b64(argon2_salt),
+                },
+            },
+            "1": {
+                "type": "luks2",
+                "key_size": key_size,
+                "af": {"type": "luks1", "stripes": STRIPES, "hash": "sha512"},
+                "area": area(1),
+                "kdf": {
+                    "type": "pbkdf2",
+                    "hash": "sha512",
+                    "iterations": 1000,
+                    "salt": b64(pbkdf2_salt),
+                },
+            },
+        },
+        "tokens": {},
+        "segments": {
+            "0": {
+                "type": "crypt",
+                "offset": "16777216",
+                "size": "dynamic",
+                "iv_tweak": "0",
+                "encryption": "aes-xts-plain64",
+                "sector_size": 512,
+            }
+        },
+        "digests": {
+            "0": {
+                "type": "pbkdf2",
+                "keyslots": ["0", "1"],
+                "segments": ["0"],
+                "hash": "sha256",
+                "iterations": 1000,
+                "salt": b64(digest_salt),
+                "digest": b64(hashlib.pbkdf2_hmac("sha256", LUKS2_KEY, digest_salt, 1000, 32)),
+            }
+        },
+        "config": {
+            "json_size": str(hdr_size - 4096),
+            "keyslots_size": str(16777216 - 2 * hdr_size),
+            "flags": ["allow-discards"],
+        },
+    }
+    json_area = pad(json_text(metadata).encode(), hdr_size - 4096)
+
+    def header(offset, magic):
+        binary = magic + struct.pack(">HQQ", 2, hdr_size, 3)
+        binary += pad(b"", 48) + pad(b"sha256", 32) + randbytes(64)
+        binary += pad(LUKS2_UUID.encode(), 40) + pad(b"", 48) + struct.pack(">Q", offset)
+        binary = pad(binary, 4096)
+        csum = hashlib.sha256(binary + json_area).digest()
+        return binary[:448] + pad(csum, 64) + binary[512:] + json_area
+
+    image = header(0, b"LUKS\xba\xbe") + header(hdr_size, b"SKUL\xba\xbe")
+    for offset, data in zip(offsets, areas):
+        image = pad(image, offset) + data
+    return image
+
+
+if __name__ == "__main__":
+    outdir = sys.argv[1] if len(sys.argv) > 1 else "."
+    with open(f"{outdir}/luks1.img", "wb") as f:
+        f.write(luks1())
+    with open(f"{outdir}/luks2.img", "wb") as f:
+        f.write(luks2())
@@ -0,0 +1,299 @@
+//! LVM2 physical volume labels and metadata areas.
+//!
+//! A PV label (`LABELONE`) is stored in one of the first four sectors of the device. It's
+//! followed by the PV header: the PV UUID, the data areas and the metadata areas. Each
+//! metadata area starts with a header pointing to the current metadata text, stored in a
+//! circular buffer.
+
+use crate::util::crc32_update;
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+const LABEL_ID: &[u8] = b"LABELONE";
+const LABEL_TYPE: &[u8] = b"LVM2 001";
+const LABEL_SCAN_SECTORS: u64 = 4;
+const SECTOR_SIZE: usize = 512;
+
+const MDA_MAGIC: &[u8] = b" LVM2 x[5A%r0N*>";
+const MDA_HEADER_SIZE: u64 = 512;
+const RAW_LOCN_IGNORED: u32 = 0x00000001;
+
+/// Upper bound on the size of the metadata text, to prevent a corrupt header from making
+/// us allocate huge amounts of memory.
+const METADATA_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Seed of the checksums used by LVM2.
+const INITIAL_CRC: u32 = 0xf597a6cf;
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn crc(data: &[u8]) -> u32 {
+    crc32_update(INITIAL_CRC, data)
+}
+
+/// Read `len` bytes at `offset`. Returns `None` if the device is too small.
+fn read_at(dev: &File, offset: u64, len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = vec![0; len];
+    match dev.read_exact_at(&mut buf, offset) {
+        Ok(()) => Ok(Some(buf)),
+        Err(io) if io.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(io) => Err(io),
+    }
+}
+
+/// Contents of a PV label.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PvLabel {
+    uuid: String,
+    metadata_areas: Vec<(u64, u64)>,
+}
+impl PvLabel {
+    /// Read the PV label of `dev`. Returns `None` if there's no valid label.
+    pub fn read(dev: &File) -> io::Result<Option<Self>> {
+        for sector in 0..LABEL_SCAN_SECTORS {
+            let buf = match read_at(dev, sector * SECTOR_SIZE as u64, SECTOR_SIZE)? {
+                Some(buf) => buf,
+                None => return Ok(None),
+            };
+            if !buf.starts_with(LABEL_ID) || &buf[24..32] != LABEL_TYPE {
+                continue;
+            }
+            // The checksum covers everything after the checksum field itself
+            if le64(&buf, 8) != sector || le32(&buf, 16) != crc(&buf[20..]) {
+                continue;
+            }
+            return Ok(Self::parse_pv_header(&buf, le32(&buf, 20) as usize));
+        }
+        Ok(None)
+    }
+
+    fn parse_pv_header(buf: &[u8], offset: usize) -> Option<Self> {
+        // struct pv_header: UUID, device size, then two lists of disk locations (data
+        // areas followed by metadata areas), each terminated by an all-zero entry
+        let uuid = buf.get(offset..offset + 32)?;
+        let uuid = String::from_utf8(uuid.to_vec()).ok()?;
+        let mut pos = offset + 40;
+        let mut lists: [Vec<(u64, u64)>; 2] = Default::default();
+        for list in lists.iter_mut() {
+            loop {
+                let locn = buf.get(pos..pos + 16)?;
+                pos += 16;
+                let (offset, size) = (le64(locn, 0), le64(locn, 8));
+                if offset == 0 {
+                    break;
+                }
+                list.push((offset, size));
+            }
+        }
+        let [_, metadata_areas] = lists;
+        Some(Self {
+            uuid,
+            metadata_areas,
+        })
+    }
+
+    /// PV UUID, without dashes.
+    pub fn uuid(&self) -> &str {
+        &self.uuid[..]
+    }
+
+    /// Read the current metadata text from the first valid metadata area, if any (PVs may
+    /// be created without metadata areas).
+    pub fn read_metadata(&self, dev: &File) -> io::Result<Option<String>> {
+        for (offset, size) in &self.metadata_areas {
+            if let Some(text) = Self::read_metadata_area(dev, *offset, *size)? {
+                return Ok(Some(text));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_metadata_area(dev: &File, offset: u64, size: u64) -> io::Result<Option<String>> {
+        let hdr = match read_at(dev, offset, MDA_HEADER_SIZE as usize)? {
+            Some(hdr) => hdr,
+            None => return Ok(None),
+        };
+        if le32(&hdr, 0) != crc(&hdr[4..]) || &hdr[4..20] != MDA_MAGIC || le64(&hdr, 24) != offset {
+            return Ok(None);
+        }
+
+        // The first raw location points to the current metadata
+        let (text_offset, text_size, text_crc, flags) = (
+            le64(&hdr, 40),
+            le64(&hdr, 48),
+            le32(&hdr, 56),
+            le32(&hdr, 60),
+        );
+        if text_offset == 0
+            || size <= MDA_HEADER_SIZE
+            || flags & RAW_LOCN_IGNORED != 0
+            || text_offset >= size
+            || text_size > METADATA_MAX_SIZE
+            || text_size > size - MDA_HEADER_SIZE
+        {
+            return Ok(None);
+        }
+
+        // The metadata may wrap around the end of the circular buffer
+        let first = text_size.min(size - text_offset);
+        let mut text = match read_at(dev, offset + text_offset, first as usize)? {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        if first < text_size {
+            match read_at(dev, offset + MDA_HEADER_SIZE, (text_size - first) as usize)? {
+                Some(rest) => text.extend_from_slice(&rest),
+                None => return Ok(None),
+            }
+        }
+        if crc(&text) != text_crc {
+            return Ok(None);
+        }
+
+        let end = text.iter().position(|b| *b == 0).unwrap_or(text.len());
+        text.truncate(end);
+        Ok(String::from_utf8(text).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestImage;
+
+    const PV_UUID: &str = "qL2c1EtN0m3O8vJc0EkW8NfV1a9m4UxA";
+    const MDA_OFFSET: u64 = 4096;
+    const MDA_SIZE: u64 = 8192;
+    const TEXT: &str = include_str!("testdata/vg0.vg");
+
+    fn put_le32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_le64(buf: &mut [u8], offset: usize, value: u64) {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // PV with its label in `label_sector` and a single metadata area holding `text` (NUL
+    // terminated) at `text_offset` within the area
+    fn pv_image(label_sector: u64, text_offset: u64) -> Vec<u8> {
+        let mut image = vec![0; (MDA_OFFSET + MDA_SIZE) as usize];
+
+        let label = &mut image[label_sector as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+        label[..8].copy_from_slice(LABEL_ID);
+        put_le64(label, 8, label_sector);
+        put_le32(label, 20, 32);
+        label[24..32].copy_from_slice(LABEL_TYPE);
+        label[32..64].copy_from_slice(PV_UUID.as_bytes());
+        put_le64(label, 64, 20 * 1024 * 1024 * 1024);
+        // Data area, terminator, metadata area, terminator
+        put_le64(label, 72, 1024 * 1024);
+        put_le64(label, 104, MDA_OFFSET);
+        put_le64(label, 112, MDA_SIZE);
+        let label_crc = crc(&label[20..]);
+        put_le32(label, 16, label_crc);
+
+        let text = [TEXT.as_bytes(), b"\0"].concat();
+        let area = &mut image[MDA_OFFSET as usize..];
+        for (i, b) in text.iter().enumerate() {
+            let mut pos = text_offset + i as u64;
+            if pos >= MDA_SIZE {
+                pos = pos - MDA_SIZE + MDA_HEADER_SIZE;
+            }
+            area[pos as usize] = *b;
+        }
+        let hdr = &mut area[..MDA_HEADER_SIZE as usize];
+        hdr[4..20].copy_from_slice(MDA_MAGIC);
+        put_le32(hdr, 20, 1);
+        put_le64(hdr, 24, MDA_OFFSET);
+        put_le64(hdr, 32, MDA_SIZE);
+        put_le64(hdr, 40, text_offset);
+        put_le64(hdr, 48, text.len() as u64);
+        put_le32(hdr, 56, crc(&text));
+        let hdr_crc = crc(&hdr[4..]);
+        put_le32(hdr, 0, hdr_crc);
+        image
+    }
+
+    fn read(image: &[u8]) -> Option<(String, Option<String>)> {
+        let image = TestImage::new(image);
+        let label = PvLabel::read(image.file()).unwrap()?;
+        let text = label.read_metadata(image.file()).unwrap();
+        Some((label.uuid().to_string(), text))
+    }
+
+    #[test]
+    fn lvm_crc() {
+        // Independently computed (CRC-32 with LVM2's seed and no final inversion)
+        assert_eq!(crc(b""), INITIAL_CRC);
+        assert_eq!(crc(b"LVM2 001"), 0x5b9bcf05);
+    }
+
+    #[test]
+    fn label_and_metadata() {
+        // pvcreate writes the label in the second sector
+        let (uuid, text) = read(&pv_image(1, MDA_HEADER_SIZE)).unwrap();
+        assert_eq!(uuid, PV_UUID);
+        assert_eq!(text.as_deref(), Some(TEXT));
+
+        let (_, text) = read(&pv_image(0, 2048)).unwrap();
+        assert_eq!(text.as_deref(), Some(TEXT));
+    }
+
+    #[test]
+    fn metadata_wraps_around() {
+        let (_, text) = read(&pv_image(1, MDA_SIZE - 100)).unwrap();
+        assert_eq!(text.as_deref(), Some(TEXT));
+    }
+
+    #[test]
+    fn bad_label() {
+        // Wrong checksum
+        let mut image = pv_image(1, MDA_HEADER_SIZE);
+        image[SECTOR_SIZE + 40] ^= 1;
+        assert_eq!(read(&image), None);
+
+        // Sector number not matching where the label was found
+        let mut image = pv_image(1, MDA_HEADER_SIZE);
+        image.copy_within(SECTOR_SIZE..2 * SECTOR_SIZE, 2 * SECTOR_SIZE);
+        image[SECTOR_SIZE..2 * SECTOR_SIZE].fill(0);
+        assert_eq!(read(&image), None);
+
+        // Only the first four sectors are scanned
+        let mut image = pv_image(4, MDA_HEADER_SIZE);
+        assert_eq!(read(&image), None);
+        image.truncate(3 * SECTOR_SIZE);
+        assert_eq!(read(&image), None);
+    }
+
+    #[test]
+    fn bad_metadata() {
+        // Metadata checksum
+        let mut image = pv_image(1, MDA_HEADER_SIZE);
+        image[(MDA_OFFSET + MDA_HEADER_SIZE) as usize + 10] ^= 1;
+        assert_eq!(read(&image), Some((PV_UUID.to_string(), None)));
+
+        // Area header checksum
+        let mut image = pv_image(1, MDA_HEADER_SIZE);
+        image[MDA_OFFSET as usize + 33] ^= 1;
+        assert_eq!(read(&image), Some((PV_UUID.to_string(), None)));
+
+        // Ignored metadata area (pvchange --metadataignore)
+        let mut image = pv_image(1, MDA_HEADER_SIZE);
+        let hdr = &mut image[MDA_OFFSET as usize..][..MDA_HEADER_SIZE as usize];
+        put_le32(hdr, 60, RAW_LOCN_IGNORED);
+        let hdr_crc = crc(&hdr[4..]);
+        put_le32(hdr, 0, hdr_crc);
+        assert_eq!(read(&image), Some((PV_UUID.to_string(), None)));
+
+        // Truncated device
+        let mut image = pv_image(1, MDA_SIZE - 100);
+        image.truncate(MDA_OFFSET as usize + 1024);
+        assert_eq!(read(&image), Some((PV_UUID.to_string(), None)));
+    }
+}
@@ -0,0 +1,558 @@
+//! LVM2 text metadata.
+//!
+//! The metadata stored in each metadata area describes the whole volume group in LVM2's
+//! configuration syntax:
+//!
+//! ```no_check
+//! vg0 {
+//!     id = "..."
+//!     seqno = 3
+//!     extent_size = 8192
+//!     physical_volumes { pv0 { id = "..." pe_start = 2048 ... } }
+//!     logical_volumes { root { id = "..." segment_count = 1 segment1 { ... } } }
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+
+/// Maximum nesting depth, to avoid overflowing the stack with malicious input.
+const MAX_DEPTH: usize = 16;
+
+/// Value in LVM2's configuration syntax.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Value {
+    Int(i64),
+    Str(String),
+    Array(Vec<Value>),
+    Section(BTreeMap<String, Value>),
+}
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Section(section) => section.get(key),
+            _ => None,
+        }
+    }
+
+    fn as_section(&self) -> Option<&BTreeMap<String, Value>> {
+        match self {
+            Value::Section(section) => Some(section),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(arr) => Some(&arr[..]),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(&s[..]),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Int(i) => u64::try_from(*i).ok(),
+            _ => None,
+        }
+    }
+
+    // Arrays of strings such as status and flags
+    fn contains_str(&self, s: &str) -> bool {
+        self.as_array()
+            .map(|arr| arr.iter().any(|v| v.as_str() == Some(s)))
+            .unwrap_or(false)
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    // Skip whitespace and comments
+    fn skip_ws(&mut self) {
+        while let Some(b) = self.peek() {
+            match b {
+                b' ' | b'\t' | b'\n' | b'\r' => self.pos += 1,
+                b'#' => {
+                    while !matches!(self.peek(), None | Some(b'\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        let start = self.pos;
+        while matches!(
+            self.peek(),
+            Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-' | b'+' | b'.')
+        ) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return None;
+        }
+        String::from_utf8(self.text[start..self.pos].to_vec()).ok()
+    }
+
+    // Section body: `key = value` and `key { ... }` items until `}` (or end of input at
+    // the top level)
+    fn section(&mut self, depth: usize) -> Option<BTreeMap<String, Value>> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let mut section = BTreeMap::new();
+        loop {
+            self.skip_ws();
+            match self.peek() {
+                None if depth == 0 => return Some(section),
+                Some(b'}') if depth > 0 => {
+                    self.pos += 1;
+                    return Some(section);
+                }
+                _ => {}
+            }
+            let key = self.ident()?;
+            self.skip_ws();
+            let value = match self.peek()? {
+                b'=' => {
+                    self.pos += 1;
+                    self.value()?
+                }
+                b'{' => {
+                    self.pos += 1;
+                    Value::Section(self.section(depth + 1)?)
+                }
+                _ => return None,
+            };
+            section.insert(key, value);
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        self.skip_ws();
+        match self.peek()? {
+            b'"' => self.string().map(Value::Str),
+            b'[' => {
+                self.pos += 1;
+                let mut arr = Vec::new();
+                loop {
+                    self.skip_ws();
+                    match self.peek()? {
+                        b']' => {
+                            self.pos += 1;
+                            return Some(Value::Array(arr));
+                        }
+                        b',' if !arr.is_empty() => self.pos += 1,
+                        _ => arr.push(self.value()?),
+                    }
+                }
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = self.pos;
+                self.pos += 1;
+                while matches!(self.peek(), Some(b'0'..=b'9')) {
+                    self.pos += 1;
+                }
+                let n = std::str::from_utf8(&self.text[start..self.pos]).ok()?;
+                n.parse().ok().map(Value::Int)
+            }
+            _ => None,
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let b = self.peek()?;
+            self.pos += 1;
+            match b {
+                b'"' => return String::from_utf8(out).ok(),
+                b'\\' => {
+                    out.push(self.peek()?);
+                    self.pos += 1;
+                }
+                b => out.push(b),
+            }
+        }
+    }
+}
+
+/// Strip the dashes from an LVM2 UUID (as they are formatted in the metadata, but not in
+/// PV labels nor in device-mapper UUIDs).
+pub fn strip_uuid(uuid: &str) -> String {
+    uuid.chars().filter(|c| *c != '-').collect()
+}
+
+/// Physical volume, as referenced by a volume group.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PhysicalVolume {
+    uuid: String,
+    pe_start: u64,
+}
+impl PhysicalVolume {
+    /// PV UUID, without dashes.
+    pub fn uuid(&self) -> &str {
+        &self.uuid[..]
+    }
+
+    /// Start of the first physical extent, in sectors.
+    pub fn pe_start(&self) -> u64 {
+        self.pe_start
+    }
+}
+
+/// Type of a logical volume segment.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SegmentType {
+    /// `striped` segment (a linear segment is a striped segment with a single stripe).
+    Striped {
+        /// Stripe size in sectors. Irrelevant for linear segments.
+        stripe_size: u64,
+
+        /// Physical volume name (as referenced in the volume group) and first physical
+        /// extent of each stripe.
+        stripes: Vec<(String, u64)>,
+    },
+
+    /// Any other segment type (e.g. `thin`, `cache` or `raid1`).
+    Unsupported(String),
+}
+
+/// Contiguous range of logical extents of a logical volume.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Segment {
+    start_extent: u64,
+    extent_count: u64,
+    segment_type: SegmentType,
+}
+impl Segment {
+    /// First logical extent.
+    pub fn start_extent(&self) -> u64 {
+        self.start_extent
+    }
+
+    /// Number of logical extents.
+    pub fn extent_count(&self) -> u64 {
+        self.extent_count
+    }
+
+    /// Segment type.
+    pub fn segment_type(&self) -> &SegmentType {
+        &self.segment_type
+    }
+}
+
+/// Logical volume.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogicalVolume {
+    name: String,
+    uuid: String,
+    visible: bool,
+    writable: bool,
+    activation_skip: bool,
+    segments: Vec<Segment>,
+}
+impl LogicalVolume {
+    /// LV name.
+    pub fn name(&self) -> &str {
+        &self.name[..]
+    }
+
+    /// LV UUID, without dashes.
+    pub fn uuid(&self) -> &str {
+        &self.uuid[..]
+    }
+
+    /// Whether the LV is visible, i.e. not a component of another LV.
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Whether the LV is writable.
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Whether the LV is flagged to be skipped on activation (`lvchange -k`).
+    pub fn activation_skip(&self) -> bool {
+        self.activation_skip
+    }
+
+    /// Segments, in logical extent order.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments[..]
+    }
+}
+
+/// Volume group.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VolumeGroup {
+    name: String,
+    uuid: String,
+    seqno: u64,
+    extent_size: u64,
+    pvs: BTreeMap<String, PhysicalVolume>,
+    lvs: Vec<LogicalVolume>,
+}
+impl VolumeGroup {
+    /// Parse the metadata text of a volume group. Returns `None` if it's malformed.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let root = parser.section(0)?;
+
+        // The volume group is the only section at the top level
+        let (name, vg) = root.iter().find(|(_, v)| matches!(v, Value::Section(_)))?;
+        let get_u64 = |v: &Value, key: &str| v.get(key).and_then(Value::as_u64);
+        let get_uuid = |v: &Value| v.get("id").and_then(Value::as_str).map(strip_uuid);
+
+        let extent_size = get_u64(vg, "extent_size").filter(|e| *e > 0)?;
+        let mut pvs = BTreeMap::new();
+        for (pv_name, pv) in vg.get("physical_volumes")?.as_section()? {
+            pvs.insert(
+                pv_name.clone(),
+                PhysicalVolume {
+                    uuid: get_uuid(pv)?,
+                    pe_start: get_u64(pv, "pe_start")?,
+                },
+            );
+        }
+
+        let mut lvs = Vec::new();
+        let no_lvs = BTreeMap::new();
+        let lv_sections = match vg.get("logical_volumes") {
+            Some(lvs) => lvs.as_section()?,
+            None => &no_lvs,
+        };
+        for (lv_name, lv) in lv_sections {
+            let status = lv.get("status")?;
+            let mut segments = Vec::new();
+            for i in 1..=get_u64(lv, "segment_count")? {
+                let seg = lv.get(&format!("segment{}", i))?;
+                let seg_type = seg.get("type").and_then(Value::as_str)?;
+                let segment_type = match seg_type {
+                    "striped" => {
+                        let stripes = seg.get("stripes")?.as_array()?;
+                        let stripes = stripes
+                            .chunks(2)
+                            .map(|c| match c {
+                                [pv, extent] => Some((pv.as_str()?.to_string(), extent.as_u64()?)),
+                                _ => None,
+                            })
+                            .collect::<Option<Vec<_>>>()?;
+                        if stripes.is_empty()
+                            || get_u64(seg, "stripe_count")? != stripes.len() as u64
+                        {
+                            return None;
+                        }
+                        SegmentType::Striped {
+                            stripe_size: get_u64(seg, "stripe_size").unwrap_or(0),
+                            stripes,
+                        }
+                    }
+                    seg_type => SegmentType::Unsupported(seg_type.to_string()),
+                };
+                segments.push(Segment {
+                    start_extent: get_u64(seg, "start_extent")?,
+                    extent_count: get_u64(seg, "extent_count")?,
+                    segment_type,
+                });
+            }
+            segments.sort_by_key(|s| s.start_extent);
+
+            lvs.push(LogicalVolume {
+                name: lv_name.clone(),
+                uuid: get_uuid(lv)?,
+                visible: status.contains_str("VISIBLE"),
+                writable: status.contains_str("WRITE"),
+                activation_skip: lv
+                    .get("flags")
+                    .map(|flags| flags.contains_str("ACTIVATION_SKIP"))
+                    .unwrap_or(false),
+                segments,
+            });
+        }
+
+        Some(Self {
+            name: name.clone(),
+            uuid: get_uuid(vg)?,
+            seqno: get_u64(vg, "seqno")?,
+            extent_size,
+            pvs,
+            lvs,
+        })
+    }
+
+    /// VG name.
+    pub fn name(&self) -> &str {
+        &self.name[..]
+    }
+
+    /// VG UUID, without dashes.
+    pub fn uuid(&self) -> &str {
+        &self.uuid[..]
+    }
+
+    /// Metadata sequence number. Higher numbers are more recent.
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+
+    /// Extent size in sectors.
+    pub fn extent_size(&self) -> u64 {
+        self.extent_size
+    }
+
+    /// Physical volumes, by name (e.g. `pv0`).
+    pub fn pvs(&self) -> &BTreeMap<String, PhysicalVolume> {
+        &self.pvs
+    }
+
+    /// Logical volumes.
+    pub fn lvs(&self) -> &[LogicalVolume] {
+        &self.lvs[..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // vgcfgbackup output: linear, multi-segment, striped and thin logical volumes
+    const VG0: &str = include_str!("testdata/vg0.vg");
+
+    fn lv<'a>(vg: &'a VolumeGroup, name: &str) -> &'a LogicalVolume {
+        vg.lvs().iter().find(|lv| lv.name() == name).unwrap()
+    }
+
+    fn striped(stripe_size: u64, stripes: &[(&str, u64)]) -> SegmentType {
+        SegmentType::Striped {
+            stripe_size,
+            stripes: stripes
+                .iter()
+                .map(|(pv, extent)| (pv.to_string(), *extent))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn vgcfgbackup() {
+        let vg = VolumeGroup::parse(VG0).unwrap();
+        assert_eq!(vg.name(), "vg0");
+        assert_eq!(vg.uuid(), "Ha3VrkPg2CSy0fe4Lbbdxz3cuRRz7Wxp");
+        assert_eq!(vg.seqno(), 9);
+        assert_eq!(vg.extent_size(), 8192);
+
+        assert_eq!(vg.pvs().len(), 2);
+        assert_eq!(vg.pvs()["pv0"].uuid(), "qL2c1EtN0m3O8vJc0EkW8NfV1a9m4UxA");
+        assert_eq!(vg.pvs()["pv1"].pe_start(), 2048);
+        assert_eq!(vg.lvs().len(), 8);
+
+        let root = lv(&vg, "root");
+        assert_eq!(root.uuid(), "7Wd3yKh0Qf2Rn1Ue8vCb4x9TgSmJ6aLp");
+        assert!(root.is_visible() && root.is_writable() && !root.activation_skip());
+        let segments: Vec<_> = root
+            .segments()
+            .iter()
+            .map(|s| (s.start_extent(), s.extent_count(), s.segment_type().clone()))
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                (0, 2560, striped(0, &[("pv0", 0)])),
+                (2560, 512, striped(0, &[("pv1", 1024)])),
+            ]
+        );
+
+        let data = lv(&vg, "data");
+        assert!(!data.is_writable());
+        assert_eq!(
+            data.segments()[0].segment_type(),
+            &striped(128, &[("pv0", 3584), ("pv1", 0)])
+        );
+
+        assert_eq!(
+            lv(&vg, "pool").segments()[0].segment_type(),
+            &SegmentType::Unsupported("thin-pool".to_string())
+        );
+        assert!(lv(&vg, "snap").activation_skip());
+        assert!(!lv(&vg, "pool_tdata").is_visible());
+        assert!(!lv(&vg, "lvol0_pmspare").is_visible());
+    }
+
+    #[test]
+    fn on_disk_metadata() {
+        // Metadata areas hold the volume group section followed by a few top-level values
+        let text = "vg1 {\nid = \"Ha3Vrk-Pg2C-Sy0f-e4Lb-bdxz-3cuR-Rz7Wxq\"\nseqno = 1\n\
+                    format = \"lvm2\"\nstatus = [\"RESIZEABLE\", \"READ\", \"WRITE\"]\n\
+                    flags = []\nextent_size = 8192\nmax_lv = 0\nmax_pv = 0\n\
+                    metadata_copies = 0\n\nphysical_volumes {\n\npv0 {\n\
+                    id = \"qL2c1E-tN0m-3O8v-Jc0E-kW8N-fV1a-9m4UxB\"\n\
+                    device = \"/dev/vdb\"\n\nstatus = [\"ALLOCATABLE\"]\nflags = []\n\
+                    dev_size = 2097152\npe_start = 2048\npe_count = 255\n}\n}\n\n}\n\
+                    # Generated by LVM2 version 2.03.16(2) (2022-05-18): Sat Jan 14 12:04:51 2023\n\n\
+                    contents = \"Text Format Volume\"\nversion = 1\n\n\
+                    description = \"\"\n\ncreation_host = \"ember\"\t# Linux ember\n\
+                    creation_time = 1673697891\t# Sat Jan 14 12:04:51 2023\n\n";
+        let vg = VolumeGroup::parse(text).unwrap();
+        assert_eq!(vg.name(), "vg1");
+        assert!(vg.lvs().is_empty());
+    }
+
+    #[test]
+    fn escapes_and_comments() {
+        let text = "vg2 { # comment { not a section\n\
+                    id = \"a-b\" seqno = 2 extent_size = 8\n\
+                    physical_volumes { pv0 { id = \"c\\\"d\" pe_start = 0 } }\n}";
+        let vg = VolumeGroup::parse(text).unwrap();
+        assert_eq!(vg.uuid(), "ab");
+        assert_eq!(vg.pvs()["pv0"].uuid(), "c\"d");
+    }
+
+    #[test]
+    fn malformed() {
+        for text in [
+            "",
+            "vg0 {",
+            "vg0 { id = \"x\" seqno = 1 extent_size = 0 physical_volumes { } }",
+            "vg0 { id = \"x\" seqno = 1 extent_size = 8 }",
+            "vg0 { id = \"x\" seqno = -1 extent_size = 8 physical_volumes { } }",
+            "vg0 { id = \"x\" seqno = 1 extent_size = 8 physical_volumes { } = }",
+            "vg0 { id = x seqno = 1 extent_size = 8 physical_volumes { } }",
+        ] {
+            assert_eq!(VolumeGroup::parse(text), None, "{}", text);
+        }
+
+        // Stripe count not matching the stripes
+        let bad = VG0.replacen("stripe_count = 2", "stripe_count = 3", 1);
+        assert_eq!(VolumeGroup::parse(&bad), None);
+
+        // Missing segment
+        let bad = VG0.replacen("segment_count = 2", "segment_count = 3", 1);
+        assert_eq!(VolumeGroup::parse(&bad), None);
+
+        let deep = format!(
+            "{}{}",
+            "a {".repeat(MAX_DEPTH + 2),
+            "}".repeat(MAX_DEPTH + 2)
+        );
+        assert_eq!(VolumeGroup::parse(&deep), None);
+    }
+}
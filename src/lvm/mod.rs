@@ -0,0 +1,365 @@
+//! LVM2 logical volumes.
+//!
+//! Logical volumes to activate are specified through the `rd.lvm.*` boot-time parameters,
+//! following the semantics of `dracut.cmdline(7)`:
+//!
+//! - `rd.lvm=<BOOL>` enables (default) or disables activating logical volumes altogether.
+//! - `rd.lvm.vg=<VG>` activates all logical volumes of the given volume group.
+//! - `rd.lvm.lv=<VG>/<LV>` activates the given logical volume.
+//!
+//! If neither `rd.lvm.vg` nor `rd.lvm.lv` is given, all logical volumes are activated.
+//! Otherwise, only the listed ones are, along with the root and resume logical volumes if
+//! `root=` or `resume=` name them (`/dev/mapper/<VG>-<LV>` or `/dev/<VG>/<LV>`).
+//!
+//! Activation is done natively, without `lvm`: PV labels and metadata areas are read as
+//! devices appear, and once all physical volumes of a volume group are present, its linear
+//! and striped logical volumes are set up through device-mapper ioctls as
+//! `/dev/mapper/<VG>-<LV>` (with `/dev/<VG>/<LV>` symlinks). Other segment types (thin,
+//! cache, raid, ...) are not supported: `lvm2` must be bundled in the image instead.
+
+pub mod label;
+pub mod metadata;
+
+use crate::{
+    dm::{DmControl, DmTarget},
+    early_logging::{buf::KmsgBuf, KConsole},
+    mount::PartitionSourceBuilder,
+    PROGRAM_NAME,
+};
+use metadata::{LogicalVolume, SegmentType, VolumeGroup};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::create_dir_all,
+    os::unix::fs::symlink,
+};
+
+/// Device-mapper name of the logical volume `lv` in the volume group `vg`, as chosen by
+/// `lvm` (dashes are doubled so that the name can be split unambiguously).
+pub fn dm_name(vg: &str, lv: &str) -> String {
+    format!("{}-{}", vg.replace('-', "--"), lv.replace('-', "--"))
+}
+
+/// LVM2 logical volumes to activate, as specified by the `rd.lvm.*` boot-time parameters.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LvmTargets {
+    enabled: bool,
+    vgs: Vec<String>,
+    lvs: Vec<(String, String)>,
+}
+impl LvmTargets {
+    /// Builder: create a new [LvmTargetsBuilder].
+    pub fn builder() -> LvmTargetsBuilder {
+        Default::default()
+    }
+
+    /// Whether logical volumes should be activated at all (`rd.lvm=0` disables them).
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether the logical volume `lv` in the volume group `vg` is explicitly listed
+    /// (either by itself or through its volume group).
+    pub fn is_listed(&self, vg: &str, lv: &str) -> bool {
+        self.vgs.iter().any(|v| v == vg) || self.lvs.iter().any(|(v, l)| v == vg && l == lv)
+    }
+
+    /// Whether the logical volume `lv` in the volume group `vg` should be activated.
+    /// `sources` are the partitions needed to boot (root and resume).
+    pub fn should_activate(&self, vg: &str, lv: &str, sources: &[&PartitionSourceBuilder]) -> bool {
+        self.enabled
+            && ((self.vgs.is_empty() && self.lvs.is_empty())
+                || self.is_listed(vg, lv)
+                || is_named(vg, lv, sources))
+    }
+}
+
+/// Builder: LVM2 logical volumes to activate.
+///
+/// In case of conflicting values, the first specified value takes precedence.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LvmTargetsBuilder {
+    enabled: Option<bool>,
+    vgs: Vec<String>,
+    lvs: Vec<(String, String)>,
+}
+impl LvmTargetsBuilder {
+    /// Parse a logical volume as `<VG>/<LV>`, optionally prefixed by `/dev/`.
+    pub fn parse_lv(lv: &str) -> Option<(&str, &str)> {
+        let lv = lv.strip_prefix("/dev/").unwrap_or(lv);
+        match lv.split_once('/') {
+            Some((vg, lv)) if !vg.is_empty() && !lv.is_empty() && !lv.contains('/') => {
+                Some((vg, lv))
+            }
+            _ => None,
+        }
+    }
+
+    /// Enable or disable activating logical volumes.
+    pub fn enable(&mut self, enabled: bool) -> &mut Self {
+        self.enabled.get_or_insert(enabled);
+        self
+    }
+
+    /// Activate all logical volumes of the volume group `vg`.
+    #[inline]
+    pub fn vg<S: Into<String>>(&mut self, vg: S) -> &mut Self {
+        self._vg(vg.into());
+        self
+    }
+    fn _vg(&mut self, vg: String) {
+        if !self.vgs.contains(&vg) {
+            self.vgs.push(vg);
+        }
+    }
+
+    /// Activate the logical volume `lv` in the volume group `vg`.
+    #[inline]
+    pub fn lv<S1: Into<String>, S2: Into<String>>(&mut self, vg: S1, lv: S2) -> &mut Self {
+        self._lv(vg.into(), lv.into());
+        self
+    }
+    fn _lv(&mut self, vg: String, lv: String) {
+        let lv = (vg, lv);
+        if !self.lvs.contains(&lv) {
+            self.lvs.push(lv);
+        }
+    }
+
+    /// Builder: build the list of logical volumes to activate.
+    pub fn build(self, kmsg_buf: &mut KmsgBuf) -> LvmTargets {
+        let enabled = self.enabled.unwrap_or(true);
+        if !enabled && (!self.vgs.is_empty() || !self.lvs.is_empty()) {
+            kmsg_buf.kwarn("rd.lvm=0 is set, ignoring rd.lvm.vg and rd.lvm.lv".to_string());
+        }
+        LvmTargets {
+            enabled,
+            vgs: self.vgs,
+            lvs: self.lvs,
+        }
+    }
+}
+
+// Whether any of `sources` (e.g. root=) names the logical volume `lv` in the volume group
+// `vg`
+fn is_named(vg: &str, lv: &str, sources: &[&PartitionSourceBuilder]) -> bool {
+    sources.iter().any(|source| match source {
+        PartitionSourceBuilder::RawDevice(path) => {
+            path.strip_prefix("/dev/mapper/") == Some(&dm_name(vg, lv)[..])
+                || LvmTargetsBuilder::parse_lv(path) == Some((vg, lv))
+        }
+        _ => false,
+    })
+}
+
+/// Volume group whose physical volumes are all present, ready to be activated.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompleteVg {
+    vg: VolumeGroup,
+    devnums: BTreeMap<String, (u32, u32)>,
+}
+impl CompleteVg {
+    /// Activate the logical volumes of this volume group selected by `targets`. Returns the
+    /// device-mapper names of the activated logical volumes.
+    ///
+    /// Logical volumes using unsupported segment types are an error if they are explicitly
+    /// listed or named by `sources` (the partitions needed to boot, i.e. root and resume),
+    /// and are skipped otherwise.
+    pub fn activate(
+        &self,
+        kcon: &mut KConsole,
+        targets: &LvmTargets,
+        sources: &[&PartitionSourceBuilder],
+    ) -> Result<Vec<String>, PrintableErrno<String>> {
+        let vg_name = self.vg.name();
+        let mut activated = Vec::new();
+        for lv in self.vg.lvs() {
+            let required =
+                targets.is_listed(vg_name, lv.name()) || is_named(vg_name, lv.name(), sources);
+            if !lv.is_visible()
+                || (lv.activation_skip() && !required)
+                || !targets.should_activate(vg_name, lv.name(), sources)
+            {
+                continue;
+            }
+            let unsupported = lv
+                .segments()
+                .iter()
+                .find_map(|seg| match seg.segment_type() {
+                    SegmentType::Unsupported(seg_type) => Some(seg_type),
+                    _ => None,
+                });
+            if let Some(seg_type) = unsupported {
+                let message = format!(
+                    "logical volume {}/{} uses unsupported segment type {}: bundle lvm2 in the image to activate it",
+                    vg_name,
+                    lv.name(),
+                    seg_type
+                );
+                if required {
+                    return Err(printable_error(PROGRAM_NAME, message));
+                }
+                kwarn!(kcon, "{}", message);
+                continue;
+            }
+
+            let name = dm_name(vg_name, lv.name());
+            kinfo!(
+                kcon,
+                "activating logical volume {}/{} as /dev/mapper/{}",
+                vg_name,
+                lv.name(),
+                name
+            );
+            let table = self.table(lv)?;
+            let dm_uuid = format!("LVM-{}{}", self.vg.uuid(), lv.uuid());
+            DmControl::open()?.create_device(&name, &dm_uuid, &table, !lv.is_writable())?;
+
+            // /dev/<VG>/<LV> is usually created by udev rules
+            let _ = create_dir_all(format!("/dev/{}", vg_name));
+            if let Err(io) = symlink(
+                format!("../mapper/{}", name),
+                format!("/dev/{}/{}", vg_name, lv.name()),
+            ) {
+                if io.kind() != std::io::ErrorKind::AlreadyExists {
+                    kwarn!(
+                        kcon,
+                        "unable to create /dev/{}/{}: {}",
+                        vg_name,
+                        lv.name(),
+                        io
+                    );
+                }
+            }
+            activated.push(name);
+        }
+        Ok(activated)
+    }
+
+    // Build the device-mapper table of `lv` (one target per segment)
+    fn table(&self, lv: &LogicalVolume) -> Result<Vec<DmTarget>, PrintableErrno<String>> {
+        let invalid = || {
+            printable_error(
+                PROGRAM_NAME,
+                format!(
+                    "invalid metadata for logical volume {}/{}",
+                    self.vg.name(),
+                    lv.name()
+                ),
+            )
+        };
+        let extent_size = self.vg.extent_size();
+
+        // (major, minor, sector) of a physical extent
+        let locate = |pv_name: &str, extent: u64| -> Option<(u32, u32, u64)> {
+            let pv = self.vg.pvs().get(pv_name)?;
+            let (major, minor) = self.devnums.get(pv_name)?;
+            let offset = extent
+                .checked_mul(extent_size)?
+                .checked_add(pv.pe_start())?;
+            Some((*major, *minor, offset))
+        };
+
+        let mut table = Vec::with_capacity(lv.segments().len());
+        for seg in lv.segments() {
+            let start = seg
+                .start_extent()
+                .checked_mul(extent_size)
+                .ok_or_else(invalid)?;
+            let length = seg
+                .extent_count()
+                .checked_mul(extent_size)
+                .ok_or_else(invalid)?;
+            let (stripe_size, stripes) = match seg.segment_type() {
+                SegmentType::Striped {
+                    stripe_size,
+                    stripes,
+                } => (*stripe_size, stripes),
+                SegmentType::Unsupported(_) => return Err(invalid()),
+            };
+            let target = match &stripes[..] {
+                // linear: <device> <offset>
+                [(pv_name, extent)] => {
+                    let (major, minor, offset) = locate(pv_name, *extent).ok_or_else(invalid)?;
+                    DmTarget::new(
+                        start,
+                        length,
+                        "linear",
+                        format!("{}:{} {}", major, minor, offset),
+                    )
+                }
+
+                // striped: <#stripes> <chunk_size> [<device> <offset>]...
+                // Each stripe holds extent_count / #stripes extents.
+                stripes => {
+                    let mut params = format!("{} {}", stripes.len(), stripe_size);
+                    for (pv_name, extent) in stripes {
+                        let (major, minor, offset) =
+                            locate(pv_name, *extent).ok_or_else(invalid)?;
+                        params.push_str(&format!(" {}:{} {}", major, minor, offset));
+                    }
+                    DmTarget::new(start, length, "striped", params)
+                }
+            };
+            table.push(target);
+        }
+        if table.is_empty() {
+            return Err(invalid());
+        }
+        Ok(table)
+    }
+}
+
+/// Physical volumes seen so far and the volume groups they belong to.
+#[derive(Debug, Clone, Default)]
+pub struct VolumeGroups {
+    pvs: BTreeMap<String, (u32, u32)>,
+    vgs: BTreeMap<String, VolumeGroup>,
+    activated: BTreeSet<String>,
+}
+impl VolumeGroups {
+    /// Record the physical volume with UUID `pv_uuid` (without dashes) and device numbers
+    /// `devnum`, along with the volume group metadata read from it (physical volumes may
+    /// have no metadata areas). The most recent metadata of each volume group is kept.
+    ///
+    /// Returns the volume groups that became complete with this physical volume. Each
+    /// volume group is only returned once.
+    pub fn add_pv(
+        &mut self,
+        pv_uuid: &str,
+        devnum: (u32, u32),
+        vg: Option<VolumeGroup>,
+    ) -> Vec<CompleteVg> {
+        self.pvs.insert(pv_uuid.to_string(), devnum);
+        if let Some(vg) = vg {
+            match self.vgs.get(vg.uuid()) {
+                Some(known) if known.seqno() >= vg.seqno() => {}
+                _ => {
+                    self.vgs.insert(vg.uuid().to_string(), vg);
+                }
+            }
+        }
+
+        let mut complete = Vec::new();
+        for (vg_uuid, vg) in &self.vgs {
+            if self.activated.contains(vg_uuid) {
+                continue;
+            }
+            let devnums: Option<BTreeMap<_, _>> = vg
+                .pvs()
+                .iter()
+                .map(|(pv_name, pv)| Some((pv_name.clone(), *self.pvs.get(pv.uuid())?)))
+                .collect();
+            if let Some(devnums) = devnums {
+                complete.push(CompleteVg {
+                    vg: vg.clone(),
+                    devnums,
+                });
+            }
+        }
+        for vg in &complete {
+            self.activated.insert(vg.vg.uuid().to_string());
+        }
+        complete
+    }
+}
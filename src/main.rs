@@ -108,10 +108,21 @@
 #[macro_use]
 mod early_logging;
 
+mod blkid;
+mod block;
 mod common;
 mod config;
+mod crypto;
+mod decompress;
+mod dm;
+mod generator;
+mod luks;
+mod lvm;
+mod md;
 mod module;
 mod mount;
+mod prompt;
+mod resume;
 mod sysfs;
 mod time;
 mod udev;
@@ -119,10 +130,12 @@ mod util;
 mod vconsole;
 
 use crate::{
-    config::{CmdlineArgs, InitramfsMetadata, RuntimeConfig},
+    block::BlockHandling,
+    config::{CmdlineArgs, RuntimeConfig},
     early_logging::KConsole,
     module::{ModAliases, ModLoading},
     mount::{Mount, TmpfsOpts},
+    prompt::Prompter,
     sysfs::SysfsWalker,
     time::InitramfsTimer,
     udev::UdevListener,
@@ -180,15 +193,17 @@ const INIT_ERROR: &str = "unable to execute init";
 /// See [RuntimeConfig] for the structure of the TOML file.
 const IGNITED_CONFIG: &str = "/etc/ignited/engine.toml";
 
-/// Path where `ignited`'s (kernel) modules are located.
+/// Path where `ignited`'s (kernel) modules are located, in one directory per kernel
+/// version.
 ///
-/// See [ModAliases] for the structure of the file.
+/// See [kern_modules_dir].
 const IGNITED_KERN_MODULES: &str = "/usr/lib/modules";
 
-/// Path where `ignited`'s module aliases file is located.
+/// Name of `ignited`'s module aliases file, found in each kernel's
+/// [modules directory][kern_modules_dir].
 ///
 /// See [ModAliases] for the structure of the file.
-const IGNITED_MODULE_ALIASES: &str = "/usr/lib/modules/ignited.alias";
+const IGNITED_MODULE_ALIASES: &str = "ignited.alias";
 
 /// Path where the target root partition is mounted.
 const IGNITED_TARGET_ROOT_PATH: &str = "/system_root";
@@ -196,6 +211,12 @@ const IGNITED_TARGET_ROOT_PATH: &str = "/system_root";
 /// Ignited main thread event loop waker.
 const IGNITED_MAIN_THREAD_WAKE_TOKEN: Token = Token(10);
 
+/// Path where the (kernel) modules of kernel version `kver` are located, along with their
+/// [aliases][IGNITED_MODULE_ALIASES].
+fn kern_modules_dir(kver: &str) -> String {
+    format!("{}/{}", IGNITED_KERN_MODULES, kver)
+}
+
 /// Perform initial work.
 ///
 /// - Mount `/dev` as `devtmpfs`.
@@ -208,31 +229,44 @@ fn initialize_kcon() -> Result<KConsole, PrintableErrno<String>> {
     Ok(kcon)
 }
 
-/// Check if booted kernel version matches initramfs kernel version.
+/// Select the module set matching the booted kernel version.
 ///
-/// The current initramfs [RuntimeConfig] contains the kernel version it was built for.
-/// To prevent a module version mismatch, check if the current booted kernel version
-/// matches the one in the config.
-fn kernel_ver_check(config: InitramfsMetadata) -> Result<(), PrintableErrno<String>> {
+/// The current initramfs [RuntimeConfig] contains the kernel versions it was built for, each
+/// with its own [metadata][crate::config::InitramfsMetadata] and modules. To prevent a
+/// module version mismatch, select the one matching the current booted kernel version, and
+/// fail only if there's none.
+fn kernel_ver_check(config: &mut RuntimeConfig) -> Result<(), PrintableErrno<String>> {
     let cur_ver = &get_booted_kernel_ver()[..];
-    let conf_ver = config.kernel_ver();
-    (cur_ver == conf_ver)
-        .then(|| ())
-        .ok_or_else(|| {
-            printable_error(
-                PROGRAM_NAME,
-                format!(
-                    "Linux kernel version mismatch. This initramfs image was built for version {con} and it is incompatible with the currently running version {cur}. Please rebuild the ignited image for kernel {cur}.",
-                    con = conf_ver,
-                    cur = cur_ver,
-                ),
-            )
-        })
+    if config.select_kernel(cur_ver) {
+        return Ok(());
+    }
+    let conf_vers: Vec<_> = config
+        .kernels()
+        .map(|kernel| kernel.kernel_ver().to_string())
+        .collect();
+    Err(printable_error(
+        PROGRAM_NAME,
+        format!(
+            "Linux kernel version mismatch. This initramfs image was built for version(s) \
+            {con} and it is incompatible with the currently running version {cur}. Please \
+            rebuild the ignited image for kernel {cur}.",
+            con = conf_vers.join(", "),
+            cur = cur_ver,
+        ),
+    ))
 }
 
 /// The entry point of the program. This function is in charge of exiting with an error
 /// code when [init] returns an [ExitError].
+///
+/// When not run as `/init` by the kernel, the [generator] runs instead.
 fn main() {
+    // Outside of the initramfs, ignited is the initramfs generator
+    if !matches!(std::env::args_os().next(), Some(arg0) if arg0 == "/init" || arg0 == "init") {
+        generator::run(std::env::args_os()).unwrap_or_eprint_exit();
+        return;
+    }
+
     // immediately start timer
     let timer = InitramfsTimer::start();
 
@@ -257,7 +291,8 @@ fn main() {
 /// - Mount `/sys`, `/proc`, and `/run` in that order.
 /// - If in EFI mode, mount `/sys/firmware/efi/efivars`.
 /// - Set path to a sensible default: `/usr/sbin:/usr/bin:/sbin:/bin`.
-/// - Read the current [RuntimeConfig] and [ModAliases].
+/// - Read the current [RuntimeConfig], select the module set of the booted kernel and
+///   read its [ModAliases].
 /// - Parse command line arguments.
 /// - Create the `/run/initramfs` directory as per
 ///   [systemd's INITRD_INTERFACE](https://systemd.io/INITRD_INTERFACE/).
@@ -267,7 +302,9 @@ fn main() {
 /// - Walk the `sysfs` filesystem to attempt to find and mount the root
 ///   partition at [`/system_root`][IGNITED_TARGET_ROOT_PATH].
 /// - Wait (optionally with a timeout) until the target root filesystem is
-///   mounted properly at [`/system_root`][IGNITED_TARGET_ROOT_PATH].
+///   mounted properly at [`/system_root`][IGNITED_TARGET_ROOT_PATH]. md RAID arrays
+///   still missing members after a while are started in degraded mode. Once the timeout
+///   expires, the root partition is looked for one last time before giving up.
 /// - Switch to the target root filesystem.
 /// - Transition to the target's init executable (usually at
 ///   [`/sbin/init`][INIT_DEFAULT_PATH]).
@@ -281,7 +318,7 @@ fn init(kcon: &mut KConsole, timer: InitramfsTimer) -> Result<(), ExitError<Stri
         match timeout {
             timeout if start == now => Ok(timeout),
             Some(timeout) => {
-                let elapsed = start - now;
+                let elapsed = now - start;
                 if elapsed > timeout {
                     return Err(printable_error(
                         PROGRAM_NAME,
@@ -314,10 +351,16 @@ fn init(kcon: &mut KConsole, timer: InitramfsTimer) -> Result<(), ExitError<Stri
 
     std::env::set_var("PATH", OsStr::new("/usr/sbin:/usr/bin:/sbin:/bin")); // Panics on error
 
-    let config = Arc::new(RuntimeConfig::try_from(Path::new(IGNITED_CONFIG)).bail(4)?);
-    kernel_ver_check(config.metadata()).bail(5)?;
+    let mut config = RuntimeConfig::try_from(Path::new(IGNITED_CONFIG)).bail(4)?;
+    kernel_ver_check(&mut config).bail(5)?;
+    let config = Arc::new(config);
 
-    let aliases = ModAliases::try_from(Path::new(IGNITED_MODULE_ALIASES)).bail(6)?;
+    let aliases_path = format!(
+        "{}/{}",
+        kern_modules_dir(config.metadata().kernel_ver()),
+        IGNITED_MODULE_ALIASES
+    );
+    let aliases = ModAliases::try_from(Path::new(&aliases_path)).bail(6)?;
     make_shutdown_pivot_dir().bail(7)?;
 
     let args = Arc::new(CmdlineArgs::parse_current(kcon).bail(8)?);
@@ -331,6 +374,8 @@ fn init(kcon: &mut KConsole, timer: InitramfsTimer) -> Result<(), ExitError<Stri
     }
 
     let mod_loading = ModLoading::new(&config, &args, aliases);
+    let prompter = Prompter::new();
+    let block_handling = BlockHandling::new(&config, &args, &mod_loading, &prompter);
 
     let mut evloop = Poll::new()
         .map_err(|io| {
@@ -353,12 +398,14 @@ fn init(kcon: &mut KConsole, timer: InitramfsTimer) -> Result<(), ExitError<Stri
             .bail(9)?,
     );
 
-    let udev = UdevListener::listen(&main_waker, &mod_loading).bail(10)?;
+    let udev =
+        UdevListener::listen(&main_waker, &mod_loading, &block_handling, &prompter).bail(10)?;
     let mod_loaded = mod_loading
         .load_modules(config.sysconf().get_force_modules())
         .bail(11)?;
     setup_vconsole(kcon, &config).bail(12)?;
-    let sysfs = SysfsWalker::walk(&main_waker, &mod_loading).bail(13)?;
+    prompter.vconsole_ready().bail(12)?;
+    let sysfs = SysfsWalker::walk(&main_waker, &mod_loading, &block_handling).bail(13)?;
 
     let start = Instant::now();
     let mut now = start; // Instant is Copy
@@ -366,8 +413,32 @@ fn init(kcon: &mut KConsole, timer: InitramfsTimer) -> Result<(), ExitError<Stri
         .sysconf()
         .get_mount_timeout()
         .map(Duration::from_secs);
+
+    // Incomplete md RAID arrays are started in degraded mode once this expires
+    let mut md_degraded_timeout = Some(args.md_targets().degraded_timeout())
+        .filter(|_| config.sysconf().has_mdraid() && args.md_targets().is_enabled());
+    // Booting goes on without resuming from hibernation once this expires (no later than
+    // the mount timeout, so that root is mounted normally rather than as a last resort)
+    let mut resume_timeout = args
+        .resume_source()
+        .map(|_| timeout.map_or(resume::RESUME_TIMEOUT, |t| t.min(resume::RESUME_TIMEOUT)));
     'main: loop {
-        match evloop.poll(&mut evs, calculate_evloop_timeout(start, now, timeout).bail(14)?) {
+        let mut evloop_timeout = match calculate_evloop_timeout(start, now, timeout) {
+            Ok(evloop_timeout) => evloop_timeout,
+            Err(e) => {
+                // Last chance, in case the root partition was missed
+                match block_handling.mount_root_now(kcon) {
+                    Ok(()) => break 'main,
+                    Err(root_e) => kerr!(kcon, "{}", root_e),
+                }
+                Err(e).bail(14)?
+            }
+        };
+        for wakeup in [md_degraded_timeout, resume_timeout].into_iter().flatten() {
+            let left = wakeup.saturating_sub(now - start);
+            evloop_timeout = Some(evloop_timeout.map_or(left, |t| t.min(left)));
+        }
+        match evloop.poll(&mut evs, evloop_timeout) {
             Ok(()) => {}
             Err(io) if io.kind() == ErrorKind::Interrupted => {
                 now = Instant::now();
@@ -391,6 +462,23 @@ fn init(kcon: &mut KConsole, timer: InitramfsTimer) -> Result<(), ExitError<Stri
             }
         }
         now = Instant::now();
+
+        if matches!(md_degraded_timeout, Some(md_timeout) if now - start >= md_timeout) {
+            md_degraded_timeout = None;
+            match block_handling.start_degraded_md(kcon) {
+                Ok(true) => break 'main,
+                Ok(false) => {}
+                Err(e) => kerr!(kcon, "{}", e),
+            }
+        }
+        if matches!(resume_timeout, Some(resume_timeout) if now - start >= resume_timeout) {
+            resume_timeout = None;
+            match block_handling.skip_resume(kcon) {
+                Ok(true) => break 'main,
+                Ok(false) => {}
+                Err(e) => kerr!(kcon, "{}", e),
+            }
+        }
     }
 
     udev.stop(kcon);
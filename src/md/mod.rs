@@ -0,0 +1,370 @@
+//! Linux md RAID arrays.
+//!
+//! Arrays to assemble are specified through the `rd.md.*` boot-time parameters, following
+//! the semantics of `dracut.cmdline(7)`:
+//!
+//! - `rd.md=<BOOL>` enables (default) or disables assembling arrays altogether.
+//! - `rd.md.uuid=<UUID>` only assembles the array with the given UUID. May be given
+//!   multiple times. The UUID can be formatted as `mdadm` does
+//!   (`xxxxxxxx:xxxxxxxx:xxxxxxxx:xxxxxxxx`) or as a standard UUID.
+//! - `rd.retry=<SECONDS>` sets how long to wait for missing members before starting
+//!   arrays in degraded mode: 2/3 of the given time (by default, 120 seconds).
+//!
+//! Assembly is done natively, without `mdadm`: member superblocks are read as devices
+//! appear and, once all active members of an array are present, the array is assembled
+//! through md ioctls as `/dev/md<N>` (with a `/dev/md/<NAME>` symlink for named arrays).
+//! The kernel then announces it through uevents like any other block device.
+
+pub mod superblock;
+
+use crate::{early_logging::KConsole, module::ModLoading, PROGRAM_NAME};
+use nix::{ioctl_none, ioctl_write_int_bad, ioctl_write_ptr, request_code_write};
+use precisej_printable_errno::{printable_error, ErrnoResult, PrintableErrno};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display, Formatter},
+    fs::{create_dir_all, write, OpenOptions},
+    io,
+    os::unix::{fs::symlink, io::AsRawFd},
+    path::Path,
+    time::Duration,
+};
+use superblock::MdMember;
+
+const MD_MAJOR: u8 = 9;
+const MD_NEW_ARRAY: &str = "/sys/module/md_mod/parameters/new_array";
+
+// Minor numbers tried for arrays without a usable preferred minor, as mdadm does
+const MD_FALLBACK_MINORS: std::ops::RangeInclusive<u32> = 0..=127;
+
+// Default rd.retry, as dracut
+const DEFAULT_RETRY: u64 = 180;
+
+// mdu_array_info_t from linux/raid/md_u.h
+#[repr(C)]
+#[derive(Default)]
+struct MduArrayInfo {
+    major_version: i32,
+    minor_version: i32,
+    patch_version: i32,
+    ctime: u32,
+    level: i32,
+    size: i32,
+    nr_disks: i32,
+    raid_disks: i32,
+    md_minor: i32,
+    not_persistent: i32,
+    utime: u32,
+    state: i32,
+    active_disks: i32,
+    working_disks: i32,
+    failed_disks: i32,
+    spare_disks: i32,
+    layout: i32,
+    chunk_size: i32,
+}
+
+// mdu_disk_info_t from linux/raid/md_u.h
+#[repr(C)]
+#[derive(Default)]
+struct MduDiskInfo {
+    number: i32,
+    major: i32,
+    minor: i32,
+    raid_disk: i32,
+    state: i32,
+}
+
+ioctl_write_ptr!(md_set_array_info, MD_MAJOR, 0x23, MduArrayInfo);
+ioctl_write_ptr!(md_add_new_disk, MD_MAJOR, 0x21, MduDiskInfo);
+// RUN_ARRAY takes a mdu_param_t, but persistent arrays are started with a NULL argument
+ioctl_write_int_bad!(md_run_array, request_code_write!(MD_MAJOR, 0x30, 12));
+ioctl_none!(md_stop_array, MD_MAJOR, 0x32);
+
+/// UUID of an md RAID array.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub struct MdUuid([u8; 16]);
+impl MdUuid {
+    /// Parse an array UUID, formatted as `mdadm` does (`xxxxxxxx:xxxxxxxx:xxxxxxxx:xxxxxxxx`)
+    /// or as a standard UUID.
+    pub fn parse(uuid: &str) -> Option<Self> {
+        let hex: Vec<u8> = uuid.bytes().filter(|b| *b != b':' && *b != b'-').collect();
+        if hex.len() != 32 {
+            return None;
+        }
+        let mut res = [0; 16];
+        for (byte, digits) in res.iter_mut().zip(hex.chunks_exact(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+        }
+        Some(Self(res))
+    }
+}
+impl Display for MdUuid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i > 0 && i % 4 == 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// md RAID arrays to assemble, as specified by the `rd.md.*` boot-time parameters.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MdTargets {
+    enabled: bool,
+    uuids: Vec<MdUuid>,
+    degraded_timeout: Duration,
+}
+impl MdTargets {
+    /// Builder: create a new [MdTargetsBuilder].
+    pub fn builder() -> MdTargetsBuilder {
+        Default::default()
+    }
+
+    /// Whether arrays should be assembled at all (`rd.md=0` disables them).
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether the array with the given UUID should be assembled.
+    pub fn should_assemble(&self, uuid: MdUuid) -> bool {
+        self.enabled && (self.uuids.is_empty() || self.uuids.contains(&uuid))
+    }
+
+    /// Time to wait for missing members before starting arrays in degraded mode.
+    pub fn degraded_timeout(&self) -> Duration {
+        self.degraded_timeout
+    }
+}
+
+/// Builder: md RAID arrays to assemble.
+///
+/// In case of conflicting values, the first specified value takes precedence.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MdTargetsBuilder {
+    enabled: Option<bool>,
+    uuids: Vec<MdUuid>,
+    retry: Option<u64>,
+}
+impl MdTargetsBuilder {
+    /// Enable or disable assembling arrays.
+    pub fn enable(&mut self, enabled: bool) -> &mut Self {
+        self.enabled.get_or_insert(enabled);
+        self
+    }
+
+    /// Only assemble the array with the given UUID (along with other listed arrays).
+    pub fn uuid(&mut self, uuid: MdUuid) -> &mut Self {
+        if !self.uuids.contains(&uuid) {
+            self.uuids.push(uuid);
+        }
+        self
+    }
+
+    /// Set `rd.retry` in seconds. Degraded arrays are started after 2/3 of it.
+    pub fn retry(&mut self, retry: u64) -> &mut Self {
+        self.retry.get_or_insert(retry);
+        self
+    }
+
+    /// Builder: build the list of arrays to assemble.
+    pub fn build(self) -> MdTargets {
+        MdTargets {
+            enabled: self.enabled.unwrap_or(true),
+            uuids: self.uuids,
+            degraded_timeout: Duration::from_secs(
+                self.retry.unwrap_or(DEFAULT_RETRY).saturating_mul(2) / 3,
+            ),
+        }
+    }
+}
+
+/// md RAID array waiting to be assembled, along with its members found so far.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MdArray {
+    uuid: MdUuid,
+    members: Vec<((u32, u32), MdMember)>,
+}
+impl MdArray {
+    /// UUID of the array.
+    pub fn uuid(&self) -> MdUuid {
+        self.uuid
+    }
+
+    // Most up-to-date member
+    fn freshest(&self) -> &MdMember {
+        self.members
+            .iter()
+            .map(|(_, member)| member)
+            .max_by_key(|member| member.events())
+            .expect("arrays have at least one member")
+    }
+
+    /// Whether every active slot of the array is filled by an up-to-date member.
+    pub fn is_complete(&self) -> bool {
+        let freshest = self.freshest();
+        let roles: BTreeSet<_> = self
+            .members
+            .iter()
+            .filter(|(_, member)| member.events() == freshest.events())
+            .filter_map(|(_, member)| member.role())
+            .collect();
+        roles.len() as u32 >= freshest.raid_disks()
+    }
+
+    /// Assemble and start the array, even if some members are missing (if the RAID level
+    /// allows it). Returns the kernel name of the array (e.g. `md127`).
+    ///
+    /// Out-of-date members are handed to the kernel as well: it decides which ones are
+    /// usable, as `mdadm` would.
+    pub fn assemble(
+        &self,
+        kcon: &mut KConsole,
+        mod_loading: &ModLoading,
+    ) -> Result<String, PrintableErrno<String>> {
+        let freshest = self.freshest();
+        let level = freshest.level();
+        let mut wgs = vec![mod_loading.load_modules(&["md_mod".to_string()])?];
+        // The personality may be built into the kernel
+        if let Some(wg) = mod_loading.load_modalias(format!("md-level-{}", level))? {
+            wgs.push(wg);
+        }
+        wgs.into_iter().for_each(|wg| wg.wait());
+
+        let name = Self::new_array(freshest.preferred_minor())?;
+        kinfo!(
+            kcon,
+            "assembling md array {} (level {}) as /dev/{}",
+            self.uuid,
+            level,
+            name
+        );
+        let path = format!("/dev/{}", name);
+        let md = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|io| {
+                printable_error(PROGRAM_NAME, format!("unable to open {}: {}", path, io))
+            })?;
+        let fd = md.as_raw_fd();
+
+        // Only the superblock version is set: everything else is read from the members
+        let (major_version, minor_version) = freshest.version();
+        let info = MduArrayInfo {
+            major_version: major_version as i32,
+            minor_version: minor_version as i32,
+            ..Default::default()
+        };
+        // SAFETY: info is a valid mdu_array_info_t
+        unsafe { md_set_array_info(fd, &info) }
+            .printable(PROGRAM_NAME, format!("unable to set up md array {}", name))?;
+
+        for ((major, minor), member) in &self.members {
+            let disk = MduDiskInfo {
+                major: *major as i32,
+                minor: *minor as i32,
+                raid_disk: member.role().map(|r| r as i32).unwrap_or(-1),
+                ..Default::default()
+            };
+            // SAFETY: disk is a valid mdu_disk_info_t
+            if let Err(e) = unsafe { md_add_new_disk(fd, &disk) } {
+                kwarn!(
+                    kcon,
+                    "unable to add {}:{} to md array {}: {}",
+                    major,
+                    minor,
+                    name,
+                    e
+                );
+            }
+        }
+
+        // SAFETY: RUN_ARRAY accepts a NULL argument for persistent arrays
+        let res = unsafe { md_run_array(fd, 0) }
+            .printable(PROGRAM_NAME, format!("unable to start md array {}", name));
+        if let Err(e) = res {
+            // Release the members
+            // SAFETY: STOP_ARRAY takes no argument
+            let _ = unsafe { md_stop_array(fd) };
+            return Err(e);
+        }
+
+        // /dev/md/<NAME> is usually created by udev rules
+        if let Some(array_name) = freshest.name() {
+            let _ = create_dir_all("/dev/md");
+            if let Err(io) = symlink(format!("../{}", name), format!("/dev/md/{}", array_name)) {
+                if io.kind() != io::ErrorKind::AlreadyExists {
+                    kwarn!(kcon, "unable to create /dev/md/{}: {}", array_name, io);
+                }
+            }
+        }
+        Ok(name)
+    }
+
+    // Create a new (empty) array device, preferably /dev/md<preferred_minor>. devtmpfs
+    // creates its device node.
+    fn new_array(preferred_minor: Option<u32>) -> Result<String, PrintableErrno<String>> {
+        let minors = preferred_minor.into_iter().chain(MD_FALLBACK_MINORS.rev());
+        for minor in minors {
+            let name = format!("md{}", minor);
+            if Path::new("/sys/block").join(&name).exists() {
+                continue;
+            }
+            match write(MD_NEW_ARRAY, &name) {
+                Ok(()) => return Ok(name),
+                // Taken by an array being assembled concurrently
+                Err(io) if io.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(io) => {
+                    return Err(printable_error(
+                        PROGRAM_NAME,
+                        format!("unable to create md array {}: {}", name, io),
+                    ))
+                }
+            }
+        }
+        Err(printable_error(
+            PROGRAM_NAME,
+            "unable to create md array: no free minor number".to_string(),
+        ))
+    }
+}
+
+/// md RAID arrays seen so far.
+#[derive(Debug, Clone, Default)]
+pub struct MdArrays {
+    pending: BTreeMap<MdUuid, MdArray>,
+    started: BTreeSet<MdUuid>,
+}
+impl MdArrays {
+    /// Record the member with device numbers `devnum`. Returns its array if this member
+    /// completed it, in which case it's no longer pending. Members of arrays that were
+    /// already started are ignored.
+    pub fn add_member(&mut self, devnum: (u32, u32), member: MdMember) -> Option<MdArray> {
+        let uuid = member.uuid();
+        if self.started.contains(&uuid) {
+            return None;
+        }
+        let array = self.pending.entry(uuid).or_insert_with(|| MdArray {
+            uuid,
+            members: Vec::new(),
+        });
+        array.members.retain(|(d, _)| *d != devnum);
+        array.members.push((devnum, member));
+        if !array.is_complete() {
+            return None;
+        }
+        self.started.insert(uuid);
+        self.pending.remove(&uuid)
+    }
+
+    /// Take every pending (i.e. incomplete) array, to be started in degraded mode.
+    pub fn take_pending(&mut self) -> Vec<MdArray> {
+        let pending = std::mem::take(&mut self.pending);
+        self.started.extend(pending.keys());
+        pending.into_values().collect()
+    }
+}
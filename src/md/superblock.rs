@@ -0,0 +1,405 @@
+//! md RAID member superblocks.
+//!
+//! Two superblock formats are in use:
+//!
+//! - v0.90: 4KiB, stored 64KiB from the end of the device (64KiB-aligned), in host byte
+//!   order.
+//! - v1.x: 256 bytes followed by the role of every member, in little-endian byte order.
+//!   Stored near the end of the device (v1.0), at its start (v1.1) or 4KiB from its start
+//!   (v1.2, the default).
+
+use super::MdUuid;
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+};
+
+const MD_MAGIC: u32 = 0xa92b4efc;
+
+const V0_SB_SIZE: usize = 4096;
+const V0_SB_CSUM_WORD: usize = 38;
+const V0_THIS_DISK_WORD: usize = 992;
+const V0_DISK_FAULTY: u32 = 1 << 0;
+const V0_DISK_SYNC: u32 = 1 << 2;
+
+const V1_SB_SIZE: usize = 256;
+const V1_SB_CSUM: usize = 216;
+const V1_MAX_DEV: u32 = (4096 - V1_SB_SIZE as u32) / 2;
+// Roles at or above this value are spares, faulty or journal devices
+const V1_ROLE_SPARE: u16 = 0xff00;
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Read `len` bytes at `offset`. Returns `None` if the device is too small.
+fn read_at(dev: &File, offset: u64, len: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = vec![0; len];
+    match dev.read_exact_at(&mut buf, offset) {
+        Ok(()) => Ok(Some(buf)),
+        Err(io) if io.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(io) => Err(io),
+    }
+}
+
+// Both formats use the same checksum: the sum of all 32-bit words (and the trailing 16-bit
+// word, if any) with the checksum field zeroed, folded into 32 bits.
+fn csum(sb: &[u8], csum_offset: usize) -> u32 {
+    let mut sum: u64 = 0;
+    let mut words = sb.chunks_exact(4);
+    for (i, word) in (&mut words).enumerate() {
+        if i * 4 != csum_offset {
+            sum += u32::from_le_bytes(word.try_into().unwrap()) as u64;
+        }
+    }
+    if let [lo, hi] = words.remainder() {
+        sum += u16::from_le_bytes([*lo, *hi]) as u64;
+    }
+    ((sum & 0xffffffff) + (sum >> 32)) as u32
+}
+
+/// Member of an md RAID array, as described by its superblock.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MdMember {
+    uuid: MdUuid,
+    major_version: u32,
+    minor_version: u32,
+    level: i32,
+    raid_disks: u32,
+    events: u64,
+    role: Option<u32>,
+    name: Option<String>,
+    preferred_minor: Option<u32>,
+}
+impl MdMember {
+    /// Read the md superblock of `dev`. Returns `None` if there's no valid superblock.
+    pub fn read(dev: &File) -> io::Result<Option<Self>> {
+        let size = {
+            let mut dev = dev;
+            dev.seek(SeekFrom::End(0))?
+        };
+
+        // (minor version, offset) in the order mdadm looks for them
+        let v1_end = (size >> 9).checked_sub(16).map(|sector| (sector & !7) << 9);
+        let v1 = [(1, Some(0)), (2, Some(4096)), (0, v1_end)];
+        for (minor_version, offset) in v1 {
+            if let Some(offset) = offset {
+                if let Some(member) = Self::read_v1(dev, minor_version, offset)? {
+                    return Ok(Some(member));
+                }
+            }
+        }
+        match (size & !0xffff).checked_sub(0x10000) {
+            Some(offset) => Self::read_v0(dev, offset),
+            None => Ok(None),
+        }
+    }
+
+    fn read_v0(dev: &File, offset: u64) -> io::Result<Option<Self>> {
+        let sb = match read_at(dev, offset, V0_SB_SIZE)? {
+            Some(sb) => sb,
+            None => return Ok(None),
+        };
+        let word = |i: usize| le32(&sb, i * 4);
+        if word(0) != MD_MAGIC
+            || word(1) != 0
+            || word(2) != 90
+            || word(V0_SB_CSUM_WORD) != csum(&sb, V0_SB_CSUM_WORD * 4)
+        {
+            return Ok(None);
+        }
+
+        // The UUID is split in two (set_uuid0 and set_uuid1..3)
+        let mut uuid = [0; 16];
+        for (i, w) in [5, 13, 14, 15].into_iter().enumerate() {
+            uuid[i * 4..i * 4 + 4].copy_from_slice(&word(w).to_be_bytes());
+        }
+        let raid_disks = word(10);
+
+        // this_disk: number, major, minor, raid_disk, state
+        let (raid_disk, state) = (word(V0_THIS_DISK_WORD + 3), word(V0_THIS_DISK_WORD + 4));
+        let active = state & V0_DISK_SYNC != 0 && state & V0_DISK_FAULTY == 0;
+        Ok(Some(Self {
+            uuid: MdUuid(uuid),
+            major_version: 0,
+            minor_version: 90,
+            level: word(7) as i32,
+            raid_disks,
+            events: ((word(40) as u64) << 32) | word(39) as u64,
+            role: Some(raid_disk).filter(|r| active && *r < raid_disks),
+            name: None,
+            preferred_minor: Some(word(11)),
+        }))
+    }
+
+    fn read_v1(dev: &File, minor_version: u32, offset: u64) -> io::Result<Option<Self>> {
+        let sb = match read_at(dev, offset, V1_SB_SIZE)? {
+            Some(sb) => sb,
+            None => return Ok(None),
+        };
+        let max_dev = le32(&sb, 220);
+        if le32(&sb, 0) != MD_MAGIC
+            || le32(&sb, 4) != 1
+            || le64(&sb, 144) != offset >> 9
+            || max_dev > V1_MAX_DEV
+        {
+            return Ok(None);
+        }
+        let sb = match read_at(dev, offset, V1_SB_SIZE + max_dev as usize * 2)? {
+            Some(sb) if le32(&sb, V1_SB_CSUM) == csum(&sb, V1_SB_CSUM) => sb,
+            _ => return Ok(None),
+        };
+
+        let raid_disks = le32(&sb, 92);
+        let dev_number = le32(&sb, 160);
+        let role = if dev_number < max_dev {
+            le16(&sb, V1_SB_SIZE + dev_number as usize * 2)
+        } else {
+            V1_ROLE_SPARE
+        };
+
+        // Names are formatted as [<homehost>:]<name>
+        let name = &sb[32..64];
+        let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+        let name = String::from_utf8_lossy(name);
+        let name = name.rsplit(':').next().unwrap_or("");
+        Ok(Some(Self {
+            uuid: MdUuid(sb[16..32].try_into().unwrap()),
+            major_version: 1,
+            minor_version,
+            level: le32(&sb, 72) as i32,
+            raid_disks,
+            events: le64(&sb, 200),
+            role: Some(role as u32).filter(|r| role < V1_ROLE_SPARE && *r < raid_disks),
+            name: Some(name.to_string()).filter(|n| !n.is_empty() && !n.contains('/')),
+            preferred_minor: name.parse().ok(),
+        }))
+    }
+
+    /// UUID of the array.
+    pub fn uuid(&self) -> MdUuid {
+        self.uuid
+    }
+
+    /// Superblock version (e.g. `(1, 2)`), as passed to the kernel on assembly.
+    pub fn version(&self) -> (u32, u32) {
+        (self.major_version, self.minor_version)
+    }
+
+    /// RAID level (`-1` for linear arrays).
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    /// Number of active members of the array.
+    pub fn raid_disks(&self) -> u32 {
+        self.raid_disks
+    }
+
+    /// Event counter. Members with a lower counter than the rest are out of date.
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    /// Slot of this member in the array, or `None` if it's a spare (or faulty).
+    pub fn role(&self) -> Option<u32> {
+        self.role
+    }
+
+    /// Name of the array (v1.x only), without the `<homehost>:` prefix.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Preferred minor number of the array (`/dev/md<N>`), if any.
+    pub fn preferred_minor(&self) -> Option<u32> {
+        self.preferred_minor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestImage;
+
+    const DEV_SIZE: usize = 1024 * 1024 + 12345;
+    const UUID: &str = "4d8a8e2c:9a1b3f5e:0c7d2e4f:6a8b0c1d";
+
+    fn put_le32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_le64(buf: &mut [u8], offset: usize, value: u64) {
+        buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // v1.x superblock at `offset` of member `dev_number` (out of 4 slots), whose role is
+    // `role`
+    fn v1_image(offset: usize, name: &str, dev_number: u32, role: u16) -> Vec<u8> {
+        let mut image = vec![0; DEV_SIZE];
+        let sb = &mut image[offset..offset + V1_SB_SIZE + 8];
+        put_le32(sb, 0, MD_MAGIC);
+        put_le32(sb, 4, 1);
+        sb[16..32].copy_from_slice(&MdUuid::parse(UUID).unwrap().0);
+        sb[32..32 + name.len()].copy_from_slice(name.as_bytes());
+        put_le32(sb, 72, 1);
+        put_le32(sb, 92, 2);
+        put_le64(sb, 144, offset as u64 / 512);
+        put_le32(sb, 160, dev_number);
+        put_le64(sb, 200, 0x1_0000_0042);
+        put_le32(sb, 220, 4);
+        for (slot, r) in [0, 1, 0xfffe, 0xffff].into_iter().enumerate() {
+            let r = if slot == dev_number as usize { role } else { r };
+            sb[V1_SB_SIZE + slot * 2..][..2].copy_from_slice(&r.to_le_bytes());
+        }
+        let sum = csum(sb, V1_SB_CSUM);
+        put_le32(sb, V1_SB_CSUM, sum);
+        image
+    }
+
+    // v0.90 superblock at the end of the device, of a member in slot `raid_disk` with
+    // disk state `state`
+    fn v0_image(raid_disk: u32, state: u32) -> Vec<u8> {
+        let mut image = vec![0; DEV_SIZE];
+        let offset = (DEV_SIZE & !0xffff) - 0x10000;
+        let sb = &mut image[offset..offset + V0_SB_SIZE];
+        let mut put_word = |i: usize, value: u32| put_le32(sb, i * 4, value);
+        put_word(0, MD_MAGIC);
+        put_word(2, 90);
+        put_word(5, 0x4d8a8e2c);
+        put_word(7, 5);
+        put_word(9, 3);
+        put_word(10, 3);
+        put_word(11, 127);
+        put_word(13, 0x9a1b3f5e);
+        put_word(14, 0x0c7d2e4f);
+        put_word(15, 0x6a8b0c1d);
+        put_word(39, 7);
+        put_word(40, 1);
+        put_word(V0_THIS_DISK_WORD + 3, raid_disk);
+        put_word(V0_THIS_DISK_WORD + 4, state);
+        let sum = csum(sb, V0_SB_CSUM_WORD * 4);
+        put_le32(sb, V0_SB_CSUM_WORD * 4, sum);
+        image
+    }
+
+    fn read(image: &[u8]) -> Option<MdMember> {
+        MdMember::read(TestImage::new(image).file()).unwrap()
+    }
+
+    #[test]
+    fn checksum() {
+        // Folded carry, checksum field skipped, trailing 16-bit word
+        let sb = [
+            1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xaa, 0xaa, 0xaa, 0xaa, 2, 0, 0, 0, 3, 0,
+        ];
+        assert_eq!(csum(&sb, 8), 6);
+    }
+
+    #[test]
+    fn v1_2() {
+        // Superblock 8 sectors into the device
+        let member = read(&v1_image(4096, "ember:root", 1, 1)).unwrap();
+        assert_eq!(member.uuid().to_string(), UUID);
+        assert_eq!(member.version(), (1, 2));
+        assert_eq!(member.level(), 1);
+        assert_eq!(member.raid_disks(), 2);
+        assert_eq!(member.events(), 0x1_0000_0042);
+        assert_eq!(member.role(), Some(1));
+        assert_eq!(member.name(), Some("root"));
+        assert_eq!(member.preferred_minor(), None);
+    }
+
+    #[test]
+    fn v1_1() {
+        // Superblock at sector 0, numeric name
+        let member = read(&v1_image(0, "ember:3", 0, 0)).unwrap();
+        assert_eq!(member.version(), (1, 1));
+        assert_eq!(member.role(), Some(0));
+        assert_eq!(member.name(), Some("3"));
+        assert_eq!(member.preferred_minor(), Some(3));
+    }
+
+    #[test]
+    fn v1_0() {
+        // Superblock at least 8KiB from the end, 4KiB-aligned
+        let offset = ((DEV_SIZE / 512 - 16) & !7) * 512;
+        let member = read(&v1_image(offset, "home", 0, 0)).unwrap();
+        assert_eq!(member.version(), (1, 0));
+        assert_eq!(member.name(), Some("home"));
+    }
+
+    #[test]
+    fn v1_spares() {
+        let member = read(&v1_image(4096, "root", 2, 0xffff)).unwrap();
+        assert_eq!(member.role(), None);
+
+        // Role beyond raid_disks (e.g. while reshaping)
+        let member = read(&v1_image(4096, "root", 2, 2)).unwrap();
+        assert_eq!(member.role(), None);
+
+        // No role slot for this device
+        let member = read(&v1_image(4096, "root", 4, 0)).unwrap();
+        assert_eq!(member.role(), None);
+    }
+
+    #[test]
+    fn v1_invalid() {
+        // Checksum
+        let mut image = v1_image(4096, "root", 0, 0);
+        image[4096 + 40] ^= 1;
+        assert_eq!(read(&image), None);
+
+        // Checksum of the roles
+        let mut image = v1_image(4096, "root", 0, 0);
+        image[4096 + V1_SB_SIZE + 2] ^= 1;
+        assert_eq!(read(&image), None);
+
+        // super_offset pointing elsewhere (e.g. a stale superblock)
+        let mut image = vec![0; DEV_SIZE];
+        image[4096..8192].copy_from_slice(&v1_image(0, "root", 0, 0)[..4096]);
+        assert_eq!(read(&image), None);
+    }
+
+    #[test]
+    fn v0_90() {
+        let member = read(&v0_image(2, V0_DISK_SYNC)).unwrap();
+        assert_eq!(member.uuid().to_string(), UUID);
+        assert_eq!(member.version(), (0, 90));
+        assert_eq!(member.level(), 5);
+        assert_eq!(member.raid_disks(), 3);
+        assert_eq!(member.events(), 0x1_0000_0007);
+        assert_eq!(member.role(), Some(2));
+        assert_eq!(member.name(), None);
+        assert_eq!(member.preferred_minor(), Some(127));
+
+        assert_eq!(
+            read(&v0_image(2, V0_DISK_SYNC | V0_DISK_FAULTY))
+                .unwrap()
+                .role(),
+            None
+        );
+        assert_eq!(read(&v0_image(2, 0)).unwrap().role(), None);
+        assert_eq!(read(&v0_image(3, V0_DISK_SYNC)).unwrap().role(), None);
+
+        let mut image = v0_image(2, V0_DISK_SYNC);
+        image[(DEV_SIZE & !0xffff) - 0x10000 + 100] ^= 1;
+        assert_eq!(read(&image), None);
+    }
+
+    #[test]
+    fn no_superblock() {
+        assert_eq!(read(&[]), None);
+        assert_eq!(read(&[0; 4096]), None);
+        assert_eq!(read(&vec![0; DEV_SIZE]), None);
+    }
+}
@@ -5,23 +5,32 @@
 //! special `/vendor` partition.
 
 use crate::{
-    early_logging::KConsole, CmdlineArgs, RuntimeConfig, IGNITED_KERN_MODULES,
+    decompress::{decompress, COMPRESSED_EXTENSIONS},
+    early_logging::KConsole, kern_modules_dir, CmdlineArgs, RuntimeConfig,
     PROGRAM_NAME,
 };
 use crossbeam_utils::sync::WaitGroup;
 use dashmap::DashSet;
 use goglob::GlobPattern;
-use nix::kmod::{finit_module, ModuleInitFlags};
+use nix::{
+    errno::Errno,
+    kmod::{finit_module, init_module, ModuleInitFlags},
+};
 use precisej_printable_errno::{printable_error, ErrnoResult, PrintableErrno};
 use std::{
     collections::{btree_map::Entry, BTreeMap},
     ffi::CString,
     fs::File,
+    io::{self, Read},
     ops::DerefMut,
     sync::{Arc, Mutex},
     thread,
 };
 
+/// `finit_module(2)` flag: the module is compressed and should be decompressed by the
+/// kernel (Linux 5.17+). Not yet exposed by `nix`.
+const MODULE_INIT_COMPRESSED_FILE: u32 = 4;
+
 /// (Kernel) Module alias.
 ///
 /// Inside of `/sys/devices/*` there is a `modalias` file for every device with a
@@ -299,15 +308,22 @@ impl ModLoading {
 
         deps_wg.wait();
 
-        Self::finit(&mut kcon, module, &self.config, &self.args)?;
+        let res = Self::finit(&mut kcon, module, &self.config, &self.args);
         let mut unlocked = self.bookkeeping.lock().map_err(|_| {
             printable_error(PROGRAM_NAME, "unable to lock module-loading".to_string())
         })?;
+
+        // Whoever is waiting on this module must be released, even on failure
         if let Some(wgs) = unlocked.loading.remove(module) {
             for wg in wgs {
                 drop(wg)
             }
         }
+        if let Err(e) = res {
+            kerr!(kcon, "{}", e);
+            return Err(e);
+        }
+        unlocked.loaded.insert(module.to_string(), ());
 
         if let Some(deps) = self.config.metadata().module_post_deps().get(module) {
             self.load_modules_unlocked(&deps[..], &orig_wg, unlocked.deref_mut())?;
@@ -315,22 +331,46 @@ impl ModLoading {
         Ok(())
     }
 
+    /// Open the specified (kernel) module of the booted kernel, either uncompressed
+    /// (`<module>.ko`) or compressed (`<module>.ko.zst`, `<module>.ko.xz` or `<module>.ko.gz`),
+    /// whichever is present. Returns whether it's compressed as well.
+    fn open(module: &str, config: &RuntimeConfig) -> Result<(File, bool), PrintableErrno<String>> {
+        let path = format!(
+            "{}/{}.ko",
+            kern_modules_dir(config.metadata().kernel_ver()),
+            module
+        );
+        for ext in std::iter::once("").chain(COMPRESSED_EXTENSIONS) {
+            match File::open(format!("{}{}", path, ext)) {
+                Ok(f) => return Ok((f, !ext.is_empty())),
+                Err(io) if io.kind() == io::ErrorKind::NotFound => continue,
+                Err(io) => {
+                    return Err(printable_error(
+                        PROGRAM_NAME,
+                        format!("unable to open {}{}: {}", path, ext, io),
+                    ))
+                }
+            }
+        }
+        Err(printable_error(
+            PROGRAM_NAME,
+            format!("unable to open {}: module not found", path),
+        ))
+    }
+
     /// Actually load the specified (kernel) module.
+    ///
+    /// Compressed modules are handed to the kernel as-is (`MODULE_INIT_COMPRESSED_FILE`)
+    /// first. If the kernel is unable to decompress them (older than 5.17, or built without
+    /// support for that format), they are decompressed here and loaded through
+    /// `init_module` instead.
     fn finit(
         kcon: &mut KConsole,
         module: &str,
         config: &RuntimeConfig,
         args: &CmdlineArgs,
     ) -> Result<(), PrintableErrno<String>> {
-        let f = File::open(format!("{}/{}.ko", IGNITED_KERN_MODULES, module)).map_err(|io| {
-            printable_error(
-                PROGRAM_NAME,
-                format!(
-                    "unable to open {}/{}.ko: {}",
-                    IGNITED_KERN_MODULES, module, io
-                ),
-            )
-        })?;
+        let (mut f, compressed) = Self::open(module, config)?;
 
         // Comment from booster:
         // I am not sure if ordering is important but we add modprobe params first and then cmdline
@@ -355,7 +395,36 @@ impl ModLoading {
                 "unable to convert parameters to string".to_string(),
             )
         })?;
-        finit_module(&f, params_c.as_ref(), ModuleInitFlags::empty())
+        if !compressed {
+            return finit_module(&f, params_c.as_ref(), ModuleInitFlags::empty())
+                .printable(PROGRAM_NAME, format!("unable to load module {}", module));
+        }
+
+        // Unknown to nix: passed as-is to finit_module(2), which validates it
+        let flags = unsafe { ModuleInitFlags::from_bits_unchecked(MODULE_INIT_COMPRESSED_FILE) };
+        match finit_module(&f, params_c.as_ref(), flags) {
+            Err(Errno::EINVAL | Errno::EOPNOTSUPP) => {
+                kdebug!(
+                    kcon,
+                    "kernel unable to decompress module {}, decompressing it",
+                    module
+                );
+            }
+            res => {
+                return res.printable(PROGRAM_NAME, format!("unable to load module {}", module))
+            }
+        }
+        let mut data = Vec::new();
+        let image = f
+            .read_to_end(&mut data)
+            .and_then(|_| decompress(data))
+            .map_err(|io| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unable to decompress module {}: {}", module, io),
+                )
+            })?;
+        init_module(&image, params_c.as_ref())
             .printable(PROGRAM_NAME, format!("unable to load module {}", module))
     }
 }
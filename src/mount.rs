@@ -1,14 +1,18 @@
 //! Mount options for filesystems.
 
-use crate::{IGNITED_TARGET_ROOT_PATH, KConsole, PROGRAM_NAME};
+use crate::{
+    blkid::{self, gpt::GPT_ATTR_NO_AUTO, BlockDevice, FsUuid},
+    IGNITED_TARGET_ROOT_PATH, KConsole, PROGRAM_NAME,
+};
 use nix::{
     errno::Errno,
     mount::{mount, umount2, MntFlags, MsFlags},
-    sys::stat::Mode,
+    sys::stat::{major, minor, stat, Mode},
     unistd::mkdir,
 };
 use precisej_printable_errno::{printable_error, ErrnoResult, PrintableErrno};
 use std::{
+    fmt::{self, Display, Formatter},
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -27,7 +31,7 @@ impl TmpfsOpts {
     /// - `source` is the name of the `tmpfs` instance (e.g. `run`).
     /// - `target` is the path to the folder where the `tmpfs` is to be mounted (e.g. `/run`).
     /// - `flags` are the mount flags to be applied (e.g.
-    /// [`MS_NOSUID`][MsFlags::MS_NOSUID]`|`[`MS_NODEV`][MsFlags::MS_NODEV]).
+    ///   [`MS_NOSUID`][MsFlags::MS_NOSUID]`|`[`MS_NODEV`][MsFlags::MS_NODEV]).
     /// - `options` are other mount options to be passed at mount-time (e.g. `mode=755`).
     pub fn new<S1: Into<String>, S2: Into<String>, P: Into<PathBuf>>(
         source: S1,
@@ -57,6 +61,16 @@ impl RootOpts {
     pub fn builder() -> RootOptsBuilder {
         Default::default()
     }
+
+    /// Path to the device node of the new root filesystem.
+    pub fn source(&self) -> &str {
+        &self.source[..]
+    }
+
+    /// Filesystem type of the new root filesystem.
+    pub fn fstype(&self) -> &str {
+        &self.fstype[..]
+    }
 }
 
 /// EFI Partition GPT PARTUUID
@@ -97,6 +111,11 @@ impl EfiPartitionGptGuid {
         Ok(EfiPartitionGptGuid(uuid))
     }
 
+    /// The EFI Partition PartUUID.
+    pub fn uuid(&self) -> uuid::Uuid {
+        self.0
+    }
+
     fn read_efi_var(name: &str, uuid: &str) -> Result<(u32, Vec<u8>), PrintableErrno<String>> {
         let data = std::fs::read(format!("/sys/firmware/efi/efivars/{}-{}", name, uuid)).map_err(
             |io| {
@@ -124,6 +143,9 @@ pub enum PartitionSourceBuilder {
     /// `root=UUID=<uuid>` or `root=/dev/disk/by-uuid/<uuid>`: partition UUID.
     Uuid(uuid::Uuid),
 
+    /// `root=UUID=<XXXX-XXXX>` or `root=/dev/disk/by-uuid/<XXXX-XXXX>`: FAT volume serial.
+    VolumeId(u32),
+
     /// `root=LABEL=<label>` or `root=/dev/disk/by-label/<label>`: partition Label.
     Label(String),
 
@@ -194,21 +216,21 @@ impl PartitionSourceBuilder {
     fn _parse(root: &str) -> Option<Self> {
         if let Some(uuid) = root
             .strip_prefix("UUID=")
-            .or_else(|| root.strip_prefix("/dev/disk/by-uuid"))
+            .or_else(|| root.strip_prefix("/dev/disk/by-uuid/"))
         {
             Self::parse_uuid(uuid)
         } else if let Some(label) = root
             .strip_prefix("LABEL=")
-            .or_else(|| root.strip_prefix("/dev/disk/by-label"))
+            .or_else(|| root.strip_prefix("/dev/disk/by-label/"))
         {
             Some(Self::parse_label(label))
         } else if let Some(partuuid_partnroff) = root.strip_prefix("PARTUUID=") {
             Self::parse_partuuid_partnroff(partuuid_partnroff)
-        } else if let Some(partuuid) = root.strip_prefix("/dev/disk/by-partuuid") {
+        } else if let Some(partuuid) = root.strip_prefix("/dev/disk/by-partuuid/") {
             Self::parse_partuuid(partuuid)
         } else if let Some(partlabel) = root
             .strip_prefix("PARTLABEL=")
-            .or_else(|| root.strip_prefix("/dev/disk/by-partlabel"))
+            .or_else(|| root.strip_prefix("/dev/disk/by-partlabel/"))
         {
             Some(Self::parse_partlabel(partlabel))
         } else if root.starts_with("/dev/") {
@@ -247,22 +269,119 @@ impl PartitionSourceBuilder {
     }
 
     fn parse_uuid(uuid: &str) -> Option<Self> {
-        Some(Self::Uuid(Self::uuid_from_str(uuid)?))
+        match Self::uuid_from_str(uuid) {
+            Some(uuid) => Some(Self::Uuid(uuid)),
+            None => Self::parse_volume_id(uuid),
+        }
+    }
+
+    // FAT volume serials are formatted as XXXX-XXXX
+    fn parse_volume_id(volume_id: &str) -> Option<Self> {
+        let (high, low) = volume_id.split_once('-')?;
+        if high.len() != 4 || low.len() != 4 {
+            return None;
+        }
+        let high = u32::from_str_radix(high, 16).ok()?;
+        let low = u32::from_str_radix(low, 16).ok()?;
+        Some(Self::VolumeId((high << 16) | low))
     }
 
     // uuid is possible quoted, should be stripped before processing
     fn uuid_from_str(uuid_str_q: &str) -> Option<uuid::Uuid> {
         let uuid_str = uuid_str_q
             .strip_prefix('"')
-            .map(|u| u.strip_suffix('"'))
-            .flatten()
+            .and_then(|u| u.strip_suffix('"'))
             .unwrap_or(uuid_str_q);
         uuid::Uuid::from_str(uuid_str).ok()
     }
 
-    /// Builder: reconstruct raw device path as String.
-    pub fn build(self) -> String {
-        todo!("convert to device path")
+    /// Check whether the given probed block device is the one described by `self`.
+    ///
+    /// - `PARTNROFF` is resolved relative to the partition number of `PARTUUID` in the same
+    ///   disk.
+    /// - Autodiscovered partitions must be in the same disk as the EFI System Partition the
+    ///   system was booted from, and must not have the `no-auto` GPT attribute set.
+    pub fn matches(&self, device: &BlockDevice) -> bool {
+        let id = device.id();
+        let part = device.part();
+        match self {
+            Self::Uuid(uuid) => id.and_then(|id| id.uuid()) == Some(FsUuid::Uuid(*uuid)),
+            Self::VolumeId(volume_id) => {
+                id.and_then(|id| id.uuid()) == Some(FsUuid::VolumeId(*volume_id))
+            }
+            Self::Label(label) => id.and_then(|id| id.label()) == Some(&label[..]),
+            Self::PartLabel(partlabel) => part.and_then(|part| part.name()) == Some(&partlabel[..]),
+            Self::PartUuid(partuuid) => part.and_then(|part| part.uuid()) == Some(*partuuid),
+            Self::PartUuidPartnroff(partuuid, partnroff) => part
+                .and_then(|part| {
+                    let base = part.gpt()?.partition_by_guid(*partuuid)?;
+                    Some(part.number() as i64 == base.number() as i64 + partnroff)
+                })
+                .unwrap_or(false),
+            Self::PartType(parttype, esp) => part
+                .and_then(|part| {
+                    let entry = part.gpt_entry()?;
+                    let gpt = part.gpt()?;
+                    Some(
+                        entry.type_guid() == *parttype
+                            && !entry.has_attribute(GPT_ATTR_NO_AUTO)
+                            && gpt.partition_by_guid(esp.uuid()).is_some(),
+                    )
+                })
+                .unwrap_or(false),
+            Self::RawDevice(raw_device) => {
+                // Compare device numbers, as raw_device might be a symlink or an
+                // alternative device node. /dev/mapper nodes might not exist at all, so
+                // compare the device-mapper name as well.
+                Path::new(raw_device) == device.path()
+                    || device
+                        .dm_name()
+                        .map(|dm_name| format!("/dev/mapper/{}", dm_name))
+                        .as_deref()
+                        == Some(&raw_device[..])
+                    || stat(&raw_device[..])
+                        .map(|st| {
+                            (major(st.st_rdev) as u32, minor(st.st_rdev) as u32)
+                                == device.devnum()
+                        })
+                        .unwrap_or(false)
+            }
+        }
+    }
+
+    /// Find the block device described by `self` among all block devices currently known
+    /// to the kernel.
+    ///
+    /// Devices that fail to be probed (e.g. removable devices without media) are skipped.
+    pub fn resolve(&self) -> Result<Option<BlockDevice>, PrintableErrno<String>> {
+        Ok(blkid::enumerate()?
+            .iter()
+            .filter_map(|name| BlockDevice::probe(name).ok())
+            .find(|device| self.matches(device)))
+    }
+
+    /// Builder: find the block device described by `self` among all block devices currently
+    /// known to the kernel. Fails if there's none.
+    pub fn build(&self) -> Result<BlockDevice, PrintableErrno<String>> {
+        self.resolve()?.ok_or_else(|| {
+            printable_error(PROGRAM_NAME, format!("unable to find device {}", self))
+        })
+    }
+}
+impl Display for PartitionSourceBuilder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uuid(uuid) => write!(f, "UUID={}", uuid),
+            Self::VolumeId(volume_id) => write!(f, "UUID={}", FsUuid::VolumeId(*volume_id)),
+            Self::Label(label) => write!(f, "LABEL={}", label),
+            Self::PartUuid(partuuid) => write!(f, "PARTUUID={}", partuuid),
+            Self::PartUuidPartnroff(partuuid, partnroff) => {
+                write!(f, "PARTUUID={}/PARTNROFF={}", partuuid, partnroff)
+            }
+            Self::PartType(parttype, _) => write!(f, "PARTTYPE={}", parttype),
+            Self::PartLabel(partlabel) => write!(f, "PARTLABEL={}", partlabel),
+            Self::RawDevice(raw_device) => write!(f, "{}", raw_device),
+        }
     }
 }
 
@@ -297,11 +416,6 @@ impl RootOptsBuilder {
         self.fstype.get_or_insert(fstype);
     }
 
-    /// Get the current root partition type if present.
-    pub fn get_fstype(&self) -> Option<&str> {
-        self.fstype.as_deref()
-    }
-
     /// Initially mount the root partition as read-only.
     pub fn ro(&mut self) -> &mut Self {
         self.rw = false;
@@ -361,20 +475,33 @@ impl RootOptsBuilder {
         }
     }
 
-    /// Builder: attempt to build the options struct used for mounting the new root filesystem.
-    /// If all parameters are present, the result shall be of type [RootOpts]. Otherwise an`Err`
-    /// containing `self` is returned.
-    pub fn try_build(self) -> Result<RootOpts, Self> {
-        let (source, fstype) = match (&self.source, &self.fstype) {
-            (Some(source), Some(fstype)) => (source.clone().build(), fstype.clone()),
-            _ => return Err(self),
+    /// Builder: attempt to build the options struct used for mounting the new root filesystem
+    /// from an already probed block device. If the device is the root partition and its
+    /// filesystem type is known (either through `rootfstype` or by probing), the result shall
+    /// be of type [RootOpts]. Otherwise `None` is returned.
+    pub fn try_build_with(&self, device: &BlockDevice) -> Option<RootOpts> {
+        if !self.source.as_ref()?.matches(device) {
+            return None;
+        }
+        self.build_with(device)
+    }
+
+    /// Builder: build the options struct used for mounting the new root filesystem from the
+    /// given block device, regardless of whether it matches the root partition source (e.g.
+    /// the device unlocked from a LUKS root partition). Returns `None` if the filesystem type
+    /// is unknown.
+    pub fn build_with(&self, device: &BlockDevice) -> Option<RootOpts> {
+        let fstype = match self.fstype {
+            Some(ref fstype) => fstype.clone(),
+            None => device.id()?.fstype().to_string(),
         };
-        let options = self.options;
+        let source = device.path().to_string_lossy().into_owned();
+        let options = self.options.clone();
 
         let mut flags = self.flags;
         flags.set(MsFlags::MS_RDONLY, !self.rw);
 
-        Ok(RootOpts {
+        Some(RootOpts {
             source,
             fstype,
             flags,
@@ -382,23 +509,38 @@ impl RootOptsBuilder {
         })
     }
 
-    /// Builder: forcibly build the options struct used for mounting the new root filesystem.
-    /// If all parameters are present, the result shall be of type [RootOpts]. Otherwise
-    /// **this function will panic**.
-    pub fn build(self) -> RootOpts {
-        let source = self.source.unwrap().build();
-        let fstype = self.fstype.unwrap();
-
-        let options = self.options;
-
-        let mut flags = self.flags;
-        flags.set(MsFlags::MS_RDONLY, !self.rw);
-
-        RootOpts {
-            source,
-            fstype,
-            flags,
-            options,
+    /// Builder: build the options struct used for mounting the new root filesystem, looking
+    /// for the root partition among the block devices currently known to the kernel. Fails if
+    /// it can't be found, if it doesn't contain a filesystem (e.g. a LUKS volume that wasn't
+    /// unlocked) or if its filesystem type is unknown.
+    pub fn build(&self) -> Result<RootOpts, PrintableErrno<String>> {
+        // Formats recognized by blkid that can't be mounted
+        const NOT_MOUNTABLE: &[&str] = &[
+            "crypto_LUKS",
+            "LVM2_member",
+            "linux_raid_member",
+            "swap",
+            "swsuspend",
+            "jbd",
+        ];
+
+        let source = self.source.as_ref().ok_or_else(|| {
+            printable_error(PROGRAM_NAME, "no root partition given".to_string())
+        })?;
+        let device = source.build()?;
+        match device.id().map(|id| id.fstype()) {
+            Some(fstype) if NOT_MOUNTABLE.contains(&fstype) && self.fstype.is_none() => {
+                Err(printable_error(
+                    PROGRAM_NAME,
+                    format!("root {} is a {} device, not a filesystem", source, fstype),
+                ))
+            }
+            _ => self.build_with(&device).ok_or_else(|| {
+                printable_error(
+                    PROGRAM_NAME,
+                    format!("unknown filesystem type on root {}", source),
+                )
+            }),
         }
     }
 }
@@ -465,7 +607,7 @@ impl Mount {
             Mount::Sysfs => Path::new("/sys"),
             Mount::Tmpfs(TmpfsOpts { target, .. }) => target.as_path(),
             Mount::Efivarfs => Path::new("/sys/firmware/efi/efivars"),
-            Mount::Root(_) => todo!(),
+            Mount::Root(_) => Path::new(IGNITED_TARGET_ROOT_PATH),
         }
     }
 
@@ -517,7 +659,7 @@ impl Mount {
 
         match mkdir(path, s_rwxu_rxg_rxo) {
             Ok(()) => return Ok(()),
-            Err(e) if e == Errno::ENOENT => {
+            Err(Errno::ENOENT) => {
                 // Recurse and try again
             }
             Err(_) if path.is_dir() => return Ok(()),
@@ -0,0 +1,404 @@
+//! Interactive passphrase prompts.
+//!
+//! Passphrases are asked for on every active console (see `/sys/class/tty/console/active`):
+//! the foreground virtual terminal and any serial console given through `console=`.
+//! `/dev/console` itself is only used if no active console can be found, as it's an alias
+//! of the last one. The first console to complete a line provides the passphrase.
+//!
+//! Input isn't echoed: each character is masked with a `*` instead. Virtual terminal
+//! input goes through the kernel keymap, so prompts wait until the keymap from the
+//! configuration has been loaded (see [setup_vconsole][crate::vconsole::setup_vconsole]),
+//! and until a keyboard is available (or a short timeout expires).
+
+use crate::{
+    crypto::{zeroize, Secret},
+    early_logging::KConsole,
+    PROGRAM_NAME,
+};
+use mio::{unix::SourceFd, Events, Interest, Poll, Token};
+use nix::{
+    libc,
+    sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, SpecialCharacterIndices, Termios},
+};
+use precisej_printable_errno::{printable_error, ErrnoResult, PrintableErrno};
+use std::{
+    fs::{read_dir, read_to_string, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Maximum passphrase length. Longer input is truncated.
+const MAX_PASSPHRASE_LEN: usize = 512;
+
+/// Time to wait for a keyboard before prompting anyway.
+const KEYBOARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval at which `sysfs` is checked for a keyboard, in case no `uevent` notifies us.
+const KEYBOARD_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// `KEY_ENTER` from linux/input-event-codes.h. Used to tell keyboards apart from other
+/// input devices.
+const KEY_ENTER: u32 = 28;
+
+// Inner struct containing Prompter's fields. Meant to be guarded by a mutex.
+#[derive(Debug, Default)]
+struct PrompterInner {
+    vconsole_ready: bool,
+}
+
+/// Passphrase prompting: waits until the console is ready to receive input, then asks for
+/// passphrases on every active console.
+#[derive(Debug, Clone)]
+pub struct Prompter {
+    state: Arc<(Mutex<PrompterInner>, Condvar)>,
+}
+impl Prompter {
+    /// Build a new instance of this struct. This should only be called once.
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new((Mutex::new(PrompterInner::default()), Condvar::new())),
+        }
+    }
+
+    /// Notify that the virtual console has been set up (i.e. its keymap has been loaded).
+    /// No prompt is shown before this is called.
+    pub fn vconsole_ready(&self) -> Result<(), PrintableErrno<String>> {
+        self.lock()?.vconsole_ready = true;
+        self.state.1.notify_all();
+        Ok(())
+    }
+
+    /// Notify that a new input device (possibly a keyboard) has been added.
+    pub fn input_added(&self) {
+        self.state.1.notify_all();
+    }
+
+    /// Ask for a passphrase with `prompt` on every active console. The input is masked and
+    /// the returned buffer is zeroed on drop.
+    pub fn ask_passphrase(
+        &self,
+        kcon: &mut KConsole,
+        prompt: &str,
+    ) -> Result<Secret, PrintableErrno<String>> {
+        let mut consoles = Console::open_active(kcon)?;
+        self.wait_ready(kcon, &consoles)?;
+
+        for console in consoles.iter_mut() {
+            console.start(prompt);
+        }
+        let res = read_line(&mut consoles);
+        for console in consoles.iter_mut() {
+            console.finish();
+        }
+        res
+    }
+
+    /// Show `message` on every active console (e.g. after a wrong passphrase).
+    pub fn show_message(&self, kcon: &mut KConsole, message: &str) {
+        for name in active_consoles() {
+            let res = OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NOCTTY)
+                .open(format!("/dev/{}", name))
+                .and_then(|mut tty| writeln!(tty, "{}", message));
+            if let Err(io) = res {
+                kwarn!(kcon, "unable to write to /dev/{}: {}", name, io);
+            }
+        }
+    }
+
+    /// Wait until the virtual console is set up and, if no serial console is available,
+    /// until a keyboard is plugged in.
+    fn wait_ready(
+        &self,
+        kcon: &mut KConsole,
+        consoles: &[Console],
+    ) -> Result<(), PrintableErrno<String>> {
+        let mut unlocked = self.lock()?;
+        while !unlocked.vconsole_ready {
+            unlocked = self.wait(unlocked, KEYBOARD_POLL_INTERVAL)?;
+        }
+
+        if consoles.iter().any(|c| !c.is_vt()) || has_keyboard() {
+            return Ok(());
+        }
+        kinfo!(kcon, "waiting for a keyboard");
+        let start = Instant::now();
+        while !has_keyboard() {
+            if start.elapsed() > KEYBOARD_TIMEOUT {
+                kwarn!(kcon, "no keyboard found, asking for passphrase anyway");
+                break;
+            }
+            unlocked = self.wait(unlocked, KEYBOARD_POLL_INTERVAL)?;
+        }
+        Ok(())
+    }
+
+    fn wait<'a>(
+        &self,
+        guard: MutexGuard<'a, PrompterInner>,
+        timeout: Duration,
+    ) -> Result<MutexGuard<'a, PrompterInner>, PrintableErrno<String>> {
+        self.state
+            .1
+            .wait_timeout(guard, timeout)
+            .map(|(guard, _)| guard)
+            .map_err(|_| printable_error(PROGRAM_NAME, "unable to lock prompter".to_string()))
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, PrompterInner>, PrintableErrno<String>> {
+        self.state
+            .0
+            .lock()
+            .map_err(|_| printable_error(PROGRAM_NAME, "unable to lock prompter".to_string()))
+    }
+}
+
+/// Names of the active consoles in `/dev` (e.g. `tty1` and `ttyS0`). `tty0` (the current
+/// virtual terminal) is resolved to the actual terminal.
+fn active_consoles() -> Vec<String> {
+    let mut consoles: Vec<String> = Vec::new();
+    let active = read_to_string("/sys/class/tty/console/active").unwrap_or_default();
+    for name in active.split_whitespace() {
+        let name = match name {
+            "tty0" => match read_to_string("/sys/class/tty/tty0/active") {
+                Ok(vt) => vt.trim().to_string(),
+                Err(_) => name.to_string(),
+            },
+            name => name.to_string(),
+        };
+        if !name.is_empty() && !consoles.contains(&name) {
+            consoles.push(name);
+        }
+    }
+    if consoles.is_empty() {
+        consoles.push("console".to_string());
+    }
+    consoles
+}
+
+/// Check whether a keyboard is plugged in, i.e. whether any input device is able to send
+/// `KEY_ENTER`.
+fn has_keyboard() -> bool {
+    let entries = match read_dir("/sys/class/input") {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.flatten().any(|entry| {
+        // The bitmap is a list of hex words, most significant first
+        let keys = read_to_string(entry.path().join("capabilities/key")).unwrap_or_default();
+        keys.split_whitespace()
+            .last()
+            .and_then(|word| u64::from_str_radix(word, 16).ok())
+            .map(|word| word & (1 << KEY_ENTER) != 0)
+            .unwrap_or(false)
+    })
+}
+
+/// Console open for reading a passphrase. Its terminal attributes are restored on drop.
+struct Console {
+    name: String,
+    tty: File,
+    orig: Option<Termios>,
+    input: Secret,
+    len: usize,
+}
+impl Console {
+    /// Open every active console. Consoles that can't be opened are skipped.
+    fn open_active(kcon: &mut KConsole) -> Result<Vec<Self>, PrintableErrno<String>> {
+        let consoles: Vec<Self> = active_consoles()
+            .into_iter()
+            .filter_map(|name| match Self::open(&name) {
+                Ok(console) => Some(console),
+                Err(e) => {
+                    kwarn!(kcon, "{}", e);
+                    None
+                }
+            })
+            .collect();
+        if consoles.is_empty() {
+            return Err(printable_error(
+                PROGRAM_NAME,
+                "unable to ask for passphrase: no console available".to_string(),
+            ));
+        }
+        Ok(consoles)
+    }
+
+    fn open(name: &str) -> Result<Self, PrintableErrno<String>> {
+        let path = format!("/dev/{}", name);
+        let tty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(&path)
+            .map_err(|io| {
+                printable_error(PROGRAM_NAME, format!("unable to open {}: {}", path, io))
+            })?;
+
+        // Disable echo and line buffering: input is masked by us instead
+        let orig = tcgetattr(tty.as_raw_fd()).printable(
+            PROGRAM_NAME,
+            format!("unable to get attributes of {}", path),
+        )?;
+        let mut raw = orig.clone();
+        raw.local_flags
+            .remove(LocalFlags::ECHO | LocalFlags::ICANON);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        tcsetattr(tty.as_raw_fd(), SetArg::TCSAFLUSH, &raw).printable(
+            PROGRAM_NAME,
+            format!("unable to set attributes of {}", path),
+        )?;
+
+        Ok(Self {
+            name: name.to_string(),
+            tty,
+            orig: Some(orig),
+            input: Secret::new(MAX_PASSPHRASE_LEN),
+            len: 0,
+        })
+    }
+
+    /// Whether this console is a virtual terminal (e.g. `tty1`), as opposed to a serial
+    /// console (e.g. `ttyS0`).
+    fn is_vt(&self) -> bool {
+        match self.name.strip_prefix("tty") {
+            Some(n) => !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()),
+            None => self.name == "console",
+        }
+    }
+
+    fn start(&mut self, prompt: &str) {
+        let _ = self.tty.write_all(prompt.as_bytes());
+    }
+
+    fn finish(&mut self) {
+        let _ = self.tty.write_all(b"\n");
+    }
+
+    /// Handle pending input. Returns `true` once a whole line has been entered.
+    fn read_pending(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 64];
+        let res = loop {
+            let n = match self.tty.read(&mut buf) {
+                Ok(0) => break Ok(false),
+                Ok(n) => n,
+                Err(io) if io.kind() == ErrorKind::WouldBlock => break Ok(false),
+                Err(io) if io.kind() == ErrorKind::Interrupted => continue,
+                Err(io) => break Err(io),
+            };
+            if self.handle_input(&buf[..n]) {
+                break Ok(true);
+            }
+        };
+        zeroize(&mut buf);
+        res
+    }
+
+    fn handle_input(&mut self, bytes: &[u8]) -> bool {
+        let mut echo = Vec::new();
+        let mut done = false;
+        for b in bytes {
+            match *b {
+                b'\r' | b'\n' => {
+                    done = true;
+                    break;
+                }
+                // Backspace or DEL: remove a whole (UTF-8) character
+                0x08 | 0x7f => {
+                    while self.len > 0 {
+                        self.len -= 1;
+                        let b = self.input[self.len];
+                        self.input[self.len] = 0;
+                        if b & 0xc0 != 0x80 {
+                            echo.extend_from_slice(b"\x08 \x08");
+                            break;
+                        }
+                    }
+                }
+                // ^U: clear the line
+                0x15 => {
+                    while self.len > 0 {
+                        self.len -= 1;
+                        if self.input[self.len] & 0xc0 != 0x80 {
+                            echo.extend_from_slice(b"\x08 \x08");
+                        }
+                        self.input[self.len] = 0;
+                    }
+                }
+                b if b < 0x20 => {}
+                b => {
+                    if self.len < MAX_PASSPHRASE_LEN {
+                        self.input[self.len] = b;
+                        self.len += 1;
+                        if b & 0xc0 != 0x80 {
+                            echo.push(b'*');
+                        }
+                    }
+                }
+            }
+        }
+        let _ = self.tty.write_all(&echo);
+        done
+    }
+
+    fn take_input(&mut self) -> Secret {
+        let input = Secret::from(self.input[..self.len].to_vec());
+        zeroize(&mut self.input[..]);
+        self.len = 0;
+        input
+    }
+}
+impl Drop for Console {
+    fn drop(&mut self) {
+        if let Some(orig) = self.orig.take() {
+            // Also discards any input typed after the passphrase
+            let _ = tcsetattr(self.tty.as_raw_fd(), SetArg::TCSAFLUSH, &orig);
+        }
+    }
+}
+
+/// Read a line from whichever console completes one first.
+fn read_line(consoles: &mut [Console]) -> Result<Secret, PrintableErrno<String>> {
+    let poll_error = |io: io::Error| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("error while waiting for passphrase: {}", io),
+        )
+    };
+    let mut poll = Poll::new().map_err(poll_error)?;
+    for (i, console) in consoles.iter().enumerate() {
+        poll.registry()
+            .register(
+                &mut SourceFd(&console.tty.as_raw_fd()),
+                Token(i),
+                Interest::READABLE,
+            )
+            .map_err(poll_error)?;
+    }
+
+    let mut evs = Events::with_capacity(consoles.len());
+    loop {
+        match poll.poll(&mut evs, None) {
+            Ok(()) => {}
+            Err(io) if io.kind() == ErrorKind::Interrupted => continue,
+            Err(io) => return Err(poll_error(io)),
+        }
+        for ev in evs.iter() {
+            let console = &mut consoles[ev.token().0];
+            match console.read_pending() {
+                Ok(true) => return Ok(console.take_input()),
+                Ok(false) => {}
+                Err(io) => {
+                    return Err(printable_error(
+                        PROGRAM_NAME,
+                        format!("unable to read from /dev/{}: {}", console.name, io),
+                    ))
+                }
+            }
+        }
+    }
+}
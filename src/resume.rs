@@ -0,0 +1,179 @@
+//! Resuming from hibernation.
+//!
+//! The hibernation image is located through the same boot-time parameters the kernel uses:
+//!
+//! - `resume=<SOURCE>` sets the swap device holding the image (see
+//!   [PartitionSourceBuilder][crate::mount::PartitionSourceBuilder] for its format). It may
+//!   be a LUKS volume unlocked through `rd.luks.*` (`/dev/mapper/<NAME>`) or an LVM2
+//!   logical volume (`/dev/<VG>/<LV>`).
+//! - `resume_offset=<PAGES>` sets the offset of the swap file holding the image, when
+//!   `resume=` points to the filesystem containing it.
+//! - `noresume` disables resuming altogether.
+//!
+//! No filesystem is mounted until the resume device has been checked: mounting filesystems
+//! that were in use when hibernating would corrupt them once the image is restored. If the
+//! resume device doesn't show up within [RESUME_TIMEOUT] (or `mount-timeout`, if shorter),
+//! booting goes on without resuming.
+
+use crate::{blkid::BlockDevice, PROGRAM_NAME};
+use nix::unistd::{sysconf, SysconfVar};
+use precisej_printable_errno::{printable_error, PrintableErrno};
+use std::{
+    convert::Infallible,
+    fs::{write, File},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+    time::Duration,
+};
+
+/// How long to wait for the resume device before booting without resuming (as systemd's
+/// default device timeout).
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(90);
+
+const SYS_POWER_RESUME: &str = "/sys/power/resume";
+const SYS_POWER_RESUME_OFFSET: &str = "/sys/power/resume_offset";
+
+// Signature the kernel leaves on the swap header when hibernating. Images with other
+// signatures (e.g. uswsusp's) can't be restored by the kernel itself.
+const HIBERNATE_SIG: &[u8] = b"S1SUSPEND";
+
+/// Check whether `device` holds a hibernation image, at `offset` pages from its start for
+/// swap files.
+pub fn has_image(
+    device: &BlockDevice,
+    offset: Option<u64>,
+) -> Result<bool, PrintableErrno<String>> {
+    let path = device.path();
+    let page_size = sysconf(SysconfVar::PAGE_SIZE)
+        .ok()
+        .flatten()
+        .ok_or_else(|| printable_error(PROGRAM_NAME, "unable to get page size".to_string()))?
+        as u64;
+    let dev = File::open(&path).map_err(|io| read_err(&path, io))?;
+    has_image_at(&dev, &path, offset, page_size)
+}
+
+fn read_err(path: &Path, io: io::Error) -> PrintableErrno<String> {
+    printable_error(
+        PROGRAM_NAME,
+        format!("unable to read swap header of {}: {}", path.display(), io),
+    )
+}
+
+// Check for the signature of the swap area at `offset` pages into `dev`
+fn has_image_at(
+    dev: &File,
+    path: &Path,
+    offset: Option<u64>,
+    page_size: u64,
+) -> Result<bool, PrintableErrno<String>> {
+    // The signature sits at the end of the first page of the swap area
+    let sig_offset = offset
+        .unwrap_or(0)
+        .checked_mul(page_size)
+        .and_then(|o| o.checked_add(page_size - 10))
+        .ok_or_else(|| {
+            printable_error(PROGRAM_NAME, "resume_offset is out of bounds".to_string())
+        })?;
+    let mut sig = [0; 10];
+    match dev.read_exact_at(&mut sig, sig_offset) {
+        Ok(()) => Ok(sig.starts_with(HIBERNATE_SIG)),
+        Err(io) if io.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(io) => Err(read_err(path, io)),
+    }
+}
+
+/// Restore the hibernation image on `device` (at `offset` pages from its start for swap
+/// files).
+///
+/// On success, the kernel jumps back into the hibernated system and this function never
+/// returns.
+pub fn resume(
+    device: &BlockDevice,
+    offset: Option<u64>,
+) -> Result<Infallible, PrintableErrno<String>> {
+    let write_err = |file: &str, io: io::Error| {
+        printable_error(PROGRAM_NAME, format!("unable to write to {}: {}", file, io))
+    };
+    if let Some(offset) = offset {
+        write(SYS_POWER_RESUME_OFFSET, offset.to_string())
+            .map_err(|io| write_err(SYS_POWER_RESUME_OFFSET, io))?;
+    }
+    let (major, minor) = device.devnum();
+    write(SYS_POWER_RESUME, format!("{}:{}", major, minor))
+        .map_err(|io| write_err(SYS_POWER_RESUME, io))?;
+
+    Err(printable_error(
+        PROGRAM_NAME,
+        format!(
+            "unable to restore hibernation image on {}",
+            device.path().display()
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestImage;
+
+    const PAGE_SIZE: u64 = 4096;
+
+    // Swap area of `pages` pages, `offset` pages into the image, with signature `sig`
+    fn swap(offset: u64, pages: u64, page_size: u64, sig: &[u8]) -> TestImage {
+        let mut image = vec![0; ((offset + pages) * page_size) as usize];
+        let sig_offset = ((offset + 1) * page_size - 10) as usize;
+        image[sig_offset..sig_offset + sig.len()].copy_from_slice(sig);
+        TestImage::new(&image)
+    }
+
+    fn check(image: &TestImage, offset: Option<u64>, page_size: u64) -> bool {
+        has_image_at(image.file(), Path::new("/dev/test"), offset, page_size).unwrap()
+    }
+
+    #[test]
+    fn swap_partition() {
+        let hibernated = swap(0, 4, PAGE_SIZE, b"S1SUSPEND");
+        assert!(check(&hibernated, None, PAGE_SIZE));
+        assert!(check(&hibernated, Some(0), PAGE_SIZE));
+
+        // Regular swap, and images written by uswsusp
+        assert!(!check(&swap(0, 4, PAGE_SIZE, b"SWAPSPACE2"), None, PAGE_SIZE));
+        assert!(!check(&swap(0, 4, PAGE_SIZE, b"ULSUSPEND"), None, PAGE_SIZE));
+        assert!(!check(&swap(0, 4, PAGE_SIZE, b""), None, PAGE_SIZE));
+    }
+
+    #[test]
+    fn page_size() {
+        // e.g. ppc64 with 64KiB pages
+        let hibernated = swap(0, 2, 65536, b"S1SUSPEND");
+        assert!(check(&hibernated, None, 65536));
+        assert!(!check(&hibernated, None, PAGE_SIZE));
+    }
+
+    #[test]
+    fn swap_file() {
+        // resume_offset is the first page of the swap file within the filesystem
+        let hibernated = swap(1234, 4, PAGE_SIZE, b"S1SUSPEND");
+        assert!(check(&hibernated, Some(1234), PAGE_SIZE));
+        assert!(!check(&hibernated, None, PAGE_SIZE));
+        assert!(!check(&hibernated, Some(1233), PAGE_SIZE));
+
+        // Past the end of the device
+        assert!(!check(&hibernated, Some(1_000_000), PAGE_SIZE));
+        assert!(has_image_at(
+            hibernated.file(),
+            Path::new("/dev/test"),
+            Some(u64::MAX / 2),
+            PAGE_SIZE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn too_small() {
+        let image = TestImage::new(&[0; 100]);
+        assert!(!check(&image, None, PAGE_SIZE));
+    }
+}
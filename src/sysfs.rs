@@ -1,43 +1,206 @@
 //! Linux `sysfs` walker.
+//!
+//! Devices probed by the kernel before [UdevListener][crate::udev::UdevListener] bound its
+//! netlink socket never generate a `uevent` we are able to see. Walking `sysfs` allows us to
+//! "coldplug" them: load their kernel modules and find block devices that already exist.
 
-use crate::{common::ThreadHandle, early_logging::KConsole, module::ModLoading, PROGRAM_NAME};
-use mio::Waker;
+use crate::{
+    block::BlockHandling, common::ThreadHandle, early_logging::KConsole, module::ModLoading,
+    PROGRAM_NAME,
+};
+use mio::{Events, Poll, Token, Waker};
 use precisej_printable_errno::{printable_error, PrintableErrno};
 use std::{
-    sync::{mpsc::channel, Arc},
+    sync::{
+        mpsc::{channel, Sender},
+        Arc,
+    },
     thread,
+    time::Duration,
 };
 
+/// `sysfs` thread event loop waker.
+const SYSFS_THREAD_WAKE_TOKEN: Token = Token(30);
+
+/// Set up the event loop of a `sysfs` thread, sending its waker back to the spawning thread.
+///
+/// Returns `None` if it wasn't possible, in which case the error has already been sent.
+fn setup_evloop(
+    name: &str,
+    tx_waker: Sender<Result<Arc<Waker>, PrintableErrno<String>>>,
+) -> Option<Poll> {
+    let evloop = match Poll::new().map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("error while setting up {} event loop: {}", name, io),
+        )
+    }) {
+        Ok(poll) => poll,
+        Err(e) => {
+            let _ = tx_waker.send(Err(e));
+            return None;
+        }
+    };
+    let waker = match Waker::new(evloop.registry(), SYSFS_THREAD_WAKE_TOKEN).map_err(|io| {
+        printable_error(
+            PROGRAM_NAME,
+            format!("error while setting up {} waker: {}", name, io),
+        )
+    }) {
+        Ok(waker) => Arc::new(waker),
+        Err(e) => {
+            let _ = tx_waker.send(Err(e));
+            return None;
+        }
+    };
+    tx_waker.send(Ok(waker)).ok()?;
+    Some(evloop)
+}
+
+/// Check (without blocking) whether the `sysfs` thread has been asked to stop.
+fn should_stop(evloop: &mut Poll, evs: &mut Events) -> bool {
+    match evloop.poll(evs, Some(Duration::ZERO)) {
+        Ok(()) => evs.iter().any(|ev| ev.token() == SYSFS_THREAD_WAKE_TOKEN),
+        Err(_) => false,
+    }
+}
+
 mod modalias {
-    use crate::module::ModLoading;
-    use mio::Waker;
+    use super::{setup_evloop, should_stop};
+    use crate::{early_logging::KConsole, module::ModLoading};
+    use mio::{Events, Poll, Waker};
     use precisej_printable_errno::PrintableErrno;
-    use std::sync::{mpsc::Sender, Arc};
+    use std::{
+        fs::{read_dir, read_to_string},
+        path::Path,
+        sync::{mpsc::Sender, Arc},
+    };
 
     /// Function called when the `sysfs` modalias thread is spawned.
     pub(super) fn spawn(
-        main_waker: Arc<Waker>,
-        tx_udev_waker: Sender<Result<Arc<Waker>, PrintableErrno<String>>>,
+        _main_waker: Arc<Waker>,
+        tx_mod_waker: Sender<Result<Arc<Waker>, PrintableErrno<String>>>,
         mod_loading: ModLoading,
     ) {
-        todo!()
+        // KConsole has been successfully opened before, so this should never fail.
+        let mut kcon = KConsole::new().unwrap();
+
+        let mut evloop = match setup_evloop("sysfs-modalias", tx_mod_waker) {
+            Some(evloop) => evloop,
+            None => return,
+        };
+        let mut evs = Events::with_capacity(1);
+
+        walk(
+            &mut kcon,
+            Path::new("/sys/devices"),
+            &mut evloop,
+            &mut evs,
+            &mod_loading,
+        );
+    }
+
+    /// Recursively load the modules of every `modalias` under `dir`. Returns `false` if
+    /// the thread has been asked to stop.
+    fn walk(
+        kcon: &mut KConsole,
+        dir: &Path,
+        evloop: &mut Poll,
+        evs: &mut Events,
+        mod_loading: &ModLoading,
+    ) -> bool {
+        if should_stop(evloop, evs) {
+            return false;
+        }
+
+        let entries = match read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return true,
+        };
+        for entry in entries.flatten() {
+            // Don't follow symlinks: sysfs is full of them, and many form loops.
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            if file_type.is_dir() {
+                if !walk(kcon, &entry.path(), evloop, evs, mod_loading) {
+                    return false;
+                }
+            } else if file_type.is_file() && entry.file_name() == "modalias" {
+                let modalias = match read_to_string(entry.path()) {
+                    Ok(modalias) => modalias,
+                    Err(_) => continue,
+                };
+                let modalias = modalias.trim();
+                if modalias.is_empty() {
+                    continue;
+                }
+                // ModAliases keeps track of already processed aliases, so the udev
+                // thread and this thread won't load the same modules twice.
+                if let Err(e) = mod_loading.load_modalias(modalias) {
+                    kerr!(kcon, "{}", e);
+                }
+            }
+        }
+        true
     }
 }
 
 mod walker {
-    use mio::Waker;
+    use super::{setup_evloop, should_stop};
+    use crate::{
+        blkid::enumerate as enumerate_block_devices, block::BlockHandling,
+        early_logging::KConsole,
+    };
+    use mio::{Events, Waker};
     use precisej_printable_errno::PrintableErrno;
     use std::sync::{mpsc::Sender, Arc};
 
     /// Function called when the `sysfs` walker thread is spawned.
     pub(super) fn spawn(
         main_waker: Arc<Waker>,
-        tx_udev_waker: Sender<Result<Arc<Waker>, PrintableErrno<String>>>,
+        tx_walk_waker: Sender<Result<Arc<Waker>, PrintableErrno<String>>>,
+        block_handling: BlockHandling,
     ) {
-        todo!()
+        // KConsole has been successfully opened before, so this should never fail.
+        let mut kcon = KConsole::new().unwrap();
+
+        let mut evloop = match setup_evloop("sysfs-walker", tx_walk_waker) {
+            Some(evloop) => evloop,
+            None => return,
+        };
+        let mut evs = Events::with_capacity(1);
+
+        let names = match enumerate_block_devices() {
+            Ok(names) => names,
+            Err(e) => {
+                kerr!(kcon, "{}", e);
+                return;
+            }
+        };
+        for name in names {
+            if should_stop(&mut evloop, &mut evs) {
+                return;
+            }
+
+            // Hot-plugged devices go through the same path in the udev thread:
+            // BlockHandling makes sure each device is only handled once.
+            match block_handling.add_device(&mut kcon, &name, false) {
+                Ok(true) => {
+                    if let Err(io) = main_waker.wake() {
+                        kcrit!(kcon, "FATAL: error while notifying main thread: {}", io);
+                    }
+                    return;
+                }
+                Ok(false) => {}
+                Err(e) => kerr!(kcon, "{}", e),
+            }
+        }
     }
 }
 
+/// `sysfs` walker.
 #[derive(Debug)]
 pub struct SysfsWalker {
     modaliases_t: ThreadHandle,
@@ -45,9 +208,13 @@ pub struct SysfsWalker {
 }
 impl SysfsWalker {
     /// Construct the `sysfs`-walking threads which will notify when `/system_root` is mounted.
+    ///
+    /// - `sysfs-modalias` loads the (kernel) modules of every device in `/sys/devices`.
+    /// - `sysfs-walker` handles every block device in `/sys/class/block`.
     pub fn walk(
         main_waker: &Arc<Waker>,
         mod_loading: &ModLoading,
+        block_handling: &BlockHandling,
     ) -> Result<Self, PrintableErrno<String>> {
         let modaliases_t = {
             let main_waker_cl = Arc::clone(main_waker);
@@ -68,7 +235,10 @@ impl SysfsWalker {
         let block_t = {
             let main_waker_cl = Arc::clone(main_waker);
             let (tx_walk_waker, rx_walk_waker) = channel();
-            let walk_handle = thread::spawn(move || walker::spawn(main_waker_cl, tx_walk_waker));
+            let block_handling = block_handling.clone();
+            let walk_handle = thread::spawn(move || {
+                walker::spawn(main_waker_cl, tx_walk_waker, block_handling)
+            });
             let walk_waker = rx_walk_waker.recv().map_err(|e| {
                 printable_error(
                     PROGRAM_NAME,
@@ -1,6 +1,9 @@
 //! (Linux) device manager based on `uevent` netlink socket.
 
-use crate::{common::ThreadHandle, early_logging::KConsole, module::ModLoading, PROGRAM_NAME};
+use crate::{
+    block::BlockHandling, common::ThreadHandle, early_logging::KConsole, module::ModLoading,
+    prompt::Prompter, PROGRAM_NAME,
+};
 use mio::{Token, Waker};
 use precisej_printable_errno::{printable_error, PrintableErrno};
 use std::{
@@ -16,7 +19,10 @@ const UDEV_THREAD_UEVENT_NL_TOKEN: Token = Token(21);
 
 mod listener {
     use super::{UDEV_THREAD_UEVENT_NL_TOKEN, UDEV_THREAD_WAKE_TOKEN};
-    use crate::{early_logging::KConsole, module::ModLoading, PROGRAM_NAME};
+    use crate::{
+        block::BlockHandling, early_logging::KConsole, module::ModLoading, prompt::Prompter,
+        PROGRAM_NAME,
+    };
     use kobject_uevent::{ActionType, UEvent};
     use mio::{Events, Interest, Poll, Waker};
     use netlink_sys::{protocols::NETLINK_KOBJECT_UEVENT, Socket, SocketAddr};
@@ -32,6 +38,8 @@ mod listener {
         main_waker: Arc<Waker>,
         tx_udev_waker: Sender<Result<Arc<Waker>, PrintableErrno<String>>>,
         mod_loading: ModLoading,
+        block_handling: BlockHandling,
+        prompter: Prompter,
     ) {
         // KConsole has been successfully opened before, so this should never fail.
         let mut kcon = KConsole::new().unwrap();
@@ -115,7 +123,11 @@ mod listener {
                         // spawn thread for each uevent
                         let main_waker = Arc::clone(&main_waker);
                         let mod_loading = mod_loading.clone();
-                        thread::spawn(move || handle_uevent(main_waker, uevent, mod_loading));
+                        let block_handling = block_handling.clone();
+                        let prompter = prompter.clone();
+                        thread::spawn(move || {
+                            handle_uevent(main_waker, uevent, mod_loading, block_handling, prompter)
+                        });
                     }
                     UDEV_THREAD_WAKE_TOKEN => {
                         // root is already mounted, we can exit
@@ -130,32 +142,68 @@ mod listener {
         }
     }
 
-    fn handle_uevent(main_waker: Arc<Waker>, uevent: UEvent, mut mod_loading: ModLoading) {
+    fn handle_uevent(
+        main_waker: Arc<Waker>,
+        uevent: UEvent,
+        mut mod_loading: ModLoading,
+        block_handling: BlockHandling,
+        prompter: Prompter,
+    ) {
         // KConsole has been successfully opened before, so this should never fail.
         let mut kcon = KConsole::new().unwrap();
 
+        // A keyboard may have been plugged in: wake up any passphrase prompt waiting for one
+        if (uevent.subsystem == "input" || uevent.subsystem == "hidraw")
+            && uevent.action == ActionType::Add
+        {
+            prompter.input_added();
+        }
+
         if let Some(modalias) = uevent.env.get("MODALIAS") {
             handle_uevent_load_modalias(&mut kcon, main_waker, modalias, &mut mod_loading);
         } else if uevent.subsystem == "block" {
-            handle_uevent_block_device(&mut kcon, main_waker, uevent);
+            handle_uevent_block_device(&mut kcon, main_waker, uevent, block_handling);
         } else if uevent.subsystem == "net" {
             handle_uevent_network(&mut kcon, main_waker, uevent);
-        } else if uevent.subsystem == "hidraw" && uevent.action == ActionType::Add {
-            todo!();
         }
     }
 
     fn handle_uevent_load_modalias(
         kcon: &mut KConsole,
-        main_waker: Arc<Waker>,
+        _main_waker: Arc<Waker>,
         modalias: &str,
         mod_loading: &mut ModLoading,
     ) {
-        todo!()
+        if let Err(e) = mod_loading.load_modalias(modalias) {
+            kerr!(kcon, "{}", e);
+        }
     }
 
-    fn handle_uevent_block_device(kcon: &mut KConsole, main_waker: Arc<Waker>, uevent: UEvent) {
-        todo!()
+    fn handle_uevent_block_device(
+        kcon: &mut KConsole,
+        main_waker: Arc<Waker>,
+        uevent: UEvent,
+        block_handling: BlockHandling,
+    ) {
+        let changed = match uevent.action {
+            ActionType::Add => false,
+            ActionType::Change => true,
+            _ => return,
+        };
+        let name = match uevent.devpath.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => return,
+        };
+
+        match block_handling.add_device(kcon, name, changed) {
+            Ok(true) => {
+                if let Err(io) = main_waker.wake() {
+                    kcrit!(kcon, "FATAL: error while notifying main thread: {}", io);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => kerr!(kcon, "{}", e),
+        }
     }
 
     fn handle_uevent_network(kcon: &mut KConsole, main_waker: Arc<Waker>, uevent: UEvent) {
@@ -171,13 +219,17 @@ impl UdevListener {
     pub fn listen(
         main_waker: &Arc<Waker>,
         mod_loading: &ModLoading,
+        block_handling: &BlockHandling,
+        prompter: &Prompter,
     ) -> Result<Self, PrintableErrno<String>> {
         let main_waker = Arc::clone(main_waker);
         let (tx_udev_waker, rx_udev_waker) = channel();
         let mod_loading = mod_loading.clone();
+        let block_handling = block_handling.clone();
+        let prompter = prompter.clone();
 
         let handle = thread::spawn(move || {
-            listener::spawn(main_waker, tx_udev_waker, mod_loading)
+            listener::spawn(main_waker, tx_udev_waker, mod_loading, block_handling, prompter)
         });
         let udev_waker = rx_udev_waker.recv().map_err(|e| {
             printable_error(
@@ -23,6 +23,34 @@ use std::{
     process::id as getpid,
 };
 
+/// Update a CRC-32 (IEEE 802.3, reflected polynomial `0xEDB88320`) with `data`.
+///
+/// No initial value nor final XOR is applied, as their use depends on the format being
+/// checked: GPT starts with `!0` and inverts the result, while LVM2 uses its own seed.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    const fn make_table() -> [u32; 256] {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    }
+    const CRC32_TABLE: [u32; 256] = make_table();
+
+    for b in data {
+        crc = CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
 /// Remove ramfs without touching the target root.
 ///
 /// We check that the current process is PID1 and that the current initramfs root
@@ -287,3 +315,44 @@ pub fn spawn_emergency_shell(kcon: &mut KConsole) -> Result<Infallible, ()> {
 
     Err(())
 }
+
+/// Temporary file holding a device image, standing in for a block device in tests. Deleted
+/// once dropped.
+#[cfg(test)]
+pub struct TestImage {
+    path: PathBuf,
+    file: File,
+}
+#[cfg(test)]
+impl TestImage {
+    /// Write `data` to a new temporary file.
+    pub fn new(data: &[u8]) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "ignited-test-{}-{}",
+            getpid(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, data).unwrap();
+        let file = File::open(&path).unwrap();
+        Self { path, file }
+    }
+
+    /// The image, opened for reading.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Path of the image.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+#[cfg(test)]
+impl Drop for TestImage {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
@@ -103,10 +103,22 @@ mod keymap {
                 continue;
             }
             for j in 0..NR_KEYS {
+                let kb_value = keymap_blob
+                    .get(pos..pos + 2)
+                    .map(|v| u16::from_ne_bytes([v[0], v[1]]))
+                    .ok_or_else(|| {
+                        printable_error(
+                            PROGRAM_NAME,
+                            format!(
+                                "unable to process keymap file at {}: truncated keymap",
+                                keymap_file_path
+                            ),
+                        )
+                    })?;
                 let ke = KbEntry {
                     kb_table: i as u8,
                     kb_index: j as u8,
-                    kb_value: (&keymap_blob[pos] as *const _) as u16,
+                    kb_value,
                 };
                 pos += 2;
 